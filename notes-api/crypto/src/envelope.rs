@@ -0,0 +1,369 @@
+use aes_gcm::{
+    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng, Payload},
+};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The current version of the envelope format.
+pub const VERSION: u8 = 2;
+
+/// The version of envelopes without a key commitment, which can only be
+/// opened using [`open_uncommitted`].
+pub const UNCOMMITTED_VERSION: u8 = 1;
+
+/// The algorithm that new data is encrypted with.
+pub const DEFAULT_ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+
+/// The length of the header: version, algorithm id and key id.
+const HEADER_LEN: usize = 2 + KEY_ID_LEN;
+
+const KEY_ID_LEN: usize = 16;
+
+const COMMITMENT_LEN: usize = 32;
+
+/// The algorithms an envelope can be encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    fn id(&self) -> u8 {
+        match self {
+            Self::Aes256Gcm => 1,
+            Self::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, EnvelopeError> {
+        match id {
+            1 => Ok(Self::Aes256Gcm),
+            2 => Ok(Self::XChaCha20Poly1305),
+            _ => Err(EnvelopeError::UnsupportedAlgorithm(id)),
+        }
+    }
+
+    fn nonce_len(&self) -> usize {
+        match self {
+            Self::Aes256Gcm => 12,
+            Self::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+/// Encrypts `plaintext` with the default algorithm.
+pub fn seal(key: &Key<Aes256Gcm>, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    seal_with(DEFAULT_ALGORITHM, key, plaintext, aad)
+}
+
+/// Encrypts `plaintext` into a self-describing envelope, laid out as
+///
+/// ```text
+/// version (1) | algorithm id (1) | key id (16) | nonce | commitment (32) | ciphertext
+/// ```
+///
+/// The key id is derived from the key, so the wrong key is detected before
+/// decrypting. AES-GCM and ChaCha20-Poly1305 are not key-committing: a crafted
+/// ciphertext can decrypt validly under two keys. The commitment, a MAC over
+/// the header and nonce under the key, ties the envelope to a single key. The
+/// header is authenticated along with `aad`.
+pub fn seal_with(
+    algorithm: Algorithm,
+    key: &Key<Aes256Gcm>,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let mut envelope =
+        Vec::with_capacity(HEADER_LEN + algorithm.nonce_len() + COMMITMENT_LEN + plaintext.len());
+    envelope.push(VERSION);
+    envelope.push(algorithm.id());
+    envelope.extend_from_slice(&key_id(key));
+
+    let aad = [&envelope, aad].concat();
+    let (nonce, ciphertext) = match algorithm {
+        Algorithm::Aes256Gcm => {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = Aes256Gcm::new(key)
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: plaintext,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| EnvelopeError::EncryptionFailed)?;
+            (nonce.to_vec(), ciphertext)
+        }
+        Algorithm::XChaCha20Poly1305 => {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = XChaCha20Poly1305::new(key)
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: plaintext,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| EnvelopeError::EncryptionFailed)?;
+            (nonce.to_vec(), ciphertext)
+        }
+    };
+
+    envelope.extend_from_slice(&nonce);
+    let commitment = commitment(key, &envelope).finalize().into_bytes();
+    envelope.extend_from_slice(&commitment);
+    envelope.extend_from_slice(&ciphertext);
+
+    Ok(envelope)
+}
+
+/// Decrypts an envelope created by [`seal`] or [`seal_with`], after checking
+/// that it commits to `key`.
+pub fn open(key: &Key<Aes256Gcm>, envelope: &[u8], aad: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    let algorithm = algorithm(envelope)?;
+    if envelope[2..HEADER_LEN] != key_id(key) {
+        return Err(EnvelopeError::KeyMismatch);
+    }
+
+    let body_start = HEADER_LEN + algorithm.nonce_len() + COMMITMENT_LEN;
+    if envelope.len() < body_start {
+        return Err(EnvelopeError::InvalidFormat);
+    }
+    let (committed, ciphertext) = envelope.split_at(body_start);
+    let (committed, commitment_tag) = committed.split_at(committed.len() - COMMITMENT_LEN);
+
+    // Compared in constant time
+    commitment(key, committed)
+        .verify_slice(commitment_tag)
+        .map_err(|_| EnvelopeError::CommitmentMismatch)?;
+
+    let (header, nonce) = committed.split_at(HEADER_LEN);
+    decrypt(algorithm, key, header, nonce, ciphertext, aad)
+}
+
+/// Decrypts an envelope of the [`UNCOMMITTED_VERSION`], which has no key
+/// commitment. Only use this to upgrade data that was encrypted before key
+/// commitments were introduced, never to read data supplied by others.
+pub fn open_uncommitted(
+    key: &Key<Aes256Gcm>,
+    envelope: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    if envelope.len() < HEADER_LEN {
+        return Err(EnvelopeError::InvalidFormat);
+    }
+    if envelope[0] != UNCOMMITTED_VERSION {
+        return Err(EnvelopeError::UnsupportedVersion(envelope[0]));
+    }
+    let algorithm = Algorithm::from_id(envelope[1])?;
+    if envelope[2..HEADER_LEN] != key_id(key) {
+        return Err(EnvelopeError::KeyMismatch);
+    }
+
+    let (header, body) = envelope.split_at(HEADER_LEN);
+    if body.len() < algorithm.nonce_len() {
+        return Err(EnvelopeError::InvalidFormat);
+    }
+    let (nonce, ciphertext) = body.split_at(algorithm.nonce_len());
+
+    decrypt(algorithm, key, header, nonce, ciphertext, aad)
+}
+
+fn decrypt(
+    algorithm: Algorithm,
+    key: &Key<Aes256Gcm>,
+    header: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let aad = [header, aad].concat();
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &aad,
+    };
+    match algorithm {
+        Algorithm::Aes256Gcm => Aes256Gcm::new(key).decrypt(Nonce::from_slice(nonce), payload),
+        Algorithm::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key).decrypt(XNonce::from_slice(nonce), payload)
+        }
+    }
+    .map_err(|_| EnvelopeError::DecryptionFailed)
+}
+
+/// The algorithm an envelope was encrypted with.
+pub fn algorithm(envelope: &[u8]) -> Result<Algorithm, EnvelopeError> {
+    if envelope.len() < HEADER_LEN {
+        return Err(EnvelopeError::InvalidFormat);
+    }
+    if envelope[0] != VERSION {
+        return Err(EnvelopeError::UnsupportedVersion(envelope[0]));
+    }
+
+    Algorithm::from_id(envelope[1])
+}
+
+/// Whether an envelope should be re-encrypted to move it to the current
+/// version and default algorithm.
+pub fn is_outdated(envelope: &[u8]) -> bool {
+    is_uncommitted(envelope)
+        || algorithm(envelope).is_ok_and(|algorithm| algorithm != DEFAULT_ALGORITHM)
+}
+
+/// Whether an envelope has no key commitment, and can only be opened using
+/// [`open_uncommitted`].
+pub fn is_uncommitted(envelope: &[u8]) -> bool {
+    envelope.first() == Some(&UNCOMMITTED_VERSION)
+}
+
+fn key_id(key: &Key<Aes256Gcm>) -> [u8; KEY_ID_LEN] {
+    let mut mac = hmac(key);
+    mac.update(b"notes envelope key id");
+
+    let mut key_id = [0u8; KEY_ID_LEN];
+    key_id.copy_from_slice(&mac.finalize().into_bytes()[..KEY_ID_LEN]);
+    key_id
+}
+
+/// The MAC that commits an envelope to its key, over the header and nonce.
+fn commitment(key: &Key<Aes256Gcm>, header_and_nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = hmac(key);
+    mac.update(b"notes envelope commitment");
+    mac.update(header_and_nonce);
+    mac
+}
+
+fn hmac(key: &Key<Aes256Gcm>) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac accepts any key length")
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EnvelopeError {
+    #[error("invalid envelope format")]
+    InvalidFormat,
+
+    #[error("unsupported envelope version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("unsupported envelope algorithm: {0}")]
+    UnsupportedAlgorithm(u8),
+
+    #[error("envelope was encrypted with another key")]
+    KeyMismatch,
+
+    #[error("envelope does not commit to the key")]
+    CommitmentMismatch,
+
+    #[error("encryption failed")]
+    EncryptionFailed,
+
+    #[error("decryption failed")]
+    DecryptionFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{
+        AeadCore, Aes256Gcm, KeyInit,
+        aead::{Aead, OsRng, Payload},
+    };
+
+    use crate::envelope::{self, Algorithm, EnvelopeError, HEADER_LEN};
+
+    #[test]
+    fn seal_open() {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+
+        for algorithm in [Algorithm::Aes256Gcm, Algorithm::XChaCha20Poly1305] {
+            let sealed = envelope::seal_with(algorithm, &key, b"hello, world", b"aad")
+                .expect("failed to seal envelope");
+
+            assert_eq!(
+                envelope::algorithm(&sealed).expect("failed to get algorithm"),
+                algorithm
+            );
+            assert_eq!(
+                envelope::open(&key, &sealed, b"aad").expect("failed to open envelope"),
+                b"hello, world"
+            );
+            assert!(matches!(
+                envelope::open(&key, &sealed, b"other aad"),
+                Err(EnvelopeError::DecryptionFailed)
+            ));
+        }
+    }
+
+    #[test]
+    fn open_with_other_key() {
+        let sealed = envelope::seal(&Aes256Gcm::generate_key(&mut OsRng), b"hello, world", &[])
+            .expect("failed to seal envelope");
+
+        assert!(matches!(
+            envelope::open(&Aes256Gcm::generate_key(&mut OsRng), &sealed, &[]),
+            Err(EnvelopeError::KeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn open_tampered_header() {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let mut sealed = envelope::seal_with(Algorithm::Aes256Gcm, &key, b"hello, world", &[])
+            .expect("failed to seal envelope");
+
+        assert!(envelope::is_outdated(&sealed));
+
+        sealed[0] = 0;
+        assert!(matches!(
+            envelope::open(&key, &sealed, &[]),
+            Err(EnvelopeError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn open_tampered_commitment() {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let mut sealed =
+            envelope::seal(&key, b"hello, world", &[]).expect("failed to seal envelope");
+
+        // The commitment follows the header and the 24 byte nonce
+        sealed[HEADER_LEN + 24] ^= 1;
+        assert!(matches!(
+            envelope::open(&key, &sealed, &[]),
+            Err(EnvelopeError::CommitmentMismatch)
+        ));
+    }
+
+    #[test]
+    fn open_uncommitted() {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+
+        // An envelope of the first version, without a commitment
+        let mut sealed = vec![envelope::UNCOMMITTED_VERSION, 1];
+        sealed.extend_from_slice(&envelope::key_id(&key));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: b"hello, world",
+                    aad: &[sealed.as_slice(), b"aad"].concat(),
+                },
+            )
+            .expect("failed to encrypt");
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        assert!(envelope::is_outdated(&sealed));
+        assert!(matches!(
+            envelope::open(&key, &sealed, b"aad"),
+            Err(EnvelopeError::UnsupportedVersion(1))
+        ));
+        assert_eq!(
+            envelope::open_uncommitted(&key, &sealed, b"aad").expect("failed to open envelope"),
+            b"hello, world"
+        );
+    }
+}
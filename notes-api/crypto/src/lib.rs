@@ -0,0 +1,10 @@
+//! The encryption of notes and note keys, shared by the server and by
+//! clients that encrypt their notes end-to-end.
+
+pub mod envelope;
+pub mod note_keys;
+pub mod notes;
+pub mod secret_key;
+
+pub use envelope::EnvelopeError;
+pub use secret_key::SecretKey;
@@ -0,0 +1,194 @@
+use aes_gcm::{Aes256Gcm, Key, aead::OsRng};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use uuid::Uuid;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    SecretKey,
+    envelope::{self, EnvelopeError},
+};
+
+/// The ids a wrapped note key is bound to: its own id, the note it belongs to
+/// and the user it is wrapped for.
+#[derive(Debug, Clone, Copy)]
+pub struct NoteKeyIds<'a> {
+    pub note_key_id: &'a Uuid,
+    pub note_id: &'a Uuid,
+    pub user_id: &'a Uuid,
+}
+
+impl NoteKeyIds<'_> {
+    /// The associated data a wrapped note key is bound to, so it cannot be
+    /// moved onto another note key row.
+    pub fn associated_data(&self) -> Vec<u8> {
+        [
+            self.note_key_id.as_bytes().as_slice(),
+            self.note_id.as_bytes(),
+            self.user_id.as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// Wraps a note key with the user key.
+pub fn encrypt(
+    user_key: &Key<Aes256Gcm>,
+    note_key: &Key<Aes256Gcm>,
+    ids: NoteKeyIds,
+) -> Result<Vec<u8>, EnvelopeError> {
+    envelope::seal(user_key, note_key, &ids.associated_data())
+}
+
+/// Unwraps a note key wrapped by [`encrypt`].
+pub fn decrypt(
+    user_key: &Key<Aes256Gcm>,
+    encrypted_key: &[u8],
+    ids: NoteKeyIds,
+) -> Result<SecretKey, EnvelopeError> {
+    to_key(envelope::open(
+        user_key,
+        encrypted_key,
+        &ids.associated_data(),
+    )?)
+}
+
+/// Seals a note key for another user's public key. Returns the sealed note
+/// key and the ephemeral public key that is needed to unseal it.
+pub fn seal(
+    public_key: &PublicKey,
+    note_key: &Key<Aes256Gcm>,
+    ids: NoteKeyIds,
+) -> Result<(Vec<u8>, PublicKey), EnvelopeError> {
+    let ephemeral_private_key = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public_key = PublicKey::from(&ephemeral_private_key);
+    let shared_secret = ephemeral_private_key.diffie_hellman(public_key);
+
+    let seal_key = derive_seal_key(shared_secret.as_bytes(), &ephemeral_public_key, public_key);
+    let sealed_key = envelope::seal(seal_key.key(), note_key, &ids.associated_data())?;
+
+    Ok((sealed_key, ephemeral_public_key))
+}
+
+/// Unseals a note key sealed by [`seal`] using the recipient's private key.
+pub fn unseal(
+    private_key: &StaticSecret,
+    ephemeral_public_key: &PublicKey,
+    sealed_key: &[u8],
+    ids: NoteKeyIds,
+) -> Result<SecretKey, EnvelopeError> {
+    let shared_secret = private_key.diffie_hellman(ephemeral_public_key);
+    let seal_key = derive_seal_key(
+        shared_secret.as_bytes(),
+        ephemeral_public_key,
+        &PublicKey::from(private_key),
+    );
+
+    to_key(envelope::open(
+        seal_key.key(),
+        sealed_key,
+        &ids.associated_data(),
+    )?)
+}
+
+/// Derives the key that seals a note key from an X25519 shared secret. Both
+/// public keys are mixed in, so the seal key is bound to this exchange.
+/// Only needed directly to read note keys sealed before the envelope format.
+pub fn derive_seal_key(
+    shared_secret: &[u8],
+    ephemeral_public_key: &PublicKey,
+    public_key: &PublicKey,
+) -> SecretKey {
+    let salt = [
+        ephemeral_public_key.as_bytes().as_slice(),
+        public_key.as_bytes(),
+    ]
+    .concat();
+
+    let mut seal_key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(b"notes note key seal", seal_key.as_mut_slice())
+        .expect("hkdf can expand into a single key");
+
+    SecretKey::from(Key::<Aes256Gcm>::from_slice(seal_key.as_slice()))
+}
+
+/// Moves a decrypted note key into a [`SecretKey`], wiping the buffer.
+fn to_key(buf: Vec<u8>) -> Result<SecretKey, EnvelopeError> {
+    SecretKey::from_slice(&Zeroizing::new(buf)).ok_or(EnvelopeError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{Aes256Gcm, KeyInit, aead::OsRng};
+    use uuid::Uuid;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use crate::note_keys::{self, NoteKeyIds};
+
+    #[test]
+    fn encrypt_decrypt() {
+        let user_key = Aes256Gcm::generate_key(&mut OsRng);
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let (note_key_id, note_id, user_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let ids = NoteKeyIds {
+            note_key_id: &note_key_id,
+            note_id: &note_id,
+            user_id: &user_id,
+        };
+
+        let encrypted_key =
+            note_keys::encrypt(&user_key, &note_key, ids).expect("failed to encrypt note key");
+
+        assert_eq!(
+            *note_keys::decrypt(&user_key, &encrypted_key, ids)
+                .expect("failed to decrypt note key")
+                .key(),
+            note_key
+        );
+        assert!(
+            note_keys::decrypt(
+                &user_key,
+                &encrypted_key,
+                NoteKeyIds {
+                    note_id: &Uuid::new_v4(),
+                    ..ids
+                }
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn seal_unseal() {
+        let private_key = StaticSecret::random_from_rng(OsRng);
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let (note_key_id, note_id, user_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let ids = NoteKeyIds {
+            note_key_id: &note_key_id,
+            note_id: &note_id,
+            user_id: &user_id,
+        };
+
+        let (sealed_key, ephemeral_public_key) =
+            note_keys::seal(&PublicKey::from(&private_key), &note_key, ids)
+                .expect("failed to seal note key");
+
+        assert_eq!(
+            *note_keys::unseal(&private_key, &ephemeral_public_key, &sealed_key, ids)
+                .expect("failed to unseal note key")
+                .key(),
+            note_key
+        );
+        assert!(
+            note_keys::unseal(
+                &StaticSecret::random_from_rng(OsRng),
+                &ephemeral_public_key,
+                &sealed_key,
+                ids
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use aes_gcm::{Aes256Gcm, Key};
+use uuid::Uuid;
+
+use crate::envelope::{self, EnvelopeError};
+
+/// Encrypts the markdown of a note with its note key. The note id is bound as
+/// associated data, so the ciphertext cannot be moved onto another note.
+pub fn encrypt(
+    note_key: &Key<Aes256Gcm>,
+    note_id: &Uuid,
+    markdown: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    envelope::seal(note_key, markdown, note_id.as_bytes())
+}
+
+/// Decrypts the markdown of a note encrypted by [`encrypt`].
+pub fn decrypt(
+    note_key: &Key<Aes256Gcm>,
+    note_id: &Uuid,
+    encrypted_markdown: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    envelope::open(note_key, encrypted_markdown, note_id.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{Aes256Gcm, KeyInit, aead::OsRng};
+    use uuid::Uuid;
+
+    use crate::notes;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let note_id = Uuid::new_v4();
+
+        let encrypted_markdown =
+            notes::encrypt(&note_key, &note_id, b"hello, world").expect("failed to encrypt note");
+
+        assert_eq!(
+            notes::decrypt(&note_key, &note_id, &encrypted_markdown)
+                .expect("failed to decrypt note"),
+            b"hello, world"
+        );
+        assert!(notes::decrypt(&note_key, &Uuid::new_v4(), &encrypted_markdown).is_err());
+    }
+}
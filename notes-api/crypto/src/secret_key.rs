@@ -0,0 +1,83 @@
+use std::fmt;
+
+use aes_gcm::{Aes256Gcm, Key, KeyInit, KeySizeUser, aead::OsRng};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// A 256-bit key that is wiped from memory when dropped. It cannot be cloned,
+/// is compared in constant time and is redacted from its `Debug` output, so
+/// key bytes do not end up in logs or panic messages.
+pub struct SecretKey(Key<Aes256Gcm>);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        Self(Aes256Gcm::generate_key(&mut OsRng))
+    }
+
+    /// Copies a key out of a buffer, which must be exactly 32 bytes long. The
+    /// caller remains responsible for wiping the buffer.
+    pub fn from_slice(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Aes256Gcm::key_size() {
+            return None;
+        }
+
+        Some(Self(*Key::<Aes256Gcm>::from_slice(buf)))
+    }
+
+    pub fn key(&self) -> &Key<Aes256Gcm> {
+        &self.0
+    }
+}
+
+impl From<&Key<Aes256Gcm>> for SecretKey {
+    fn from(key: &Key<Aes256Gcm>) -> Self {
+        Self(*key)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.as_mut_slice().zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretKey {}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_slice().ct_eq(other.0.as_slice()).into()
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SecretKey;
+
+    #[test]
+    fn from_slice() {
+        let secret_key = SecretKey::generate();
+
+        assert_eq!(
+            SecretKey::from_slice(secret_key.key()).expect("failed to copy key"),
+            secret_key
+        );
+        assert!(SecretKey::from_slice(&[0u8; 16]).is_none());
+        assert_ne!(SecretKey::generate(), secret_key);
+    }
+
+    #[test]
+    fn debug_is_redacted() {
+        assert_eq!(
+            format!("{:?}", SecretKey::generate()),
+            "SecretKey([REDACTED])"
+        );
+    }
+}
@@ -0,0 +1,8 @@
+CREATE TABLE user_key_pairs (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL UNIQUE,
+    public_key BLOB NOT NULL,
+    encrypted_private_key BLOB NOT NULL,
+    nonce BLOB NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+)
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE note_keys ADD COLUMN ephemeral_public_key BLOB
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE user_recovery_codes (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL UNIQUE,
+    user_key_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_key_id) REFERENCES user_keys (id) ON DELETE CASCADE
+)
\ No newline at end of file
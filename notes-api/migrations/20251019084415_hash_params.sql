@@ -0,0 +1,3 @@
+-- Rows created before the parameters were stored used the Argon2 defaults
+ALTER TABLE user_passwords ADD COLUMN hash_params TEXT NOT NULL DEFAULT '$argon2id$v=19$m=19456,t=2,p=1';
+ALTER TABLE user_keys ADD COLUMN hash_params TEXT NOT NULL DEFAULT '$argon2id$v=19$m=19456,t=2,p=1';
@@ -0,0 +1,4 @@
+-- Rows encrypted before their ids were bound as associated data are
+-- re-encrypted when their owner signs in
+ALTER TABLE notes ADD COLUMN has_aad BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE note_keys ADD COLUMN has_aad BOOLEAN NOT NULL DEFAULT FALSE;
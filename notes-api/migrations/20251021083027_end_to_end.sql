@@ -0,0 +1,3 @@
+-- Notes that are encrypted end-to-end by the client, and can never be
+-- decrypted by the server
+ALTER TABLE notes ADD COLUMN end_to_end BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+-- Notes, note keys and key pairs are upgraded to envelopes with a key
+-- commitment when their owner signs in, as only then the user key is at hand.
+-- Every decrypt path outside of sign in requires a key commitment, so all
+-- sessions are dropped to have every user sign in, and upgrade, again.
+DELETE FROM user_sessions;
@@ -0,0 +1,15 @@
+CREATE TABLE user_totp (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL UNIQUE,
+    encrypted_secret BLOB NOT NULL,
+    confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+    last_used_step INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE user_totp_backup_codes (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL,
+    hash BLOB NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,21 @@
+CREATE TABLE webauthn_challenges (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID,
+    challenge BLOB NOT NULL,
+    expiration_time TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE user_passkeys (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL,
+    user_key_id UUID NOT NULL,
+    credential_id BLOB NOT NULL UNIQUE,
+    public_key BLOB NOT NULL,
+    sign_count INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    time_created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_time TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_key_id) REFERENCES user_keys (id) ON DELETE CASCADE
+);
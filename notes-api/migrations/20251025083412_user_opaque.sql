@@ -0,0 +1,16 @@
+CREATE TABLE user_opaque_records (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL UNIQUE,
+    user_key_id UUID NOT NULL,
+    password_file BLOB NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_key_id) REFERENCES user_keys (id) ON DELETE CASCADE
+);
+
+CREATE TABLE opaque_logins (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID,
+    state BLOB NOT NULL,
+    expiration_time TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+CREATE TABLE user_access_tokens (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL,
+    user_key_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    time_created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expiration_time TIMESTAMP,
+    last_used_time TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_key_id) REFERENCES user_keys (id) ON DELETE CASCADE
+);
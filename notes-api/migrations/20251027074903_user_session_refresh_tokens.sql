@@ -0,0 +1,8 @@
+CREATE TABLE user_session_refresh_tokens (
+    id UUID PRIMARY KEY NOT NULL,
+    session_id UUID NOT NULL,
+    encrypted_user_key BLOB,
+    time_created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    time_used TIMESTAMP,
+    FOREIGN KEY (session_id) REFERENCES user_sessions (id) ON DELETE CASCADE
+);
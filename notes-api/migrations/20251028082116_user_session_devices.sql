@@ -0,0 +1,5 @@
+ALTER TABLE user_sessions ADD COLUMN time_created TIMESTAMP;
+ALTER TABLE user_sessions ADD COLUMN last_seen_time TIMESTAMP;
+ALTER TABLE user_sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE user_sessions ADD COLUMN ip_address TEXT;
+ALTER TABLE user_sessions ADD COLUMN device_label TEXT;
@@ -0,0 +1,4 @@
+-- The expiration time is the absolute timeout of a session; the idle
+-- expiration time moves forward while the session is used. Existing sessions
+-- only have the absolute timeout.
+ALTER TABLE user_sessions ADD COLUMN idle_expiration_time TIMESTAMP;
@@ -0,0 +1,21 @@
+-- Every attempt to sign in, for the user to review. Attempts with an unknown
+-- username have no user.
+CREATE TABLE login_attempts (
+    id UUID PRIMARY KEY NOT NULL,
+    user_id UUID,
+    username TEXT,
+    ip_address TEXT,
+    method TEXT NOT NULL,
+    succeeded BOOLEAN NOT NULL,
+    time_created TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- The recent failures of an IP address or a username, which lock it out for
+-- longer and longer
+CREATE TABLE login_lockouts (
+    subject TEXT PRIMARY KEY NOT NULL,
+    failure_count INTEGER NOT NULL,
+    last_failure_time TIMESTAMP NOT NULL,
+    locked_until TIMESTAMP
+);
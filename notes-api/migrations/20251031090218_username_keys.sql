@@ -0,0 +1,8 @@
+-- The username key is the case folded NFKC form of the username. It is unique,
+-- so "Alice" and "alice" can no longer both be registered. SQLite cannot
+-- normalise Unicode, so the server fills in the keys of existing users when it
+-- starts, and reports the users whose key collides with another user's. These
+-- keep a missing key, and sign in with their exact username, until they rename.
+ALTER TABLE users ADD COLUMN username_key VARCHAR(256);
+
+CREATE UNIQUE INDEX users_username_key ON users (username_key);
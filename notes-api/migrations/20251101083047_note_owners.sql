@@ -0,0 +1,14 @@
+-- The owner of a note is the user who created it, and the only member who may
+-- revoke the others. The creator of an existing note is not recorded, so it is
+-- taken to be the member with the oldest note key that was not shared with
+-- them.
+ALTER TABLE notes ADD COLUMN owner_id UUID REFERENCES users (id) ON DELETE SET NULL;
+
+UPDATE notes
+SET owner_id = (
+    SELECT user_id
+    FROM note_keys
+    WHERE note_keys.note_id = notes.id
+    ORDER BY ephemeral_public_key IS NOT NULL, rowid
+    LIMIT 1
+);
@@ -0,0 +1,6 @@
+-- Every row written since ids were bound as associated data carries them, and
+-- older rows predate the envelope format, so they are told apart by their
+-- nonce. Rows without associated data are only opened by the upgrade at sign
+-- in, until its deadline, and no row can opt out of associated data anymore.
+ALTER TABLE notes DROP COLUMN has_aad;
+ALTER TABLE note_keys DROP COLUMN has_aad;
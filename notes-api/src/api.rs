@@ -1,33 +1,144 @@
 use std::sync::Arc;
 
 use axum::{
-    Router,
-    routing::{delete, get, post, put},
+    Extension, Router,
+    routing::{MethodRouter, delete, get, patch, post, put},
 };
 
-use crate::state::AppState;
+use crate::{services::user_access_tokens::Scope, state::AppState};
 
+pub mod access_tokens;
 pub mod auth;
+pub mod e2e_notes;
+pub mod login_attempts;
 pub mod notes;
+pub mod opaque;
+pub mod passkeys;
 pub mod users;
 
 pub fn create_router(state: Arc<AppState>) -> Router {
     Router::new().merge(
         Router::new()
             .route("/auth", post(auth::create_user_session_token))
+            .route("/auth/passkey", post(passkeys::create_passkey_challenge))
+            .route("/auth/refresh", post(auth::refresh_user_session_token))
             .route("/users", post(users::create_user))
+            .route("/users/{user_id}", patch(users::update_user))
+            .route("/users/{user_id}", delete(users::delete_user))
             .route(
                 "/users/{user_id}/password",
-                put(users::create_or_update_user_password),
+                put(users::update_user_password),
+            )
+            .route(
+                "/users/{user_id}/recovery-code",
+                post(users::create_user_recovery_code),
+            )
+            .route("/users/{user_id}/totp", post(users::enroll_user_totp))
+            .route("/users/{user_id}/totp", delete(users::delete_user_totp))
+            .route(
+                "/users/{user_id}/totp/confirm",
+                post(users::confirm_user_totp),
+            )
+            .route(
+                "/users/{user_id}/totp/backup-codes",
+                post(users::create_user_totp_backup_codes),
+            )
+            .route(
+                "/users/{user_id}/passkeys",
+                get(passkeys::get_user_passkeys),
+            )
+            .route(
+                "/users/{user_id}/passkeys",
+                post(passkeys::create_user_passkey),
+            )
+            .route(
+                "/users/{user_id}/passkeys/challenge",
+                post(passkeys::create_user_passkey_challenge),
+            )
+            .route(
+                "/users/{user_id}/passkeys/{passkey_id}",
+                delete(passkeys::delete_user_passkey),
+            )
+            .route(
+                "/users/{user_id}/opaque",
+                put(opaque::create_or_update_user_opaque),
+            )
+            .route(
+                "/users/{user_id}/opaque/registration",
+                post(opaque::start_user_opaque_registration),
+            )
+            .route(
+                "/users/{user_id}/access-tokens",
+                get(access_tokens::get_user_access_tokens),
+            )
+            .route(
+                "/users/{user_id}/access-tokens",
+                post(access_tokens::create_user_access_token),
+            )
+            .route(
+                "/users/{user_id}/access-tokens/{access_token_id}",
+                delete(access_tokens::delete_user_access_token),
+            )
+            .route(
+                "/users/{user_id}/login-attempts",
+                get(login_attempts::get_user_login_attempts),
+            )
+            .route("/users/{user_id}/key", post(users::rotate_user_key))
+            .route("/users/{user_id}/sessions", get(users::get_user_sessions))
+            .route(
+                "/users/{user_id}/sessions",
+                delete(users::delete_user_sessions),
             )
             .route(
                 "/users/{user_id}/sessions/{session_id}",
                 delete(users::delete_user_session),
             )
-            .route("/notes", get(notes::get_notes))
-            .route("/notes/{note_id}", get(notes::get_note))
-            .route("/notes/{note_id}", put(notes::create_or_update_note))
-            .route("/notes/{note_id}", delete(notes::delete_note))
+            .route("/notes", scoped(Scope::NotesRead, get(notes::get_notes)))
+            .route(
+                "/notes/{note_id}",
+                scoped(Scope::NotesRead, get(notes::get_note)),
+            )
+            .route(
+                "/notes/{note_id}",
+                scoped(Scope::NotesWrite, put(notes::create_or_update_note)),
+            )
+            .route(
+                "/notes/{note_id}",
+                scoped(Scope::NotesWrite, delete(notes::delete_note)),
+            )
+            .route(
+                "/notes/{note_id}/shares",
+                scoped(Scope::NotesWrite, post(notes::create_note_share)),
+            )
+            .route(
+                "/notes/{note_id}/shares/{user_id}",
+                scoped(Scope::NotesWrite, delete(notes::delete_note_share)),
+            )
+            .route(
+                "/e2e/notes",
+                scoped(Scope::NotesRead, get(e2e_notes::get_notes)),
+            )
+            .route(
+                "/e2e/notes/{note_id}",
+                scoped(Scope::NotesRead, get(e2e_notes::get_note)),
+            )
+            .route(
+                "/e2e/notes/{note_id}",
+                scoped(Scope::NotesWrite, put(e2e_notes::create_or_update_note)),
+            )
+            .route(
+                "/e2e/notes/{note_id}",
+                scoped(Scope::NotesWrite, delete(e2e_notes::delete_note)),
+            )
             .with_state(state.clone()),
     )
 }
+
+/// Lets access tokens with the scope use a route. Routes without a scope,
+/// such as those that manage the account or its tokens, only accept sessions.
+fn scoped<S>(scope: Scope, method_router: MethodRouter<S>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    method_router.layer(Extension(scope))
+}
@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{extractors::auth::Auth, services, state::AppState};
+
+#[derive(Deserialize)]
+pub struct CreateUserAccessTokenRequest {
+    name: String,
+
+    /// Such as `notes:read` and `notes:write`
+    scopes: Vec<String>,
+
+    /// Tokens without an expiration time stay valid until they are revoked
+    expiration_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreateUserAccessTokenResponse {
+    access_token: UserAccessTokenResponse,
+
+    /// The bearer token; it is not shown again
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct GetUserAccessTokensResponse {
+    access_tokens: Vec<UserAccessTokenResponse>,
+}
+
+#[derive(Serialize)]
+pub struct UserAccessTokenResponse {
+    id: Uuid,
+    name: String,
+    scopes: Vec<&'static str>,
+    time_created: Option<DateTime<Utc>>,
+    expiration_time: Option<DateTime<Utc>>,
+    last_used_time: Option<DateTime<Utc>>,
+}
+
+impl From<services::user_access_tokens::UserAccessToken> for UserAccessTokenResponse {
+    fn from(user_access_token: services::user_access_tokens::UserAccessToken) -> Self {
+        Self {
+            id: *user_access_token.id(),
+            name: user_access_token.name().to_string(),
+            scopes: user_access_token
+                .scopes()
+                .iter()
+                .map(services::user_access_tokens::Scope::as_str)
+                .collect(),
+            time_created: user_access_token.time_created().copied(),
+            expiration_time: user_access_token.expiration_time().copied(),
+            last_used_time: user_access_token.last_used_time().copied(),
+        }
+    }
+}
+
+pub async fn create_user_access_token(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<CreateUserAccessTokenRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Validate the scopes and the expiration time
+    let scopes = payload
+        .scopes
+        .iter()
+        .map(|scope| services::user_access_tokens::Scope::parse(scope))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            println!("unknown scope");
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+    if scopes.is_empty() {
+        println!("at least one scope is required");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    if payload
+        .expiration_time
+        .is_some_and(|expiration_time| expiration_time < Utc::now())
+    {
+        println!("expiration time has passed");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Create the token and wrap the user key using its secret
+    let (user_access_token, access_token) = services::user_access_tokens::create(
+        &mut tx,
+        &user_id,
+        user_claims.user_key(),
+        &payload.name,
+        &scopes,
+        payload.expiration_time.as_ref(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to create user access token: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateUserAccessTokenResponse {
+            access_token: UserAccessTokenResponse::from(user_access_token),
+            token: access_token.to_string(),
+        }),
+    ))
+}
+
+pub async fn get_user_access_tokens(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let user_access_tokens = services::user_access_tokens::get_by_user_id(&state.db, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to get user access tokens: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(GetUserAccessTokensResponse {
+            access_tokens: user_access_tokens
+                .into_iter()
+                .map(UserAccessTokenResponse::from)
+                .collect(),
+        }),
+    ))
+}
+
+pub async fn delete_user_access_token(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path((user_id, access_token_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Only access tokens of the user can be revoked
+    let user_access_token = services::user_access_tokens::get_by_id(&mut *tx, &access_token_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get user access token: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    if user_access_token.user_id() != &user_id {
+        println!("resource could not be found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Drop the token and its wrapping of the user key
+    services::user_access_tokens::delete(&mut tx, &access_token_id)
+        .await
+        .map_err(|e| {
+            println!("failed to delete user access token: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
@@ -1,22 +1,129 @@
 use std::sync::Arc;
 
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
-use chrono::Duration;
+use axum::{
+    Json,
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use crypto::SecretKey;
 use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
 use uuid::Uuid;
 
-use crate::{services, state::AppState, tokens};
+use crate::{
+    api::{
+        opaque::decode_export_key,
+        passkeys::{decode_base64url, decode_prf_output},
+    },
+    extractors::client::ClientInfo,
+    services,
+    state::AppState,
+    tokens,
+};
+
+#[derive(Deserialize)]
+pub struct CreateUserSessionRequest {
+    #[serde(flatten)]
+    method: AuthenticationMethod,
+
+    /// Keeps the session until the user signs out, instead of until it has
+    /// not been used for a week or at the latest after 31 days
+    #[serde(default)]
+    persistent: bool,
+
+    /// Names the device in the sessions list; derived from the user agent if
+    /// missing
+    device_label: Option<String>,
+}
 
 #[derive(Deserialize)]
 #[serde(tag = "method", rename_all = "snake_case")]
-pub enum CreateUserSessionRequest {
-    Password { username: String, password: String },
+pub enum AuthenticationMethod {
+    Password {
+        username: String,
+        password: String,
+    },
+    RecoveryCode {
+        username: String,
+        recovery_code: String,
+        new_password: String,
+    },
+
+    /// The second step of a password login of a user with TOTP enabled
+    Totp {
+        challenge_token: String,
+        code: String,
+    },
+
+    /// The second step of a password login, for a user that lost their
+    /// authenticator
+    BackupCode {
+        challenge_token: String,
+        backup_code: String,
+    },
+
+    /// A login with a discoverable passkey, answering a challenge of
+    /// `/auth/passkey`. Binary fields are encoded as unpadded base64url.
+    Passkey {
+        challenge_id: Uuid,
+        credential_id: String,
+        client_data_json: String,
+        authenticator_data: String,
+        signature: String,
+        prf_output: String,
+    },
+
+    /// The first step of an OPAQUE login, which is answered with the
+    /// credential response. Binary fields are encoded as unpadded base64url.
+    OpaqueStart {
+        username: String,
+        credential_request: String,
+    },
+
+    /// The second step of an OPAQUE login. The export key unwraps the user
+    /// key, so the password itself never reaches the server.
+    Opaque {
+        login_id: Uuid,
+        credential_finalization: String,
+        export_key: String,
+    },
 }
 
 #[derive(Serialize)]
 pub struct CreateUserSessionResponse {
     user: UserResponse,
     session: UserSessionResponse,
+
+    /// The replacement of a recovery code that was used to authenticate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_code: Option<String>,
+}
+
+/// Returned instead of a session when the user has to pass a second factor.
+/// The challenge token is passed back with a TOTP code or a backup code.
+#[derive(Serialize)]
+pub struct CreateUserChallengeResponse {
+    challenge: ChallengeResponse,
+}
+
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    token: String,
+    methods: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct CreateOpaqueLoginResponse {
+    opaque: OpaqueLoginResponse,
+}
+
+#[derive(Serialize)]
+pub struct OpaqueLoginResponse {
+    login_id: Uuid,
+    credential_response: String,
 }
 
 #[derive(Serialize)]
@@ -24,15 +131,159 @@ pub struct UserResponse {
     id: Uuid,
 }
 
+/// The session token expires within minutes; the refresh token is traded for
+/// a new pair at `/auth/refresh` and can only be used once.
 #[derive(Serialize)]
 pub struct UserSessionResponse {
     token: String,
+
+    /// Authenticates the routes of notes that are encrypted end-to-end, and
+    /// carries no user key
+    end_to_end_token: String,
+
+    refresh_token: String,
+    expiration_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshUserSessionRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct RefreshUserSessionResponse {
+    session: UserSessionResponse,
+}
+
+impl AuthenticationMethod {
+    /// The name of the method, as recorded with login attempts.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Password { .. } => "password",
+            Self::RecoveryCode { .. } => "recovery_code",
+            Self::Totp { .. } => "totp",
+            Self::BackupCode { .. } => "backup_code",
+            Self::Passkey { .. } => "passkey",
+            Self::OpaqueStart { .. } => "opaque_start",
+            Self::Opaque { .. } => "opaque",
+        }
+    }
 }
 
 pub async fn create_user_session_token(
     State(state): State<Arc<AppState>>,
+    client_info: ClientInfo,
     Json(payload): Json<CreateUserSessionRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<Response, Response> {
+    let username = get_attempted_username(&state, &payload.method).await;
+    let ip_address = client_info.ip_address.clone();
+    let attempt = services::login_attempts::Attempt {
+        username: username.as_deref(),
+        ip_address: ip_address.as_deref(),
+        method: payload.method.name(),
+    };
+
+    // Refuse attempts of a locked out IP address or username before any
+    // secret is verified
+    let mut conn = state.db.acquire().await.map_err(|e| {
+        println!("failed to acquire connection: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    if let Some(locked_until) = services::login_attempts::locked_until(&mut conn, &attempt)
+        .await
+        .map_err(|e| {
+            println!("failed to get login lockout: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?
+    {
+        println!("login attempt is locked out");
+        return Err(retry_after(StatusCode::TOO_MANY_REQUESTS, &locked_until));
+    }
+    drop(conn);
+
+    let mut user_id = None;
+    let result = authenticate(&state, client_info, payload, &mut user_id).await;
+
+    // Record the attempt for the user to review. Failures lock out the IP
+    // address and the username for longer and longer.
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    let locked_until = match (&result, user_id) {
+        (Ok(_), Some(user_id)) => {
+            services::login_attempts::record_success(&mut tx, &attempt, &user_id)
+                .await
+                .map_err(|e| {
+                    println!("failed to record login attempt: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                })?;
+            None
+        }
+        (Err(StatusCode::UNAUTHORIZED), _) => {
+            services::login_attempts::record_failure(&mut tx, &attempt)
+                .await
+                .map_err(|e| {
+                    println!("failed to record login attempt: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                })?
+        }
+        // Challenges are not recorded until the second step is passed
+        _ => None,
+    };
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+
+    match locked_until {
+        Some(locked_until) => Err(retry_after(StatusCode::UNAUTHORIZED, &locked_until)),
+        None => result.map_err(IntoResponse::into_response),
+    }
+}
+
+/// The username an attempt is for, if it is known before the attempt is
+/// verified. Passkey logins only name their user once they succeeded.
+async fn get_attempted_username(state: &AppState, method: &AuthenticationMethod) -> Option<String> {
+    let user_id = match method {
+        AuthenticationMethod::Password { username, .. }
+        | AuthenticationMethod::RecoveryCode { username, .. }
+        | AuthenticationMethod::OpaqueStart { username, .. } => return Some(username.clone()),
+        AuthenticationMethod::Totp {
+            challenge_token, ..
+        }
+        | AuthenticationMethod::BackupCode {
+            challenge_token, ..
+        } => *decrypt_challenge(challenge_token, state).ok()?.user_id(),
+        AuthenticationMethod::Opaque { login_id, .. } => {
+            services::user_opaque::get_login_user_id(&state.db, login_id)
+                .await
+                .ok()??
+        }
+        AuthenticationMethod::Passkey { .. } => return None,
+    };
+
+    services::users::get_by_id(&state.db, &user_id)
+        .await
+        .ok()
+        .map(|user| user.username().to_string())
+}
+
+/// Rejects a request until the lockout ends.
+fn retry_after(status: StatusCode, locked_until: &DateTime<Utc>) -> Response {
+    let seconds = (*locked_until - Utc::now()).num_seconds().max(0) + 1;
+
+    (status, [(header::RETRY_AFTER, seconds.to_string())]).into_response()
+}
+
+/// Verifies the attempt and creates a session, or a challenge for a second
+/// step. The id of the user is set once a session was created.
+async fn authenticate(
+    state: &AppState,
+    client_info: ClientInfo,
+    payload: CreateUserSessionRequest,
+    authenticated_user_id: &mut Option<Uuid>,
+) -> Result<Response, StatusCode> {
     // Start database transaction
     let mut tx = state.db.begin().await.map_err(|e| {
         println!("failed to start transaction: {}", e);
@@ -40,71 +291,704 @@ pub async fn create_user_session_token(
     })?;
 
     // Authenticate user and get user key
-    let (user, user_key) = match payload {
-        CreateUserSessionRequest::Password { username, password } => {
-            let user = services::users::get_by_username(&mut *tx, &username)
+    let (user, user_key, recovery_code) = match payload.method {
+        AuthenticationMethod::Password { username, password } => {
+            // Unknown usernames fail just like wrong passwords
+            let (user, user_password) = services::authentication::authenticate_password(
+                &mut tx,
+                &state.hash_config,
+                &username,
+                &password,
+            )
+            .await
+            .map_err(|e| match e {
+                services::Error::InvalidCredentials => {
+                    println!("invalid credentials");
+                    StatusCode::UNAUTHORIZED
+                }
+                _ => {
+                    println!("failed to authenticate user: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+
+            // User keys without a key commitment are only accepted until the
+            // upgrade deadline
+            let user_key = if AppState::upgrades_uncommitted() {
+                services::user_keys::get_outdated_using_password(
+                    &mut *tx,
+                    &state.hash_config,
+                    user_password.user_key_id(),
+                    &password,
+                )
+                .await
+            } else {
+                services::user_keys::get_using_password(
+                    &mut *tx,
+                    &state.hash_config,
+                    user_password.user_key_id(),
+                    &password,
+                )
+                .await
+            }
+            .map_err(|e| {
+                println!("failed to get user key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+            // Rehash the password and re-wrap the user key if either is
+            // outdated
+            let user_key_is_outdated = services::user_keys::is_outdated(
+                &mut *tx,
+                &state.hash_config,
+                user_password.user_key_id(),
+            )
+            .await
+            .map_err(|e| {
+                println!("failed to get user key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            if user_password.is_outdated(&state.hash_config) || user_key_is_outdated {
+                services::user_passwords::change(
+                    &mut tx,
+                    &state.hash_config,
+                    user.id(),
+                    user_key.key(),
+                    &password,
+                )
+                .await
+                .map_err(|e| {
+                    println!("failed to rehash user password: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            }
+
+            // Users with TOTP enabled have to pass the second step first
+            if let Some(response) =
+                create_totp_challenge(&mut tx, state, &user, &user_key, None).await?
+            {
+                // Commit database transaction
+                tx.commit().await.map_err(|e| {
+                    println!("failed to commit transaction: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+                return Ok(response);
+            }
+
+            (user, user_key, None)
+        }
+        AuthenticationMethod::RecoveryCode {
+            username,
+            recovery_code,
+            new_password,
+        } => {
+            let recovery_code = services::user_recovery_codes::RecoveryCode::parse(&recovery_code)
+                .map_err(|e| match e {
+                    services::Error::InvalidCredentials => {
+                        println!("invalid recovery code");
+                        StatusCode::UNAUTHORIZED
+                    }
+                    _ => {
+                        println!("failed to parse recovery code: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                })?;
+
+            // The recovery code can only unwrap the user key if it is correct,
+            // and unknown usernames fail just like wrong codes
+            let (user, user_key) = services::authentication::authenticate_recovery_code(
+                &mut tx,
+                &state.hash_config,
+                &username,
+                &recovery_code,
+            )
+            .await
+            .map_err(|e| match e {
+                services::Error::InvalidCredentials => {
+                    println!("invalid credentials");
+                    StatusCode::UNAUTHORIZED
+                }
+                _ => {
+                    println!("failed to authenticate user: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+
+            // Users with TOTP enabled have to pass the second step first, so
+            // the password is only reset once they have
+            if let Some(response) =
+                create_totp_challenge(&mut tx, state, &user, &user_key, Some(&new_password)).await?
+            {
+                // Commit database transaction
+                tx.commit().await.map_err(|e| {
+                    println!("failed to commit transaction: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+                return Ok(response);
+            }
+
+            let recovery_code =
+                reset_password(&mut tx, state, &user, &user_key, &new_password).await?;
+            (user, user_key, Some(recovery_code))
+        }
+        AuthenticationMethod::Totp {
+            challenge_token,
+            code,
+        } => {
+            let challenge_claims = decrypt_challenge(&challenge_token, state)?;
+            let user = services::users::get_by_id(&mut *tx, challenge_claims.user_id())
                 .await
                 .map_err(|e| {
                     println!("failed to get user: {}", e);
                     StatusCode::INTERNAL_SERVER_ERROR
                 })?;
 
-            let user_password = services::user_passwords::get_by_user_id(&mut *tx, user.id())
+            // The code can only be used once
+            if !services::user_totp::verify(&mut tx, user.id(), challenge_claims.user_key(), &code)
                 .await
                 .map_err(|e| {
-                    println!("failed to get user password: {}", e);
+                    println!("failed to verify totp code: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?
+            {
+                println!("incorrect totp code");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+
+            let user_key = services::user_keys::UserKey::from(challenge_claims.user_key());
+            let recovery_code = match challenge_claims.new_password() {
+                Some(new_password) => {
+                    Some(reset_password(&mut tx, state, &user, &user_key, new_password).await?)
+                }
+                None => None,
+            };
+            (user, user_key, recovery_code)
+        }
+        AuthenticationMethod::BackupCode {
+            challenge_token,
+            backup_code,
+        } => {
+            let challenge_claims = decrypt_challenge(&challenge_token, state)?;
+            let user = services::users::get_by_id(&mut *tx, challenge_claims.user_id())
+                .await
+                .map_err(|e| {
+                    println!("failed to get user: {}", e);
                     StatusCode::INTERNAL_SERVER_ERROR
                 })?;
-            if !user_password.verify(&password).map_err(|e| {
-                println!("failed to verify user password: {}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })? {
-                println!("incorrect password");
+
+            // The backup code is used up
+            let backup_code =
+                services::user_totp::BackupCode::parse(&backup_code).map_err(|e| match e {
+                    services::Error::InvalidCredentials => {
+                        println!("invalid backup code");
+                        StatusCode::UNAUTHORIZED
+                    }
+                    _ => {
+                        println!("failed to parse backup code: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                })?;
+            if !services::user_totp::use_backup_code(&mut *tx, user.id(), &backup_code)
+                .await
+                .map_err(|e| {
+                    println!("failed to use backup code: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?
+            {
+                println!("incorrect backup code");
                 return Err(StatusCode::UNAUTHORIZED);
             }
 
-            let user_key = services::user_keys::get_using_password(
-                &mut *tx,
-                user_password.user_key_id(),
-                &password,
+            let user_key = services::user_keys::UserKey::from(challenge_claims.user_key());
+            let recovery_code = match challenge_claims.new_password() {
+                Some(new_password) => {
+                    Some(reset_password(&mut tx, state, &user, &user_key, new_password).await?)
+                }
+                None => None,
+            };
+            (user, user_key, recovery_code)
+        }
+        AuthenticationMethod::Passkey {
+            challenge_id,
+            credential_id,
+            client_data_json,
+            authenticator_data,
+            signature,
+            prf_output,
+        } => {
+            let assertion = services::user_passkeys::PasskeyAssertion {
+                challenge_id,
+                credential_id: decode_base64url("credential id", &credential_id)?,
+                client_data_json: decode_base64url("client data", &client_data_json)?,
+                authenticator_data: decode_base64url("authenticator data", &authenticator_data)?,
+                signature: decode_base64url("signature", &signature)?,
+                prf_output: decode_prf_output(&prf_output)?,
+            };
+
+            // The passkey verifies the user itself, so no second factor is
+            // asked for. The PRF output can only unwrap the user key if it
+            // is correct.
+            let (user_id, user_key) = services::user_passkeys::authenticate(
+                &mut tx,
+                &state.relying_party,
+                &state.hash_config,
+                &assertion,
             )
             .await
-            .map_err(|e| {
-                println!("failed to get user key: {}", e);
+            .map_err(|e| match e {
+                services::Error::NotFound
+                | services::Error::InvalidPasskey(_)
+                | services::Error::DecryptionFailed => {
+                    println!("invalid passkey: {}", e);
+                    StatusCode::UNAUTHORIZED
+                }
+                _ => {
+                    println!("failed to authenticate with passkey: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+
+            let user = services::users::get_by_id(&mut *tx, &user_id)
+                .await
+                .map_err(|e| {
+                    println!("failed to get user: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+            (user, user_key, None)
+        }
+        AuthenticationMethod::OpaqueStart {
+            username,
+            credential_request,
+        } => {
+            let credential_request = decode_base64url("credential request", &credential_request)?;
+
+            let opaque_login = services::user_opaque::start_login(
+                &mut tx,
+                &state.opaque_setup,
+                &username,
+                &credential_request,
+            )
+            .await
+            .map_err(|e| match e {
+                services::Error::InvalidOpaqueMessage(_) => {
+                    println!("invalid credential request: {}", e);
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                _ => {
+                    println!("failed to start opaque login: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+
+            // Commit database transaction
+            tx.commit().await.map_err(|e| {
+                println!("failed to commit transaction: {}", e);
                 StatusCode::INTERNAL_SERVER_ERROR
             })?;
 
-            (user, user_key)
+            return Ok((
+                StatusCode::ACCEPTED,
+                Json(CreateOpaqueLoginResponse {
+                    opaque: OpaqueLoginResponse {
+                        login_id: *opaque_login.id(),
+                        credential_response: BASE64_URL_SAFE_NO_PAD
+                            .encode(opaque_login.credential_response()),
+                    },
+                }),
+            )
+                .into_response());
+        }
+        AuthenticationMethod::Opaque {
+            login_id,
+            credential_finalization,
+            export_key,
+        } => {
+            let credential_finalization =
+                decode_base64url("credential finalization", &credential_finalization)?;
+            let export_key = decode_export_key(&export_key)?;
+
+            // The export key can only unwrap the user key if the client
+            // derived it from the right password
+            let (user_id, user_key) = services::user_opaque::finish_login(
+                &mut tx,
+                &login_id,
+                &credential_finalization,
+                &export_key,
+            )
+            .await
+            .map_err(|e| match e {
+                services::Error::NotFound
+                | services::Error::InvalidOpaqueMessage(_)
+                | services::Error::DecryptionFailed => {
+                    println!("invalid opaque login: {}", e);
+                    StatusCode::UNAUTHORIZED
+                }
+                _ => {
+                    println!("failed to finish opaque login: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+
+            let user = services::users::get_by_id(&mut *tx, &user_id)
+                .await
+                .map_err(|e| {
+                    println!("failed to get user: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+            // Users with TOTP enabled have to pass the second step first
+            if let Some(response) =
+                create_totp_challenge(&mut tx, state, &user, &user_key, None).await?
+            {
+                // Commit database transaction
+                tx.commit().await.map_err(|e| {
+                    println!("failed to commit transaction: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+                return Ok(response);
+            }
+
+            (user, user_key, None)
         }
     };
 
+    *authenticated_user_id = Some(*user.id());
+
+    // Create the user's key pair for note sharing, unless it already exists
+    match services::user_key_pairs::get_by_user_id(&mut *tx, user.id()).await {
+        Ok(_) => {}
+        Err(services::Error::NotFound) => {
+            let user_key_pair = services::user_key_pairs::UserKeyPair::new();
+            services::user_key_pairs::store(
+                &mut *tx,
+                user_key_pair.encrypt(user_key.key()).map_err(|e| {
+                    println!("failed to encrypt user key pair: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?,
+                user.id(),
+            )
+            .await
+            .map_err(|e| {
+                println!("failed to store user key pair: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        }
+        Err(e) => {
+            println!("failed to get user key pair: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
     // Create user session
-    let user_session = services::user_sessions::UserSession::new(Duration::days(31));
-    services::user_sessions::store(&mut *tx, &user_session, user.id())
+    let user_session = new_session(
+        payload.persistent,
+        services::user_sessions::SessionDevice::new(
+            client_info.user_agent,
+            client_info.ip_address,
+            payload.device_label,
+        ),
+    );
+    let reencrypt_key = SecretKey::from(user_key.key());
+    let session = create_session(
+        &mut tx,
+        state,
+        user.id(),
+        &user_session,
+        user_key.into_key(),
+    )
+    .await?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Re-encrypt outdated notes, note keys and the key pair in the background,
+    // while the user key is at hand
+    tokio::spawn(reencrypt_outdated(
+        state.db.clone(),
+        *user.id(),
+        reencrypt_key,
+        AppState::upgrades_uncommitted(),
+    ));
+
+    Ok((
+        StatusCode::OK,
+        Json(CreateUserSessionResponse {
+            user: UserResponse { id: *user.id() },
+            session,
+            recovery_code,
+        }),
+    )
+        .into_response())
+}
+
+/// Takes its own copy of the user key, which is wiped when the task is done.
+async fn reencrypt_outdated(
+    db: SqlitePool,
+    user_id: Uuid,
+    user_key: SecretKey,
+    upgrade_uncommitted: bool,
+) {
+    let result = async {
+        let mut tx = db.begin().await?;
+        let failures = services::notes::reencrypt_outdated(
+            &mut tx,
+            &user_id,
+            user_key.key(),
+            upgrade_uncommitted,
+        )
+        .await?;
+        tx.commit().await?;
+        anyhow::Ok(failures)
+    }
+    .await;
+
+    match result {
+        Ok(0) => {}
+        Ok(failures) => println!(
+            "skipped {} outdated items of user {} that failed to re-encrypt",
+            failures, user_id
+        ),
+        Err(e) => println!("failed to re-encrypt outdated notes: {}", e),
+    }
+}
+
+pub async fn refresh_user_session_token(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RefreshUserSessionRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let refresh_token =
+        services::user_session_refresh_tokens::RefreshToken::parse(&payload.refresh_token)
+            .ok_or_else(|| {
+                println!("invalid refresh token");
+                StatusCode::UNAUTHORIZED
+            })?;
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Trade the refresh token in for a new one
+    let refresh = services::user_session_refresh_tokens::refresh(&mut tx, &refresh_token)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound | services::Error::DecryptionFailed => {
+                println!("invalid refresh token: {}", e);
+                StatusCode::UNAUTHORIZED
+            }
+            _ => {
+                println!("failed to refresh user session: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Commit database transaction, which also keeps the revocation of a
+    // session whose refresh token was reused
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let services::user_session_refresh_tokens::Refresh::Rotated {
+        session_id,
+        user_id,
+        user_key,
+        refresh_token,
+    } = refresh
+    else {
+        println!("refresh token was reused; session revoked");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let user_claims = tokens::UserClaims::new(session_id, user_id, user_key);
+    Ok((
+        StatusCode::OK,
+        Json(RefreshUserSessionResponse {
+            session: encrypt_session(&state, &user_claims, &refresh_token)?,
+        }),
+    ))
+}
+
+/// A new session on the device, which times out unless it is persistent.
+pub(super) fn new_session(
+    persistent: bool,
+    device: services::user_sessions::SessionDevice,
+) -> services::user_sessions::UserSession {
+    if persistent {
+        services::user_sessions::UserSession::new_persistent()
+    } else {
+        services::user_sessions::UserSession::new()
+    }
+    .with_device(device)
+}
+
+/// Stores a new session of the user along with its first refresh token, and
+/// wraps the session id, user id and user key in a session token.
+pub(super) async fn create_session(
+    conn: &mut SqliteConnection,
+    state: &AppState,
+    user_id: &Uuid,
+    user_session: &services::user_sessions::UserSession,
+    user_key: SecretKey,
+) -> Result<UserSessionResponse, StatusCode> {
+    services::user_sessions::store(&mut *conn, user_session, user_id)
         .await
         .map_err(|e| {
             println!("failed to store user session: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // Commit database transaction
-    tx.commit().await.map_err(|e| {
-        println!("failed to commit transaction: {}", e);
+    let refresh_token = services::user_session_refresh_tokens::create(
+        &mut *conn,
+        user_session.id(),
+        user_key.key(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to create refresh token: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    let user_claims = tokens::UserClaims::new(*user_session.id(), *user_id, user_key);
+    encrypt_session(state, &user_claims, &refresh_token)
+}
+
+fn encrypt_session(
+    state: &AppState,
+    user_claims: &tokens::UserClaims,
+    refresh_token: &services::user_session_refresh_tokens::RefreshToken,
+) -> Result<UserSessionResponse, StatusCode> {
     // Wrap user session id, user id and user key in a JWT/JWE
-    let user_claims = tokens::UserClaims::new(*user_session.id(), *user.id(), *user_key.key());
-    let jwt = tokens::encrypt(&user_claims, &state.jwk).map_err(|e| {
+    let jwt = tokens::encrypt(user_claims, &state.key_ring).map_err(|e| {
         println!("failed to encrypt user claims: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    Ok((
-        StatusCode::OK,
-        Json(CreateUserSessionResponse {
-            user: UserResponse { id: *user.id() },
-            session: UserSessionResponse { token: jwt },
-        }),
+    let end_to_end_jwt = tokens::encrypt_end_to_end(&user_claims.end_to_end(), &state.key_ring)
+        .map_err(|e| {
+            println!("failed to encrypt end-to-end claims: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(UserSessionResponse {
+        token: jwt,
+        end_to_end_token: end_to_end_jwt,
+        refresh_token: refresh_token.to_string(),
+        expiration_time: *user_claims.expiration_time(),
+    })
+}
+
+/// Users with TOTP enabled get a short-lived challenge instead of a session,
+/// which carries the user key to the second step. A password chosen with a
+/// recovery code is carried along, and only set once the second step passes.
+async fn create_totp_challenge(
+    conn: &mut SqliteConnection,
+    state: &AppState,
+    user: &services::users::User,
+    user_key: &services::user_keys::UserKey,
+    new_password: Option<&str>,
+) -> Result<Option<Response>, StatusCode> {
+    if !services::user_totp::is_enabled(&mut *conn, user.id())
+        .await
+        .map_err(|e| {
+            println!("failed to get user totp: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    {
+        return Ok(None);
+    }
+
+    let mut challenge_claims = tokens::ChallengeClaims::new(
+        *user.id(),
+        SecretKey::from(user_key.key()),
+        Duration::minutes(5),
+    );
+    if let Some(new_password) = new_password {
+        challenge_claims = challenge_claims.with_new_password(new_password);
+    }
+    let challenge_token =
+        tokens::encrypt_challenge(&challenge_claims, &state.key_ring).map_err(|e| {
+            println!("failed to encrypt challenge claims: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Some(
+        (
+            StatusCode::ACCEPTED,
+            Json(CreateUserChallengeResponse {
+                challenge: ChallengeResponse {
+                    token: challenge_token,
+                    methods: vec!["totp", "backup_code"],
+                },
+            }),
+        )
+            .into_response(),
     ))
 }
+
+/// Resets the password of a user that signed in with a recovery code, and
+/// replaces the used recovery code, as it has been revealed. Returns the new
+/// recovery code.
+async fn reset_password(
+    conn: &mut SqliteConnection,
+    state: &AppState,
+    user: &services::users::User,
+    user_key: &services::user_keys::UserKey,
+    new_password: &str,
+) -> Result<String, StatusCode> {
+    // Reset the password by wrapping the user key using the new password
+    services::user_passwords::reset(
+        &mut *conn,
+        &state.hash_config,
+        user.id(),
+        user_key.key(),
+        new_password,
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to change user password: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let recovery_code = services::user_recovery_codes::replace(
+        &mut *conn,
+        &state.hash_config,
+        user.id(),
+        user_key.key(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to replace user recovery code: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(recovery_code.to_string())
+}
+
+fn decrypt_challenge(
+    challenge_token: &str,
+    state: &AppState,
+) -> Result<tokens::ChallengeClaims, StatusCode> {
+    tokens::decrypt_challenge(challenge_token.as_bytes(), &state.key_ring).map_err(|e| match e {
+        tokens::TokenDecryptionError::InvalidKey | tokens::TokenDecryptionError::Expired => {
+            println!("invalid challenge token: {}", e);
+            StatusCode::UNAUTHORIZED
+        }
+        tokens::TokenDecryptionError::InvalidClaim(_) => {
+            println!("invalid challenge token: {}", e);
+            StatusCode::BAD_REQUEST
+        }
+        tokens::TokenDecryptionError::Internal => {
+            println!("failed to decrypt challenge token: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })
+}
@@ -0,0 +1,360 @@
+//! Routes for notes that are encrypted end-to-end. The client encrypts notes
+//! and wraps note keys itself, using the `crypto` crate, so the server only
+//! stores and returns envelopes and never holds the keys to open them. The
+//! routes accept the end-to-end token of a session, which carries no user key.
+
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{extractors::auth::EndToEndAuth, services, state::AppState};
+
+#[derive(Deserialize)]
+pub struct CreateOrUpdateNoteRequest {
+    encrypted_markdown: String,
+
+    /// The note key wrapped by the client, required when creating a note
+    note_key: Option<NoteKeyRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct NoteKeyRequest {
+    id: Uuid,
+    encrypted_key: String,
+}
+
+pub async fn create_or_update_note(
+    State(state): State<Arc<AppState>>,
+    EndToEndAuth(end_to_end_claims): EndToEndAuth,
+    Path(note_id): Path<Uuid>,
+    Json(payload): Json<CreateOrUpdateNoteRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Decode and check the note envelope
+    let note = services::notes::EncryptedNote::end_to_end(
+        note_id,
+        BASE64_STANDARD
+            .decode(&payload.encrypted_markdown)
+            .map_err(|e| {
+                println!("failed to decode encrypted markdown: {}", e);
+                StatusCode::BAD_REQUEST
+            })?,
+    )
+    .map_err(|e| {
+        println!("invalid encrypted markdown: {}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Update or create note
+    let status = match services::notes::get_by_id(&mut *tx, &note_id).await {
+        // Update existing note
+        Ok(existing_note) => {
+            // Notes encrypted by the server are only served by the other routes
+            if !existing_note.is_end_to_end() {
+                println!("note is not encrypted end-to-end");
+                return Err(StatusCode::CONFLICT);
+            }
+
+            // Check that the user has access to the note
+            services::note_keys::get(&mut *tx, &note_id, end_to_end_claims.user_id())
+                .await
+                .map_err(|e| match e {
+                    services::Error::NotFound => {
+                        println!("access denied");
+                        StatusCode::FORBIDDEN
+                    }
+                    _ => {
+                        println!("failed to get note key: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                })?;
+
+            // Store note
+            services::notes::store(&mut *tx, note).await.map_err(|e| {
+                println!("failed to store note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+            StatusCode::OK
+        }
+
+        // Create new note
+        Err(services::Error::NotFound) => {
+            // Decode and check the note key envelope
+            let note_key = payload.note_key.ok_or_else(|| {
+                println!("note key is required to create a note");
+                StatusCode::UNPROCESSABLE_ENTITY
+            })?;
+            let note_key = services::note_keys::EncryptedNoteKey::end_to_end(
+                note_key.id,
+                BASE64_STANDARD
+                    .decode(&note_key.encrypted_key)
+                    .map_err(|e| {
+                        println!("failed to decode encrypted note key: {}", e);
+                        StatusCode::BAD_REQUEST
+                    })?,
+            )
+            .map_err(|e| {
+                println!("invalid encrypted note key: {}", e);
+                StatusCode::UNPROCESSABLE_ENTITY
+            })?;
+
+            // Store note
+            services::notes::store(&mut *tx, note).await.map_err(|e| {
+                println!("failed to store note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+            // Store note key
+            services::note_keys::store(&mut *tx, note_key, &note_id, end_to_end_claims.user_id())
+                .await
+                .map_err(|e| {
+                    println!("failed to store note key: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+            // The creator owns the note
+            services::notes::set_owner_id(&mut *tx, &note_id, Some(end_to_end_claims.user_id()))
+                .await
+                .map_err(|e| {
+                    println!("failed to set note owner: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
+            StatusCode::CREATED
+        }
+
+        // Internal error
+        Err(e) => {
+            println!("failed to get note: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(status)
+}
+
+#[derive(Serialize)]
+pub struct GetNotesResponse {
+    data: Vec<NoteResponse>,
+}
+
+#[derive(Serialize)]
+pub struct NoteResponse {
+    id: Uuid,
+    encrypted_markdown: String,
+    note_key: NoteKeyResponse,
+    time_created: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct NoteKeyResponse {
+    id: Uuid,
+    encrypted_key: String,
+}
+
+impl NoteResponse {
+    fn new(
+        note: &services::notes::EncryptedNote,
+        note_key: &services::note_keys::EncryptedNoteKey,
+    ) -> Self {
+        Self {
+            id: *note.id(),
+            encrypted_markdown: BASE64_STANDARD.encode(note.encrypted_markdown()),
+            note_key: NoteKeyResponse {
+                id: *note_key.id(),
+                encrypted_key: BASE64_STANDARD.encode(note_key.encrypted_key()),
+            },
+            time_created: *note.time_created(),
+        }
+    }
+}
+
+pub async fn get_notes(
+    State(state): State<Arc<AppState>>,
+    EndToEndAuth(end_to_end_claims): EndToEndAuth,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Get note keys
+    let mut notes = vec![];
+    for link in services::note_keys::search(&mut *tx, end_to_end_claims.user_id())
+        .await
+        .map_err(|e| {
+            println!("failed to search user keys: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    {
+        // Get note
+        let note = services::notes::get_by_id(&mut *tx, &link.note_id)
+            .await
+            .map_err(|e| {
+                println!("failed to get note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+        // Notes encrypted by the server are only listed by the other routes
+        if !note.is_end_to_end() {
+            continue;
+        }
+
+        notes.push(NoteResponse::new(&note, &link.note_key));
+    }
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(GetNotesResponse { data: notes }))
+}
+
+pub async fn get_note(
+    State(state): State<Arc<AppState>>,
+    EndToEndAuth(end_to_end_claims): EndToEndAuth,
+    Path(note_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Get note
+    let note = services::notes::get_by_id(&mut *tx, &note_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource cannot be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Notes encrypted by the server are only served by the other routes
+    if !note.is_end_to_end() {
+        println!("note is not encrypted end-to-end");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Get note key
+    let note_key = services::note_keys::get(&mut *tx, &note_id, end_to_end_claims.user_id())
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("access denied");
+                StatusCode::FORBIDDEN
+            }
+            _ => {
+                println!("failed to get note key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(NoteResponse::new(&note, &note_key)))
+}
+
+pub async fn delete_note(
+    State(state): State<Arc<AppState>>,
+    EndToEndAuth(end_to_end_claims): EndToEndAuth,
+    Path(note_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Get note
+    let note = services::notes::get_by_id(&mut *tx, &note_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Notes encrypted by the server are only served by the other routes
+    if !note.is_end_to_end() {
+        println!("note is not encrypted end-to-end");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Delete note key, which fails if the user has no access to the note
+    services::note_keys::delete(&mut *tx, &note_id, end_to_end_claims.user_id())
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("access denied");
+                StatusCode::FORBIDDEN
+            }
+            _ => {
+                println!("failed to delete note key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Delete note, unless it is still shared with other users
+    if services::note_keys::search_by_note_id(&mut *tx, &note_id)
+        .await
+        .map_err(|e| {
+            println!("failed to search note keys: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .is_empty()
+    {
+        services::notes::delete(&mut *tx, &note_id)
+            .await
+            .map_err(|e| {
+                println!("failed to delete note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    }
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{extractors::auth::Auth, services, state::AppState};
+
+#[derive(Serialize)]
+pub struct GetUserLoginAttemptsResponse {
+    login_attempts: Vec<LoginAttemptResponse>,
+}
+
+#[derive(Serialize)]
+pub struct LoginAttemptResponse {
+    id: Uuid,
+    ip_address: Option<String>,
+    method: String,
+    succeeded: bool,
+    time_created: DateTime<Utc>,
+}
+
+impl From<services::login_attempts::LoginAttempt> for LoginAttemptResponse {
+    fn from(login_attempt: services::login_attempts::LoginAttempt) -> Self {
+        Self {
+            id: *login_attempt.id(),
+            ip_address: login_attempt.ip_address().map(str::to_string),
+            method: login_attempt.method().to_string(),
+            succeeded: login_attempt.succeeded(),
+            time_created: *login_attempt.time_created(),
+        }
+    }
+}
+
+pub async fn get_user_login_attempts(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let login_attempts = services::login_attempts::get_by_user_id(&state.db, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to get login attempts: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(GetUserLoginAttemptsResponse {
+            login_attempts: login_attempts
+                .into_iter()
+                .map(LoginAttemptResponse::from)
+                .collect(),
+        }),
+    ))
+}
@@ -33,7 +33,14 @@ pub async fn create_or_update_note(
     let status = match services::notes::get_by_id(&mut *tx, &note_id).await {
         // Update existing note
         Ok(note) => {
-            // Get and decrypt note key (decryption should not fail)
+            // Notes encrypted by the client are only served by the end-to-end
+            // routes
+            if note.is_end_to_end() {
+                println!("note is encrypted end-to-end");
+                return Err(StatusCode::CONFLICT);
+            }
+
+            // Get note key
             let note_key = services::note_keys::get(&mut *tx, &note_id, user_claims.user_id())
                 .await
                 .map_err(|e| match e {
@@ -45,13 +52,22 @@ pub async fn create_or_update_note(
                         println!("failed to get note key: {}", e);
                         StatusCode::INTERNAL_SERVER_ERROR
                     }
-                })?
-                .decrypt(user_claims.user_key())
-                .map_err(|e| {
-                    println!("failed to decrypt note key: {}", e);
-                    StatusCode::INTERNAL_SERVER_ERROR
                 })?;
 
+            // Decrypt note key (should not fail)
+            let note_key = services::note_keys::decrypt_for_user(
+                &mut tx,
+                &note_key,
+                &note_id,
+                user_claims.user_id(),
+                user_claims.user_key(),
+            )
+            .await
+            .map_err(|e| {
+                println!("failed to decrypt note key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
             // Decrypt note (should not fail)
             let mut note = note.decrypt(note_key.key()).map_err(|e| {
                 println!("failed to decrypt note: {}", e);
@@ -100,10 +116,12 @@ pub async fn create_or_update_note(
             // Encrypt and store note key
             services::note_keys::store(
                 &mut *tx,
-                note_key.encrypt(user_claims.user_key()).map_err(|e| {
-                    println!("failed to encrypt note key: {}", e);
-                    StatusCode::INTERNAL_SERVER_ERROR
-                })?,
+                note_key
+                    .encrypt(user_claims.user_key(), note.id(), user_claims.user_id())
+                    .map_err(|e| {
+                        println!("failed to encrypt note key: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?,
                 note.id(),
                 user_claims.user_id(),
             )
@@ -113,6 +131,14 @@ pub async fn create_or_update_note(
                 StatusCode::INTERNAL_SERVER_ERROR
             })?;
 
+            // The creator owns the note
+            services::notes::set_owner_id(&mut *tx, note.id(), Some(user_claims.user_id()))
+                .await
+                .map_err(|e| {
+                    println!("failed to set note owner: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+
             StatusCode::CREATED
         }
 
@@ -171,8 +197,22 @@ pub async fn get_notes(
                 StatusCode::INTERNAL_SERVER_ERROR
             })?;
 
+        // Notes encrypted by the client are only listed by the end-to-end
+        // routes
+        if note.is_end_to_end() {
+            continue;
+        }
+
         // Decrypt note key (should not fail)
-        let note_key = link.note_key.decrypt(user_claims.user_key()).map_err(|e| {
+        let note_key = services::note_keys::decrypt_for_user(
+            &mut tx,
+            &link.note_key,
+            &link.note_id,
+            user_claims.user_id(),
+            user_claims.user_key(),
+        )
+        .await
+        .map_err(|e| {
             println!("failed to decrypt note key: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
@@ -232,6 +272,12 @@ pub async fn get_note(
             }
         })?;
 
+    // Notes encrypted by the client are only served by the end-to-end routes
+    if note.is_end_to_end() {
+        println!("note is encrypted end-to-end");
+        return Err(StatusCode::CONFLICT);
+    }
+
     // Get note key
     let note_key = services::note_keys::get(&mut *tx, &note_id, user_claims.user_id())
         .await
@@ -246,15 +292,23 @@ pub async fn get_note(
             }
         })?;
 
-    // Commit database transaction
-    tx.commit().await.map_err(|e| {
-        println!("failed to commit transaction: {}", e);
+    // Decrypt note key (should not fail)
+    let note_key = services::note_keys::decrypt_for_user(
+        &mut tx,
+        &note_key,
+        &note_id,
+        user_claims.user_id(),
+        user_claims.user_key(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to decrypt note key: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Decrypt note key (should not fail)
-    let note_key = note_key.decrypt(user_claims.user_key()).map_err(|e| {
-        println!("failed to decrypt note key: {}", e);
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
@@ -297,6 +351,12 @@ pub async fn delete_note(
             }
         })?;
 
+    // Notes encrypted by the client are only served by the end-to-end routes
+    if note.is_end_to_end() {
+        println!("note is encrypted end-to-end");
+        return Err(StatusCode::CONFLICT);
+    }
+
     // Get note key
     let note_key = services::note_keys::get(&mut *tx, &note_id, user_claims.user_id())
         .await
@@ -312,7 +372,15 @@ pub async fn delete_note(
         })?;
 
     // Decrypt note key (should not fail)
-    let note_key = note_key.decrypt(user_claims.user_key()).map_err(|e| {
+    let note_key = services::note_keys::decrypt_for_user(
+        &mut tx,
+        &note_key,
+        &note_id,
+        user_claims.user_id(),
+        user_claims.user_key(),
+    )
+    .await
+    .map_err(|e| {
         println!("failed to decrypt note key: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
@@ -331,14 +399,287 @@ pub async fn delete_note(
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // Delete note
-    services::notes::delete(&mut *tx, note.id())
+    // Delete note, unless it is still shared with other users
+    if services::note_keys::search_by_note_id(&mut *tx, note.id())
         .await
         .map_err(|e| {
-            println!("failed to delete note: {}", e);
+            println!("failed to search note keys: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .is_empty()
+    {
+        services::notes::delete(&mut *tx, note.id())
+            .await
+            .map_err(|e| {
+                println!("failed to delete note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    }
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+pub struct CreateNoteShareRequest {
+    username: String,
+}
+
+#[derive(Serialize)]
+pub struct CreateNoteShareResponse {
+    user: NoteShareUserResponse,
+}
+
+#[derive(Serialize)]
+pub struct NoteShareUserResponse {
+    id: Uuid,
+    username: String,
+}
+
+pub async fn create_note_share(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(note_id): Path<Uuid>,
+    Json(payload): Json<CreateNoteShareRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Get note
+    let note = services::notes::get_by_id(&mut *tx, &note_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Notes encrypted by the client are only served by the end-to-end routes
+    if note.is_end_to_end() {
+        println!("note is encrypted end-to-end");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Get note key
+    let note_key = services::note_keys::get(&mut *tx, &note_id, user_claims.user_id())
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("access denied");
+                StatusCode::FORBIDDEN
+            }
+            _ => {
+                println!("failed to get note key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Decrypt note key (should not fail)
+    let note_key = services::note_keys::decrypt_for_user(
+        &mut tx,
+        &note_key,
+        &note_id,
+        user_claims.user_id(),
+        user_claims.user_key(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to decrypt note key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Get recipient
+    let recipient = services::users::get_by_username(&mut *tx, &payload.username)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("recipient could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get recipient: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Check that the note is not shared with the recipient yet
+    match services::note_keys::get(&mut *tx, &note_id, recipient.id()).await {
+        Ok(_) => {
+            println!("note is already shared with recipient");
+            return Err(StatusCode::CONFLICT);
+        }
+        Err(services::Error::NotFound) => {}
+        Err(e) => {
+            println!("failed to get recipient note key: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    // Get recipient public key
+    let recipient_key_pair = services::user_key_pairs::get_by_user_id(&mut *tx, recipient.id())
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("recipient has no key pair");
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            _ => {
+                println!("failed to get recipient key pair: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         })?;
 
+    // Seal and store note key for the recipient
+    services::note_keys::store(
+        &mut *tx,
+        note_key
+            .seal(recipient_key_pair.public_key(), &note_id, recipient.id())
+            .map_err(|e| {
+                println!("failed to seal note key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+        &note_id,
+        recipient.id(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to store note key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateNoteShareResponse {
+            user: NoteShareUserResponse {
+                id: *recipient.id(),
+                username: recipient.username().to_string(),
+            },
+        }),
+    ))
+}
+
+/// Withdraws a user's access to a note. Only the owner of the note may revoke
+/// other members, while every member may leave. Because the user may still
+/// hold the old note key, the note is re-encrypted with a fresh note key,
+/// which is then wrapped for every remaining member.
+pub async fn delete_note_share(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path((note_id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Get note
+    let note = services::notes::get_by_id(&mut *tx, &note_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get note: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Notes encrypted by the client are only served by the end-to-end routes
+    if note.is_end_to_end() {
+        println!("note is encrypted end-to-end");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Get note key
+    let note_key = services::note_keys::get(&mut *tx, &note_id, user_claims.user_id())
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("access denied");
+                StatusCode::FORBIDDEN
+            }
+            _ => {
+                println!("failed to get note key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Authorize user, who must own the note to revoke another member
+    if &user_id != user_claims.user_id() {
+        let owner_id = services::notes::get_owner_id(&mut *tx, &note_id)
+            .await
+            .map_err(|e| {
+                println!("failed to get note owner: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if owner_id.as_ref() != Some(user_claims.user_id()) {
+            println!("access denied");
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    // Decrypt note key (should not fail)
+    let note_key = services::note_keys::decrypt_for_user(
+        &mut tx,
+        &note_key,
+        &note_id,
+        user_claims.user_id(),
+        user_claims.user_key(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to decrypt note key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Decrypt note (should not fail)
+    let note = note.decrypt(note_key.key()).map_err(|e| {
+        println!("failed to decrypt note: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Re-encrypt the note for the remaining members
+    services::notes::revoke_member(
+        &mut tx,
+        &note,
+        &user_id,
+        user_claims.user_id(),
+        user_claims.user_key(),
+    )
+    .await
+    .map_err(|e| match e {
+        services::Error::NotFound => {
+            println!("note is not shared with user");
+            StatusCode::NOT_FOUND
+        }
+        _ => {
+            println!("failed to revoke note member: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
     // Commit database transaction
     tx.commit().await.map_err(|e| {
         println!("failed to commit transaction: {}", e);
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    api::{passkeys::decode_base64url, users::verify_user_secret},
+    extractors::auth::Auth,
+    services,
+    state::AppState,
+};
+
+/// Binary fields of the OPAQUE messages are encoded as unpadded base64url.
+#[derive(Deserialize)]
+pub struct StartUserOpaqueRegistrationRequest {
+    registration_request: String,
+}
+
+#[derive(Serialize)]
+pub struct StartUserOpaqueRegistrationResponse {
+    registration_response: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateOrUpdateUserOpaqueRequest {
+    registration_upload: String,
+    export_key: String,
+
+    /// Required when the user has a password, which is replaced
+    current_password: Option<String>,
+
+    /// Required when the user already has an OPAQUE record
+    current_export_key: Option<String>,
+}
+
+/// Decodes the export key, which unwraps the user key of users with an
+/// OPAQUE record.
+pub(super) fn decode_export_key(input: &str) -> Result<Zeroizing<Vec<u8>>, StatusCode> {
+    let export_key = Zeroizing::new(decode_base64url("export key", input)?);
+    if export_key.len() != services::user_opaque::EXPORT_KEY_LEN {
+        println!("invalid export key");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    Ok(export_key)
+}
+
+pub async fn start_user_opaque_registration(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<StartUserOpaqueRegistrationRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let registration_request =
+        decode_base64url("registration request", &payload.registration_request)?;
+
+    let registration_response = services::user_opaque::start_registration(
+        &state.opaque_setup,
+        &user_id,
+        &registration_request,
+    )
+    .map_err(|e| match e {
+        services::Error::InvalidOpaqueMessage(_) => {
+            println!("invalid registration request: {}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        _ => {
+            println!("failed to start opaque registration: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(StartUserOpaqueRegistrationResponse {
+            registration_response: BASE64_URL_SAFE_NO_PAD.encode(registration_response),
+        }),
+    ))
+}
+
+pub async fn create_or_update_user_opaque(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<CreateOrUpdateUserOpaqueRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let registration_upload =
+        decode_base64url("registration upload", &payload.registration_upload)?;
+    let export_key = decode_export_key(&payload.export_key)?;
+    let current_export_key = payload
+        .current_export_key
+        .as_deref()
+        .map(decode_export_key)
+        .transpose()?;
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Users have to prove they know their password or their current record,
+    // so a stolen session cannot take over the account
+    verify_user_secret(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        payload.current_password.as_deref(),
+        current_export_key.as_deref().map(Vec::as_slice),
+    )
+    .await?;
+
+    // Store the record and wrap the user key using the export key, which
+    // replaces the password or the previous record
+    services::user_opaque::register(
+        &mut tx,
+        &user_id,
+        user_claims.user_key(),
+        &registration_upload,
+        &export_key,
+    )
+    .await
+    .map_err(|e| match e {
+        services::Error::InvalidOpaqueMessage(_) => {
+            println!("invalid registration upload: {}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        _ => {
+            println!("failed to register opaque record: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,346 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{extractors::auth::Auth, services, state::AppState, webauthn};
+
+/// How long the browser waits for the user to complete a ceremony, in
+/// milliseconds.
+const CEREMONY_TIMEOUT: u64 = 5 * 60 * 1000;
+
+/// Passkeys are created and used with the options serialized the way
+/// `PublicKeyCredential.parseCreationOptionsFromJSON()` and
+/// `PublicKeyCredential.parseRequestOptionsFromJSON()` expect them.
+#[derive(Serialize)]
+pub struct PasskeyChallengeResponse {
+    challenge_id: Uuid,
+    options: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct CreateUserPasskeyRequest {
+    challenge_id: Uuid,
+    client_data_json: String,
+    attestation_object: String,
+
+    /// The output of the PRF extension, evaluated with the salt of the
+    /// registration options
+    prf_output: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct GetUserPasskeysResponse {
+    passkeys: Vec<UserPasskeyResponse>,
+}
+
+#[derive(Serialize)]
+pub struct UserPasskeyResponse {
+    id: Uuid,
+    name: String,
+    time_created: Option<DateTime<Utc>>,
+    last_used_time: Option<DateTime<Utc>>,
+}
+
+impl From<services::user_passkeys::UserPasskey> for UserPasskeyResponse {
+    fn from(user_passkey: services::user_passkeys::UserPasskey) -> Self {
+        Self {
+            id: *user_passkey.id(),
+            name: user_passkey.name().to_string(),
+            time_created: user_passkey.time_created().copied(),
+            last_used_time: user_passkey.last_used_time().copied(),
+        }
+    }
+}
+
+/// Decodes a binary field of a WebAuthn response, which the client encodes
+/// as unpadded base64url.
+pub(super) fn decode_base64url(field: &str, input: &str) -> Result<Vec<u8>, StatusCode> {
+    BASE64_URL_SAFE_NO_PAD.decode(input).map_err(|e| {
+        println!("failed to decode {}: {}", field, e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Decodes the PRF output, without which a passkey cannot unlock the notes.
+pub(super) fn decode_prf_output(input: &str) -> Result<Zeroizing<Vec<u8>>, StatusCode> {
+    let prf_output = Zeroizing::new(decode_base64url("prf output", input)?);
+    if prf_output.len() != 32 {
+        println!("invalid prf output");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    Ok(prf_output)
+}
+
+/// The PRF extension input, which asks the authenticator to evaluate the PRF
+/// with the salt that wraps the user key.
+fn prf_extension() -> serde_json::Value {
+    json!({
+        "prf": {
+            "eval": {
+                "first": BASE64_URL_SAFE_NO_PAD.encode(webauthn::prf_salt()),
+            },
+        },
+    })
+}
+
+pub async fn create_passkey_challenge(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // The challenge is not bound to a user, as passkeys are discoverable
+    let challenge = services::user_passkeys::create_challenge(&mut tx, None)
+        .await
+        .map_err(|e| {
+            println!("failed to create passkey challenge: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(PasskeyChallengeResponse {
+            challenge_id: *challenge.id(),
+            options: json!({
+                "challenge": BASE64_URL_SAFE_NO_PAD.encode(challenge.challenge()),
+                "rpId": state.relying_party.id(),
+                "timeout": CEREMONY_TIMEOUT,
+                "userVerification": "required",
+                "extensions": prf_extension(),
+            }),
+        }),
+    ))
+}
+
+pub async fn create_user_passkey_challenge(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let user = services::users::get_by_id(&mut *tx, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to get user: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let challenge = services::user_passkeys::create_challenge(&mut tx, Some(&user_id))
+        .await
+        .map_err(|e| {
+            println!("failed to create passkey challenge: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Passkeys must be discoverable, so the user can sign in without a
+    // username, and must verify the user, as they replace the password
+    Ok((
+        StatusCode::CREATED,
+        Json(PasskeyChallengeResponse {
+            challenge_id: *challenge.id(),
+            options: json!({
+                "rp": {
+                    "id": state.relying_party.id(),
+                    "name": state.relying_party.name(),
+                },
+                "user": {
+                    "id": BASE64_URL_SAFE_NO_PAD.encode(user.id().as_bytes()),
+                    "name": user.username(),
+                    "displayName": user.username(),
+                },
+                "challenge": BASE64_URL_SAFE_NO_PAD.encode(challenge.challenge()),
+                "pubKeyCredParams": [
+                    { "type": "public-key", "alg": webauthn::ES256 },
+                    { "type": "public-key", "alg": webauthn::EDDSA },
+                ],
+                "timeout": CEREMONY_TIMEOUT,
+                "authenticatorSelection": {
+                    "residentKey": "required",
+                    "requireResidentKey": true,
+                    "userVerification": "required",
+                },
+                "attestation": "none",
+                "extensions": prf_extension(),
+            }),
+        }),
+    ))
+}
+
+pub async fn create_user_passkey(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<CreateUserPasskeyRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let registration = services::user_passkeys::PasskeyRegistration {
+        challenge_id: payload.challenge_id,
+        client_data_json: decode_base64url("client data", &payload.client_data_json)?,
+        attestation_object: decode_base64url("attestation object", &payload.attestation_object)?,
+        prf_output: decode_prf_output(&payload.prf_output)?,
+        name: payload.name,
+    };
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Verify the passkey and wrap the user key using its PRF output
+    let user_passkey = services::user_passkeys::register(
+        &mut tx,
+        &state.relying_party,
+        &state.hash_config,
+        &user_id,
+        user_claims.user_key(),
+        &registration,
+    )
+    .await
+    .map_err(|e| match e {
+        services::Error::NotFound | services::Error::InvalidPasskey(_) => {
+            println!("invalid passkey registration: {}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        _ => {
+            println!("failed to register passkey: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UserPasskeyResponse::from(user_passkey)),
+    ))
+}
+
+pub async fn get_user_passkeys(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let user_passkeys = services::user_passkeys::get_by_user_id(&state.db, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to get user passkeys: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(GetUserPasskeysResponse {
+            passkeys: user_passkeys
+                .into_iter()
+                .map(UserPasskeyResponse::from)
+                .collect(),
+        }),
+    ))
+}
+
+pub async fn delete_user_passkey(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path((user_id, passkey_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Only passkeys of the user can be deleted
+    let user_passkey = services::user_passkeys::get_by_id(&mut *tx, &passkey_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get user passkey: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    if user_passkey.user_id() != &user_id {
+        println!("resource could not be found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Drop the passkey and its wrapping of the user key
+    services::user_passkeys::delete(&mut tx, &passkey_id)
+        .await
+        .map_err(|e| {
+            println!("failed to delete user passkey: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
@@ -6,21 +6,34 @@ use axum::{
     http::StatusCode,
     response::IntoResponse,
 };
-use chrono::Duration;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
 use uuid::Uuid;
 
-use crate::{extractors::auth::Auth, services, state::AppState, tokens};
+use crate::{
+    api::{
+        auth::{UserSessionResponse, create_session, new_session},
+        opaque::decode_export_key,
+    },
+    extractors::{auth::Auth, client::ClientInfo},
+    services::{self, hash_params::HashConfig},
+    state::AppState,
+};
 
 #[derive(Deserialize)]
 pub struct CreateUserRequest {
     username: String,
+    password: String,
 }
 
 #[derive(Serialize)]
 pub struct CreateUserResponse {
     user: UserResponse,
     session: UserSessionResponse,
+
+    /// The recovery code of the user; it is not shown again
+    recovery_code: String,
 }
 
 #[derive(Serialize)]
@@ -29,34 +42,434 @@ pub struct UserResponse {
     username: String,
 }
 
+pub async fn create_user(
+    State(state): State<Arc<AppState>>,
+    client_info: ClientInfo,
+    Json(payload): Json<CreateUserRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Create the user along with its password, user key, key pair and
+    // recovery code, so no user is left without credentials
+    let (user, user_key, recovery_code) = services::users::sign_up(
+        &mut tx,
+        &state.hash_config,
+        &payload.username,
+        &payload.password,
+    )
+    .await
+    .map_err(|e| match e {
+        services::Error::InvalidUsername(e) => {
+            println!("invalid username: {}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        services::Error::Conflict => {
+            println!("username is taken");
+            StatusCode::CONFLICT
+        }
+        _ => {
+            println!("failed to sign up user: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    // Create user session
+    let user_session = new_session(
+        false,
+        services::user_sessions::SessionDevice::new(
+            client_info.user_agent,
+            client_info.ip_address,
+            None,
+        ),
+    );
+    let session = create_session(
+        &mut tx,
+        &state,
+        user.id(),
+        &user_session,
+        user_key.into_key(),
+    )
+    .await?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateUserResponse {
+            user: UserResponse {
+                id: *user.id(),
+                username: user.username().to_string(),
+            },
+            session,
+            recovery_code: recovery_code.to_string(),
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserRequest {
+    username: String,
+}
+
+pub async fn update_user(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<UpdateUserRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Validate the new username
+    let username = services::usernames::Username::parse(&payload.username).map_err(|e| {
+        println!("invalid username: {}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Rename the user
+    let mut user = services::users::get_by_id(&mut *tx, &user_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get user: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    services::users::rename(&mut *tx, &mut user, &username)
+        .await
+        .map_err(|e| match e {
+            services::Error::Conflict => {
+                println!("username is taken");
+                StatusCode::CONFLICT
+            }
+            _ => {
+                println!("failed to rename user: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(UserResponse {
+            id: *user.id(),
+            username: user.username().to_string(),
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteUserRequest {
+    password: Option<String>,
+
+    /// Sent instead of the password by users with an OPAQUE record
+    export_key: Option<String>,
+}
+
 #[derive(Serialize)]
-pub struct UserSessionResponse {
-    token: String,
+pub struct DeleteUserResponse {
+    deleted: DeletedUserResponse,
 }
 
-pub async fn create_user(
+#[derive(Serialize)]
+pub struct DeletedUserResponse {
+    user_keys: u64,
+    note_keys: u64,
+    notes: u64,
+    sessions: u64,
+}
+
+pub async fn delete_user(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateUserRequest>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<DeleteUserRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Verify the password, so a stolen session cannot delete the account
+    let export_key = payload
+        .export_key
+        .as_deref()
+        .map(decode_export_key)
+        .transpose()?;
+    verify_user_secret(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        payload.password.as_deref(),
+        export_key.as_deref().map(Vec::as_slice),
+    )
+    .await?;
+
+    // Shred the key material and delete the user
+    let deleted_user = services::users::delete(&mut tx, &user_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to delete user: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(DeleteUserResponse {
+            deleted: DeletedUserResponse {
+                user_keys: deleted_user.user_keys(),
+                note_keys: deleted_user.note_keys(),
+                notes: deleted_user.notes(),
+                sessions: deleted_user.sessions(),
+            },
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserPasswordRequest {
+    password: String,
+    current_password: String,
+}
+
+pub async fn update_user_password(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<UpdateUserPasswordRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     // Start database transaction
     let mut tx = state.db.begin().await.map_err(|e| {
         println!("failed to start transaction: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Create user
-    let user = services::users::User::new(payload.username);
-    services::users::store(&mut *tx, &user).await.map_err(|e| {
-        println!("failed to store user: {}", e);
+    // Users that moved to OPAQUE change their password through it
+    match services::user_opaque::get_by_user_id(&mut *tx, &user_id).await {
+        Ok(_) => {
+            println!("user has an opaque record");
+            return Err(StatusCode::CONFLICT);
+        }
+        Err(services::Error::NotFound) => {}
+        Err(e) => {
+            println!("failed to get user opaque record: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    // Users get their password when they sign up, so there always is one to
+    // change
+    let user_password = services::user_passwords::get_by_user_id(&mut *tx, &user_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("user has no password");
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            _ => {
+                println!("failed to get user password: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Verify the current password
+    if !user_password
+        .verify(&state.hash_config, &payload.current_password)
+        .map_err(|e| {
+            println!("failed to verify user password: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    {
+        println!("incorrect password");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // Get user key using the current password
+    let user_key = services::user_keys::get_using_password(
+        &mut *tx,
+        &state.hash_config,
+        user_password.user_key_id(),
+        &payload.current_password,
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to get user key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Wrap the user key using the new password
+    services::user_passwords::change(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        user_key.key(),
+        &payload.password,
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to change user password: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Create user session
-    let user_session = services::user_sessions::UserSession::new(Duration::days(31));
-    services::user_sessions::store(&mut *tx, &user_session, user.id())
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Serialize)]
+pub struct CreateUserRecoveryCodeResponse {
+    recovery_code: String,
+}
+
+pub async fn create_user_recovery_code(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Replace any previous recovery code by a new one
+    let recovery_code = services::user_recovery_codes::replace(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        user_claims.user_key(),
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to create user recovery code: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateUserRecoveryCodeResponse {
+            recovery_code: recovery_code.to_string(),
+        }),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct EnrollUserTotpResponse {
+    /// The secret in base32, for users that type it into their authenticator
+    secret: String,
+
+    /// The `otpauth://` URI, for authenticator apps that scan a QR code
+    uri: String,
+}
+
+pub async fn enroll_user_totp(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // A confirmed secret must be disabled before enrolling again
+    if services::user_totp::is_enabled(&mut *tx, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to get user totp: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    {
+        println!("totp is already enabled");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let user = services::users::get_by_id(&mut *tx, &user_id)
         .await
         .map_err(|e| {
-            println!("failed to store user session: {}", e);
+            println!("failed to get user: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Generate a secret, which is required at login once it is confirmed
+    let secret = services::user_totp::enroll(&mut tx, &user_id, user_claims.user_key())
+        .await
+        .map_err(|e| {
+            println!("failed to enroll user totp: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
@@ -66,38 +479,232 @@ pub async fn create_user(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Create temporary user key
-    let user_key = services::user_keys::UserKey::new();
+    Ok((
+        StatusCode::CREATED,
+        Json(EnrollUserTotpResponse {
+            secret: secret.to_base32(),
+            uri: secret.to_uri(user.username()),
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmUserTotpRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct UserTotpBackupCodesResponse {
+    backup_codes: Vec<String>,
+}
+
+pub async fn confirm_user_totp(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<ConfirmUserTotpRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
 
-    // Wrap user session id, user id and user key in a JWT/JWE
-    let user_claims = tokens::UserClaims::new(*user_session.id(), *user.id(), *user_key.key());
-    let jwt = tokens::encrypt(&user_claims, &state.jwk).map_err(|e| {
-        println!("failed to encrypt user claims: {}", e);
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Only secrets that are not confirmed yet can be confirmed
+    let user_totp = services::user_totp::get_by_user_id(&mut *tx, &user_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to get user totp: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+    if user_totp.is_confirmed() {
+        println!("totp is already enabled");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Check the code, which confirms the secret
+    if !services::user_totp::verify(&mut tx, &user_id, user_claims.user_key(), &payload.code)
+        .await
+        .map_err(|e| {
+            println!("failed to verify totp code: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    {
+        println!("incorrect totp code");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    // Generate backup codes for when the authenticator is lost
+    let backup_codes = services::user_totp::replace_backup_codes(&mut tx, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to create backup codes: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(UserTotpBackupCodesResponse {
+            backup_codes: backup_codes.iter().map(ToString::to_string).collect(),
+        }),
+    ))
+}
+
+pub async fn create_user_totp_backup_codes(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Backup codes are only useful while totp is enabled
+    if !services::user_totp::is_enabled(&mut *tx, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to get user totp: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    {
+        println!("totp is not enabled");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Replace any previous backup codes by new ones
+    let backup_codes = services::user_totp::replace_backup_codes(&mut tx, &user_id)
+        .await
+        .map_err(|e| {
+            println!("failed to create backup codes: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
     Ok((
         StatusCode::CREATED,
-        Json(CreateUserResponse {
-            user: UserResponse {
-                id: *user.id(),
-                username: user.username().to_string(),
-            },
-            session: UserSessionResponse { token: jwt },
+        Json(UserTotpBackupCodesResponse {
+            backup_codes: backup_codes.iter().map(ToString::to_string).collect(),
         }),
     ))
 }
 
 #[derive(Deserialize)]
-pub struct CreateOrUpdateUserPasswordRequest {
-    password: String,
+pub struct DeleteUserTotpRequest {
+    password: Option<String>,
+
+    /// Sent instead of the password by users with an OPAQUE record
+    export_key: Option<String>,
+}
+
+pub async fn delete_user_totp(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<DeleteUserTotpRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Verify the password, so a stolen session cannot disable the second factor
+    let export_key = payload
+        .export_key
+        .as_deref()
+        .map(decode_export_key)
+        .transpose()?;
+    verify_user_secret(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        payload.password.as_deref(),
+        export_key.as_deref().map(Vec::as_slice),
+    )
+    .await?;
+
+    // Drop the secret and the backup codes
+    services::user_totp::delete(&mut tx, &user_id)
+        .await
+        .map_err(|e| match e {
+            services::Error::NotFound => {
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
+            }
+            _ => {
+                println!("failed to delete user totp: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+pub struct RotateUserKeyRequest {
+    password: Option<String>,
+
+    /// Sent instead of the password by users with an OPAQUE record
+    export_key: Option<String>,
 }
 
-pub async fn create_or_update_user_password(
+#[derive(Serialize)]
+pub struct RotateUserKeyResponse {
+    session: UserSessionResponse,
+
+    /// The replacement of the user's recovery code, if the user had one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_code: Option<String>,
+}
+
+pub async fn rotate_user_key(
     State(state): State<Arc<AppState>>,
     Auth(user_claims): Auth,
     Path(user_id): Path<Uuid>,
-    Json(payload): Json<CreateOrUpdateUserPasswordRequest>,
+    Json(payload): Json<RotateUserKeyRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
     // Authorize user
     if &user_id != user_claims.user_id() {
@@ -111,23 +718,224 @@ pub async fn create_or_update_user_password(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Encrypt and store user key using user password
-    let user_key = user_claims.user_key().into();
-    services::user_keys::store_using_password(&mut *tx, &user_id, &user_key, &payload.password)
+    // Verify the password, which is needed to wrap the new user key
+    let export_key = payload
+        .export_key
+        .as_deref()
+        .map(decode_export_key)
+        .transpose()?;
+    let user_secret = verify_user_secret(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        payload.password.as_deref(),
+        export_key.as_deref().map(Vec::as_slice),
+    )
+    .await?;
+
+    // The new session replaces the current one, on the same device
+    let current_session = services::user_sessions::get(&mut *tx, user_claims.session_id())
         .await
         .map_err(|e| {
-            println!("failed to store user key: {}", e);
+            println!("failed to get user session: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    // Store the user password
-    let user_password =
-        services::user_passwords::UserPassword::new(user_key.id(), &payload.password)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    services::user_passwords::store(&mut *tx, &user_password, &user_id)
+    // Replace the user key and drop all sessions
+    let (user_key, recovery_code) = services::user_keys::rotate(
+        &mut tx,
+        &state.hash_config,
+        &user_id,
+        user_claims.user_key(),
+        user_secret,
+    )
+    .await
+    .map_err(|e| {
+        println!("failed to rotate user key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Create a new user session for the new user key
+    let user_session = new_session(
+        current_session.expiration_time().is_none(),
+        current_session.device().clone(),
+    );
+    let session = create_session(
+        &mut tx,
+        &state,
+        &user_id,
+        &user_session,
+        user_key.into_key(),
+    )
+    .await?;
+
+    // Commit database transaction
+    tx.commit().await.map_err(|e| {
+        println!("failed to commit transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(RotateUserKeyResponse {
+            session,
+            recovery_code: recovery_code.map(|recovery_code| recovery_code.to_string()),
+        }),
+    ))
+}
+
+/// Verifies the secret the user signs in with, which is the export key for
+/// users with an OPAQUE record and the password otherwise. Returns the secret,
+/// so the user key can be wrapped under it.
+pub(super) async fn verify_user_secret<'a>(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    password: Option<&'a str>,
+    export_key: Option<&'a [u8]>,
+) -> Result<services::user_keys::UserSecret<'a>, StatusCode> {
+    match services::user_opaque::get_by_user_id(&mut *conn, user_id).await {
+        // The export key can only unwrap the user key if it is correct
+        Ok(_) => {
+            let Some(export_key) = export_key else {
+                println!("export key is required");
+                return Err(StatusCode::BAD_REQUEST);
+            };
+            services::user_opaque::get_user_key(&mut *conn, user_id, export_key)
+                .await
+                .map_err(|e| match e {
+                    services::Error::DecryptionFailed => {
+                        println!("incorrect export key");
+                        StatusCode::UNAUTHORIZED
+                    }
+                    _ => {
+                        println!("failed to get user key: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                })?;
+
+            Ok(services::user_keys::UserSecret::ExportKey(export_key))
+        }
+
+        Err(services::Error::NotFound) => {
+            let user_password = services::user_passwords::get_by_user_id(&mut *conn, user_id)
+                .await
+                .map_err(|e| match e {
+                    services::Error::NotFound => {
+                        println!("user has no password");
+                        StatusCode::UNPROCESSABLE_ENTITY
+                    }
+                    _ => {
+                        println!("failed to get user password: {}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                })?;
+            let Some(password) = password else {
+                println!("password is required");
+                return Err(StatusCode::BAD_REQUEST);
+            };
+            if !user_password.verify(hash_config, password).map_err(|e| {
+                println!("failed to verify user password: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })? {
+                println!("incorrect password");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+
+            Ok(services::user_keys::UserSecret::Password(password))
+        }
+
+        Err(e) => {
+            println!("failed to get user opaque record: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GetUserSessionsResponse {
+    sessions: Vec<UserSessionInfoResponse>,
+}
+
+#[derive(Serialize)]
+pub struct UserSessionInfoResponse {
+    id: Uuid,
+    time_created: Option<DateTime<Utc>>,
+    last_seen_time: Option<DateTime<Utc>>,
+
+    /// Persistent sessions have no expiration times
+    expiration_time: Option<DateTime<Utc>>,
+    idle_expiration_time: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    device_label: Option<String>,
+
+    /// Whether this is the session of the request
+    current: bool,
+}
+
+pub async fn get_user_sessions(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let user_sessions = services::user_sessions::get_by_user_id(&state.db, &user_id)
         .await
         .map_err(|e| {
-            println!("failed to store user password: {}", e);
+            println!("failed to get user sessions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((
+        StatusCode::OK,
+        Json(GetUserSessionsResponse {
+            sessions: user_sessions
+                .into_iter()
+                .map(|user_session| UserSessionInfoResponse {
+                    id: *user_session.id(),
+                    time_created: user_session.time_created().copied(),
+                    last_seen_time: user_session.last_seen_time().copied(),
+                    expiration_time: user_session.expiration_time().copied(),
+                    idle_expiration_time: user_session.idle_expiration_time().copied(),
+                    user_agent: user_session.device().user_agent().map(str::to_string),
+                    ip_address: user_session.device().ip_address().map(str::to_string),
+                    device_label: user_session.device().label().map(str::to_string),
+                    current: user_session.id() == user_claims.session_id(),
+                })
+                .collect(),
+        }),
+    ))
+}
+
+/// Signs the user out everywhere but the session of the request.
+pub async fn delete_user_sessions(
+    State(state): State<Arc<AppState>>,
+    Auth(user_claims): Auth,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Authorize user
+    if &user_id != user_claims.user_id() {
+        println!("access denied");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Start database transaction
+    let mut tx = state.db.begin().await.map_err(|e| {
+        println!("failed to start transaction: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Delete the other user sessions, along with their refresh tokens
+    services::user_sessions::delete_others(&mut *tx, &user_id, user_claims.session_id())
+        .await
+        .map_err(|e| {
+            println!("failed to delete user sessions: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
@@ -137,7 +945,7 @@ pub async fn create_or_update_user_password(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    Ok(StatusCode::CREATED)
+    Ok(StatusCode::OK)
 }
 
 pub async fn delete_user_session(
@@ -157,13 +965,13 @@ pub async fn delete_user_session(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Delete the user session
-    services::user_sessions::delete(&mut *tx, &session_id)
+    // Only sessions of the user can be deleted
+    services::user_sessions::delete_of_user(&mut *tx, &user_id, &session_id)
         .await
         .map_err(|e| match e {
             services::Error::NotFound => {
-                println!("access denied");
-                StatusCode::FORBIDDEN
+                println!("resource could not be found");
+                StatusCode::NOT_FOUND
             }
             _ => {
                 println!("failed to delete user session: {}", e);
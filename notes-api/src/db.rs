@@ -1,8 +1,21 @@
+pub mod user_access_tokens;
+pub mod user_key_pairs;
 pub mod user_keys;
+pub mod user_opaque_records;
+pub mod user_passkeys;
 pub mod user_passwords;
+pub mod user_recovery_codes;
+pub mod user_session_refresh_tokens;
 pub mod user_sessions;
+pub mod user_totp;
+pub mod user_totp_backup_codes;
 pub mod users;
 
+pub mod login_attempts;
+pub mod login_lockouts;
+pub mod opaque_logins;
+pub mod webauthn_challenges;
+
 pub mod note_keys;
 pub mod notes;
 
@@ -16,6 +29,9 @@ pub enum Error {
     #[error("too many items matched the query")]
     TooMany,
 
+    #[error("item conflicts with an existing item")]
+    Conflict,
+
     #[error("internal error: {0}")]
     Internal(anyhow::Error),
 }
@@ -24,6 +40,7 @@ impl From<sqlx::Error> for Error {
     fn from(e: sqlx::Error) -> Self {
         match e {
             sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => Self::Conflict,
             _ => Self::Internal(e.into()),
         }
     }
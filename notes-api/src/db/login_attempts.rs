@@ -0,0 +1,128 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct LoginAttemptRow {
+    pub id: Uuid,
+
+    /// The user is missing when the username is unknown
+    pub user_id: Option<Uuid>,
+    pub username: Option<String>,
+    pub ip_address: Option<String>,
+    pub method: String,
+    pub succeeded: bool,
+    pub time_created: DateTime<Utc>,
+}
+
+pub async fn create<'e, E>(executor: E, login_attempt: &LoginAttemptRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO login_attempts (id, user_id, username, ip_address, method, succeeded, time_created)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        "#,
+    )
+    .bind(login_attempt.id)
+    .bind(login_attempt.user_id)
+    .bind(&login_attempt.username)
+    .bind(&login_attempt.ip_address)
+    .bind(&login_attempt.method)
+    .bind(login_attempt.succeeded)
+    .bind(login_attempt.time_created)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Gets the latest attempts of a user, newest first.
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    limit: i64,
+) -> db::Result<Vec<LoginAttemptRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, username, ip_address, method, succeeded, time_created
+        FROM login_attempts
+        WHERE user_id = ?1
+        ORDER BY time_created DESC
+        LIMIT ?2
+        "#,
+    )
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(executor)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::login_attempts::{self, LoginAttemptRow};
+
+    #[tokio::test]
+    async fn create_and_get_by_user_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind("test".to_string())
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        // Perform test
+
+        let time_created = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        let login_attempts = [false, true].map(|succeeded| LoginAttemptRow {
+            id: Uuid::new_v4(),
+            user_id: Some(user_id),
+            username: Some("test".to_string()),
+            ip_address: Some("127.0.0.1".to_string()),
+            method: "password".to_string(),
+            succeeded,
+            time_created: time_created + Duration::seconds(succeeded as i64),
+        });
+        for login_attempt in &login_attempts {
+            login_attempts::create(&pool, login_attempt)
+                .await
+                .expect("failed to create login attempt");
+        }
+
+        // Newest first, up to the limit
+        assert_eq!(
+            login_attempts::get_by_user_id(&pool, &user_id, 1)
+                .await
+                .expect("failed to get login attempts"),
+            vec![LoginAttemptRow {
+                id: login_attempts[1].id,
+                user_id: Some(user_id),
+                username: Some("test".to_string()),
+                ip_address: Some("127.0.0.1".to_string()),
+                method: "password".to_string(),
+                succeeded: true,
+                time_created: time_created + Duration::seconds(1),
+            }]
+        );
+    }
+}
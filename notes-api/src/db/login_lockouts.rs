@@ -0,0 +1,141 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct LoginLockoutRow {
+    /// An IP address or a username, such as `ip:127.0.0.1`
+    pub subject: String,
+    pub failure_count: i64,
+    pub last_failure_time: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+pub async fn get_by_subject<'e, E>(executor: E, subject: &str) -> db::Result<LoginLockoutRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT subject, failure_count, last_failure_time, locked_until
+        FROM login_lockouts
+        WHERE subject = ?1
+        "#,
+    )
+    .bind(subject)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn create_or_update<'e, E>(executor: E, login_lockout: &LoginLockoutRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO login_lockouts (subject, failure_count, last_failure_time, locked_until)
+        VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (subject) DO UPDATE
+        SET failure_count = ?2, last_failure_time = ?3, locked_until = ?4
+        "#,
+    )
+    .bind(&login_lockout.subject)
+    .bind(login_lockout.failure_count)
+    .bind(login_lockout.last_failure_time)
+    .bind(login_lockout.locked_until)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Forgets the failures of a subject. Subjects without failures are ignored.
+pub async fn delete_by_subject<'e, E>(executor: E, subject: &str) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        DELETE FROM login_lockouts
+        WHERE subject = ?1
+        "#,
+    )
+    .bind(subject)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+    use utilities::db::init_db;
+
+    use crate::db::{
+        self,
+        login_lockouts::{self, LoginLockoutRow},
+    };
+
+    #[tokio::test]
+    async fn create_or_update() {
+        let pool = init_db().await;
+
+        // Perform test
+
+        let last_failure_time = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        let mut login_lockout = LoginLockoutRow {
+            subject: "ip:127.0.0.1".to_string(),
+            failure_count: 1,
+            last_failure_time,
+            locked_until: None,
+        };
+        login_lockouts::create_or_update(&pool, &login_lockout)
+            .await
+            .expect("failed to create login lockout");
+
+        login_lockout.failure_count = 2;
+        login_lockout.locked_until = Some(last_failure_time + Duration::seconds(30));
+        login_lockouts::create_or_update(&pool, &login_lockout)
+            .await
+            .expect("failed to update login lockout");
+
+        assert_eq!(
+            login_lockouts::get_by_subject(&pool, "ip:127.0.0.1")
+                .await
+                .expect("failed to get login lockout"),
+            login_lockout
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_by_subject() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        login_lockouts::create_or_update(
+            &pool,
+            &LoginLockoutRow {
+                subject: "username:test".to_string(),
+                failure_count: 1,
+                last_failure_time: DateTime::from_timestamp(0, 0).expect("invalid timestamp"),
+                locked_until: None,
+            },
+        )
+        .await
+        .expect("failed to create login lockout");
+
+        // Perform test
+
+        login_lockouts::delete_by_subject(&pool, "username:test")
+            .await
+            .expect("failed to delete login lockout");
+        assert!(
+            login_lockouts::get_by_subject(&pool, "username:test")
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+}
@@ -10,6 +10,10 @@ pub struct NoteKeyRow {
     pub user_id: Uuid,
     pub encrypted_key: Vec<u8>,
     pub nonce: Vec<u8>,
+
+    /// Note keys shared by another user are sealed for the recipient's
+    /// public key. The ephemeral public key is needed to unseal them.
+    pub ephemeral_public_key: Option<Vec<u8>>,
 }
 
 pub async fn create<'e, E>(executor: E, note_key: &NoteKeyRow) -> db::Result<()>
@@ -18,8 +22,10 @@ where
 {
     sqlx::query(
         r#"
-        INSERT INTO note_keys (id, note_id, user_id, encrypted_key, nonce)
-        VALUES (?1, ?2, ?3, ?4, ?5)
+        INSERT INTO note_keys (
+            id, note_id, user_id, encrypted_key, nonce, ephemeral_public_key
+        )
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
         "#,
     )
     .bind(&note_key.id)
@@ -27,19 +33,45 @@ where
     .bind(&note_key.user_id)
     .bind(&note_key.encrypted_key)
     .bind(&note_key.nonce)
+    .bind(&note_key.ephemeral_public_key)
     .execute(executor)
     .await?;
 
     Ok(())
 }
 
+pub async fn update<'e, E>(executor: E, note_key: &NoteKeyRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE note_keys
+        SET encrypted_key = ?2, nonce = ?3, ephemeral_public_key = ?4
+        WHERE id = ?1
+        "#,
+    )
+    .bind(note_key.id)
+    .bind(&note_key.encrypted_key)
+    .bind(&note_key.nonce)
+    .bind(&note_key.ephemeral_public_key)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
 pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<NoteKeyRow>
 where
     E: SqliteExecutor<'e>,
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, note_id, user_id, encrypted_key, nonce
+        SELECT id, note_id, user_id, encrypted_key, nonce, ephemeral_public_key
         FROM note_keys
         WHERE id = ?1
         "#,
@@ -60,7 +92,8 @@ where
             note_keys.note_id,
             note_keys.user_id,
             note_keys.encrypted_key,
-            note_keys.nonce
+            note_keys.nonce,
+            note_keys.ephemeral_public_key
         FROM note_keys
             LEFT JOIN notes
                 ON note_keys.note_id = notes.id
@@ -73,6 +106,22 @@ where
     .await?)
 }
 
+pub async fn get_by_note_id<'e, E>(executor: E, note_id: &Uuid) -> db::Result<Vec<NoteKeyRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, note_id, user_id, encrypted_key, nonce, ephemeral_public_key
+        FROM note_keys
+        WHERE note_id = ?1
+        "#,
+    )
+    .bind(note_id)
+    .fetch_all(executor)
+    .await?)
+}
+
 pub async fn get_by_note_id_and_user_id<'e, E>(
     executor: E,
     note_id: &Uuid,
@@ -83,7 +132,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, note_id, user_id, encrypted_key, nonce
+        SELECT id, note_id, user_id, encrypted_key, nonce, ephemeral_public_key
         FROM note_keys
         WHERE note_id = ?1 AND user_id = ?2
         "#,
@@ -120,6 +169,45 @@ where
     }
 }
 
+pub async fn delete_by_note_id<'e, E>(executor: E, note_id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM note_keys
+        WHERE note_id = ?1
+        "#,
+    )
+    .bind(note_id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the wrapped note keys of a user with zeros, before the user is
+/// deleted. Returns the number of note keys.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE note_keys
+        SET encrypted_key = zeroblob(length(encrypted_key))
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
 #[cfg(test)]
 mod tests {
     use utilities::db::init_db;
@@ -176,6 +264,7 @@ mod tests {
             user_id,
             encrypted_key: vec![1, 2, 3, 4],
             nonce: vec![5, 6, 7, 8],
+            ephemeral_public_key: None,
         };
 
         note_keys::create(&pool, &note_key)
@@ -258,7 +347,8 @@ mod tests {
                 note_id,
                 user_id,
                 encrypted_key,
-                nonce
+                nonce,
+                ephemeral_public_key: None,
             }
         )
     }
@@ -368,19 +458,95 @@ mod tests {
                     note_id: note_id_1,
                     user_id,
                     encrypted_key: encrypted_key_1,
-                    nonce: nonce_1
+                    nonce: nonce_1,
+                    ephemeral_public_key: None,
                 },
                 NoteKeyRow {
                     id: id_2,
                     note_id: note_id_2,
                     user_id,
                     encrypted_key: encrypted_key_2,
-                    nonce: nonce_2
+                    nonce: nonce_2,
+                    ephemeral_public_key: None,
                 }
             ]
         )
     }
 
+    #[tokio::test]
+    async fn get_by_note_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let note_id = Uuid::new_v4();
+        let encryted_markdown = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+
+        sqlx::query(
+            r#"
+            INSERT INTO notes (id, encrypted_markdown, nonce)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(note_id)
+        .bind(&encryted_markdown)
+        .bind(&nonce)
+        .execute(&pool)
+        .await
+        .expect("failed to insert note");
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let id = Uuid::new_v4();
+        let encrypted_key = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+
+        sqlx::query(
+            r#"
+            INSERT INTO note_keys (id, note_id, user_id, encrypted_key, nonce)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(id)
+        .bind(note_id)
+        .bind(user_id)
+        .bind(&encrypted_key)
+        .bind(&nonce)
+        .execute(&pool)
+        .await
+        .expect("failed to insert note_key");
+
+        // Perform test
+
+        assert_eq!(
+            note_keys::get_by_note_id(&pool, &note_id)
+                .await
+                .expect("failed to get note keys by note id"),
+            vec![NoteKeyRow {
+                id,
+                note_id,
+                user_id,
+                encrypted_key,
+                nonce,
+                ephemeral_public_key: None,
+            }]
+        )
+    }
+
     #[tokio::test]
     async fn get_by_note_id_and_user_id() {
         let pool = init_db().await;
@@ -449,11 +615,94 @@ mod tests {
                 note_id,
                 user_id,
                 encrypted_key,
-                nonce
+                nonce,
+                ephemeral_public_key: None,
             }
         )
     }
 
+    #[tokio::test]
+    async fn update() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let note_id = Uuid::new_v4();
+        let encryted_markdown = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+
+        sqlx::query(
+            r#"
+            INSERT INTO notes (id, encrypted_markdown, nonce)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(note_id)
+        .bind(&encryted_markdown)
+        .bind(&nonce)
+        .execute(&pool)
+        .await
+        .expect("failed to insert note");
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let id = Uuid::new_v4();
+        let encrypted_key = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+        let ephemeral_public_key = vec![9, 10, 11, 12];
+
+        sqlx::query(
+            r#"
+            INSERT INTO note_keys (id, note_id, user_id, encrypted_key, nonce, ephemeral_public_key)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+        )
+        .bind(id)
+        .bind(note_id)
+        .bind(user_id)
+        .bind(&encrypted_key)
+        .bind(&nonce)
+        .bind(&ephemeral_public_key)
+        .execute(&pool)
+        .await
+        .expect("failed to insert note_key");
+
+        // Perform test
+
+        let note_key = NoteKeyRow {
+            id,
+            note_id,
+            user_id,
+            encrypted_key: vec![4, 3, 2, 1],
+            nonce: vec![8, 7, 6, 5],
+            ephemeral_public_key: None,
+        };
+
+        note_keys::update(&pool, &note_key)
+            .await
+            .expect("failed to update note key");
+
+        assert_eq!(
+            note_keys::get_by_id(&pool, &id)
+                .await
+                .expect("failed to get note key by id"),
+            note_key
+        )
+    }
+
     #[tokio::test]
     async fn delete_by_note_id_and_user_id() {
         let pool = init_db().await;
@@ -523,4 +772,72 @@ mod tests {
                 .is_err_and(|e| matches!(e, db::Error::NotFound))
         );
     }
+
+    #[tokio::test]
+    async fn delete_by_note_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let note_id = Uuid::new_v4();
+        let encryted_markdown = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+
+        sqlx::query(
+            r#"
+            INSERT INTO notes (id, encrypted_markdown, nonce)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(note_id)
+        .bind(&encryted_markdown)
+        .bind(&nonce)
+        .execute(&pool)
+        .await
+        .expect("failed to insert note");
+
+        for username in ["test 1", "test 2"] {
+            let user_id = Uuid::new_v4();
+
+            sqlx::query(
+                r#"
+                INSERT INTO users (id, username)
+                VALUES (?1, ?2)
+                "#,
+            )
+            .bind(user_id)
+            .bind(username)
+            .execute(&pool)
+            .await
+            .expect("failed to insert user");
+
+            sqlx::query(
+                r#"
+                INSERT INTO note_keys (id, note_id, user_id, encrypted_key, nonce)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(note_id)
+            .bind(user_id)
+            .bind(vec![1, 2, 3, 4])
+            .bind(vec![5, 6, 7, 8])
+            .execute(&pool)
+            .await
+            .expect("failed to insert note_key");
+        }
+
+        // Perform test
+
+        note_keys::delete_by_note_id(&pool, &note_id)
+            .await
+            .expect("failed to delete note keys by note id");
+
+        assert!(
+            note_keys::get_by_note_id(&pool, &note_id)
+                .await
+                .expect("failed to get note keys by note id")
+                .is_empty()
+        );
+    }
 }
@@ -10,6 +10,10 @@ pub struct NoteRow {
     pub encrypted_markdown: Vec<u8>,
     pub nonce: Vec<u8>,
 
+    /// Whether the note was encrypted by the client. The server does not hold
+    /// the keys of these notes and can only store and return them.
+    pub end_to_end: bool,
+
     /// Time created is set by the database server when
     /// when creating a new Note Row. It must therefore
     /// be optional.
@@ -22,16 +26,18 @@ where
 {
     sqlx::query(
         r#"
-        INSERT INTO notes (id, encrypted_markdown, nonce)
-        VALUES (?1, ?2, ?3)
+        INSERT INTO notes (id, encrypted_markdown, nonce, end_to_end)
+        VALUES (?1, ?2, ?3, ?4)
         ON CONFLICT (id) DO UPDATE SET
             encrypted_markdown = ?2,
-            nonce = ?3
+            nonce = ?3,
+            end_to_end = ?4
         "#,
     )
     .bind(&note.id)
     .bind(&note.encrypted_markdown)
     .bind(&note.nonce)
+    .bind(note.end_to_end)
     .execute(executor)
     .await?;
 
@@ -44,7 +50,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, encrypted_markdown, nonce, time_created
+        SELECT id, encrypted_markdown, nonce, end_to_end, time_created
         FROM notes
         WHERE id = ?1
         "#,
@@ -75,6 +81,70 @@ where
     }
 }
 
+/// Gets the owner of a note, which is missing if the owner left the note or
+/// was deleted.
+pub async fn get_owner_id<'e, E>(executor: E, id: &Uuid) -> db::Result<Option<Uuid>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_scalar(
+        r#"
+        SELECT owner_id
+        FROM notes
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn update_owner_id<'e, E>(
+    executor: E,
+    id: &Uuid,
+    owner_id: Option<&Uuid>,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE notes
+        SET owner_id = ?2
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .bind(owner_id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes the notes of a user that are not shared with anyone else, along
+/// with their note keys. Returns the number of notes.
+pub async fn delete_unshared_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        DELETE FROM notes
+        WHERE id IN (SELECT note_id FROM note_keys WHERE user_id = ?1)
+        AND id NOT IN (SELECT note_id FROM note_keys WHERE user_id != ?1)
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
 #[cfg(test)]
 mod tests {
     use utilities::db::init_db;
@@ -93,6 +163,7 @@ mod tests {
             id: Uuid::new_v4(),
             encrypted_markdown: vec![1, 2, 3, 4],
             nonce: vec![5, 6, 7, 8],
+            end_to_end: false,
             time_created: None,
         };
 
@@ -106,9 +177,11 @@ mod tests {
         assert_eq!(inserted.id, note.id);
         assert_eq!(inserted.encrypted_markdown, note.encrypted_markdown);
         assert_eq!(inserted.nonce, note.nonce);
+        assert_eq!(inserted.end_to_end, note.end_to_end);
 
         note.encrypted_markdown = vec![5, 6, 7, 8];
         note.nonce = vec![1, 2, 3, 4];
+        note.end_to_end = true;
 
         notes::upsert(&pool, &note)
             .await
@@ -120,6 +193,7 @@ mod tests {
         assert_eq!(updated.id, note.id);
         assert_eq!(updated.encrypted_markdown, note.encrypted_markdown);
         assert_eq!(updated.nonce, note.nonce);
+        assert_eq!(updated.end_to_end, note.end_to_end);
     }
 
     #[tokio::test]
@@ -132,6 +206,7 @@ mod tests {
             id: Uuid::new_v4(),
             encrypted_markdown: vec![1, 2, 3, 4],
             nonce: vec![5, 6, 7, 8],
+            end_to_end: false,
             time_created: None,
         };
 
@@ -0,0 +1,176 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct OpaqueLoginRow {
+    pub id: Uuid,
+
+    /// The user signing in, or none when the username is unknown
+    pub user_id: Option<Uuid>,
+
+    /// The serialized server state between the two login messages
+    pub state: Vec<u8>,
+    pub expiration_time: DateTime<Utc>,
+}
+
+pub async fn create<'e, E>(executor: E, opaque_login: &OpaqueLoginRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO opaque_logins (id, user_id, state, expiration_time)
+        VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(opaque_login.id)
+    .bind(opaque_login.user_id)
+    .bind(&opaque_login.state)
+    .bind(opaque_login.expiration_time)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<OpaqueLoginRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, state, expiration_time
+        FROM opaque_logins
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Deletes and returns a login state, so a login can only be finished once.
+pub async fn take_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<OpaqueLoginRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        DELETE FROM opaque_logins
+        WHERE id = ?1
+        RETURNING id, user_id, state, expiration_time
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Deletes the login states that expired before `time` without being finished.
+pub async fn delete_expired<'e, E>(executor: E, time: &DateTime<Utc>) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        DELETE FROM opaque_logins
+        WHERE expiration_time < ?1
+        "#,
+    )
+    .bind(time)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        opaque_logins::{self, OpaqueLoginRow},
+    };
+
+    #[tokio::test]
+    async fn create_and_take() {
+        let pool = init_db().await;
+
+        // Perform test
+
+        let opaque_login = OpaqueLoginRow {
+            id: Uuid::new_v4(),
+            user_id: None,
+            state: vec![1, 2, 3, 4],
+            expiration_time: DateTime::from_timestamp(0, 0).expect("invalid timestamp"),
+        };
+
+        opaque_logins::create(&pool, &opaque_login)
+            .await
+            .expect("failed to create opaque login");
+
+        assert_eq!(
+            opaque_logins::get_by_id(&pool, &opaque_login.id)
+                .await
+                .expect("failed to get opaque login"),
+            opaque_login
+        );
+        assert_eq!(
+            opaque_logins::take_by_id(&pool, &opaque_login.id)
+                .await
+                .expect("failed to take opaque login"),
+            opaque_login
+        );
+
+        // Login states can only be taken once
+        assert!(
+            opaque_logins::take_by_id(&pool, &opaque_login.id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_expired() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let expiration_time = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        let expired_id = Uuid::new_v4();
+        let valid_id = Uuid::new_v4();
+
+        for (id, expiration_time) in [
+            (expired_id, expiration_time),
+            (valid_id, expiration_time + Duration::minutes(5)),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO opaque_logins (id, state, expiration_time)
+                VALUES (?1, ?2, ?3)
+                "#,
+            )
+            .bind(id)
+            .bind(vec![1, 2, 3, 4])
+            .bind(expiration_time)
+            .execute(&pool)
+            .await
+            .expect("failed to insert opaque login");
+        }
+
+        // Perform test
+
+        opaque_logins::delete_expired(&pool, &(expiration_time + Duration::minutes(1)))
+            .await
+            .expect("failed to delete expired opaque logins");
+
+        assert!(opaque_logins::take_by_id(&pool, &expired_id).await.is_err());
+        assert!(opaque_logins::take_by_id(&pool, &valid_id).await.is_ok());
+    }
+}
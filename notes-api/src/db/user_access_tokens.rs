@@ -0,0 +1,275 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserAccessTokenRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+
+    /// The wrapping of the user key that the token's secret unlocks
+    pub user_key_id: Uuid,
+    pub name: String,
+
+    /// The scopes of the token, separated by spaces
+    pub scopes: String,
+
+    /// Time created is set by the database server when creating a new
+    /// access token row. It must therefore be optional.
+    pub time_created: Option<DateTime<Utc>>,
+    pub expiration_time: Option<DateTime<Utc>>,
+    pub last_used_time: Option<DateTime<Utc>>,
+}
+
+pub async fn create<'e, E>(executor: E, user_access_token: &UserAccessTokenRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_access_tokens (id, user_id, user_key_id, name, scopes, expiration_time, last_used_time)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        "#,
+    )
+    .bind(user_access_token.id)
+    .bind(user_access_token.user_id)
+    .bind(user_access_token.user_key_id)
+    .bind(&user_access_token.name)
+    .bind(&user_access_token.scopes)
+    .bind(user_access_token.expiration_time)
+    .bind(user_access_token.last_used_time)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<UserAccessTokenRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id, name, scopes, time_created, expiration_time, last_used_time
+        FROM user_access_tokens
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> db::Result<Vec<UserAccessTokenRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id, name, scopes, time_created, expiration_time, last_used_time
+        FROM user_access_tokens
+        WHERE user_id = ?1
+        ORDER BY time_created
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await?)
+}
+
+pub async fn update_last_used_time<'e, E>(
+    executor: E,
+    id: &Uuid,
+    last_used_time: &DateTime<Utc>,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE user_access_tokens
+        SET last_used_time = ?2
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .bind(last_used_time)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_access_tokens
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_access_tokens::{self, UserAccessTokenRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> (Uuid, Uuid) {
+        let user_id = Uuid::new_v4();
+        let user_key_id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind("test".to_string())
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(user_key_id)
+        .bind(user_id)
+        .bind(vec![1, 2, 3, 4])
+        .bind(vec![5, 6, 7, 8])
+        .bind(vec![9, 10, 11, 12])
+        .execute(pool)
+        .await
+        .expect("failed to insert user key");
+
+        (user_id, user_key_id)
+    }
+
+    fn user_access_token(user_id: Uuid, user_key_id: Uuid) -> UserAccessTokenRow {
+        UserAccessTokenRow {
+            id: Uuid::new_v4(),
+            user_id,
+            user_key_id,
+            name: "test".to_string(),
+            scopes: "notes:read".to_string(),
+            time_created: None,
+            expiration_time: None,
+            last_used_time: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+
+        // Perform test
+
+        let user_access_token = user_access_token(user_id, user_key_id);
+
+        user_access_tokens::create(&pool, &user_access_token)
+            .await
+            .expect("failed to create user access token");
+
+        let inserted = user_access_tokens::get_by_id(&pool, &user_access_token.id)
+            .await
+            .expect("failed to get user access token");
+        assert_eq!(inserted.scopes, user_access_token.scopes);
+        assert!(inserted.time_created.is_some());
+
+        assert_eq!(
+            user_access_tokens::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user access tokens by user id"),
+            vec![inserted]
+        );
+    }
+
+    #[tokio::test]
+    async fn update_last_used_time() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+        let user_access_token = user_access_token(user_id, user_key_id);
+
+        user_access_tokens::create(&pool, &user_access_token)
+            .await
+            .expect("failed to create user access token");
+
+        // Perform test
+
+        let last_used_time = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        user_access_tokens::update_last_used_time(&pool, &user_access_token.id, &last_used_time)
+            .await
+            .expect("failed to update last used time");
+
+        assert_eq!(
+            user_access_tokens::get_by_id(&pool, &user_access_token.id)
+                .await
+                .expect("failed to get user access token")
+                .last_used_time,
+            Some(last_used_time)
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_by_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+        let user_access_token = user_access_token(user_id, user_key_id);
+
+        user_access_tokens::create(&pool, &user_access_token)
+            .await
+            .expect("failed to create user access token");
+
+        // Perform test
+
+        user_access_tokens::delete_by_id(&pool, &user_access_token.id)
+            .await
+            .expect("failed to delete user access token");
+
+        assert!(
+            user_access_tokens::get_by_id(&pool, &user_access_token.id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+}
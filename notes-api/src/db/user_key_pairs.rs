@@ -0,0 +1,250 @@
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserKeyPairRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub public_key: Vec<u8>,
+    pub encrypted_private_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+pub async fn create<'e, E>(executor: E, user_key_pair: &UserKeyPairRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_key_pairs (id, user_id, public_key, encrypted_private_key, nonce)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        "#,
+    )
+    .bind(user_key_pair.id)
+    .bind(user_key_pair.user_id)
+    .bind(&user_key_pair.public_key)
+    .bind(&user_key_pair.encrypted_private_key)
+    .bind(&user_key_pair.nonce)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<UserKeyPairRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, public_key, encrypted_private_key, nonce
+        FROM user_key_pairs
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn delete_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_key_pairs
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the wrapped private key of a user with zeros, before the user
+/// is deleted.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE user_key_pairs
+        SET encrypted_private_key = zeroblob(length(encrypted_private_key))
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_key_pairs::{self, UserKeyPairRow},
+    };
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        // Perform test
+
+        let user_key_pair = UserKeyPairRow {
+            id: Uuid::new_v4(),
+            user_id,
+            public_key: vec![1, 2, 3, 4],
+            encrypted_private_key: vec![5, 6, 7, 8],
+            nonce: vec![4, 3, 2, 1],
+        };
+
+        user_key_pairs::create(&pool, &user_key_pair)
+            .await
+            .expect("failed to create user key pair");
+
+        assert_eq!(
+            user_key_pairs::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user key pair by user id"),
+            user_key_pair
+        )
+    }
+
+    #[tokio::test]
+    async fn get_by_user_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let id = Uuid::new_v4();
+        let public_key = vec![1, 2, 3, 4];
+        let encrypted_private_key = vec![5, 6, 7, 8];
+        let nonce = vec![4, 3, 2, 1];
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_key_pairs (id, user_id, public_key, encrypted_private_key, nonce)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(&public_key)
+        .bind(&encrypted_private_key)
+        .bind(&nonce)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user key pair");
+
+        // Perform test
+
+        assert_eq!(
+            user_key_pairs::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user key pair by user id"),
+            UserKeyPairRow {
+                id,
+                user_id,
+                public_key,
+                encrypted_private_key,
+                nonce
+            }
+        )
+    }
+
+    #[tokio::test]
+    async fn delete_by_user_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_key_pairs (id, user_id, public_key, encrypted_private_key, nonce)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(vec![1, 2, 3, 4])
+        .bind(vec![5, 6, 7, 8])
+        .bind(vec![4, 3, 2, 1])
+        .execute(&pool)
+        .await
+        .expect("failed to insert user key pair");
+
+        // Perform test
+
+        user_key_pairs::delete_by_user_id(&pool, &user_id)
+            .await
+            .expect("failed to delete user key pair by user id");
+
+        assert!(
+            user_key_pairs::get_by_user_id(&pool, &user_id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        )
+    }
+}
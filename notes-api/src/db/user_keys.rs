@@ -10,6 +10,7 @@ pub struct UserKeyRow {
     pub encrypted_key: Vec<u8>,
     pub nonce: Vec<u8>,
     pub salt: Vec<u8>,
+    pub hash_params: String,
 }
 
 pub async fn create<'e, E>(executor: E, user_key: &UserKeyRow) -> anyhow::Result<()>
@@ -18,8 +19,8 @@ where
 {
     sqlx::query(
         r#"
-        INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
-        VALUES (?1, ?2, ?3, ?4, ?5)
+        INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt, hash_params)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
         "#,
     )
     .bind(&user_key.id)
@@ -27,6 +28,7 @@ where
     .bind(&user_key.encrypted_key)
     .bind(&user_key.nonce)
     .bind(&user_key.salt)
+    .bind(&user_key.hash_params)
     .execute(executor)
     .await?;
 
@@ -39,7 +41,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, user_id, encrypted_key, nonce, salt
+        SELECT id, user_id, encrypted_key, nonce, salt, hash_params
         FROM user_keys
         WHERE id = ?1
         "#,
@@ -55,7 +57,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, user_id, encrypted_key, nonce, salt
+        SELECT id, user_id, encrypted_key, nonce, salt, hash_params
         FROM user_keys
         WHERE user_id = ?1
         "#,
@@ -65,12 +67,55 @@ where
     .await?)
 }
 
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_keys
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the wrapped user keys of a user with zeros, before the user is
+/// deleted. Returns the number of user keys.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE user_keys
+        SET encrypted_key = zeroblob(length(encrypted_key)), salt = zeroblob(length(salt))
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
 #[cfg(test)]
 mod tests {
     use utilities::db::init_db;
     use uuid::Uuid;
 
-    use crate::db::user_keys::{self, UserKeyRow};
+    use crate::db::{
+        self,
+        user_keys::{self, UserKeyRow},
+    };
 
     #[tokio::test]
     async fn create() {
@@ -101,6 +146,7 @@ mod tests {
             encrypted_key: vec![1, 2, 3, 4],
             nonce: vec![5, 6, 7, 8],
             salt: vec![4, 3, 2, 1],
+            hash_params: "$argon2id$v=19$m=19456,t=2,p=1".to_string(),
         };
 
         user_keys::create(&pool, &user_key)
@@ -140,11 +186,12 @@ mod tests {
         let encrypted_key = vec![1, 2, 3, 4];
         let nonce = vec![5, 6, 7, 8];
         let salt = vec![4, 3, 2, 1];
+        let hash_params = "$argon2id$v=19$m=19456,t=2,p=1".to_string();
 
         sqlx::query(
             r#"
-            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
-            VALUES (?1, ?2, ?3, ?4, ?5)
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt, hash_params)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
             "#,
         )
         .bind(&id)
@@ -152,6 +199,7 @@ mod tests {
         .bind(&encrypted_key)
         .bind(&nonce)
         .bind(&salt)
+        .bind(&hash_params)
         .execute(&pool)
         .await
         .expect("failed to insert user key");
@@ -168,6 +216,7 @@ mod tests {
                 encrypted_key,
                 nonce,
                 salt,
+                hash_params,
             }
         )
     }
@@ -197,11 +246,12 @@ mod tests {
         let encrypted_key = vec![1, 2, 3, 4];
         let nonce = vec![5, 6, 7, 8];
         let salt = vec![4, 3, 2, 1];
+        let hash_params = "$argon2id$v=19$m=19456,t=2,p=1".to_string();
 
         sqlx::query(
             r#"
-            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
-            VALUES (?1, ?2, ?3, ?4, ?5)
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt, hash_params)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
             "#,
         )
         .bind(&id)
@@ -209,6 +259,7 @@ mod tests {
         .bind(&encrypted_key)
         .bind(&nonce)
         .bind(&salt)
+        .bind(&hash_params)
         .execute(&pool)
         .await
         .expect("failed to insert user key");
@@ -224,8 +275,65 @@ mod tests {
                 user_id,
                 encrypted_key,
                 nonce,
-                salt
+                salt,
+                hash_params,
             }]
         )
     }
+
+    #[tokio::test]
+    async fn delete_by_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let id = Uuid::new_v4();
+        let encrypted_key = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+        let salt = vec![4, 3, 2, 1];
+        let hash_params = "$argon2id$v=19$m=19456,t=2,p=1".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt, hash_params)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(&encrypted_key)
+        .bind(&nonce)
+        .bind(&salt)
+        .bind(&hash_params)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user key");
+
+        // Perform test
+
+        user_keys::delete_by_id(&pool, &id)
+            .await
+            .expect("failed to delete user key by id");
+
+        assert!(
+            user_keys::get_by_id(&pool, &id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        )
+    }
 }
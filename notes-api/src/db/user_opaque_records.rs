@@ -0,0 +1,202 @@
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserOpaqueRecordRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+
+    /// The wrapping of the user key under the OPAQUE export key
+    pub user_key_id: Uuid,
+
+    /// The serialized OPAQUE registration record
+    pub password_file: Vec<u8>,
+}
+
+pub async fn create<'e, E>(executor: E, user_opaque_record: &UserOpaqueRecordRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_opaque_records (id, user_id, user_key_id, password_file)
+        VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(user_opaque_record.id)
+    .bind(user_opaque_record.user_id)
+    .bind(user_opaque_record.user_key_id)
+    .bind(&user_opaque_record.password_file)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<UserOpaqueRecordRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id, password_file
+        FROM user_opaque_records
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_opaque_records
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the OPAQUE record of a user with zeros, before the user is
+/// deleted.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE user_opaque_records
+        SET password_file = zeroblob(length(password_file))
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_opaque_records::{self, UserOpaqueRecordRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> (Uuid, Uuid) {
+        let user_id = Uuid::new_v4();
+        let user_key_id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind("test".to_string())
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(user_key_id)
+        .bind(user_id)
+        .bind(vec![1, 2, 3, 4])
+        .bind(vec![5, 6, 7, 8])
+        .bind(vec![9, 10, 11, 12])
+        .execute(pool)
+        .await
+        .expect("failed to insert user key");
+
+        (user_id, user_key_id)
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+
+        // Perform test
+
+        let user_opaque_record = UserOpaqueRecordRow {
+            id: Uuid::new_v4(),
+            user_id,
+            user_key_id,
+            password_file: vec![1, 2, 3, 4],
+        };
+
+        user_opaque_records::create(&pool, &user_opaque_record)
+            .await
+            .expect("failed to create user opaque record");
+
+        assert_eq!(
+            user_opaque_records::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user opaque record by user id"),
+            user_opaque_record
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_by_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+        let id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_opaque_records (id, user_id, user_key_id, password_file)
+            VALUES (?1, ?2, ?3, ?4)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(user_key_id)
+        .bind(vec![1, 2, 3, 4])
+        .execute(&pool)
+        .await
+        .expect("failed to insert user opaque record");
+
+        // Perform test
+
+        user_opaque_records::delete_by_id(&pool, &id)
+            .await
+            .expect("failed to delete user opaque record");
+
+        assert!(
+            user_opaque_records::get_by_user_id(&pool, &user_id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserPasskeyRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+
+    /// The wrapping of the user key that the passkey's PRF output unlocks
+    pub user_key_id: Uuid,
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub name: String,
+
+    /// Time created is set by the database server when creating a new
+    /// passkey row. It must therefore be optional.
+    pub time_created: Option<DateTime<Utc>>,
+    pub last_used_time: Option<DateTime<Utc>>,
+}
+
+pub async fn create<'e, E>(executor: E, user_passkey: &UserPasskeyRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_passkeys (id, user_id, user_key_id, credential_id, public_key, sign_count, name, last_used_time)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        "#,
+    )
+    .bind(user_passkey.id)
+    .bind(user_passkey.user_id)
+    .bind(user_passkey.user_key_id)
+    .bind(&user_passkey.credential_id)
+    .bind(&user_passkey.public_key)
+    .bind(user_passkey.sign_count)
+    .bind(&user_passkey.name)
+    .bind(user_passkey.last_used_time)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<UserPasskeyRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id, credential_id, public_key, sign_count, name, time_created, last_used_time
+        FROM user_passkeys
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn get_by_credential_id<'e, E>(
+    executor: E,
+    credential_id: &[u8],
+) -> db::Result<UserPasskeyRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id, credential_id, public_key, sign_count, name, time_created, last_used_time
+        FROM user_passkeys
+        WHERE credential_id = ?1
+        "#,
+    )
+    .bind(credential_id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<Vec<UserPasskeyRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id, credential_id, public_key, sign_count, name, time_created, last_used_time
+        FROM user_passkeys
+        WHERE user_id = ?1
+        ORDER BY time_created
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Records the sign count of an assertion. Fails with `NotFound` if another
+/// assertion raised the sign count in the meantime, unless the authenticator
+/// does not count.
+pub async fn update_sign_count<'e, E>(
+    executor: E,
+    id: &Uuid,
+    sign_count: i64,
+    last_used_time: &DateTime<Utc>,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE user_passkeys
+        SET sign_count = ?2, last_used_time = ?3
+        WHERE id = ?1 AND (sign_count < ?2 OR sign_count = 0)
+        "#,
+    )
+    .bind(id)
+    .bind(sign_count)
+    .bind(last_used_time)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_passkeys
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_passkeys::{self, UserPasskeyRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> (Uuid, Uuid) {
+        let user_id = Uuid::new_v4();
+        let user_key_id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind("test".to_string())
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(user_key_id)
+        .bind(user_id)
+        .bind(vec![1, 2, 3, 4])
+        .bind(vec![5, 6, 7, 8])
+        .bind(vec![9, 10, 11, 12])
+        .execute(pool)
+        .await
+        .expect("failed to insert user key");
+
+        (user_id, user_key_id)
+    }
+
+    fn user_passkey(user_id: Uuid, user_key_id: Uuid) -> UserPasskeyRow {
+        UserPasskeyRow {
+            id: Uuid::new_v4(),
+            user_id,
+            user_key_id,
+            credential_id: vec![1, 2, 3, 4],
+            public_key: vec![5, 6, 7, 8],
+            sign_count: 0,
+            name: "test".to_string(),
+            time_created: None,
+            last_used_time: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+
+        // Perform test
+
+        let user_passkey = user_passkey(user_id, user_key_id);
+
+        user_passkeys::create(&pool, &user_passkey)
+            .await
+            .expect("failed to create user passkey");
+
+        let inserted = user_passkeys::get_by_credential_id(&pool, &user_passkey.credential_id)
+            .await
+            .expect("failed to get user passkey by credential id");
+        assert_eq!(inserted.id, user_passkey.id);
+        assert!(inserted.time_created.is_some());
+
+        assert_eq!(
+            user_passkeys::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user passkeys by user id"),
+            vec![inserted]
+        );
+    }
+
+    #[tokio::test]
+    async fn update_sign_count() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+        let user_passkey = user_passkey(user_id, user_key_id);
+
+        user_passkeys::create(&pool, &user_passkey)
+            .await
+            .expect("failed to create user passkey");
+
+        // Perform test
+
+        let last_used_time = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        user_passkeys::update_sign_count(&pool, &user_passkey.id, 5, &last_used_time)
+            .await
+            .expect("failed to update sign count");
+
+        let updated = user_passkeys::get_by_id(&pool, &user_passkey.id)
+            .await
+            .expect("failed to get user passkey");
+        assert_eq!(updated.sign_count, 5);
+        assert_eq!(updated.last_used_time, Some(last_used_time));
+
+        // Sign counts cannot be reused, nor can they decrease
+        for sign_count in [5, 4] {
+            assert!(
+                user_passkeys::update_sign_count(
+                    &pool,
+                    &user_passkey.id,
+                    sign_count,
+                    &last_used_time
+                )
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_by_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+        let user_passkey = user_passkey(user_id, user_key_id);
+
+        user_passkeys::create(&pool, &user_passkey)
+            .await
+            .expect("failed to create user passkey");
+
+        // Perform test
+
+        user_passkeys::delete_by_id(&pool, &user_passkey.id)
+            .await
+            .expect("failed to delete user passkey");
+
+        assert!(
+            user_passkeys::get_by_id(&pool, &user_passkey.id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+}
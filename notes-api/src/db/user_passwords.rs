@@ -10,6 +10,7 @@ pub struct UserPasswordRow {
     pub user_key_id: Uuid,
     pub hash: Vec<u8>,
     pub salt: Vec<u8>,
+    pub hash_params: String,
 }
 
 pub async fn create<'e, E>(executor: E, user_password: &UserPasswordRow) -> db::Result<()>
@@ -18,8 +19,8 @@ where
 {
     sqlx::query(
         r#"
-        INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt)
-        VALUES (?1, ?2, ?3, ?4, ?5)
+        INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt, hash_params)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
         "#,
     )
     .bind(&user_password.id)
@@ -27,6 +28,7 @@ where
     .bind(&user_password.user_key_id)
     .bind(&user_password.hash)
     .bind(&user_password.salt)
+    .bind(&user_password.hash_params)
     .execute(executor)
     .await?;
 
@@ -39,7 +41,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, user_id, user_key_id, hash, salt
+        SELECT id, user_id, user_key_id, hash, salt, hash_params
         FROM user_passwords
         WHERE id = ?1
         "#,
@@ -55,7 +57,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, user_id, user_key_id, hash, salt
+        SELECT id, user_id, user_key_id, hash, salt, hash_params
         FROM user_passwords
         WHERE user_id = ?1
         "#,
@@ -65,6 +67,66 @@ where
     .await?)
 }
 
+/// The hash parameters of the password at `index`, ordered by id and counted
+/// modulo the number of passwords, or `None` if there are no passwords.
+pub async fn get_hash_params_at<'e, E>(executor: E, index: i64) -> db::Result<Option<String>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_scalar(
+        r#"
+        SELECT hash_params
+        FROM user_passwords
+        ORDER BY id
+        LIMIT 1
+        OFFSET ?1 % MAX((SELECT COUNT(*) FROM user_passwords), 1)
+        "#,
+    )
+    .bind(index)
+    .fetch_optional(executor)
+    .await?)
+}
+
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_passwords
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the password hash of a user with zeros, before the user is
+/// deleted.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE user_passwords
+        SET hash = zeroblob(length(hash)), salt = zeroblob(length(salt))
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
 #[cfg(test)]
 mod tests {
     use std::vec;
@@ -72,7 +134,10 @@ mod tests {
     use utilities::db::init_db;
     use uuid::Uuid;
 
-    use crate::db::user_passwords::{self, UserPasswordRow};
+    use crate::db::{
+        self,
+        user_passwords::{self, UserPasswordRow},
+    };
 
     #[tokio::test]
     async fn create() {
@@ -123,6 +188,7 @@ mod tests {
             user_key_id,
             hash: vec![1, 2, 3, 4],
             salt: vec![4, 3, 2, 1],
+            hash_params: "$argon2id$v=19$m=19456,t=2,p=1".to_string(),
         };
 
         user_passwords::create(&pool, &user_password)
@@ -181,11 +247,12 @@ mod tests {
         let id = Uuid::new_v4();
         let hash = vec![1, 2, 3, 4];
         let salt = vec![4, 3, 2, 1];
+        let hash_params = "$argon2id$v=19$m=19456,t=2,p=1".to_string();
 
         sqlx::query(
             r#"
-            INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt)
-            VALUES (?1, ?2, ?3, ?4, ?5)
+            INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt, hash_params)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
             "#,
         )
         .bind(&id)
@@ -193,6 +260,7 @@ mod tests {
         .bind(&user_key_id)
         .bind(&hash)
         .bind(&salt)
+        .bind(&hash_params)
         .execute(&pool)
         .await
         .expect("failed to insert user password");
@@ -208,7 +276,8 @@ mod tests {
                 user_id,
                 user_key_id,
                 hash,
-                salt
+                salt,
+                hash_params,
             }
         )
     }
@@ -257,11 +326,12 @@ mod tests {
         let id = Uuid::new_v4();
         let hash = vec![1, 2, 3, 4];
         let salt = vec![4, 3, 2, 1];
+        let hash_params = "$argon2id$v=19$m=19456,t=2,p=1".to_string();
 
         sqlx::query(
             r#"
-            INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt)
-            VALUES (?1, ?2, ?3, ?4, ?5)
+            INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt, hash_params)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
             "#,
         )
         .bind(&id)
@@ -269,6 +339,7 @@ mod tests {
         .bind(&user_key_id)
         .bind(&hash)
         .bind(&salt)
+        .bind(&hash_params)
         .execute(&pool)
         .await
         .expect("failed to insert user password");
@@ -284,8 +355,160 @@ mod tests {
                 user_id,
                 user_key_id,
                 hash,
-                salt
+                salt,
+                hash_params,
             }
         )
     }
+
+    #[tokio::test]
+    async fn delete_by_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let user_key_id = Uuid::new_v4();
+        let encrypted_key = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+        let salt = vec![4, 3, 2, 1];
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(user_key_id)
+        .bind(user_id)
+        .bind(&encrypted_key)
+        .bind(&nonce)
+        .bind(&salt)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user key");
+
+        let id = Uuid::new_v4();
+        let hash = vec![1, 2, 3, 4];
+        let salt = vec![4, 3, 2, 1];
+        let hash_params = "$argon2id$v=19$m=19456,t=2,p=1".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_passwords (id, user_id, user_key_id, hash, salt, hash_params)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(user_key_id)
+        .bind(&hash)
+        .bind(&salt)
+        .bind(&hash_params)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user password");
+
+        // Perform test
+
+        user_passwords::delete_by_id(&pool, &id)
+            .await
+            .expect("failed to delete user password by id");
+
+        assert!(
+            user_passwords::get_by_id(&pool, &id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        )
+    }
+
+    #[tokio::test]
+    async fn get_hash_params_at() {
+        let pool = init_db().await;
+
+        // Without passwords there are no parameters to pick
+        assert_eq!(
+            user_passwords::get_hash_params_at(&pool, 0)
+                .await
+                .expect("failed to get hash params"),
+            None
+        );
+
+        // Populate database
+
+        let mut user_passwords = Vec::new();
+        for hash_params in [
+            "$argon2id$v=19$m=19456,t=2,p=1",
+            "$argon2id$v=19$m=8192,t=1,p=1",
+        ] {
+            let user_id = Uuid::new_v4();
+            sqlx::query(
+                r#"
+                INSERT INTO users (id, username)
+                VALUES (?1, ?2)
+                "#,
+            )
+            .bind(user_id)
+            .bind(user_id.to_string())
+            .execute(&pool)
+            .await
+            .expect("failed to insert user");
+
+            let user_key_id = Uuid::new_v4();
+            sqlx::query(
+                r#"
+                INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+            )
+            .bind(user_key_id)
+            .bind(user_id)
+            .bind(vec![1, 2, 3, 4])
+            .bind(vec![5, 6, 7, 8])
+            .bind(vec![4, 3, 2, 1])
+            .execute(&pool)
+            .await
+            .expect("failed to insert user key");
+
+            let user_password = UserPasswordRow {
+                id: Uuid::new_v4(),
+                user_id,
+                user_key_id,
+                hash: vec![1, 2, 3, 4],
+                salt: vec![4, 3, 2, 1],
+                hash_params: hash_params.to_string(),
+            };
+            user_passwords::create(&pool, &user_password)
+                .await
+                .expect("failed to create user password");
+            user_passwords.push(user_password);
+        }
+        user_passwords.sort_by_key(|user_password| user_password.id);
+
+        // Perform test
+
+        // Indexes wrap around at the number of passwords
+        for index in 0..4 {
+            assert_eq!(
+                user_passwords::get_hash_params_at(&pool, index)
+                    .await
+                    .expect("failed to get hash params"),
+                Some(user_passwords[index as usize % 2].hash_params.clone())
+            );
+        }
+    }
 }
@@ -0,0 +1,241 @@
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserRecoveryCodeRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_key_id: Uuid,
+}
+
+pub async fn create<'e, E>(executor: E, user_recovery_code: &UserRecoveryCodeRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_recovery_codes (id, user_id, user_key_id)
+        VALUES (?1, ?2, ?3)
+        "#,
+    )
+    .bind(user_recovery_code.id)
+    .bind(user_recovery_code.user_id)
+    .bind(user_recovery_code.user_key_id)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<UserRecoveryCodeRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, user_key_id
+        FROM user_recovery_codes
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// The hash parameters of the user key wrapping of the recovery code at
+/// `index`, ordered by id and counted modulo the number of recovery codes, or
+/// `None` if there are no recovery codes.
+pub async fn get_hash_params_at<'e, E>(executor: E, index: i64) -> db::Result<Option<String>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_scalar(
+        r#"
+        SELECT user_keys.hash_params
+        FROM user_recovery_codes
+            JOIN user_keys
+                ON user_recovery_codes.user_key_id = user_keys.id
+        ORDER BY user_recovery_codes.id
+        LIMIT 1
+        OFFSET ?1 % MAX((SELECT COUNT(*) FROM user_recovery_codes), 1)
+        "#,
+    )
+    .bind(index)
+    .fetch_optional(executor)
+    .await?)
+}
+
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_recovery_codes
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_recovery_codes::{self, UserRecoveryCodeRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> (Uuid, Uuid) {
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        let user_key_id = Uuid::new_v4();
+        let encrypted_key = vec![1, 2, 3, 4];
+        let nonce = vec![5, 6, 7, 8];
+        let salt = vec![4, 3, 2, 1];
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_keys (id, user_id, encrypted_key, nonce, salt)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )
+        .bind(user_key_id)
+        .bind(user_id)
+        .bind(&encrypted_key)
+        .bind(&nonce)
+        .bind(&salt)
+        .execute(pool)
+        .await
+        .expect("failed to insert user key");
+
+        (user_id, user_key_id)
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+
+        // Perform test
+
+        let user_recovery_code = UserRecoveryCodeRow {
+            id: Uuid::new_v4(),
+            user_id,
+            user_key_id,
+        };
+
+        user_recovery_codes::create(&pool, &user_recovery_code)
+            .await
+            .expect("failed to create user recovery code");
+
+        assert_eq!(
+            user_recovery_codes::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user recovery code by user id"),
+            user_recovery_code
+        )
+    }
+
+    #[tokio::test]
+    async fn get_by_user_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+
+        let id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_recovery_codes (id, user_id, user_key_id)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(user_key_id)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user recovery code");
+
+        // Perform test
+
+        assert_eq!(
+            user_recovery_codes::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user recovery code by user id"),
+            UserRecoveryCodeRow {
+                id,
+                user_id,
+                user_key_id
+            }
+        )
+    }
+
+    #[tokio::test]
+    async fn delete_by_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let (user_id, user_key_id) = populate(&pool).await;
+
+        let id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_recovery_codes (id, user_id, user_key_id)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(user_key_id)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user recovery code");
+
+        // Perform test
+
+        user_recovery_codes::delete_by_id(&pool, &id)
+            .await
+            .expect("failed to delete user recovery code by id");
+
+        assert!(
+            user_recovery_codes::get_by_user_id(&pool, &user_id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        )
+    }
+}
@@ -0,0 +1,242 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserSessionRefreshTokenRow {
+    pub id: Uuid,
+    pub session_id: Uuid,
+
+    /// The user key, wrapped with the secret of the token. It is dropped when
+    /// the token is used.
+    pub encrypted_user_key: Option<Vec<u8>>,
+
+    /// Time created is set by the database server when creating a new
+    /// refresh token row. It must therefore be optional.
+    pub time_created: Option<DateTime<Utc>>,
+    pub time_used: Option<DateTime<Utc>>,
+}
+
+pub async fn create<'e, E>(
+    executor: E,
+    refresh_token: &UserSessionRefreshTokenRow,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_session_refresh_tokens (id, session_id, encrypted_user_key, time_used)
+        VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(refresh_token.id)
+    .bind(refresh_token.session_id)
+    .bind(&refresh_token.encrypted_user_key)
+    .bind(refresh_token.time_used)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<UserSessionRefreshTokenRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, session_id, encrypted_user_key, time_created, time_used
+        FROM user_session_refresh_tokens
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Marks a refresh token as used and drops its wrapping of the user key,
+/// which fails with `NotFound` if the token was used before.
+pub async fn update_used<'e, E>(executor: E, id: &Uuid, time_used: &DateTime<Utc>) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE user_session_refresh_tokens
+        SET encrypted_user_key = NULL, time_used = ?2
+        WHERE id = ?1 AND time_used IS NULL
+        "#,
+    )
+    .bind(id)
+    .bind(time_used)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the user keys wrapped by the refresh tokens of a user with
+/// zeros, before the user is deleted.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE user_session_refresh_tokens
+        SET encrypted_user_key = zeroblob(length(encrypted_user_key))
+        WHERE session_id IN (SELECT id FROM user_sessions WHERE user_id = ?1)
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_session_refresh_tokens::{self, UserSessionRefreshTokenRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> Uuid {
+        let user_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind("test".to_string())
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_sessions (id, user_id)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .expect("failed to insert user session");
+
+        session_id
+    }
+
+    fn refresh_token(session_id: Uuid) -> UserSessionRefreshTokenRow {
+        UserSessionRefreshTokenRow {
+            id: Uuid::new_v4(),
+            session_id,
+            encrypted_user_key: Some(vec![1, 2, 3, 4]),
+            time_created: None,
+            time_used: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let session_id = populate(&pool).await;
+
+        // Perform test
+
+        let refresh_token = refresh_token(session_id);
+
+        user_session_refresh_tokens::create(&pool, &refresh_token)
+            .await
+            .expect("failed to create refresh token");
+
+        let inserted = user_session_refresh_tokens::get_by_id(&pool, &refresh_token.id)
+            .await
+            .expect("failed to get refresh token");
+        assert_eq!(
+            inserted.encrypted_user_key,
+            refresh_token.encrypted_user_key
+        );
+        assert!(inserted.time_created.is_some());
+    }
+
+    #[tokio::test]
+    async fn update_used() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let session_id = populate(&pool).await;
+        let refresh_token = refresh_token(session_id);
+
+        user_session_refresh_tokens::create(&pool, &refresh_token)
+            .await
+            .expect("failed to create refresh token");
+
+        // Perform test
+
+        let time_used = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        user_session_refresh_tokens::update_used(&pool, &refresh_token.id, &time_used)
+            .await
+            .expect("failed to mark refresh token as used");
+
+        let updated = user_session_refresh_tokens::get_by_id(&pool, &refresh_token.id)
+            .await
+            .expect("failed to get refresh token");
+        assert_eq!(updated.encrypted_user_key, None);
+        assert_eq!(updated.time_used, Some(time_used));
+
+        // Tokens can only be used once
+        assert!(
+            user_session_refresh_tokens::update_used(&pool, &refresh_token.id, &time_used)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_with_session() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let session_id = populate(&pool).await;
+        let refresh_token = refresh_token(session_id);
+
+        user_session_refresh_tokens::create(&pool, &refresh_token)
+            .await
+            .expect("failed to create refresh token");
+
+        // Perform test
+
+        db::user_sessions::delete_by_id(&pool, &session_id)
+            .await
+            .expect("failed to delete user session");
+
+        assert!(
+            user_session_refresh_tokens::get_by_id(&pool, &refresh_token.id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+}
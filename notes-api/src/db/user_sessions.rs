@@ -9,6 +9,14 @@ pub struct UserSessionRow {
     pub id: Uuid,
     pub user_id: Uuid,
     pub expiration_time: Option<DateTime<Utc>>,
+    pub idle_expiration_time: Option<DateTime<Utc>>,
+
+    /// Time created is missing for sessions created before it was recorded
+    pub time_created: Option<DateTime<Utc>>,
+    pub last_seen_time: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub device_label: Option<String>,
 }
 
 pub async fn create<'e, E>(executor: E, user_session: &UserSessionRow) -> db::Result<()>
@@ -17,13 +25,19 @@ where
 {
     sqlx::query(
         r#"
-        INSERT INTO user_sessions (id, user_id, expiration_time)
-        VALUES (?1, ?2, ?3)
+        INSERT INTO user_sessions (id, user_id, expiration_time, idle_expiration_time, time_created, last_seen_time, user_agent, ip_address, device_label)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
         "#,
     )
     .bind(&user_session.id)
     .bind(&user_session.user_id)
     .bind(&user_session.expiration_time)
+    .bind(user_session.idle_expiration_time)
+    .bind(user_session.time_created)
+    .bind(user_session.last_seen_time)
+    .bind(&user_session.user_agent)
+    .bind(&user_session.ip_address)
+    .bind(&user_session.device_label)
     .execute(executor)
     .await?;
 
@@ -36,7 +50,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, user_id, expiration_time
+        SELECT id, user_id, expiration_time, idle_expiration_time, time_created, last_seen_time, user_agent, ip_address, device_label
         FROM user_sessions
         WHERE id = ?1
         "#,
@@ -46,6 +60,52 @@ where
     .await?)
 }
 
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<Vec<UserSessionRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, expiration_time, idle_expiration_time, time_created, last_seen_time, user_agent, ip_address, device_label
+        FROM user_sessions
+        WHERE user_id = ?1
+        ORDER BY time_created
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await?)
+}
+
+pub async fn update_activity<'e, E>(
+    executor: E,
+    id: &Uuid,
+    last_seen_time: &DateTime<Utc>,
+    idle_expiration_time: Option<&DateTime<Utc>>,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE user_sessions
+        SET last_seen_time = ?2, idle_expiration_time = ?3
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .bind(last_seen_time)
+    .bind(idle_expiration_time)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
 pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
 where
     E: SqliteExecutor<'e>,
@@ -67,6 +127,74 @@ where
     }
 }
 
+/// Deletes the session of the user with `id`, which fails with `NotFound` if
+/// the user has no such session.
+pub async fn delete_by_user_id_and_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    id: &Uuid,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_sessions
+        WHERE user_id = ?1 AND id = ?2
+        "#,
+    )
+    .bind(user_id)
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes every session of the user except the one with `id`.
+pub async fn delete_by_user_id_except<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    id: &Uuid,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        DELETE FROM user_sessions
+        WHERE user_id = ?1 AND id != ?2
+        "#,
+    )
+    .bind(user_id)
+    .bind(id)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        DELETE FROM user_sessions
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::DateTime;
@@ -105,6 +233,12 @@ mod tests {
             id: Uuid::new_v4(),
             user_id,
             expiration_time: DateTime::from_timestamp(0, 0),
+            idle_expiration_time: DateTime::from_timestamp(0, 0),
+            time_created: DateTime::from_timestamp(0, 0),
+            last_seen_time: None,
+            user_agent: Some("test".to_string()),
+            ip_address: Some("127.0.0.1".to_string()),
+            device_label: Some("test".to_string()),
         };
 
         user_sessions::create(&pool, &user_session)
@@ -163,7 +297,13 @@ mod tests {
             UserSessionRow {
                 id,
                 user_id,
-                expiration_time
+                expiration_time,
+                idle_expiration_time: None,
+                time_created: None,
+                last_seen_time: None,
+                user_agent: None,
+                ip_address: None,
+                device_label: None,
             }
         )
     }
@@ -215,4 +355,160 @@ mod tests {
                 .is_err_and(|e| matches!(e, db::Error::NotFound))
         )
     }
+
+    #[tokio::test]
+    async fn delete_by_user_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for id in &ids {
+            sqlx::query(
+                r#"
+                INSERT INTO user_sessions (id, user_id, expiration_time)
+                VALUES (?1, ?2, NULL)
+                "#,
+            )
+            .bind(id)
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .expect("failed to insert user session");
+        }
+
+        // Perform test
+
+        user_sessions::delete_by_user_id(&pool, &user_id)
+            .await
+            .expect("failed to delete user sessions by user id");
+
+        for id in &ids {
+            assert!(
+                user_sessions::get_by_id(&pool, id)
+                    .await
+                    .is_err_and(|e| matches!(e, db::Error::NotFound))
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_by_user_id_and_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for (index, (user_id, id)) in user_ids.iter().zip(&ids).enumerate() {
+            sqlx::query(
+                r#"
+                INSERT INTO users (id, username)
+                VALUES (?1, ?2)
+                "#,
+            )
+            .bind(user_id)
+            .bind(format!("test{}", index))
+            .execute(&pool)
+            .await
+            .expect("failed to insert user");
+
+            sqlx::query(
+                r#"
+                INSERT INTO user_sessions (id, user_id, expiration_time)
+                VALUES (?1, ?2, NULL)
+                "#,
+            )
+            .bind(id)
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .expect("failed to insert user session");
+        }
+
+        // Perform test
+
+        // Sessions of other users cannot be deleted
+        assert!(
+            user_sessions::delete_by_user_id_and_id(&pool, &user_ids[0], &ids[1])
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+
+        user_sessions::delete_by_user_id_and_id(&pool, &user_ids[0], &ids[0])
+            .await
+            .expect("failed to delete user session");
+
+        assert!(
+            user_sessions::get_by_id(&pool, &ids[0])
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+        assert!(user_sessions::get_by_id(&pool, &ids[1]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn delete_by_user_id_except() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind("test".to_string())
+        .execute(&pool)
+        .await
+        .expect("failed to insert user");
+
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        for id in &ids {
+            sqlx::query(
+                r#"
+                INSERT INTO user_sessions (id, user_id, expiration_time)
+                VALUES (?1, ?2, NULL)
+                "#,
+            )
+            .bind(id)
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .expect("failed to insert user session");
+        }
+
+        // Perform test
+
+        user_sessions::delete_by_user_id_except(&pool, &user_id, &ids[0])
+            .await
+            .expect("failed to delete other user sessions");
+
+        assert_eq!(
+            user_sessions::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user sessions by user id")
+                .into_iter()
+                .map(|row| row.id)
+                .collect::<Vec<_>>(),
+            vec![ids[0]]
+        );
+    }
 }
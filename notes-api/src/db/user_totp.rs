@@ -0,0 +1,286 @@
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserTotpRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub encrypted_secret: Vec<u8>,
+
+    /// Whether the user confirmed the secret with a code. Only confirmed
+    /// secrets are required at login.
+    pub confirmed: bool,
+
+    /// The time step of the last code that was accepted, so a code cannot be
+    /// used twice.
+    pub last_used_step: Option<i64>,
+}
+
+pub async fn create<'e, E>(executor: E, user_totp: &UserTotpRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_totp (id, user_id, encrypted_secret, confirmed, last_used_step)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        "#,
+    )
+    .bind(user_totp.id)
+    .bind(user_totp.user_id)
+    .bind(&user_totp.encrypted_secret)
+    .bind(user_totp.confirmed)
+    .bind(user_totp.last_used_step)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<UserTotpRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, encrypted_secret, confirmed, last_used_step
+        FROM user_totp
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?)
+}
+
+pub async fn update_encrypted_secret<'e, E>(
+    executor: E,
+    id: &Uuid,
+    encrypted_secret: &[u8],
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE user_totp
+        SET encrypted_secret = ?2
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .bind(encrypted_secret)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Records that the code of `step` was used and confirms the secret. Fails
+/// with `NotFound` if a code of the same or a later step was used before.
+pub async fn update_last_used_step<'e, E>(executor: E, id: &Uuid, step: i64) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE user_totp
+        SET confirmed = TRUE, last_used_step = ?2
+        WHERE id = ?1 AND (last_used_step IS NULL OR last_used_step < ?2)
+        "#,
+    )
+    .bind(id)
+    .bind(step)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+pub async fn delete_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_totp
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+/// Overwrites the TOTP secret of a user with zeros, before the user is
+/// deleted.
+pub async fn overwrite_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<u64>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query(
+        r#"
+        UPDATE user_totp
+        SET encrypted_secret = zeroblob(length(encrypted_secret))
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?
+    .rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_totp::{self, UserTotpRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> Uuid {
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        user_id
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+
+        // Perform test
+
+        let user_totp = UserTotpRow {
+            id: Uuid::new_v4(),
+            user_id,
+            encrypted_secret: vec![1, 2, 3, 4],
+            confirmed: false,
+            last_used_step: None,
+        };
+
+        user_totp::create(&pool, &user_totp)
+            .await
+            .expect("failed to create user totp");
+
+        assert_eq!(
+            user_totp::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user totp by user id"),
+            user_totp
+        )
+    }
+
+    #[tokio::test]
+    async fn update_last_used_step() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+        let id = Uuid::new_v4();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_totp (id, user_id, encrypted_secret)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(vec![1, 2, 3, 4])
+        .execute(&pool)
+        .await
+        .expect("failed to insert user totp");
+
+        // Perform test
+
+        user_totp::update_last_used_step(&pool, &id, 10)
+            .await
+            .expect("failed to update last used step");
+
+        let updated = user_totp::get_by_user_id(&pool, &user_id)
+            .await
+            .expect("failed to get user totp by user id");
+        assert!(updated.confirmed);
+        assert_eq!(updated.last_used_step, Some(10));
+
+        // Steps cannot be used twice, nor can earlier steps be used
+        for step in [10, 9] {
+            assert!(
+                user_totp::update_last_used_step(&pool, &id, step)
+                    .await
+                    .is_err_and(|e| matches!(e, db::Error::NotFound))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_by_user_id() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_totp (id, user_id, encrypted_secret)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(vec![1, 2, 3, 4])
+        .execute(&pool)
+        .await
+        .expect("failed to insert user totp");
+
+        // Perform test
+
+        user_totp::delete_by_user_id(&pool, &user_id)
+            .await
+            .expect("failed to delete user totp by user id");
+
+        assert!(
+            user_totp::get_by_user_id(&pool, &user_id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        )
+    }
+}
@@ -0,0 +1,184 @@
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct UserTotpBackupCodeRow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub hash: Vec<u8>,
+}
+
+pub async fn create<'e, E>(executor: E, backup_code: &UserTotpBackupCodeRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO user_totp_backup_codes (id, user_id, hash)
+        VALUES (?1, ?2, ?3)
+        "#,
+    )
+    .bind(backup_code.id)
+    .bind(backup_code.user_id)
+    .bind(&backup_code.hash)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> db::Result<Vec<UserTotpBackupCodeRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, user_id, hash
+        FROM user_totp_backup_codes
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Deletes the backup code of the user with `hash`, which fails with
+/// `NotFound` if the user has no such backup code.
+pub async fn delete_by_hash<'e, E>(executor: E, user_id: &Uuid, hash: &[u8]) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM user_totp_backup_codes
+        WHERE user_id = ?1 AND hash = ?2
+        "#,
+    )
+    .bind(user_id)
+    .bind(hash)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+pub async fn delete_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        DELETE FROM user_totp_backup_codes
+        WHERE user_id = ?1
+        "#,
+    )
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        user_totp_backup_codes::{self, UserTotpBackupCodeRow},
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> Uuid {
+        let user_id = Uuid::new_v4();
+        let username = "test".to_string();
+
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username)
+            VALUES (?1, ?2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .execute(pool)
+        .await
+        .expect("failed to insert user");
+
+        user_id
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+
+        // Perform test
+
+        let backup_code = UserTotpBackupCodeRow {
+            id: Uuid::new_v4(),
+            user_id,
+            hash: vec![1, 2, 3, 4],
+        };
+
+        user_totp_backup_codes::create(&pool, &backup_code)
+            .await
+            .expect("failed to create backup code");
+
+        assert_eq!(
+            user_totp_backup_codes::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get backup codes by user id"),
+            vec![backup_code]
+        )
+    }
+
+    #[tokio::test]
+    async fn delete_by_hash() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+        let hash = vec![1, 2, 3, 4];
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_totp_backup_codes (id, user_id, hash)
+            VALUES (?1, ?2, ?3)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(&hash)
+        .execute(&pool)
+        .await
+        .expect("failed to insert backup code");
+
+        // Perform test
+
+        user_totp_backup_codes::delete_by_hash(&pool, &user_id, &hash)
+            .await
+            .expect("failed to delete backup code by hash");
+
+        // Backup codes can only be used once
+        assert!(
+            user_totp_backup_codes::delete_by_hash(&pool, &user_id, &hash)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        )
+    }
+}
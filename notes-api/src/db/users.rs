@@ -7,6 +7,10 @@ use crate::db;
 pub struct UserRow {
     pub id: Uuid,
     pub username: String,
+
+    /// The case folded NFKC form of the username, which is unique. Users whose
+    /// key collided with another user's when keys were introduced have none.
+    pub username_key: Option<String>,
 }
 
 pub async fn create<'e, E>(executor: E, user: &UserRow) -> db::Result<()>
@@ -15,12 +19,13 @@ where
 {
     sqlx::query(
         r#"
-        INSERT INTO users (id, username)
-        VALUES (?1, ?2)
+        INSERT INTO users (id, username, username_key)
+        VALUES (?1, ?2, ?3)
         "#,
     )
     .bind(&user.id)
     .bind(&user.username)
+    .bind(&user.username_key)
     .execute(executor)
     .await?;
 
@@ -33,7 +38,7 @@ where
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, username
+        SELECT id, username, username_key
         FROM users
         WHERE id = ?1
         "#,
@@ -43,28 +48,105 @@ where
     .await?)
 }
 
-pub async fn get_by_username<'e, E>(executor: E, username: &str) -> db::Result<UserRow>
+/// Gets a user by the key of a username. Users without a key are matched by
+/// their exact username, which takes precedence.
+pub async fn get_by_username<'e, E>(
+    executor: E,
+    username: &str,
+    username_key: &str,
+) -> db::Result<UserRow>
 where
     E: SqliteExecutor<'e>,
 {
     Ok(sqlx::query_as(
         r#"
-        SELECT id, username
+        SELECT id, username, username_key
         FROM users
-        WHERE username = ?1
+        WHERE username_key = ?2 OR (username_key IS NULL AND username = ?1)
+        ORDER BY username_key IS NULL DESC
+        LIMIT 1
         "#,
     )
     .bind(username)
+    .bind(username_key)
     .fetch_one(executor)
     .await?)
 }
 
+pub async fn get_without_username_key<'e, E>(executor: E) -> db::Result<Vec<UserRow>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, username, username_key
+        FROM users
+        WHERE username_key IS NULL
+        "#,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+pub async fn update_username<'e, E>(
+    executor: E,
+    id: &Uuid,
+    username: &str,
+    username_key: Option<&str>,
+) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        UPDATE users
+        SET username = ?2, username_key = ?3
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .bind(username)
+    .bind(username_key)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
+pub async fn delete_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    match sqlx::query(
+        r#"
+        DELETE FROM users
+        WHERE id = ?1
+        "#,
+    )
+    .bind(id)
+    .execute(executor)
+    .await?
+    .rows_affected()
+    {
+        x if x < 1 => Err(db::Error::NotFound),
+        x if x > 1 => Err(db::Error::TooMany),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use utilities::db::init_db;
     use uuid::Uuid;
 
-    use crate::db::users::{self, UserRow};
+    use crate::db::{
+        self,
+        users::{self, UserRow},
+    };
 
     #[tokio::test]
     async fn create() {
@@ -73,6 +155,7 @@ mod tests {
         let user = UserRow {
             id: Uuid::new_v4(),
             username: "test".to_string(),
+            username_key: Some("test".to_string()),
         };
 
         users::create(&pool, &user)
@@ -84,7 +167,21 @@ mod tests {
                 .await
                 .expect("failed to get user"),
             user
-        )
+        );
+
+        // Username keys are unique
+        assert!(matches!(
+            users::create(
+                &pool,
+                &UserRow {
+                    id: Uuid::new_v4(),
+                    username: "TEST".to_string(),
+                    username_key: Some("test".to_string()),
+                },
+            )
+            .await,
+            Err(db::Error::Conflict)
+        ));
     }
 
     #[tokio::test]
@@ -114,7 +211,11 @@ mod tests {
             users::get_by_id(&pool, &id)
                 .await
                 .expect("failed to get user by id"),
-            UserRow { id, username }
+            UserRow {
+                id,
+                username,
+                username_key: None
+            }
         )
     }
 
@@ -124,28 +225,87 @@ mod tests {
 
         // Populate database
 
-        let id = Uuid::new_v4();
-        let username = "test".to_string();
+        let user = UserRow {
+            id: Uuid::new_v4(),
+            username: "Test".to_string(),
+            username_key: Some("test".to_string()),
+        };
+        let colliding_user = UserRow {
+            id: Uuid::new_v4(),
+            username: "TEST".to_string(),
+            username_key: None,
+        };
+        for user in [&user, &colliding_user] {
+            users::create(&pool, user)
+                .await
+                .expect("failed to create user");
+        }
 
-        sqlx::query(
-            r#"
-            INSERT INTO users (id, username)
-            VALUES (?1, ?2)
-            "#,
+        // Perform test
+
+        // Users are found by their username key
+        assert_eq!(
+            users::get_by_username(&pool, "test", "test")
+                .await
+                .expect("failed to get user by username"),
+            user
+        );
+
+        // Users without a key are found by their exact username only
+        assert_eq!(
+            users::get_by_username(&pool, "TEST", "test")
+                .await
+                .expect("failed to get user by username"),
+            colliding_user
+        );
+        assert!(matches!(
+            users::get_by_username(&pool, "tesT", "test2").await,
+            Err(db::Error::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_username() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let id = Uuid::new_v4();
+        users::create(
+            &pool,
+            &UserRow {
+                id,
+                username: "TEST".to_string(),
+                username_key: None,
+            },
         )
-        .bind(&id)
-        .bind(&username)
-        .execute(&pool)
         .await
-        .expect("failed to insert user");
+        .expect("failed to create user");
 
         // Perform test
 
+        users::update_username(&pool, &id, "Test", Some("test"))
+            .await
+            .expect("failed to update username");
         assert_eq!(
-            users::get_by_username(&pool, &username)
+            users::get_by_id(&pool, &id)
                 .await
-                .expect("failed to get user by username"),
-            UserRow { id, username }
-        )
+                .expect("failed to get user by id"),
+            UserRow {
+                id,
+                username: "Test".to_string(),
+                username_key: Some("test".to_string())
+            }
+        );
+        assert!(
+            users::get_without_username_key(&pool)
+                .await
+                .expect("failed to get users without username key")
+                .is_empty()
+        );
+        assert!(matches!(
+            users::update_username(&pool, &Uuid::new_v4(), "test", Some("test")).await,
+            Err(db::Error::NotFound)
+        ));
     }
 }
@@ -0,0 +1,160 @@
+use chrono::{DateTime, Utc};
+use sqlx::{SqliteExecutor, prelude::FromRow};
+use uuid::Uuid;
+
+use crate::db;
+
+#[derive(FromRow, Debug, PartialEq)]
+pub struct WebauthnChallengeRow {
+    pub id: Uuid,
+
+    /// The user registering a passkey, or none when signing in
+    pub user_id: Option<Uuid>,
+    pub challenge: Vec<u8>,
+    pub expiration_time: DateTime<Utc>,
+}
+
+pub async fn create<'e, E>(executor: E, webauthn_challenge: &WebauthnChallengeRow) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO webauthn_challenges (id, user_id, challenge, expiration_time)
+        VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(webauthn_challenge.id)
+    .bind(webauthn_challenge.user_id)
+    .bind(&webauthn_challenge.challenge)
+    .bind(webauthn_challenge.expiration_time)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes and returns a challenge, so it can only be answered once.
+pub async fn take_by_id<'e, E>(executor: E, id: &Uuid) -> db::Result<WebauthnChallengeRow>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(sqlx::query_as(
+        r#"
+        DELETE FROM webauthn_challenges
+        WHERE id = ?1
+        RETURNING id, user_id, challenge, expiration_time
+        "#,
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Deletes the challenges that expired before `time` without being answered.
+pub async fn delete_expired<'e, E>(executor: E, time: &DateTime<Utc>) -> db::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        DELETE FROM webauthn_challenges
+        WHERE expiration_time < ?1
+        "#,
+    )
+    .bind(time)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::db::{
+        self,
+        webauthn_challenges::{self, WebauthnChallengeRow},
+    };
+
+    #[tokio::test]
+    async fn create_and_take() {
+        let pool = init_db().await;
+
+        // Perform test
+
+        let webauthn_challenge = WebauthnChallengeRow {
+            id: Uuid::new_v4(),
+            user_id: None,
+            challenge: vec![1, 2, 3, 4],
+            expiration_time: DateTime::from_timestamp(0, 0).expect("invalid timestamp"),
+        };
+
+        webauthn_challenges::create(&pool, &webauthn_challenge)
+            .await
+            .expect("failed to create webauthn challenge");
+
+        assert_eq!(
+            webauthn_challenges::take_by_id(&pool, &webauthn_challenge.id)
+                .await
+                .expect("failed to take webauthn challenge"),
+            webauthn_challenge
+        );
+
+        // Challenges can only be taken once
+        assert!(
+            webauthn_challenges::take_by_id(&pool, &webauthn_challenge.id)
+                .await
+                .is_err_and(|e| matches!(e, db::Error::NotFound))
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_expired() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let expiration_time = DateTime::from_timestamp(0, 0).expect("invalid timestamp");
+        let expired_id = Uuid::new_v4();
+        let valid_id = Uuid::new_v4();
+
+        for (id, expiration_time) in [
+            (expired_id, expiration_time),
+            (valid_id, expiration_time + Duration::minutes(5)),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO webauthn_challenges (id, challenge, expiration_time)
+                VALUES (?1, ?2, ?3)
+                "#,
+            )
+            .bind(id)
+            .bind(vec![1, 2, 3, 4])
+            .bind(expiration_time)
+            .execute(&pool)
+            .await
+            .expect("failed to insert webauthn challenge");
+        }
+
+        // Perform test
+
+        webauthn_challenges::delete_expired(&pool, &(expiration_time + Duration::minutes(1)))
+            .await
+            .expect("failed to delete expired webauthn challenges");
+
+        assert!(
+            webauthn_challenges::take_by_id(&pool, &expired_id)
+                .await
+                .is_err()
+        );
+        assert!(
+            webauthn_challenges::take_by_id(&pool, &valid_id)
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -2,30 +2,83 @@ use std::sync::Arc;
 
 use axum::{
     extract::{FromRef, FromRequestParts},
-    http::{StatusCode, request::Parts},
+    http::{StatusCode, header, request::Parts},
+    response::{IntoResponse, Response},
 };
 use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
     typed_header::TypedHeaderRejectionReason,
 };
-use josekit::jwk::Jwk;
 use sqlx::SqlitePool;
+use uuid::Uuid;
 
 use crate::{
-    services,
+    key_ring::KeyRing,
+    services::{
+        self,
+        user_access_tokens::{AccessToken, Scope},
+    },
     state::AppState,
     tokens::{self, TokenDecryptionError},
 };
 
 pub struct Auth(pub tokens::UserClaims);
 
+/// Authenticates the routes of notes that are encrypted end-to-end, which
+/// accept the end-to-end token of a session instead of its session token, so
+/// the user key never reaches them.
+pub struct EndToEndAuth(pub tokens::EndToEndClaims);
+
+/// Why a request was not authenticated. Both expiries are answered with
+/// `401 Unauthorized` and a `WWW-Authenticate` header that tells them apart,
+/// so clients know whether to refresh, to sign in again, or that they are
+/// simply not allowed to do something.
+#[derive(Debug)]
+pub enum AuthRejection {
+    /// The session token expired; the refresh token gets a new one
+    TokenExpired,
+
+    /// The session timed out or was revoked, so the user is signed out
+    SessionExpired,
+
+    Status(StatusCode),
+}
+
+impl From<StatusCode> for AuthRejection {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        let description = match self {
+            Self::TokenExpired => "token expired",
+            Self::SessionExpired => "session expired",
+            Self::Status(status) => return status.into_response(),
+        };
+
+        (
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                format!(
+                    "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                    description
+                ),
+            )],
+        )
+            .into_response()
+    }
+}
+
 impl<S> FromRequestParts<S> for Auth
 where
     AuthState: FromRef<S>,
     S: Send + Sync,
 {
-    type Rejection = StatusCode;
+    type Rejection = AuthRejection;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         // Extract auth state
@@ -39,36 +92,301 @@ where
                 _ => StatusCode::INTERNAL_SERVER_ERROR,
             })?;
 
+        // Access tokens are only accepted by the routes of their scopes, which
+        // the router attaches to the request
+        if let Some(access_token) = AccessToken::parse(token.token()) {
+            let Some(scope) = parts.extensions.get::<Scope>().copied() else {
+                return Err(StatusCode::FORBIDDEN.into());
+            };
+
+            return authenticate_access_token(&auth_state, &access_token, scope)
+                .await
+                .map(Auth)
+                .map_err(AuthRejection::from);
+        }
+
         // Get user claims from token cookie
-        let user_claims =
-            tokens::decrypt(token.token().as_bytes(), &auth_state.jwk).map_err(|e| match e {
-                TokenDecryptionError::InvalidKey => StatusCode::UNAUTHORIZED,
-                TokenDecryptionError::InvalidClaim(_) => StatusCode::BAD_REQUEST,
-                TokenDecryptionError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
-            })?;
+        let user_claims = tokens::decrypt(token.token().as_bytes(), &auth_state.key_ring)
+            .map_err(token_rejection)?;
+        authenticate_session(&auth_state, user_claims.session_id()).await?;
+
+        Ok(Auth(user_claims))
+    }
+}
+
+impl<S> FromRequestParts<S> for EndToEndAuth
+where
+    AuthState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Extract auth state
+        let auth_state = AuthState::from_ref(state);
 
-        // Validate user session
-        if !services::user_sessions::get(&auth_state.db, user_claims.session_id())
+        // Extract authorization bearer
+        let token = TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
             .await
-            .is_ok_and(|user_session| user_session.is_valid())
-        {
-            return Err(StatusCode::UNAUTHORIZED);
+            .map_err(|e| match e.reason() {
+                TypedHeaderRejectionReason::Missing => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+
+        // Access tokens can only be checked by unwrapping the user key, which
+        // is wiped right away
+        if let Some(access_token) = AccessToken::parse(token.token()) {
+            let Some(scope) = parts.extensions.get::<Scope>().copied() else {
+                return Err(StatusCode::FORBIDDEN.into());
+            };
+
+            return authenticate_access_token(&auth_state, &access_token, scope)
+                .await
+                .map(|user_claims| EndToEndAuth(user_claims.end_to_end()))
+                .map_err(AuthRejection::from);
         }
 
-        Ok(Auth(user_claims))
+        // Get end-to-end claims from token
+        let end_to_end_claims =
+            tokens::decrypt_end_to_end(token.token().as_bytes(), &auth_state.key_ring)
+                .map_err(token_rejection)?;
+        authenticate_session(&auth_state, end_to_end_claims.session_id()).await?;
+
+        Ok(EndToEndAuth(end_to_end_claims))
     }
 }
 
+fn token_rejection(e: TokenDecryptionError) -> AuthRejection {
+    match e {
+        TokenDecryptionError::Expired => AuthRejection::TokenExpired,
+        TokenDecryptionError::InvalidKey => StatusCode::UNAUTHORIZED.into(),
+        TokenDecryptionError::InvalidClaim(_) => StatusCode::BAD_REQUEST.into(),
+        TokenDecryptionError::Internal => StatusCode::INTERNAL_SERVER_ERROR.into(),
+    }
+}
+
+/// Checks that the session of a token is still valid, as it is gone once it
+/// was revoked.
+async fn authenticate_session(
+    auth_state: &AuthState,
+    session_id: &Uuid,
+) -> Result<(), AuthRejection> {
+    let user_session = match services::user_sessions::get(&auth_state.db, session_id).await {
+        Ok(user_session) if user_session.is_valid() => user_session,
+        Ok(_) | Err(services::Error::NotFound) => {
+            return Err(AuthRejection::SessionExpired);
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+    };
+
+    // Move the idle timeout forward and record when the session was last
+    // seen, which is throttled
+    services::user_sessions::touch(&auth_state.db, &user_session)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+/// Unwraps the user key with the secret of an access token, which stands in
+/// for a session.
+async fn authenticate_access_token(
+    auth_state: &AuthState,
+    access_token: &AccessToken,
+    scope: Scope,
+) -> Result<tokens::UserClaims, StatusCode> {
+    let mut conn = auth_state
+        .db
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (user_access_token, user_key) =
+        services::user_access_tokens::authenticate(&mut conn, access_token)
+            .await
+            .map_err(|e| match e {
+                services::Error::NotFound | services::Error::DecryptionFailed => {
+                    StatusCode::UNAUTHORIZED
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+    if !user_access_token.has_scope(scope) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(tokens::UserClaims::new(
+        *user_access_token.id(),
+        *user_access_token.user_id(),
+        user_key.into_key(),
+    ))
+}
+
 pub struct AuthState {
     db: SqlitePool,
-    jwk: Jwk,
+    key_ring: KeyRing,
 }
 
 impl FromRef<Arc<AppState>> for AuthState {
     fn from_ref(input: &Arc<AppState>) -> Self {
         Self {
             db: input.db.clone(),
-            jwk: input.jwk.clone(),
+            key_ring: input.key_ring.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use aes_gcm::aead::OsRng;
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode, header},
+    };
+    use tower::ServiceExt;
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        create_app,
+        key_ring::KeyRing,
+        services::{
+            self, hash_params::HashConfig, user_access_tokens::Scope, user_opaque::OpaqueSetup,
+        },
+        state::AppState,
+        tokens,
+        webauthn::RelyingParty,
+    };
+
+    #[tokio::test]
+    async fn access_token_scopes() {
+        let pool = init_db().await;
+
+        // Populate database
+        let hash_config = HashConfig::default();
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let (user, user_key, _) = services::users::sign_up(&mut conn, &hash_config, "test", "1234")
+            .await
+            .expect("failed to sign up");
+        let mut access_tokens = Vec::new();
+        for scope in [Scope::NotesRead, Scope::NotesWrite] {
+            let (_, access_token) = services::user_access_tokens::create(
+                &mut conn,
+                user.id(),
+                user_key.key(),
+                scope.as_str(),
+                &[scope],
+                None,
+            )
+            .await
+            .expect("failed to create access token");
+            access_tokens.push(access_token.to_string());
+        }
+        drop(conn);
+
+        // Perform test
+
+        // The router is nested under `/api`, as it is when serving
+        let app = create_app(Arc::new(AppState {
+            db: pool,
+            key_ring: KeyRing::generate().expect("failed to generate key ring"),
+            hash_config,
+            opaque_setup: OpaqueSetup::new(&mut OsRng),
+            relying_party: RelyingParty::new("localhost", "notes", "http://localhost"),
+        }));
+        let (read_token, write_token) = (&access_tokens[0], &access_tokens[1]);
+        let note_path = format!("/api/notes/{}", Uuid::new_v4());
+        let sessions_path = format!("/api/users/{}/sessions", user.id());
+        for (access_token, method, path, status) in [
+            (
+                read_token,
+                Method::PUT,
+                note_path.as_str(),
+                StatusCode::FORBIDDEN,
+            ),
+            (write_token, Method::PUT, &note_path, StatusCode::CREATED),
+            (write_token, Method::GET, &note_path, StatusCode::FORBIDDEN),
+            (read_token, Method::GET, &note_path, StatusCode::OK),
+            (read_token, Method::GET, "/api/notes", StatusCode::OK),
+            (
+                read_token,
+                Method::GET,
+                &sessions_path,
+                StatusCode::FORBIDDEN,
+            ),
+            (write_token, Method::DELETE, &note_path, StatusCode::OK),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(&method)
+                        .uri(path)
+                        .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(r#"{"markdown":"Test"}"#))
+                        .expect("failed to build request"),
+                )
+                .await
+                .expect("failed to send request");
+            assert_eq!(response.status(), status, "{} {}", method, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn end_to_end_token() {
+        let pool = init_db().await;
+
+        // Populate database
+        let hash_config = HashConfig::default();
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let (user, user_key, _) = services::users::sign_up(&mut conn, &hash_config, "test", "1234")
+            .await
+            .expect("failed to sign up");
+        let user_session = services::user_sessions::UserSession::new();
+        services::user_sessions::store(&mut *conn, &user_session, user.id())
+            .await
+            .expect("failed to store user session");
+        drop(conn);
+
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
+        let user_claims =
+            tokens::UserClaims::new(*user_session.id(), *user.id(), user_key.into_key());
+        let session_token =
+            tokens::encrypt(&user_claims, &key_ring).expect("failed to encrypt user claims");
+        let end_to_end_token = tokens::encrypt_end_to_end(&user_claims.end_to_end(), &key_ring)
+            .expect("failed to encrypt end-to-end claims");
+
+        // Perform test
+
+        // Only the routes of notes that are encrypted end-to-end accept the
+        // token without the user key, and only they refuse the session token
+        let app = create_app(Arc::new(AppState {
+            db: pool,
+            key_ring,
+            hash_config,
+            opaque_setup: OpaqueSetup::new(&mut OsRng),
+            relying_party: RelyingParty::new("localhost", "notes", "http://localhost"),
+        }));
+        for (token, path, status) in [
+            (&end_to_end_token, "/api/e2e/notes", StatusCode::OK),
+            (&session_token, "/api/e2e/notes", StatusCode::UNAUTHORIZED),
+            (&end_to_end_token, "/api/notes", StatusCode::UNAUTHORIZED),
+            (&session_token, "/api/notes", StatusCode::OK),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(path)
+                        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                        .body(Body::empty())
+                        .expect("failed to build request"),
+                )
+                .await
+                .expect("failed to send request");
+            assert_eq!(response.status(), status, "{}", path);
         }
     }
 }
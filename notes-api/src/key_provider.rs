@@ -0,0 +1,174 @@
+//! Key-encryption-key providers. The server's own secrets, the token key ring,
+//! the OPAQUE server setup and the optional password pepper, are only written
+//! to disk wrapped by a provider, so a copy of the database directory alone
+//! does not reveal them.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+pub mod file;
+pub mod pkcs11;
+
+pub use file::FileKeyProvider;
+pub use pkcs11::Pkcs11KeyProvider;
+
+/// Wraps and unwraps secrets with a key-encryption key the provider holds.
+/// The `label` names what the secret is used for and is bound to the wrapped
+/// secret, so a wrapped secret cannot be passed off as another one.
+pub trait KeyProvider: Send + Sync {
+    /// The name the provider is selected by, stored next to wrapped secrets.
+    fn name(&self) -> &'static str;
+
+    fn wrap(&self, secret: &[u8], label: &str) -> anyhow::Result<Vec<u8>>;
+
+    fn unwrap(&self, wrapped: &[u8], label: &str) -> anyhow::Result<Zeroizing<Vec<u8>>>;
+}
+
+/// Selects the provider using the `KEY_PROVIDER` environment variable, either
+/// `file` (the default) or `pkcs11`. The file provider refuses to keep its
+/// key next to the database at `db_path`.
+pub fn from_env(db_path: &Path) -> anyhow::Result<Box<dyn KeyProvider>> {
+    match env::var("KEY_PROVIDER").as_deref() {
+        Ok("file") | Err(env::VarError::NotPresent) => {
+            Ok(Box::new(FileKeyProvider::from_env(db_path)?))
+        }
+        Ok("pkcs11") => Ok(Box::new(Pkcs11KeyProvider::from_env()?)),
+        Ok(name) => anyhow::bail!("unknown key provider `{}`", name),
+        Err(e) => Err(e.clone().into()),
+    }
+}
+
+/// A secret as it is stored on disk.
+#[derive(Serialize, Deserialize)]
+pub struct WrappedSecret {
+    provider: String,
+    wrapped: String,
+}
+
+impl WrappedSecret {
+    pub fn wrap(provider: &dyn KeyProvider, secret: &[u8], label: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            provider: provider.name().to_string(),
+            wrapped: BASE64_STANDARD.encode(provider.wrap(secret, label)?),
+        })
+    }
+
+    pub fn unwrap(
+        &self,
+        provider: &dyn KeyProvider,
+        label: &str,
+    ) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        if self.provider != provider.name() {
+            anyhow::bail!(
+                "{} is wrapped by the `{}` key provider, but `{}` is configured",
+                label,
+                self.provider,
+                provider.name()
+            );
+        }
+
+        provider.unwrap(&BASE64_STANDARD.decode(&self.wrapped)?, label)
+    }
+
+    pub fn from_slice(input: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(input)?)
+    }
+
+    pub fn to_vec(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Loads the secret at `path`, or generates, wraps and persists a new random
+/// secret of `len` bytes when the file does not exist yet.
+pub fn load_or_generate_secret(
+    provider: &dyn KeyProvider,
+    path: &Path,
+    label: &str,
+    len: usize,
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    load_or_create_secret(provider, path, label, || {
+        let mut secret = Zeroizing::new(vec![0u8; len]);
+        OsRng.fill_bytes(secret.as_mut_slice());
+        Ok(secret)
+    })
+}
+
+/// Loads the secret at `path`, or wraps and persists the secret returned by
+/// `create` when the file does not exist yet, for secrets that are more than
+/// random bytes.
+pub fn load_or_create_secret(
+    provider: &dyn KeyProvider,
+    path: &Path,
+    label: &str,
+    create: impl FnOnce() -> anyhow::Result<Zeroizing<Vec<u8>>>,
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(input) => WrappedSecret::from_slice(&input)?.unwrap(provider, label),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let secret = create()?;
+            write_private(
+                path,
+                &WrappedSecret::wrap(provider, &secret, label)?.to_vec()?,
+            )?;
+            Ok(secret)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `contents` to a temporary file that only the owner can read first
+/// and moves it into place, so a crash cannot leave a truncated file behind.
+pub fn write_private(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use crate::key_provider::{self, FileKeyProvider};
+
+    #[test]
+    fn load_or_generate_secret() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).expect("failed to create directory");
+
+        let provider =
+            FileKeyProvider::load_or_generate(&dir.join("kek.key")).expect("failed to load kek");
+        let path = dir.join("secret.json");
+
+        let generated = key_provider::load_or_generate_secret(&provider, &path, "secret", 32)
+            .expect("failed to generate secret");
+        let loaded = key_provider::load_or_generate_secret(&provider, &path, "secret", 32)
+            .expect("failed to load secret");
+        assert_eq!(generated, loaded);
+
+        // The wrapped secret is bound to its label
+        assert!(key_provider::load_or_generate_secret(&provider, &path, "other", 32).is_err());
+
+        std::fs::remove_dir_all(&dir).expect("failed to remove directory");
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crypto::{SecretKey, envelope};
+use zeroize::Zeroizing;
+
+use crate::key_provider::{self, KeyProvider};
+
+/// Keeps the key-encryption key in a file of its own. `KEK_PATH` has to point
+/// at a location outside the database directory, such as a mounted secret, so
+/// a copy of the database and the key ring alone does not reveal the secrets.
+pub struct FileKeyProvider {
+    kek: SecretKey,
+}
+
+impl FileKeyProvider {
+    /// Loads the key-encryption key from `KEK_PATH`, which must be set and
+    /// must not be in the same directory as the database at `db_path`.
+    pub fn from_env(db_path: &Path) -> anyhow::Result<Self> {
+        let path = env::var("KEK_PATH")
+            .map_err(|e| anyhow::anyhow!("failed to read `KEK_PATH`: {}", e))?;
+        let path = Path::new(&path);
+        if directory(path)? == directory(db_path)? {
+            anyhow::bail!(
+                "key-encryption key `{}` must not be in the database directory",
+                path.display()
+            );
+        }
+
+        Self::load_or_generate(path)
+    }
+
+    /// Loads the key-encryption key from `path`, or generates and persists a
+    /// new key when the file does not exist yet.
+    pub fn load_or_generate(path: &Path) -> anyhow::Result<Self> {
+        match fs::read(path).map(Zeroizing::new) {
+            Ok(input) => Ok(Self {
+                kek: SecretKey::from_slice(&input).ok_or(anyhow::anyhow!(
+                    "key-encryption key `{}` has an invalid length",
+                    path.display()
+                ))?,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let kek = SecretKey::generate();
+                key_provider::write_private(path, kek.key())?;
+                Ok(Self { kek })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Returns the canonical directory `path` is in, which has to exist.
+fn directory(path: &Path) -> anyhow::Result<PathBuf> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    fs::canonicalize(dir)
+        .map_err(|e| anyhow::anyhow!("failed to resolve `{}`: {}", dir.display(), e))
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn wrap(&self, secret: &[u8], label: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(envelope::seal(self.kek.key(), secret, label.as_bytes())?)
+    }
+
+    fn unwrap(&self, wrapped: &[u8], label: &str) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(envelope::open(
+            self.kek.key(),
+            wrapped,
+            label.as_bytes(),
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::Path};
+
+    use uuid::Uuid;
+
+    use crate::key_provider::{FileKeyProvider, KeyProvider};
+
+    #[test]
+    fn wrap_unwrap() {
+        let path = env::temp_dir().join(format!("{}.key", Uuid::new_v4()));
+        let provider = FileKeyProvider::load_or_generate(&path).expect("failed to generate kek");
+
+        let wrapped = provider
+            .wrap(b"server secret", "label")
+            .expect("failed to wrap secret");
+
+        // The key-encryption key is persisted
+        let provider = FileKeyProvider::load_or_generate(&path).expect("failed to load kek");
+        assert_eq!(
+            provider
+                .unwrap(&wrapped, "label")
+                .expect("failed to unwrap secret")
+                .as_slice(),
+            b"server secret"
+        );
+        assert!(provider.unwrap(&wrapped, "other label").is_err());
+
+        std::fs::remove_file(&path).expect("failed to remove kek");
+    }
+
+    #[test]
+    fn directory() {
+        let dir = env::temp_dir();
+
+        assert_eq!(
+            super::directory(&dir.join("kek.key")).expect("failed to resolve directory"),
+            super::directory(&dir.join(".").join("db.sqlite"))
+                .expect("failed to resolve directory")
+        );
+        assert_eq!(
+            super::directory(Path::new("kek.key")).expect("failed to resolve directory"),
+            env::current_dir().expect("failed to get current directory")
+        );
+        assert!(super::directory(&dir.join(Uuid::new_v4().to_string()).join("kek.key")).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    env,
+    sync::{Mutex, MutexGuard},
+};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use cryptoki::{
+    context::{CInitializeArgs, CInitializeFlags, Pkcs11},
+    error::{Error, RvError},
+    mechanism::{Mechanism, aead::GcmParams},
+    object::{Attribute, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use zeroize::Zeroizing;
+
+use crate::key_provider::KeyProvider;
+
+const IV_LEN: usize = 12;
+const TAG_BITS: u64 = 128;
+
+/// Keeps the key-encryption key on a PKCS#11 token, such as an HSM or a
+/// cloud KMS exposing a PKCS#11 module. The key is created on the token as
+/// non-extractable, so secrets are wrapped and unwrapped by the token itself
+/// and the key never enters the process. A software token like SoftHSM can
+/// stand in for a hardware token during development and in tests.
+///
+/// The provider logs in once, when it is opened, and wraps and unwraps every
+/// secret in that session. Tokens keep the login state per application, so
+/// logging in again for every secret could fail as already logged in.
+pub struct Pkcs11KeyProvider {
+    session: Mutex<Session>,
+    key: ObjectHandle,
+}
+
+impl Pkcs11KeyProvider {
+    /// Loads the module at `PKCS11_MODULE` and logs in to the token labeled
+    /// `PKCS11_TOKEN_LABEL` using `PKCS11_PIN`. The key is looked up by
+    /// `PKCS11_KEY_LABEL`, which defaults to `notes-api-kek`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            env::var(name).map_err(|e| anyhow::anyhow!("failed to read `{}`: {}", name, e))
+        };
+
+        Self::open(
+            &var("PKCS11_MODULE")?,
+            &var("PKCS11_TOKEN_LABEL")?,
+            AuthPin::from(var("PKCS11_PIN")?),
+            &env::var("PKCS11_KEY_LABEL").unwrap_or("notes-api-kek".into()),
+        )
+    }
+
+    /// Opens the token and generates the key-encryption key on it when the
+    /// token does not hold a key labeled `key_label` yet.
+    pub fn open(
+        module: &str,
+        token_label: &str,
+        pin: AuthPin,
+        key_label: &str,
+    ) -> anyhow::Result<Self> {
+        let context = Pkcs11::new(module)?;
+        context.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK))?;
+
+        let slot = context
+            .get_slots_with_token()?
+            .into_iter()
+            .find(|slot| {
+                context
+                    .get_token_info(*slot)
+                    .is_ok_and(|info| info.label() == token_label)
+            })
+            .ok_or(anyhow::anyhow!(
+                "token `{}` could not be found",
+                token_label
+            ))?;
+
+        // A login that is still held by this application is reused
+        let session = context.open_rw_session(slot)?;
+        match session.login(UserType::User, Some(&pin)) {
+            Ok(()) | Err(Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let key = match Self::find_key(&session, key_label)? {
+            Some(key) => key,
+            None => session.generate_key(
+                &Mechanism::AesKeyGen,
+                &[
+                    Attribute::Class(ObjectClass::SECRET_KEY),
+                    Attribute::KeyType(KeyType::AES),
+                    Attribute::ValueLen(32.into()),
+                    Attribute::Label(key_label.as_bytes().to_vec()),
+                    Attribute::Token(true),
+                    Attribute::Private(true),
+                    Attribute::Sensitive(true),
+                    Attribute::Extractable(false),
+                    Attribute::Encrypt(true),
+                    Attribute::Decrypt(true),
+                ],
+            )?,
+        };
+
+        Ok(Self {
+            session: Mutex::new(session),
+            key,
+        })
+    }
+
+    fn find_key(session: &Session, key_label: &str) -> anyhow::Result<Option<ObjectHandle>> {
+        Ok(session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::SECRET_KEY),
+                Attribute::Label(key_label.as_bytes().to_vec()),
+            ])?
+            .into_iter()
+            .next())
+    }
+
+    fn session(&self) -> anyhow::Result<MutexGuard<'_, Session>> {
+        self.session
+            .lock()
+            .map_err(|_| anyhow::anyhow!("session of the token is poisoned"))
+    }
+}
+
+impl KeyProvider for Pkcs11KeyProvider {
+    fn name(&self) -> &'static str {
+        "pkcs11"
+    }
+
+    fn wrap(&self, secret: &[u8], label: &str) -> anyhow::Result<Vec<u8>> {
+        let session = self.session()?;
+
+        // Tokens differ in whether they generate the IV themselves, so it is
+        // generated here and stored in front of the ciphertext
+        let mut iv = [0u8; IV_LEN];
+        OsRng.fill_bytes(&mut iv);
+        let mut wrapped = iv.to_vec();
+
+        let params = GcmParams::new(&mut iv, label.as_bytes(), TAG_BITS.into())?;
+        wrapped.extend(session.encrypt(&Mechanism::AesGcm(params), self.key, secret)?);
+
+        Ok(wrapped)
+    }
+
+    fn unwrap(&self, wrapped: &[u8], label: &str) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        if wrapped.len() < IV_LEN {
+            anyhow::bail!("wrapped secret is too short");
+        }
+
+        let session = self.session()?;
+
+        let mut iv = [0u8; IV_LEN];
+        iv.copy_from_slice(&wrapped[..IV_LEN]);
+
+        let params = GcmParams::new(&mut iv, label.as_bytes(), TAG_BITS.into())?;
+        Ok(Zeroizing::new(session.decrypt(
+            &Mechanism::AesGcm(params),
+            self.key,
+            &wrapped[IV_LEN..],
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_provider::{KeyProvider, Pkcs11KeyProvider};
+
+    /// Runs against a software token, for example one initialized with
+    /// `softhsm2-util --init-token --free --label notes-api-test --pin 1234 --so-pin 1234`
+    /// and `PKCS11_MODULE` pointing at `libsofthsm2.so`. Run it with
+    /// `cargo test -- --ignored` once the token is configured.
+    #[test]
+    #[ignore = "requires SoftHSM via PKCS11_MODULE"]
+    fn wrap_unwrap() {
+        let provider = Pkcs11KeyProvider::from_env().expect("failed to open token");
+
+        let wrapped = provider
+            .wrap(b"server secret", "label")
+            .expect("failed to wrap secret");
+        assert_eq!(
+            provider
+                .unwrap(&wrapped, "label")
+                .expect("failed to unwrap secret")
+                .as_slice(),
+            b"server secret"
+        );
+        assert!(provider.unwrap(&wrapped, "other label").is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{fs, io, path::Path};
+
+use chrono::{DateTime, Duration, Utc};
+use josekit::jwk::Jwk;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::key_provider::{self, KeyProvider, WrappedSecret};
+
+/// Binds the wrapped key ring to its purpose.
+const KEY_RING_LABEL: &str = "key ring";
+
+/// The server's token keys. Every key is tagged with a `kid`; the newest key
+/// without a retire time is the active key and is used to encrypt new tokens.
+/// Older keys keep decrypting tokens until their retire time has passed.
+#[derive(Debug, Clone)]
+pub struct KeyRing {
+    keys: Vec<RingKey>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RingKey {
+    jwk: Jwk,
+    time_created: DateTime<Utc>,
+    retire_time: Option<DateTime<Utc>>,
+}
+
+impl RingKey {
+    fn generate() -> anyhow::Result<Self> {
+        let mut jwk = Jwk::generate_oct_key(32)?;
+        jwk.set_key_id(Uuid::new_v4().to_string());
+
+        Ok(Self {
+            jwk,
+            time_created: Utc::now(),
+            retire_time: None,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        // Keys are always created or loaded with a key id
+        self.jwk.key_id().unwrap_or_default()
+    }
+
+    pub fn jwk(&self) -> &Jwk {
+        &self.jwk
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retire_time
+            .is_some_and(|retire_time| retire_time <= Utc::now())
+    }
+}
+
+impl KeyRing {
+    pub fn generate() -> anyhow::Result<Self> {
+        Ok(Self {
+            keys: vec![RingKey::generate()?],
+        })
+    }
+
+    pub fn from_slice(input: &[u8]) -> anyhow::Result<Self> {
+        let file: KeyRingFile = serde_json::from_slice(input)?;
+
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|key| {
+                let jwk = Jwk::from_map(key.jwk)?;
+                if jwk.key_id() != Some(key.kid.as_str()) {
+                    anyhow::bail!("key `{}` has a mismatching jwk key id", key.kid);
+                }
+
+                Ok(RingKey {
+                    jwk,
+                    time_created: key.time_created,
+                    retire_time: key.retire_time,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let key_ring = Self { keys };
+        key_ring.active()?;
+
+        Ok(key_ring)
+    }
+
+    pub fn to_vec(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(&KeyRingFile {
+            keys: self
+                .keys
+                .iter()
+                .map(|key| RingKeyFile {
+                    kid: key.kid().to_string(),
+                    time_created: key.time_created,
+                    retire_time: key.retire_time,
+                    jwk: key.jwk.as_ref().clone(),
+                })
+                .collect(),
+        })?)
+    }
+
+    /// Loads the key ring from `path`, or generates and persists a new key
+    /// ring when the file does not exist yet. A key ring that was stored
+    /// before it was wrapped by a key provider is wrapped and stored again.
+    pub fn load_or_generate(path: &Path, provider: &dyn KeyProvider) -> anyhow::Result<Self> {
+        match fs::read(path) {
+            Ok(input) => {
+                let (key_ring, is_wrapped) = Self::read(&input, provider)?;
+                if !is_wrapped {
+                    key_ring.save(path, provider)?;
+                }
+                Ok(key_ring)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key_ring = Self::generate()?;
+                key_ring.save(path, provider)?;
+                Ok(key_ring)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn load(path: &Path, provider: &dyn KeyProvider) -> anyhow::Result<Self> {
+        Ok(Self::read(&fs::read(path)?, provider)?.0)
+    }
+
+    /// Reads a wrapped key ring, or a plain one stored before key providers
+    /// were introduced. Returns whether the key ring was wrapped.
+    fn read(input: &[u8], provider: &dyn KeyProvider) -> anyhow::Result<(Self, bool)> {
+        match WrappedSecret::from_slice(input) {
+            Ok(wrapped) => Ok((
+                Self::from_slice(&wrapped.unwrap(provider, KEY_RING_LABEL)?)?,
+                true,
+            )),
+            Err(_) => Ok((Self::from_slice(input)?, false)),
+        }
+    }
+
+    pub fn save(&self, path: &Path, provider: &dyn KeyProvider) -> anyhow::Result<()> {
+        let key_ring = Zeroizing::new(self.to_vec()?);
+        key_provider::write_private(
+            path,
+            &WrappedSecret::wrap(provider, &key_ring, KEY_RING_LABEL)?.to_vec()?,
+        )
+    }
+
+    /// The key used to encrypt new tokens.
+    pub fn active(&self) -> anyhow::Result<&RingKey> {
+        self.keys
+            .iter()
+            .filter(|key| key.retire_time.is_none())
+            .max_by_key(|key| key.time_created)
+            .ok_or(anyhow::anyhow!("key ring has no active key"))
+    }
+
+    /// All keys that may still be used to decrypt tokens.
+    pub fn accepted(&self) -> impl Iterator<Item = &RingKey> {
+        self.keys.iter().filter(|key| !key.is_retired())
+    }
+
+    pub fn get(&self, kid: &str) -> Option<&RingKey> {
+        self.accepted().find(|key| key.kid() == kid)
+    }
+
+    /// Adds a new active key. The previously active keys keep being accepted
+    /// for `grace`, after which they are retired. Keys that are already
+    /// retired are removed from the key ring.
+    pub fn rotate(&mut self, grace: Duration) -> anyhow::Result<&RingKey> {
+        let retire_time = Utc::now() + grace;
+
+        self.keys.retain(|key| !key.is_retired());
+        for key in self.keys.iter_mut() {
+            if key.retire_time.is_none_or(|time| time > retire_time) {
+                key.retire_time = Some(retire_time);
+            }
+        }
+
+        self.keys.push(RingKey::generate()?);
+        self.active()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyRingFile {
+    keys: Vec<RingKeyFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RingKeyFile {
+    kid: String,
+    time_created: DateTime<Utc>,
+    retire_time: Option<DateTime<Utc>>,
+    jwk: josekit::Map<String, josekit::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    use crate::{
+        key_provider::{FileKeyProvider, WrappedSecret},
+        key_ring::KeyRing,
+    };
+
+    #[test]
+    fn serialize_deserialize() {
+        let mut key_ring = KeyRing::generate().expect("failed to generate key ring");
+        key_ring
+            .rotate(Duration::days(1))
+            .expect("failed to rotate key ring");
+
+        let deserialized =
+            KeyRing::from_slice(&key_ring.to_vec().expect("failed to serialize key ring"))
+                .expect("failed to deserialize key ring");
+
+        assert_eq!(
+            deserialized.active().expect("missing active key").kid(),
+            key_ring.active().expect("missing active key").kid()
+        );
+        assert_eq!(deserialized.accepted().count(), 2);
+    }
+
+    #[test]
+    fn rotate_with_grace_period() {
+        let mut key_ring = KeyRing::generate().expect("failed to generate key ring");
+        let old_kid = key_ring
+            .active()
+            .expect("missing active key")
+            .kid()
+            .to_string();
+
+        let new_kid = key_ring
+            .rotate(Duration::days(1))
+            .expect("failed to rotate key ring")
+            .kid()
+            .to_string();
+
+        assert_ne!(old_kid, new_kid);
+        assert_eq!(
+            key_ring.active().expect("missing active key").kid(),
+            new_kid
+        );
+        assert!(key_ring.get(&old_kid).is_some());
+    }
+
+    #[test]
+    fn rotate_without_grace_period() {
+        let mut key_ring = KeyRing::generate().expect("failed to generate key ring");
+        let old_kid = key_ring
+            .active()
+            .expect("missing active key")
+            .kid()
+            .to_string();
+
+        key_ring
+            .rotate(Duration::zero())
+            .expect("failed to rotate key ring");
+        assert!(key_ring.get(&old_kid).is_none());
+
+        key_ring
+            .rotate(Duration::zero())
+            .expect("failed to rotate key ring");
+        assert_eq!(key_ring.keys.len(), 2);
+    }
+
+    #[test]
+    fn load_or_generate_wraps_plain_key_ring() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).expect("failed to create directory");
+
+        let provider =
+            FileKeyProvider::load_or_generate(&dir.join("kek.key")).expect("failed to load kek");
+        let path = dir.join("key_ring.json");
+
+        // Store a key ring the way it was stored before key providers
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
+        std::fs::write(
+            &path,
+            key_ring.to_vec().expect("failed to serialize key ring"),
+        )
+        .expect("failed to write key ring");
+
+        let loaded = KeyRing::load_or_generate(&path, &provider).expect("failed to load key ring");
+        assert_eq!(
+            loaded.active().expect("missing active key").kid(),
+            key_ring.active().expect("missing active key").kid()
+        );
+
+        // The key ring is now stored wrapped
+        let input = std::fs::read(&path).expect("failed to read key ring");
+        assert!(WrappedSecret::from_slice(&input).is_ok());
+        assert_eq!(
+            KeyRing::load(&path, &provider)
+                .expect("failed to load key ring")
+                .active()
+                .expect("missing active key")
+                .kid(),
+            key_ring.active().expect("missing active key").kid()
+        );
+
+        std::fs::remove_dir_all(&dir).expect("failed to remove directory");
+    }
+}
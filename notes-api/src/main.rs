@@ -1,29 +1,69 @@
-use std::{env, sync::Arc};
+use std::{env, net::SocketAddr, sync::Arc};
 
 use axum::Router;
+use chrono::Duration;
 
-use crate::state::AppState;
+use crate::{key_ring::KeyRing, state::AppState};
 
 pub mod api;
 pub mod db;
 pub mod extractors;
+pub mod key_provider;
+pub mod key_ring;
 pub mod services;
 pub mod state;
 pub mod tokens;
 pub mod utilities;
+pub mod webauthn;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] | ["serve"] => serve().await,
+        ["rotate-keys"] => rotate_keys(None),
+        ["rotate-keys", grace_days] => rotate_keys(Some(grace_days.parse()?)),
+        _ => anyhow::bail!("usage: notes-api [serve | rotate-keys [GRACE_DAYS]]"),
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
     let app_state = Arc::new(AppState::init().await?);
 
-    let app = Router::new().nest("/api", api::create_router(app_state));
+    let app = create_app(app_state);
 
     let ip = env::var("LISTENER_IP").unwrap_or("0.0.0.0".into());
     let port = env::var("LISTENER_PORT").unwrap_or("3123".into());
     let address = format!("{}:{}", ip, port);
 
     let listener = tokio::net::TcpListener::bind(address).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn create_app(app_state: Arc<AppState>) -> Router {
+    Router::new().nest("/api", api::create_router(app_state))
+}
+
+/// Adds a new active token key to the key ring. Tokens encrypted with the
+/// previous keys remain valid for the grace period, which defaults to the
+/// lifetime of a user session. Running servers pick up the new key ring
+/// when they are restarted.
+fn rotate_keys(grace_days: Option<i64>) -> anyhow::Result<()> {
+    let path = AppState::key_ring_path();
+    let grace = Duration::days(grace_days.unwrap_or(31));
+    let key_provider = key_provider::from_env(&AppState::db_path())?;
+
+    let mut key_ring = KeyRing::load(&path, key_provider.as_ref())?;
+    let kid = key_ring.rotate(grace)?.kid().to_string();
+    key_ring.save(&path, key_provider.as_ref())?;
+
+    println!("rotated key ring, active key is now `{}`", kid);
 
     Ok(())
 }
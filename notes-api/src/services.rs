@@ -1,11 +1,25 @@
-use crate::db;
+use crypto::EnvelopeError;
+use opaque_ke::errors::ProtocolError;
 
+use crate::{db, services::usernames::UsernameError, webauthn::WebauthnError};
+
+pub mod authentication;
+pub mod hash_params;
+pub mod login_attempts;
 pub mod note_keys;
 pub mod notes;
 
+pub mod user_access_tokens;
+pub mod user_key_pairs;
 pub mod user_keys;
+pub mod user_opaque;
+pub mod user_passkeys;
 pub mod user_passwords;
+pub mod user_recovery_codes;
+pub mod user_session_refresh_tokens;
 pub mod user_sessions;
+pub mod user_totp;
+pub mod usernames;
 pub mod users;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -18,12 +32,29 @@ pub enum Error {
     #[error("too many resource where found")]
     TooMany,
 
+    #[error("resource conflicts with an existing resource")]
+    Conflict,
+
     #[error("resource encryption failed")]
     EncryptionFailed,
 
     #[error("resource decryption failed")]
     DecryptionFailed,
 
+    /// The username or the secret is wrong, which are deliberately not told
+    /// apart
+    #[error("credentials are invalid")]
+    InvalidCredentials,
+
+    #[error("username is invalid: {0}")]
+    InvalidUsername(UsernameError),
+
+    #[error("passkey could not be verified: {0}")]
+    InvalidPasskey(WebauthnError),
+
+    #[error("opaque message is invalid: {0}")]
+    InvalidOpaqueMessage(ProtocolError),
+
     #[error("internal error: {0}")]
     Internal(anyhow::Error),
 }
@@ -33,11 +64,39 @@ impl From<db::Error> for Error {
         match e {
             db::Error::NotFound => Self::NotFound,
             db::Error::TooMany => Self::TooMany,
+            db::Error::Conflict => Self::Conflict,
             db::Error::Internal(_) => Self::Internal(e.into()),
         }
     }
 }
 
+impl From<EnvelopeError> for Error {
+    fn from(e: EnvelopeError) -> Self {
+        match e {
+            EnvelopeError::EncryptionFailed => Self::EncryptionFailed,
+            _ => Self::DecryptionFailed,
+        }
+    }
+}
+
+impl From<UsernameError> for Error {
+    fn from(e: UsernameError) -> Self {
+        Self::InvalidUsername(e)
+    }
+}
+
+impl From<WebauthnError> for Error {
+    fn from(e: WebauthnError) -> Self {
+        Self::InvalidPasskey(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Self::InvalidOpaqueMessage(e)
+    }
+}
+
 impl From<std::string::FromUtf8Error> for Error {
     fn from(e: std::string::FromUtf8Error) -> Self {
         Self::Internal(e.into())
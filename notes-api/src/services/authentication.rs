@@ -0,0 +1,163 @@
+use sqlx::SqliteConnection;
+
+use crate::services::{
+    self, hash_params::HashConfig, user_keys::UserKey, user_passwords::UserPassword,
+    user_recovery_codes::RecoveryCode, users::User,
+};
+
+/// Verifies the password of a user. Unknown usernames, users without a
+/// password and wrong passwords all fail with `InvalidCredentials` after one
+/// password hash with the parameters of an existing password, so neither the
+/// outcome nor its timing tells which usernames exist.
+pub async fn authenticate_password(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    username: &str,
+    password: &str,
+) -> services::Result<(User, UserPassword)> {
+    let user = match services::users::get_by_username(&mut *conn, username).await {
+        Ok(user) => user,
+        Err(services::Error::NotFound) => {
+            services::user_passwords::verify_dummy(&mut *conn, hash_config, username, password)
+                .await?;
+            return Err(services::Error::InvalidCredentials);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Users that moved to OPAQUE no longer have a password
+    let user_password = match services::user_passwords::get_by_user_id(&mut *conn, user.id()).await
+    {
+        Ok(user_password) => user_password,
+        Err(services::Error::NotFound) => {
+            services::user_passwords::verify_dummy(&mut *conn, hash_config, username, password)
+                .await?;
+            return Err(services::Error::InvalidCredentials);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if !user_password.verify(hash_config, password)? {
+        return Err(services::Error::InvalidCredentials);
+    }
+
+    Ok((user, user_password))
+}
+
+/// Unwraps the user key with a recovery code. Like passwords, unknown
+/// usernames, users without a recovery code and wrong codes all fail with
+/// `InvalidCredentials` after one hash with the parameters of an existing
+/// recovery code.
+pub async fn authenticate_recovery_code(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    username: &str,
+    recovery_code: &RecoveryCode,
+) -> services::Result<(User, UserKey)> {
+    let user = match services::users::get_by_username(&mut *conn, username).await {
+        Ok(user) => user,
+        Err(services::Error::NotFound) => {
+            services::user_recovery_codes::verify_dummy(
+                &mut *conn,
+                hash_config,
+                username,
+                recovery_code,
+            )
+            .await?;
+            return Err(services::Error::InvalidCredentials);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // The recovery code can only unwrap the user key if it is correct
+    match services::user_recovery_codes::get_user_key(
+        &mut *conn,
+        hash_config,
+        user.id(),
+        recovery_code,
+    )
+    .await
+    {
+        Ok(user_key) => Ok((user, user_key)),
+        Err(services::Error::NotFound) => {
+            services::user_recovery_codes::verify_dummy(
+                &mut *conn,
+                hash_config,
+                username,
+                recovery_code,
+            )
+            .await?;
+            Err(services::Error::InvalidCredentials)
+        }
+        Err(services::Error::DecryptionFailed) => Err(services::Error::InvalidCredentials),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        db,
+        services::{self, hash_params::HashConfig, user_keys::UserKey},
+    };
+
+    #[tokio::test]
+    async fn authenticate_password() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        services::user_passwords::reset(
+            &mut conn,
+            &HashConfig::default(),
+            &user_id,
+            UserKey::new().key(),
+            "1234",
+        )
+        .await
+        .expect("failed to set user password");
+
+        // Perform test
+
+        let (user, _) = services::authentication::authenticate_password(
+            &mut conn,
+            &HashConfig::default(),
+            "test",
+            "1234",
+        )
+        .await
+        .expect("failed to authenticate");
+        assert_eq!(user.id(), &user_id);
+
+        // A wrong password and an unknown username fail alike
+        for (username, password) in [("test", "4321"), ("unknown", "1234")] {
+            assert!(
+                services::authentication::authenticate_password(
+                    &mut conn,
+                    &HashConfig::default(),
+                    username,
+                    password
+                )
+                .await
+                .is_err_and(|e| matches!(e, services::Error::InvalidCredentials))
+            );
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::{env, fmt};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use argon2::{Algorithm, Argon2, KeyId, Params, ParamsBuilder, Version};
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use password_hash::PasswordHash;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::services::{self, usernames};
+
+/// A server-side secret mixed into every password hash, so hashes taken from
+/// the database cannot be brute-forced without the server's secrets as well.
+/// Hashes record the id of the pepper in their `keyid` parameter.
+#[derive(Clone)]
+struct Pepper {
+    id: KeyId,
+    secret: Zeroizing<Vec<u8>>,
+}
+
+impl Pepper {
+    fn new(secret: Zeroizing<Vec<u8>>) -> anyhow::Result<Self> {
+        let digest = Sha256::new()
+            .chain_update(b"notes pepper id")
+            .chain_update(secret.as_slice())
+            .finalize();
+        let id = KeyId::new(&digest[..Params::MAX_KEYID_LEN])
+            .map_err(|e| anyhow::anyhow!("invalid pepper id: {}", e))?;
+
+        Ok(Self { id, secret })
+    }
+}
+
+/// The parameters new hashes are created with, along with the pepper that is
+/// mixed into them. Hashes created with a pepper can only be verified using
+/// the same pepper, so it cannot be changed or removed once in use.
+#[derive(Clone, Default)]
+pub struct HashConfig {
+    target: HashParams,
+    pepper: Option<Pepper>,
+}
+
+impl HashConfig {
+    /// Reads the target cost parameters from the `ARGON2_M_COST`,
+    /// `ARGON2_T_COST` and `ARGON2_P_COST` environment variables. Unset
+    /// variables fall back to the defaults of the Argon2 crate. New hashes
+    /// name the pepper, if one is given.
+    pub fn from_env(pepper: Option<Zeroizing<Vec<u8>>>) -> anyhow::Result<Self> {
+        let cost = |name: &str, default: u32| -> anyhow::Result<u32> {
+            match env::var(name) {
+                Ok(value) => Ok(value.parse()?),
+                Err(env::VarError::NotPresent) => Ok(default),
+                Err(e) => Err(e.into()),
+            }
+        };
+        let pepper = pepper.map(Pepper::new).transpose()?;
+
+        let mut params = ParamsBuilder::new();
+        params
+            .m_cost(cost("ARGON2_M_COST", Params::DEFAULT_M_COST)?)
+            .t_cost(cost("ARGON2_T_COST", Params::DEFAULT_T_COST)?)
+            .p_cost(cost("ARGON2_P_COST", Params::DEFAULT_P_COST)?);
+        if let Some(pepper) = &pepper {
+            params.keyid(pepper.id);
+        }
+        let params = params
+            .build()
+            .map_err(|e| anyhow::anyhow!("invalid argon2 parameters: {}", e))?;
+
+        Ok(Self {
+            target: HashParams {
+                algorithm: Algorithm::default(),
+                version: Version::default(),
+                params,
+            },
+            pepper,
+        })
+    }
+
+    /// The parameters that new hashes are created with.
+    pub fn target(&self) -> &HashParams {
+        &self.target
+    }
+
+    /// Whether a hash created with `hash_params` should be recreated using
+    /// the target parameters.
+    pub fn is_outdated(&self, hash_params: &HashParams) -> bool {
+        hash_params != &self.target
+    }
+
+    /// The hasher for `hash_params`, which includes the pepper when the
+    /// parameters name one.
+    pub fn argon2(&self, hash_params: &HashParams) -> services::Result<Argon2<'_>> {
+        if hash_params.params.keyid().is_empty() {
+            return Ok(Argon2::new(
+                hash_params.algorithm,
+                hash_params.version,
+                hash_params.params.clone(),
+            ));
+        }
+
+        let pepper = self
+            .pepper
+            .as_ref()
+            .filter(|pepper| pepper.id.as_bytes() == hash_params.params.keyid())
+            .ok_or(anyhow::anyhow!(
+                "hash requires pepper `{}`, which is not loaded",
+                BASE64_STANDARD_NO_PAD.encode(hash_params.params.keyid())
+            ))?;
+
+        Ok(Argon2::new_with_secret(
+            &pepper.secret,
+            hash_params.algorithm,
+            hash_params.version,
+            hash_params.params.clone(),
+        )
+        .map_err(|e| anyhow::anyhow!("failed to use pepper: {}", e))?)
+    }
+
+    /// Hashes `password` with `hash_params` and a random salt, and throws the
+    /// hash away. Failed logins of unknown users take as long as those of
+    /// existing users this way.
+    pub fn hash_dummy(&self, hash_params: &HashParams, password: &str) -> services::Result<()> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut hash = Zeroizing::new([0u8; 32]);
+        self.argon2(hash_params)?
+            .hash_password_into(password.as_bytes(), &salt, hash.as_mut_slice())
+            .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?;
+
+        Ok(())
+    }
+}
+
+/// Picks the existing hash that failed logins of an unknown `username` hash
+/// like. Every attempt with the username, in any spelling, picks the same
+/// one, just like every attempt with an existing username hashes like its
+/// user's hash.
+pub fn dummy_index(username: &str) -> i64 {
+    let digest = Sha256::digest(usernames::key(username).as_bytes());
+    let mut index = [0u8; 8];
+    index.copy_from_slice(&digest[..8]);
+
+    (u64::from_le_bytes(index) >> 1) as i64
+}
+
+/// The Argon2 algorithm, version and cost parameters a password hash or a
+/// password derived key was created with. They are stored next to the hash
+/// as a PHC string without salt and hash, e.g. `$argon2id$v=19$m=19456,t=2,p=1`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HashParams {
+    algorithm: Algorithm,
+    version: Version,
+    params: Params,
+}
+
+impl HashParams {
+    pub fn parse(input: &str) -> services::Result<Self> {
+        let hash = PasswordHash::new(input)
+            .map_err(|e| anyhow::anyhow!("failed to parse hash parameters: {}", e))?;
+
+        Ok(Self {
+            algorithm: Algorithm::try_from(hash.algorithm)
+                .map_err(|e| anyhow::anyhow!("invalid hash algorithm: {}", e))?,
+            version: Version::try_from(hash.version.unwrap_or(Version::V0x10 as u32))
+                .map_err(|e| anyhow::anyhow!("invalid hash version: {}", e))?,
+            params: Params::try_from(&hash)
+                .map_err(|e| anyhow::anyhow!("invalid hash parameters: {}", e))?,
+        })
+    }
+}
+
+impl Default for HashParams {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::default(),
+            version: Version::default(),
+            params: Params::DEFAULT,
+        }
+    }
+}
+
+impl fmt::Display for HashParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "${}$v={}$m={},t={},p={}",
+            self.algorithm,
+            self.version as u32,
+            self.params.m_cost(),
+            self.params.t_cost(),
+            self.params.p_cost()
+        )?;
+        if !self.params.keyid().is_empty() {
+            write!(
+                f,
+                ",keyid={}",
+                BASE64_STANDARD_NO_PAD.encode(self.params.keyid())
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Params, Version};
+    use zeroize::Zeroizing;
+
+    use crate::services::hash_params::{HashConfig, HashParams, Pepper};
+
+    #[test]
+    fn parse_and_format() {
+        let input = "$argon2id$v=19$m=19456,t=2,p=1";
+        let hash_params = HashParams::parse(input).expect("failed to parse hash parameters");
+
+        assert_eq!(
+            hash_params,
+            HashParams {
+                algorithm: Algorithm::Argon2id,
+                version: Version::V0x13,
+                params: Params::DEFAULT,
+            }
+        );
+        assert_eq!(hash_params.to_string(), input);
+    }
+
+    #[test]
+    fn is_outdated() {
+        let hash_config = HashConfig::default();
+
+        assert!(!hash_config.is_outdated(hash_config.target()));
+        assert!(
+            hash_config.is_outdated(
+                &HashParams::parse("$argon2id$v=19$m=8192,t=1,p=1")
+                    .expect("failed to parse hash parameters")
+            )
+        );
+    }
+
+    #[test]
+    fn pepper() {
+        let pepper = Pepper::new(Zeroizing::new(vec![7; 32])).expect("failed to create pepper");
+
+        let mut params = argon2::ParamsBuilder::new();
+        params.m_cost(8192).t_cost(1).p_cost(1).keyid(pepper.id);
+        let peppered = HashParams {
+            algorithm: Algorithm::Argon2id,
+            version: Version::V0x13,
+            params: params.build().expect("failed to build parameters"),
+        };
+        let hash_config = HashConfig {
+            target: peppered.clone(),
+            pepper: Some(pepper),
+        };
+
+        // The pepper id survives formatting and parsing
+        let parsed =
+            HashParams::parse(&peppered.to_string()).expect("failed to parse hash parameters");
+        assert_eq!(parsed, peppered);
+
+        // The pepper changes the hash
+        let hash = |hash_params: &HashParams| {
+            let mut output = [0u8; 32];
+            hash_config
+                .argon2(hash_params)
+                .expect("failed to create hasher")
+                .hash_password_into(b"1234", b"somesaltsomesalt", &mut output)
+                .expect("failed to hash password");
+            output
+        };
+        let unpeppered = HashParams::parse("$argon2id$v=19$m=8192,t=1,p=1")
+            .expect("failed to parse hash parameters");
+        assert_ne!(hash(&parsed), hash(&unpeppered));
+
+        // Hashes naming an unknown pepper cannot be recreated
+        let unknown = HashParams::parse("$argon2id$v=19$m=8192,t=1,p=1,keyid=AAAAAAAAAAA")
+            .expect("failed to parse hash parameters");
+        assert!(hash_config.argon2(&unknown).is_err());
+
+        // Peppered hashes cannot be recreated without the pepper
+        assert!(HashConfig::default().argon2(&peppered).is_err());
+    }
+}
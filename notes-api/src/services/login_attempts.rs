@@ -0,0 +1,343 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use uuid::Uuid;
+
+use crate::{
+    db,
+    services::{self, usernames},
+};
+
+/// Failures that are allowed before a subject is locked out.
+const FREE_FAILURES: i64 = 5;
+
+/// The first lockout, which doubles with every further failure.
+const BASE_LOCKOUT: Duration = Duration::seconds(30);
+
+/// The longest lockout, however many failures there were.
+const MAX_LOCKOUT: Duration = Duration::hours(1);
+
+/// Failures are forgotten after a day without another one.
+const FAILURE_WINDOW: Duration = Duration::days(1);
+
+/// The number of attempts that are kept for the user to review.
+const REVIEW_LIMIT: i64 = 100;
+
+/// Who attempts to sign in, and how. Failures count against both the IP
+/// address and the username, whichever are known. Usernames are counted by
+/// their key, so changing their case does not evade a lockout.
+pub struct Attempt<'a> {
+    pub username: Option<&'a str>,
+    pub ip_address: Option<&'a str>,
+    pub method: &'a str,
+}
+
+impl Attempt<'_> {
+    fn subjects(&self) -> Vec<String> {
+        self.ip_address
+            .map(|ip_address| format!("ip:{}", ip_address))
+            .into_iter()
+            .chain(
+                self.username
+                    .map(|username| format!("username:{}", usernames::key(username))),
+            )
+            .collect()
+    }
+}
+
+/// A recorded attempt to sign in, which the user can review.
+#[derive(Debug, PartialEq)]
+pub struct LoginAttempt {
+    id: Uuid,
+    username: Option<String>,
+    ip_address: Option<String>,
+    method: String,
+    succeeded: bool,
+    time_created: DateTime<Utc>,
+}
+
+impl LoginAttempt {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn ip_address(&self) -> Option<&str> {
+        self.ip_address.as_deref()
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.succeeded
+    }
+
+    pub fn time_created(&self) -> &DateTime<Utc> {
+        &self.time_created
+    }
+}
+
+impl From<db::login_attempts::LoginAttemptRow> for LoginAttempt {
+    fn from(row: db::login_attempts::LoginAttemptRow) -> Self {
+        Self {
+            id: row.id,
+            username: row.username,
+            ip_address: row.ip_address,
+            method: row.method,
+            succeeded: row.succeeded,
+            time_created: row.time_created,
+        }
+    }
+}
+
+/// The lockout after a number of consecutive failures, if any.
+fn lockout(failure_count: i64) -> Option<Duration> {
+    let excess = failure_count - FREE_FAILURES;
+    if excess < 0 {
+        return None;
+    }
+
+    // The lockout is capped long before the factor could overflow
+    Some((BASE_LOCKOUT * 2_i32.pow(excess.min(16) as u32)).min(MAX_LOCKOUT))
+}
+
+/// Returns until when the attempt is locked out, which is `None` if neither
+/// its IP address nor its username is.
+pub async fn locked_until(
+    conn: &mut SqliteConnection,
+    attempt: &Attempt<'_>,
+) -> services::Result<Option<DateTime<Utc>>> {
+    let now = Utc::now();
+
+    let mut locked_until = None;
+    for subject in attempt.subjects() {
+        match db::login_lockouts::get_by_subject(&mut *conn, &subject).await {
+            Ok(row) => {
+                locked_until = locked_until.max(row.locked_until.filter(|time| *time > now));
+            }
+            Err(db::Error::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(locked_until)
+}
+
+/// Records a failed attempt, which counts against its IP address and
+/// username. Returns until when the attempt is locked out now, if it is.
+pub async fn record_failure(
+    conn: &mut SqliteConnection,
+    attempt: &Attempt<'_>,
+) -> services::Result<Option<DateTime<Utc>>> {
+    let now = Utc::now();
+
+    // Attempts with an unknown username are not shown to any user
+    let user_id = match attempt.username {
+        Some(username) => match services::users::get_by_username(&mut *conn, username).await {
+            Ok(user) => Some(*user.id()),
+            Err(services::Error::NotFound) => None,
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    create(&mut *conn, attempt, user_id.as_ref(), false, &now).await?;
+
+    let mut locked_until = None;
+    for subject in attempt.subjects() {
+        let failure_count = match db::login_lockouts::get_by_subject(&mut *conn, &subject).await {
+            Ok(row) if now - row.last_failure_time < FAILURE_WINDOW => row.failure_count,
+            Ok(_) | Err(db::Error::NotFound) => 0,
+            Err(e) => return Err(e.into()),
+        } + 1;
+
+        let subject_locked_until = lockout(failure_count).map(|lockout| now + lockout);
+        db::login_lockouts::create_or_update(
+            &mut *conn,
+            &db::login_lockouts::LoginLockoutRow {
+                subject,
+                failure_count,
+                last_failure_time: now,
+                locked_until: subject_locked_until,
+            },
+        )
+        .await?;
+
+        locked_until = locked_until.max(subject_locked_until);
+    }
+
+    Ok(locked_until)
+}
+
+/// Records a successful attempt of the user, which forgets the failures of
+/// its username. Failures of the IP address are kept, so that signing in to
+/// an account of one's own does not reset them.
+pub async fn record_success(
+    conn: &mut SqliteConnection,
+    attempt: &Attempt<'_>,
+    user_id: &Uuid,
+) -> services::Result<()> {
+    create(&mut *conn, attempt, Some(user_id), true, &Utc::now()).await?;
+
+    if let Some(username) = attempt.username {
+        db::login_lockouts::delete_by_subject(
+            &mut *conn,
+            &format!("username:{}", usernames::key(username)),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn create<'e, E>(
+    executor: E,
+    attempt: &Attempt<'_>,
+    user_id: Option<&Uuid>,
+    succeeded: bool,
+    time_created: &DateTime<Utc>,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    db::login_attempts::create(
+        executor,
+        &db::login_attempts::LoginAttemptRow {
+            id: Uuid::new_v4(),
+            user_id: user_id.copied(),
+            username: attempt.username.map(str::to_string),
+            ip_address: attempt.ip_address.map(str::to_string),
+            method: attempt.method.to_string(),
+            succeeded,
+            time_created: *time_created,
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Gets the latest attempts to sign in to the account of a user, newest
+/// first.
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<Vec<LoginAttempt>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(
+        db::login_attempts::get_by_user_id(executor, user_id, REVIEW_LIMIT)
+            .await?
+            .into_iter()
+            .map(LoginAttempt::from)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        db,
+        services::{
+            self,
+            login_attempts::{Attempt, FREE_FAILURES, MAX_LOCKOUT, lockout},
+        },
+    };
+
+    #[test]
+    fn lockout_backs_off() {
+        assert_eq!(lockout(FREE_FAILURES - 1), None);
+        assert_eq!(lockout(FREE_FAILURES), Some(Duration::seconds(30)));
+        assert_eq!(lockout(FREE_FAILURES + 1), Some(Duration::seconds(60)));
+        assert_eq!(lockout(FREE_FAILURES + 100), Some(MAX_LOCKOUT));
+    }
+
+    #[tokio::test]
+    async fn record_failure_and_success() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+
+        // Perform test
+
+        let attempt = Attempt {
+            username: Some("test"),
+            ip_address: Some("127.0.0.1"),
+            method: "password",
+        };
+
+        // The subjects are locked out after the free failures
+        for _ in 1..FREE_FAILURES {
+            assert_eq!(
+                services::login_attempts::record_failure(&mut conn, &attempt)
+                    .await
+                    .expect("failed to record failure"),
+                None
+            );
+        }
+        let locked_until = services::login_attempts::record_failure(&mut conn, &attempt)
+            .await
+            .expect("failed to record failure");
+        assert!(locked_until.is_some());
+        assert_eq!(
+            services::login_attempts::locked_until(&mut conn, &attempt)
+                .await
+                .expect("failed to get lockout"),
+            locked_until
+        );
+
+        // A success forgets the failures of the username, but not those of
+        // the IP address
+        services::login_attempts::record_success(&mut conn, &attempt, &user_id)
+            .await
+            .expect("failed to record success");
+        assert_eq!(
+            services::login_attempts::locked_until(
+                &mut conn,
+                &Attempt {
+                    ip_address: None,
+                    ..attempt
+                }
+            )
+            .await
+            .expect("failed to get lockout"),
+            None
+        );
+        assert_eq!(
+            services::login_attempts::locked_until(&mut conn, &attempt)
+                .await
+                .expect("failed to get lockout"),
+            locked_until
+        );
+
+        // All attempts are recorded for the user
+        let login_attempts = services::login_attempts::get_by_user_id(&mut *conn, &user_id)
+            .await
+            .expect("failed to get login attempts");
+        assert_eq!(login_attempts.len() as i64, FREE_FAILURES + 1);
+        assert!(login_attempts[0].succeeded());
+    }
+}
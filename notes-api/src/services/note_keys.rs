@@ -1,42 +1,95 @@
 use aes_gcm::{
-    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
-    aead::{Aead, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
 };
-use sqlx::SqliteExecutor;
+use crypto::{SecretKey, envelope, note_keys::NoteKeyIds};
+use sqlx::{SqliteConnection, SqliteExecutor};
 use uuid::Uuid;
+use x25519_dalek::PublicKey;
+use zeroize::Zeroizing;
 
-use crate::{db, services};
+use crate::{
+    db,
+    services::{self, user_key_pairs::UserKeyPair},
+};
 
 #[derive(Debug, PartialEq)]
 pub struct DecryptedNoteKey {
     id: Uuid,
-    key: Key<Aes256Gcm>,
+    key: SecretKey,
 }
 
 impl DecryptedNoteKey {
     pub fn new() -> Self {
         Self {
             id: Uuid::new_v4(),
-            key: Aes256Gcm::generate_key(&mut OsRng),
+            key: SecretKey::generate(),
         }
     }
 
     pub fn key(&self) -> &Key<Aes256Gcm> {
-        &self.key
+        self.key.key()
     }
 
-    pub fn encrypt(&self, user_key: &Key<Aes256Gcm>) -> services::Result<EncryptedNoteKey> {
-        let note_key_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        let note_key_ciphertext = Aes256Gcm::new(user_key)
-            .encrypt(&note_key_nonce, self.key.as_ref())
-            .map_err(|_| services::Error::EncryptionFailed)?;
+    /// Wraps the note key with the user key. The note key id, `note_id` and
+    /// `user_id` are bound as associated data, so the wrapped note key cannot
+    /// be moved onto another note key row.
+    pub fn encrypt(
+        &self,
+        user_key: &Key<Aes256Gcm>,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<EncryptedNoteKey> {
+        let note_key_envelope = crypto::note_keys::encrypt(
+            user_key,
+            self.key.key(),
+            NoteKeyIds {
+                note_key_id: &self.id,
+                note_id,
+                user_id,
+            },
+        )?;
 
         Ok(EncryptedNoteKey {
             id: self.id,
-            encrypted_key: note_key_ciphertext,
-            nonce: note_key_nonce.to_vec(),
+            encrypted_key: note_key_envelope,
+            nonce: Vec::new(),
+            ephemeral_public_key: None,
         })
     }
+
+    /// Seals the note key for another user's public key. The sealed note key
+    /// gets its own id, because it is stored as a separate note key row.
+    pub fn seal(
+        &self,
+        public_key: &PublicKey,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<EncryptedNoteKey> {
+        let id = Uuid::new_v4();
+        let (note_key_envelope, ephemeral_public_key) = crypto::note_keys::seal(
+            public_key,
+            self.key.key(),
+            NoteKeyIds {
+                note_key_id: &id,
+                note_id,
+                user_id,
+            },
+        )?;
+
+        Ok(EncryptedNoteKey {
+            id,
+            encrypted_key: note_key_envelope,
+            nonce: Vec::new(),
+            ephemeral_public_key: Some(ephemeral_public_key.as_bytes().to_vec()),
+        })
+    }
+}
+
+impl Default for DecryptedNoteKey {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -44,18 +97,189 @@ pub struct EncryptedNoteKey {
     id: Uuid,
     encrypted_key: Vec<u8>,
     nonce: Vec<u8>,
+    ephemeral_public_key: Option<Vec<u8>>,
 }
 
 impl EncryptedNoteKey {
-    pub fn decrypt(&self, user_key: &Key<Aes256Gcm>) -> services::Result<DecryptedNoteKey> {
-        let note_key_nonce = Nonce::from_slice(&self.nonce);
-        let note_key_buf = Aes256Gcm::new(user_key)
-            .decrypt(&note_key_nonce, self.encrypted_key.as_ref())
+    /// A note key that was wrapped by the client using
+    /// [`crypto::note_keys::encrypt`]. Only the envelope format is checked,
+    /// as the server does not hold the user key.
+    pub fn end_to_end(id: Uuid, encrypted_key: Vec<u8>) -> services::Result<Self> {
+        envelope::algorithm(&encrypted_key)?;
+
+        Ok(Self {
+            id,
+            encrypted_key,
+            nonce: Vec::new(),
+            ephemeral_public_key: None,
+        })
+    }
+
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn encrypted_key(&self) -> &[u8] {
+        &self.encrypted_key
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.ephemeral_public_key.is_some()
+    }
+
+    /// Whether the note key was encrypted before the envelope format or with
+    /// an outdated algorithm, in which case it should be re-encrypted.
+    pub fn is_outdated(&self) -> bool {
+        !self.nonce.is_empty() || envelope::is_outdated(&self.encrypted_key)
+    }
+
+    /// Unseals the note key, which must be in the current envelope format so
+    /// its commitment to the seal key is checked.
+    pub fn unseal(
+        &self,
+        user_key_pair: &UserKeyPair,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<DecryptedNoteKey> {
+        if !self.nonce.is_empty() {
+            return Err(services::Error::DecryptionFailed);
+        }
+
+        Ok(DecryptedNoteKey {
+            id: self.id,
+            key: crypto::note_keys::unseal(
+                user_key_pair.private_key(),
+                &self.ephemeral_public_key()?,
+                &self.encrypted_key,
+                self.ids(note_id, user_id),
+            )?,
+        })
+    }
+
+    /// Decrypts the note key, which must be in the current envelope format so
+    /// its commitment to the user key is checked.
+    pub fn decrypt(
+        &self,
+        user_key: &Key<Aes256Gcm>,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<DecryptedNoteKey> {
+        if !self.nonce.is_empty() {
+            return Err(services::Error::DecryptionFailed);
+        }
+
+        Ok(DecryptedNoteKey {
+            id: self.id,
+            key: crypto::note_keys::decrypt(
+                user_key,
+                &self.encrypted_key,
+                self.ids(note_id, user_id),
+            )?,
+        })
+    }
+
+    /// Unseals the note key in any format it was ever sealed in, including
+    /// those without a key commitment. Only used to upgrade outdated note keys
+    /// at sign in.
+    pub fn unseal_outdated(
+        &self,
+        user_key_pair: &UserKeyPair,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<DecryptedNoteKey> {
+        if !self.is_uncommitted() {
+            return self.unseal(user_key_pair, note_id, user_id);
+        }
+
+        let ephemeral_public_key = self.ephemeral_public_key()?;
+        let shared_secret = user_key_pair
+            .private_key()
+            .diffie_hellman(&ephemeral_public_key);
+        let seal_key = crypto::note_keys::derive_seal_key(
+            shared_secret.as_bytes(),
+            &ephemeral_public_key,
+            user_key_pair.public_key(),
+        );
+
+        self.open_uncommitted(seal_key.key(), note_id, user_id)
+    }
+
+    /// Decrypts the note key in any format it was ever encrypted in,
+    /// including those without a key commitment. Only used to upgrade
+    /// outdated note keys at sign in.
+    pub fn decrypt_outdated(
+        &self,
+        user_key: &Key<Aes256Gcm>,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<DecryptedNoteKey> {
+        if !self.is_uncommitted() {
+            return self.decrypt(user_key, note_id, user_id);
+        }
+
+        self.open_uncommitted(user_key, note_id, user_id)
+    }
+
+    /// Whether the note key was encrypted before key commitments, either in
+    /// the first envelope format or before the envelope format.
+    fn is_uncommitted(&self) -> bool {
+        !self.nonce.is_empty() || envelope::is_uncommitted(&self.encrypted_key)
+    }
+
+    fn ephemeral_public_key(&self) -> services::Result<PublicKey> {
+        let ephemeral_public_key: [u8; 32] = self
+            .ephemeral_public_key
+            .as_deref()
+            .ok_or(services::Error::DecryptionFailed)?
+            .try_into()
             .map_err(|_| services::Error::DecryptionFailed)?;
 
+        Ok(PublicKey::from(ephemeral_public_key))
+    }
+
+    fn ids<'a>(&'a self, note_id: &'a Uuid, user_id: &'a Uuid) -> NoteKeyIds<'a> {
+        NoteKeyIds {
+            note_key_id: &self.id,
+            note_id,
+            user_id,
+        }
+    }
+
+    /// Decrypts a note key encrypted before key commitments, either in the
+    /// first envelope format or before the envelope format, with or without
+    /// associated data.
+    fn open_uncommitted(
+        &self,
+        key: &Key<Aes256Gcm>,
+        note_id: &Uuid,
+        user_id: &Uuid,
+    ) -> services::Result<DecryptedNoteKey> {
+        let note_key_buf = Zeroizing::new(if self.nonce.is_empty() {
+            envelope::open_uncommitted(
+                key,
+                &self.encrypted_key,
+                &self.ids(note_id, user_id).associated_data(),
+            )?
+        } else {
+            // Note keys encrypted before ids were bound as associated data are
+            // tried without it
+            let cipher = Aes256Gcm::new(key);
+            let nonce = Nonce::from_slice(&self.nonce);
+            cipher
+                .decrypt(
+                    nonce,
+                    Payload {
+                        msg: &self.encrypted_key,
+                        aad: &self.ids(note_id, user_id).associated_data(),
+                    },
+                )
+                .or_else(|_| cipher.decrypt(nonce, self.encrypted_key.as_ref()))
+                .map_err(|_| services::Error::DecryptionFailed)?
+        });
+
         Ok(DecryptedNoteKey {
             id: self.id,
-            key: *Key::<Aes256Gcm>::from_slice(&note_key_buf),
+            key: SecretKey::from_slice(&note_key_buf).ok_or(services::Error::DecryptionFailed)?,
         })
     }
 }
@@ -78,6 +302,33 @@ where
             user_id: *user_id,
             encrypted_key: note_key.encrypted_key,
             nonce: note_key.nonce,
+            ephemeral_public_key: note_key.ephemeral_public_key,
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update<'e, E>(
+    executor: E,
+    note_key: EncryptedNoteKey,
+    note_id: &Uuid,
+    user_id: &Uuid,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Update note key in database
+    db::note_keys::update(
+        executor,
+        &db::note_keys::NoteKeyRow {
+            id: note_key.id,
+            note_id: *note_id,
+            user_id: *user_id,
+            encrypted_key: note_key.encrypted_key,
+            nonce: note_key.nonce,
+            ephemeral_public_key: note_key.ephemeral_public_key,
         },
     )
     .await?;
@@ -90,6 +341,11 @@ pub struct NoteKeyLink {
     pub note_key: EncryptedNoteKey,
 }
 
+pub struct NoteKeyMember {
+    pub user_id: Uuid,
+    pub note_key: EncryptedNoteKey,
+}
+
 pub async fn search<'e, E>(executor: E, user_id: &Uuid) -> services::Result<Vec<NoteKeyLink>>
 where
     E: SqliteExecutor<'e>,
@@ -105,6 +361,31 @@ where
                 id: row.id,
                 encrypted_key: row.encrypted_key.clone(),
                 nonce: row.nonce.clone(),
+                ephemeral_public_key: row.ephemeral_public_key.clone(),
+            },
+        })
+        .collect())
+}
+
+pub async fn search_by_note_id<'e, E>(
+    executor: E,
+    note_id: &Uuid,
+) -> services::Result<Vec<NoteKeyMember>>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Get note keys from database
+    let note_key_rows = db::note_keys::get_by_note_id(executor, note_id).await?;
+
+    Ok(note_key_rows
+        .into_iter()
+        .map(|row| NoteKeyMember {
+            user_id: row.user_id,
+            note_key: EncryptedNoteKey {
+                id: row.id,
+                encrypted_key: row.encrypted_key,
+                nonce: row.nonce,
+                ephemeral_public_key: row.ephemeral_public_key,
             },
         })
         .collect())
@@ -126,9 +407,52 @@ where
         id: note_key_row.id,
         encrypted_key: note_key_row.encrypted_key,
         nonce: note_key_row.nonce,
+        ephemeral_public_key: note_key_row.ephemeral_public_key,
     })
 }
 
+pub async fn delete_by_note_id<'e, E>(executor: E, note_id: &Uuid) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Delete note keys from database
+    db::note_keys::delete_by_note_id(executor, note_id).await?;
+
+    Ok(())
+}
+
+/// Decrypts a note key of `user_id`. Note keys that were shared with the user
+/// are sealed for their key pair; these are unsealed once and stored wrapped
+/// with the user key, so later requests can decrypt them directly.
+pub async fn decrypt_for_user(
+    conn: &mut SqliteConnection,
+    note_key: &EncryptedNoteKey,
+    note_id: &Uuid,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+) -> services::Result<DecryptedNoteKey> {
+    if !note_key.is_sealed() {
+        return note_key.decrypt(user_key, note_id, user_id);
+    }
+
+    // Unseal the note key using the user's key pair
+    let user_key_pair = services::user_key_pairs::get_by_user_id(&mut *conn, user_id)
+        .await?
+        .decrypt(user_key)?;
+    let decrypted_note_key = note_key.unseal(&user_key_pair, note_id, user_id)?;
+
+    // Wrap the note key with the user key
+    update(
+        &mut *conn,
+        decrypted_note_key.encrypt(user_key, note_id, user_id)?,
+        note_id,
+        user_id,
+    )
+    .await?;
+
+    Ok(decrypted_note_key)
+}
+
 pub async fn delete<'e, E>(executor: E, note_id: &Uuid, user_id: &Uuid) -> services::Result<()>
 where
     E: SqliteExecutor<'e>,
@@ -141,7 +465,10 @@ where
 
 #[cfg(test)]
 mod tests {
-    use aes_gcm::{Aes256Gcm, KeyInit, aead::OsRng};
+    use aes_gcm::{
+        AeadCore, Aes256Gcm, KeyInit,
+        aead::{Aead, OsRng},
+    };
     use utilities::db::init_db;
     use uuid::Uuid;
 
@@ -150,6 +477,7 @@ mod tests {
         services::{
             self,
             note_keys::{DecryptedNoteKey, EncryptedNoteKey},
+            user_key_pairs::UserKeyPair,
         },
     };
 
@@ -166,6 +494,7 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -179,6 +508,7 @@ mod tests {
                 id: note_id,
                 encrypted_markdown: vec![1, 2, 3, 4],
                 nonce: vec![1, 2, 3, 4],
+                end_to_end: false,
                 time_created: None,
             },
         )
@@ -191,6 +521,7 @@ mod tests {
             id: Uuid::new_v4(),
             encrypted_key: vec![0, 1, 2, 3],
             nonce: vec![3, 2, 1, 0],
+            ephemeral_public_key: None,
         };
 
         services::note_keys::store(&pool, note_key.clone(), &note_id, &user_id)
@@ -207,17 +538,186 @@ mod tests {
 
     #[tokio::test]
     async fn encrypt_decrypt() {
+        let (note_id, user_id) = (Uuid::new_v4(), Uuid::new_v4());
         let user_key = Aes256Gcm::generate_key(&mut OsRng);
         let note_key = DecryptedNoteKey::new();
         let encrypted_note_key = note_key
-            .encrypt(&user_key)
+            .encrypt(&user_key, &note_id, &user_id)
             .expect("failed to encrypt note key");
 
         assert_eq!(
             encrypted_note_key
-                .decrypt(&user_key)
+                .decrypt(&user_key, &note_id, &user_id)
+                .expect("failed to decrypt note key"),
+            note_key
+        );
+
+        // The note key cannot be moved onto another note or user
+        assert!(
+            encrypted_note_key
+                .decrypt(&user_key, &Uuid::new_v4(), &user_id)
+                .is_err()
+        );
+        assert!(
+            encrypted_note_key
+                .decrypt(&user_key, &note_id, &Uuid::new_v4())
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn decrypt_outdated() {
+        let (note_id, user_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let user_key = Aes256Gcm::generate_key(&mut OsRng);
+        let note_key = DecryptedNoteKey::new();
+
+        // A note key wrapped before the envelope format, without a commitment
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted_note_key = EncryptedNoteKey {
+            id: note_key.id,
+            encrypted_key: Aes256Gcm::new(&user_key)
+                .encrypt(&nonce, note_key.key().as_ref())
+                .expect("failed to encrypt note key"),
+            nonce: nonce.to_vec(),
+            ephemeral_public_key: None,
+        };
+
+        // Note keys without a key commitment only decrypt to be upgraded
+        assert!(encrypted_note_key.is_outdated());
+        assert!(
+            encrypted_note_key
+                .decrypt(&user_key, &note_id, &user_id)
+                .is_err()
+        );
+        assert_eq!(
+            encrypted_note_key
+                .decrypt_outdated(&user_key, &note_id, &user_id)
                 .expect("failed to decrypt note key"),
             note_key
         );
     }
+
+    #[tokio::test]
+    async fn seal_unseal() {
+        let (note_id, user_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let user_key_pair = UserKeyPair::new();
+        let note_key = DecryptedNoteKey::new();
+        let sealed_note_key = note_key
+            .seal(user_key_pair.public_key(), &note_id, &user_id)
+            .expect("failed to seal note key");
+
+        assert!(sealed_note_key.is_sealed());
+        assert_eq!(
+            sealed_note_key
+                .unseal(&user_key_pair, &note_id, &user_id)
+                .expect("failed to unseal note key")
+                .key(),
+            note_key.key()
+        );
+        assert!(
+            sealed_note_key
+                .unseal(&UserKeyPair::new(), &note_id, &user_id)
+                .is_err()
+        );
+        assert!(
+            sealed_note_key
+                .unseal(&user_key_pair, &Uuid::new_v4(), &user_id)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn decrypt_for_user() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let note_id = Uuid::new_v4();
+
+        db::notes::upsert(
+            &pool,
+            &db::notes::NoteRow {
+                id: note_id,
+                encrypted_markdown: vec![1, 2, 3, 4],
+                nonce: vec![1, 2, 3, 4],
+                end_to_end: false,
+                time_created: None,
+            },
+        )
+        .await
+        .expect("failed to create note");
+
+        let user_key = Aes256Gcm::generate_key(&mut OsRng);
+        let user_key_pair = UserKeyPair::new();
+
+        services::user_key_pairs::store(
+            &pool,
+            user_key_pair
+                .encrypt(&user_key)
+                .expect("failed to encrypt user key pair"),
+            &user_id,
+        )
+        .await
+        .expect("failed to store user key pair");
+
+        let note_key = DecryptedNoteKey::new();
+
+        services::note_keys::store(
+            &pool,
+            note_key
+                .seal(user_key_pair.public_key(), &note_id, &user_id)
+                .expect("failed to seal note key"),
+            &note_id,
+            &user_id,
+        )
+        .await
+        .expect("failed to store note key");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let sealed_note_key = services::note_keys::get(&mut *conn, &note_id, &user_id)
+            .await
+            .expect("failed to get note key");
+
+        assert_eq!(
+            services::note_keys::decrypt_for_user(
+                &mut conn,
+                &sealed_note_key,
+                &note_id,
+                &user_id,
+                &user_key
+            )
+            .await
+            .expect("failed to decrypt note key")
+            .key(),
+            note_key.key()
+        );
+
+        let note_key_row = services::note_keys::get(&mut *conn, &note_id, &user_id)
+            .await
+            .expect("failed to get note key");
+
+        assert!(!note_key_row.is_sealed());
+        assert_eq!(
+            note_key_row
+                .decrypt(&user_key, &note_id, &user_id)
+                .expect("failed to decrypt note key")
+                .key(),
+            note_key.key()
+        );
+    }
 }
@@ -1,10 +1,12 @@
 use aes_gcm::{
-    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
-    aead::{Aead, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
 };
 use chrono::{DateTime, Utc};
-use sqlx::SqliteExecutor;
+use crypto::envelope;
+use sqlx::{SqliteConnection, SqliteExecutor};
 use uuid::Uuid;
+use zeroize::Zeroize;
 
 use crate::{db, services, utilities::notes::get_title};
 
@@ -37,6 +39,7 @@ impl DecryptedNote {
     }
 
     pub fn set_markdown(&mut self, markdown: String) {
+        self.markdown.zeroize();
         self.markdown = markdown;
     }
 
@@ -45,34 +48,120 @@ impl DecryptedNote {
     }
 
     pub fn encrypt(&self, note_key: &Key<Aes256Gcm>) -> services::Result<EncryptedNote> {
-        let markdown_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        let markdown_ciphertext = Aes256Gcm::new(note_key)
-            .encrypt(&markdown_nonce, self.markdown.as_bytes())
-            .map_err(|_| services::Error::EncryptionFailed)?;
+        let markdown_envelope =
+            crypto::notes::encrypt(note_key, &self.id, self.markdown.as_bytes())?;
 
         Ok(EncryptedNote {
             id: self.id,
-            encrypted_markdown: markdown_ciphertext,
-            nonce: markdown_nonce.to_vec(),
+            encrypted_markdown: markdown_envelope,
+            nonce: Vec::new(),
+            end_to_end: false,
             time_created: self.time_created,
         })
     }
 }
 
+/// Wipes the decrypted markdown from memory.
+impl Drop for DecryptedNote {
+    fn drop(&mut self) {
+        self.markdown.zeroize();
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct EncryptedNote {
     id: Uuid,
     encrypted_markdown: Vec<u8>,
     nonce: Vec<u8>,
+    end_to_end: bool,
     time_created: Option<DateTime<Utc>>,
 }
 
 impl EncryptedNote {
+    /// A note that was encrypted by the client using
+    /// [`crypto::notes::encrypt`]. Only the envelope format is checked, as
+    /// the server does not hold the note key.
+    pub fn end_to_end(id: Uuid, encrypted_markdown: Vec<u8>) -> services::Result<Self> {
+        envelope::algorithm(&encrypted_markdown)?;
+
+        Ok(Self {
+            id,
+            encrypted_markdown,
+            nonce: Vec::new(),
+            end_to_end: true,
+            time_created: None,
+        })
+    }
+
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn encrypted_markdown(&self) -> &[u8] {
+        &self.encrypted_markdown
+    }
+
+    /// Whether the note was encrypted by the client, in which case the server
+    /// cannot decrypt it.
+    pub fn is_end_to_end(&self) -> bool {
+        self.end_to_end
+    }
+
+    pub fn time_created(&self) -> &Option<DateTime<Utc>> {
+        &self.time_created
+    }
+
+    /// Whether the note was encrypted before the envelope format or with an
+    /// outdated algorithm, in which case it should be re-encrypted.
+    pub fn is_outdated(&self) -> bool {
+        !self.nonce.is_empty() || envelope::is_outdated(&self.encrypted_markdown)
+    }
+
+    /// Decrypts the note, which must be in the current envelope format so its
+    /// commitment to the note key is checked.
     pub fn decrypt(&self, note_key: &Key<Aes256Gcm>) -> services::Result<DecryptedNote> {
-        let markdown_nonce = Nonce::from_slice(&self.nonce);
-        let markdown_buf = Aes256Gcm::new(note_key)
-            .decrypt(&markdown_nonce, self.encrypted_markdown.as_ref())
-            .map_err(|_| services::Error::DecryptionFailed)?;
+        if !self.nonce.is_empty() {
+            return Err(services::Error::DecryptionFailed);
+        }
+
+        Ok(DecryptedNote {
+            id: self.id,
+            markdown: String::from_utf8(crypto::notes::decrypt(
+                note_key,
+                &self.id,
+                &self.encrypted_markdown,
+            )?)?,
+            time_created: self.time_created,
+        })
+    }
+
+    /// Decrypts the note in any format it was ever encrypted in, including
+    /// those without a key commitment. Only used to upgrade outdated notes at
+    /// sign in.
+    fn decrypt_outdated(&self, note_key: &Key<Aes256Gcm>) -> services::Result<DecryptedNote> {
+        // Notes in the envelope format carry their own nonce
+        let markdown_buf = if self.nonce.is_empty() {
+            if envelope::is_uncommitted(&self.encrypted_markdown) {
+                envelope::open_uncommitted(note_key, &self.encrypted_markdown, self.id.as_bytes())?
+            } else {
+                crypto::notes::decrypt(note_key, &self.id, &self.encrypted_markdown)?
+            }
+        } else {
+            // Notes encrypted before ids were bound as associated data are
+            // tried without it
+            let cipher = Aes256Gcm::new(note_key);
+            let nonce = Nonce::from_slice(&self.nonce);
+            cipher
+                .decrypt(
+                    nonce,
+                    Payload {
+                        msg: &self.encrypted_markdown,
+                        aad: self.id.as_bytes(),
+                    },
+                )
+                .or_else(|_| cipher.decrypt(nonce, self.encrypted_markdown.as_ref()))
+                .map_err(|_| services::Error::DecryptionFailed)?
+        };
 
         Ok(DecryptedNote {
             id: self.id,
@@ -93,6 +182,7 @@ where
             id: note.id,
             encrypted_markdown: note.encrypted_markdown,
             nonce: note.nonce,
+            end_to_end: note.end_to_end,
             time_created: None,
         },
     )
@@ -112,6 +202,7 @@ where
         id: note.id,
         encrypted_markdown: note.encrypted_markdown,
         nonce: note.nonce,
+        end_to_end: note.end_to_end,
         time_created: note.time_created,
     })
 }
@@ -126,15 +217,238 @@ where
     Ok(())
 }
 
+pub async fn get_owner_id<'e, E>(executor: E, note_id: &Uuid) -> services::Result<Option<Uuid>>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Get note owner from database
+    Ok(db::notes::get_owner_id(executor, note_id).await?)
+}
+
+pub async fn set_owner_id<'e, E>(
+    executor: E,
+    note_id: &Uuid,
+    owner_id: Option<&Uuid>,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Update note owner in database
+    db::notes::update_owner_id(executor, note_id, owner_id).await?;
+
+    Ok(())
+}
+
+/// Withdraws the access of `member_id` to a note, on behalf of `user_id`,
+/// who is a member too. Because the departing member may still hold the old
+/// note key, the note is re-encrypted with a fresh note key. The new key is
+/// wrapped with the user key for `user_id` and sealed for every other
+/// remaining member. The note is deleted once no members remain, and an owner
+/// who leaves gives up ownership.
+pub async fn revoke_member(
+    conn: &mut SqliteConnection,
+    note: &DecryptedNote,
+    member_id: &Uuid,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+) -> services::Result<()> {
+    // Get the members of the note, excluding the departing member
+    let members = services::note_keys::search_by_note_id(&mut *conn, &note.id).await?;
+    if !members.iter().any(|member| &member.user_id == member_id) {
+        return Err(services::Error::NotFound);
+    }
+    let remaining_user_ids: Vec<Uuid> = members
+        .iter()
+        .map(|member| member.user_id)
+        .filter(|remaining_user_id| remaining_user_id != member_id)
+        .collect();
+
+    // Delete all note keys that wrap the old note key
+    services::note_keys::delete_by_note_id(&mut *conn, &note.id).await?;
+
+    // Delete the note if no members remain
+    if remaining_user_ids.is_empty() {
+        return delete(&mut *conn, &note.id).await;
+    }
+
+    if get_owner_id(&mut *conn, &note.id).await?.as_ref() == Some(member_id) {
+        set_owner_id(&mut *conn, &note.id, None).await?;
+    }
+
+    // Encrypt and store note using a new note key
+    let note_key = services::note_keys::DecryptedNoteKey::new();
+    store(&mut *conn, note.encrypt(note_key.key())?).await?;
+
+    // Wrap the new note key for every remaining member
+    for remaining_user_id in remaining_user_ids {
+        let encrypted_note_key = if &remaining_user_id == user_id {
+            note_key.encrypt(user_key, &note.id, &remaining_user_id)?
+        } else {
+            let user_key_pair =
+                services::user_key_pairs::get_by_user_id(&mut *conn, &remaining_user_id).await?;
+            note_key.seal(user_key_pair.public_key(), &note.id, &remaining_user_id)?
+        };
+        services::note_keys::store(&mut *conn, encrypted_note_key, &note.id, &remaining_user_id)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Re-encrypts the outdated notes, note keys and key pair of `user_id` using
+/// the current envelope format. These can only be re-encrypted while the user
+/// key is at hand, which is when the user signs in. Sealed note keys are
+/// re-wrapped with the user key. Data without a key commitment is only
+/// decrypted when `upgrade_uncommitted` is set. This is best-effort: data that
+/// fails to re-encrypt is logged and skipped. Returns the number of failures.
+pub async fn reencrypt_outdated(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    upgrade_uncommitted: bool,
+) -> services::Result<usize> {
+    let mut failures = 0;
+
+    let user_key_pair = match services::user_key_pairs::get_by_user_id(&mut *conn, user_id).await {
+        Ok(encrypted_user_key_pair) => {
+            match reencrypt_outdated_key_pair(
+                &mut *conn,
+                user_id,
+                user_key,
+                encrypted_user_key_pair,
+                upgrade_uncommitted,
+            )
+            .await
+            {
+                Ok(user_key_pair) => Some(user_key_pair),
+                Err(e) => {
+                    println!("failed to re-encrypt key pair of user {}: {}", user_id, e);
+                    failures += 1;
+                    None
+                }
+            }
+        }
+        Err(services::Error::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+
+    for note_key_link in services::note_keys::search(&mut *conn, user_id).await? {
+        if let Err(e) = reencrypt_outdated_note(
+            &mut *conn,
+            user_id,
+            user_key,
+            user_key_pair.as_ref(),
+            &note_key_link,
+            upgrade_uncommitted,
+        )
+        .await
+        {
+            println!(
+                "failed to re-encrypt note {} of user {}: {}",
+                note_key_link.note_id, user_id, e
+            );
+            failures += 1;
+        }
+    }
+
+    Ok(failures)
+}
+
+async fn reencrypt_outdated_key_pair(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    encrypted_user_key_pair: services::user_key_pairs::EncryptedUserKeyPair,
+    upgrade_uncommitted: bool,
+) -> services::Result<services::user_key_pairs::UserKeyPair> {
+    let user_key_pair = if upgrade_uncommitted {
+        encrypted_user_key_pair.decrypt_outdated(user_key)?
+    } else {
+        encrypted_user_key_pair.decrypt(user_key)?
+    };
+    if encrypted_user_key_pair.is_outdated() {
+        services::user_key_pairs::delete_by_user_id(&mut *conn, user_id).await?;
+        services::user_key_pairs::store(&mut *conn, user_key_pair.encrypt(user_key)?, user_id)
+            .await?;
+    }
+
+    Ok(user_key_pair)
+}
+
+async fn reencrypt_outdated_note(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    user_key_pair: Option<&services::user_key_pairs::UserKeyPair>,
+    note_key_link: &services::note_keys::NoteKeyLink,
+    upgrade_uncommitted: bool,
+) -> services::Result<()> {
+    // Notes encrypted by the client cannot be re-encrypted by the server
+    let note = get_by_id(&mut *conn, &note_key_link.note_id).await?;
+    if note.is_end_to_end() {
+        return Ok(());
+    }
+
+    // Only note keys that have something to upgrade are decrypted
+    let encrypted_note_key = &note_key_link.note_key;
+    let note_key_is_outdated = encrypted_note_key.is_outdated() || encrypted_note_key.is_sealed();
+    if !note_key_is_outdated && !note.is_outdated() {
+        return Ok(());
+    }
+
+    let note_id = &note_key_link.note_id;
+    let note_key = match user_key_pair {
+        Some(user_key_pair) if encrypted_note_key.is_sealed() => {
+            if upgrade_uncommitted {
+                encrypted_note_key.unseal_outdated(user_key_pair, note_id, user_id)?
+            } else {
+                encrypted_note_key.unseal(user_key_pair, note_id, user_id)?
+            }
+        }
+        _ if upgrade_uncommitted => {
+            encrypted_note_key.decrypt_outdated(user_key, note_id, user_id)?
+        }
+        _ => encrypted_note_key.decrypt(user_key, note_id, user_id)?,
+    };
+    if note_key_is_outdated {
+        services::note_keys::update(
+            &mut *conn,
+            note_key.encrypt(user_key, &note_key_link.note_id, user_id)?,
+            &note_key_link.note_id,
+            user_id,
+        )
+        .await?;
+    }
+
+    if note.is_outdated() {
+        let decrypted_note = if upgrade_uncommitted {
+            note.decrypt_outdated(note_key.key())?
+        } else {
+            note.decrypt(note_key.key())?
+        };
+        store(&mut *conn, decrypted_note.encrypt(note_key.key())?).await?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use aes_gcm::{Aes256Gcm, KeyInit, aead::OsRng};
+    use aes_gcm::{
+        AeadCore, Aes256Gcm, KeyInit,
+        aead::{Aead, OsRng, Payload},
+    };
     use utilities::db::init_db;
     use uuid::Uuid;
+    use x25519_dalek::{PublicKey, StaticSecret};
 
-    use crate::services::{
-        self,
-        notes::{DecryptedNote, EncryptedNote},
+    use crate::{
+        db,
+        services::{
+            self,
+            hash_params::HashConfig,
+            notes::{DecryptedNote, EncryptedNote},
+        },
     };
 
     #[tokio::test]
@@ -145,6 +459,7 @@ mod tests {
             id: Uuid::new_v4(),
             encrypted_markdown: vec![0, 1, 2, 3],
             nonce: vec![3, 2, 1, 0],
+            end_to_end: false,
             time_created: None,
         };
 
@@ -163,6 +478,37 @@ mod tests {
         assert_eq!(inserted.nonce, encrypted_note.nonce);
     }
 
+    #[tokio::test]
+    async fn store_end_to_end() {
+        let pool = init_db().await;
+
+        // Only envelopes are accepted from the client
+        let note_id = Uuid::new_v4();
+        assert!(EncryptedNote::end_to_end(note_id, vec![0, 1, 2, 3]).is_err());
+
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let encrypted_note = EncryptedNote::end_to_end(
+            note_id,
+            crypto::notes::encrypt(&note_key, &note_id, b"hello, world")
+                .expect("failed to encrypt note"),
+        )
+        .expect("failed to create end-to-end note");
+
+        services::notes::store(&pool, encrypted_note)
+            .await
+            .expect("failed to store note");
+
+        let inserted = services::notes::get_by_id(&pool, &note_id)
+            .await
+            .expect("failed to get note");
+        assert!(inserted.is_end_to_end());
+        assert_eq!(
+            crypto::notes::decrypt(&note_key, &note_id, inserted.encrypted_markdown())
+                .expect("failed to decrypt note"),
+            b"hello, world"
+        );
+    }
+
     #[tokio::test]
     async fn encrypt_decrypt() {
         let note = DecryptedNote::new(Uuid::new_v4(), "hello, world".to_string());
@@ -176,4 +522,352 @@ mod tests {
             note
         );
     }
+
+    #[tokio::test]
+    async fn decrypt_swapped() {
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let encrypted_note = DecryptedNote::new(Uuid::new_v4(), "hello, world".to_string())
+            .encrypt(&note_key)
+            .expect("failed to encrypt note");
+
+        // The ciphertext of one note cannot be moved onto another note
+        let swapped = EncryptedNote {
+            id: Uuid::new_v4(),
+            ..encrypted_note
+        };
+
+        assert!(swapped.decrypt(&note_key).is_err());
+    }
+
+    #[tokio::test]
+    async fn decrypt_outdated() {
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted_note = EncryptedNote {
+            id: Uuid::new_v4(),
+            encrypted_markdown: Aes256Gcm::new(&note_key)
+                .encrypt(&nonce, "hello, world".as_bytes())
+                .expect("failed to encrypt note"),
+            nonce: nonce.to_vec(),
+            end_to_end: false,
+            time_created: None,
+        };
+
+        // Notes without a key commitment only decrypt to be upgraded
+        assert!(encrypted_note.is_outdated());
+        assert!(encrypted_note.decrypt(&note_key).is_err());
+        assert_eq!(
+            encrypted_note
+                .decrypt_outdated(&note_key)
+                .expect("failed to decrypt note")
+                .markdown(),
+            "hello, world"
+        );
+        assert!(
+            encrypted_note
+                .decrypt_outdated(&Aes256Gcm::generate_key(&mut OsRng))
+                .is_err()
+        );
+
+        // Notes encrypted with their id as associated data, but before the
+        // envelope format
+        let encrypted_note = EncryptedNote {
+            encrypted_markdown: Aes256Gcm::new(&note_key)
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: "hello, world".as_bytes(),
+                        aad: encrypted_note.id.as_bytes(),
+                    },
+                )
+                .expect("failed to encrypt note"),
+            ..encrypted_note
+        };
+        assert_eq!(
+            encrypted_note
+                .decrypt_outdated(&note_key)
+                .expect("failed to decrypt note")
+                .markdown(),
+            "hello, world"
+        );
+    }
+
+    #[tokio::test]
+    async fn reencrypt_outdated() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let user_key = Aes256Gcm::generate_key(&mut OsRng);
+        let note_key = Aes256Gcm::generate_key(&mut OsRng);
+        let note_id = Uuid::new_v4();
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        db::notes::upsert(
+            &pool,
+            &db::notes::NoteRow {
+                id: note_id,
+                encrypted_markdown: Aes256Gcm::new(&note_key)
+                    .encrypt(&nonce, "hello, world".as_bytes())
+                    .expect("failed to encrypt note"),
+                nonce: nonce.to_vec(),
+                end_to_end: false,
+                time_created: None,
+            },
+        )
+        .await
+        .expect("failed to create note");
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        db::note_keys::create(
+            &pool,
+            &db::note_keys::NoteKeyRow {
+                id: Uuid::new_v4(),
+                note_id,
+                user_id,
+                encrypted_key: Aes256Gcm::new(&user_key)
+                    .encrypt(&nonce, note_key.as_ref())
+                    .expect("failed to encrypt note key"),
+                nonce: nonce.to_vec(),
+                ephemeral_public_key: None,
+            },
+        )
+        .await
+        .expect("failed to create note key");
+
+        let private_key = StaticSecret::random_from_rng(OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        db::user_key_pairs::create(
+            &pool,
+            &db::user_key_pairs::UserKeyPairRow {
+                id: Uuid::new_v4(),
+                user_id,
+                public_key: PublicKey::from(&private_key).as_bytes().to_vec(),
+                encrypted_private_key: Aes256Gcm::new(&user_key)
+                    .encrypt(&nonce, private_key.as_bytes().as_ref())
+                    .expect("failed to encrypt private key"),
+                nonce: nonce.to_vec(),
+            },
+        )
+        .await
+        .expect("failed to create user key pair");
+
+        // A note whose key does not decrypt, which is skipped
+        let broken_note_id = Uuid::new_v4();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        db::notes::upsert(
+            &pool,
+            &db::notes::NoteRow {
+                id: broken_note_id,
+                encrypted_markdown: vec![0, 1, 2, 3],
+                nonce: nonce.to_vec(),
+                end_to_end: false,
+                time_created: None,
+            },
+        )
+        .await
+        .expect("failed to create note");
+        db::note_keys::create(
+            &pool,
+            &db::note_keys::NoteKeyRow {
+                id: Uuid::new_v4(),
+                note_id: broken_note_id,
+                user_id,
+                encrypted_key: vec![0, 1, 2, 3],
+                nonce: nonce.to_vec(),
+                ephemeral_public_key: None,
+            },
+        )
+        .await
+        .expect("failed to create note key");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+
+        // Without a key commitment nothing is decrypted past the deadline
+        assert_eq!(
+            services::notes::reencrypt_outdated(&mut conn, &user_id, &user_key, false)
+                .await
+                .expect("failed to re-encrypt outdated notes"),
+            3
+        );
+        assert!(
+            services::notes::get_by_id(&mut *conn, &note_id)
+                .await
+                .expect("failed to get note")
+                .is_outdated()
+        );
+
+        assert_eq!(
+            services::notes::reencrypt_outdated(&mut conn, &user_id, &user_key, true)
+                .await
+                .expect("failed to re-encrypt outdated notes"),
+            1
+        );
+
+        let encrypted_note_key = services::note_keys::get(&mut *conn, &note_id, &user_id)
+            .await
+            .expect("failed to get note key");
+        assert!(!encrypted_note_key.is_outdated());
+
+        let encrypted_note = services::notes::get_by_id(&mut *conn, &note_id)
+            .await
+            .expect("failed to get note");
+        assert!(!encrypted_note.is_outdated());
+
+        let encrypted_user_key_pair =
+            services::user_key_pairs::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get user key pair");
+        assert!(!encrypted_user_key_pair.is_outdated());
+        assert_eq!(
+            encrypted_user_key_pair
+                .decrypt(&user_key)
+                .expect("failed to decrypt user key pair")
+                .private_key()
+                .as_bytes(),
+            private_key.as_bytes()
+        );
+
+        assert_eq!(
+            encrypted_note
+                .decrypt(
+                    encrypted_note_key
+                        .decrypt(&user_key, &note_id, &user_id)
+                        .expect("failed to decrypt note key")
+                        .key()
+                )
+                .expect("failed to decrypt note")
+                .markdown(),
+            "hello, world"
+        );
+    }
+
+    #[tokio::test]
+    async fn revoke_member() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let mut users = Vec::new();
+        for username in ["owner", "member", "departing"] {
+            let (user, user_key, _) =
+                services::users::sign_up(&mut conn, &HashConfig::default(), username, "1234")
+                    .await
+                    .expect("failed to sign up");
+            users.push((user, user_key));
+        }
+        let (owner, owner_key) = &users[0];
+        let (member, member_key) = &users[1];
+        let (departing, _) = &users[2];
+
+        let note = DecryptedNote::new(Uuid::new_v4(), "hello, world".to_string());
+        let old_note_key = services::note_keys::DecryptedNoteKey::new();
+        services::notes::store(
+            &mut *conn,
+            note.encrypt(old_note_key.key())
+                .expect("failed to encrypt note"),
+        )
+        .await
+        .expect("failed to store note");
+        services::notes::set_owner_id(&mut *conn, note.id(), Some(departing.id()))
+            .await
+            .expect("failed to set note owner");
+        for (user, _) in &users {
+            let encrypted_note_key = if user.id() == owner.id() {
+                old_note_key.encrypt(owner_key.key(), note.id(), user.id())
+            } else {
+                let user_key_pair = services::user_key_pairs::get_by_user_id(&mut *conn, user.id())
+                    .await
+                    .expect("failed to get user key pair");
+                old_note_key.seal(user_key_pair.public_key(), note.id(), user.id())
+            }
+            .expect("failed to encrypt note key");
+            services::note_keys::store(&mut *conn, encrypted_note_key, note.id(), user.id())
+                .await
+                .expect("failed to store note key");
+        }
+
+        // Perform test
+
+        services::notes::revoke_member(
+            &mut conn,
+            &note,
+            departing.id(),
+            owner.id(),
+            owner_key.key(),
+        )
+        .await
+        .expect("failed to revoke note member");
+
+        // The departed member's note key is gone, and with it the ownership
+        assert!(matches!(
+            services::note_keys::get(&mut *conn, note.id(), departing.id()).await,
+            Err(services::Error::NotFound)
+        ));
+        assert_eq!(
+            services::notes::get_owner_id(&mut *conn, note.id())
+                .await
+                .expect("failed to get note owner"),
+            None
+        );
+
+        // The remaining members hold a new note key, which decrypts the note,
+        // while the old note key no longer does
+        let encrypted_note = services::notes::get_by_id(&mut *conn, note.id())
+            .await
+            .expect("failed to get note");
+        assert!(encrypted_note.decrypt(old_note_key.key()).is_err());
+        for (user, user_key) in [(owner, owner_key), (member, member_key)] {
+            let encrypted_note_key = services::note_keys::get(&mut *conn, note.id(), user.id())
+                .await
+                .expect("failed to get note key");
+            assert_eq!(encrypted_note_key.is_sealed(), user.id() == member.id());
+
+            let note_key = services::note_keys::decrypt_for_user(
+                &mut conn,
+                &encrypted_note_key,
+                note.id(),
+                user.id(),
+                user_key.key(),
+            )
+            .await
+            .expect("failed to decrypt note key");
+            assert_eq!(
+                encrypted_note
+                    .decrypt(note_key.key())
+                    .expect("failed to decrypt note")
+                    .markdown(),
+                "hello, world"
+            );
+        }
+
+        // Members who already left cannot be revoked again
+        assert!(matches!(
+            services::notes::revoke_member(
+                &mut conn,
+                &note,
+                departing.id(),
+                owner.id(),
+                owner_key.key(),
+            )
+            .await,
+            Err(services::Error::NotFound)
+        ));
+    }
 }
@@ -0,0 +1,417 @@
+use std::fmt;
+
+use aes_gcm::{Aes256Gcm, Key};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use crypto::SecretKey;
+use sqlx::{SqliteConnection, SqliteExecutor};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    db,
+    services::{self, user_keys::UserKey},
+};
+
+/// The prefix of access tokens, which tells them apart from session tokens.
+const TOKEN_PREFIX: &str = "pat_";
+
+/// What an access token may be used for. Tokens are only accepted by the
+/// routes of their scopes; managing the account always takes a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    NotesRead,
+    NotesWrite,
+}
+
+impl Scope {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input {
+            "notes:read" => Some(Self::NotesRead),
+            "notes:write" => Some(Self::NotesWrite),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotesRead => "notes:read",
+            Self::NotesWrite => "notes:write",
+        }
+    }
+}
+
+/// The bearer token of an access token: its id and a random secret that
+/// unwraps its copy of the user key. The secret is not stored, so the token
+/// is only shown when it is created. It is wiped from memory when dropped and
+/// redacted from its `Debug` output.
+#[derive(Debug, PartialEq)]
+pub struct AccessToken {
+    id: Uuid,
+    secret: SecretKey,
+}
+
+impl AccessToken {
+    fn generate() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            secret: SecretKey::generate(),
+        }
+    }
+
+    /// Parses a bearer token, which is `None` if it is not an access token.
+    pub fn parse(input: &str) -> Option<Self> {
+        let buf = Zeroizing::new(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(input.strip_prefix(TOKEN_PREFIX)?)
+                .ok()?,
+        );
+        if buf.len() != 48 {
+            return None;
+        }
+
+        Some(Self {
+            id: Uuid::from_slice(&buf[..16]).ok()?,
+            secret: SecretKey::from_slice(&buf[16..])?,
+        })
+    }
+
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+}
+
+impl fmt::Display for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = Zeroizing::new(Vec::with_capacity(48));
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(self.secret.key());
+
+        write!(
+            f,
+            "{}{}",
+            TOKEN_PREFIX,
+            Zeroizing::new(BASE64_URL_SAFE_NO_PAD.encode(&buf)).as_str()
+        )
+    }
+}
+
+/// A long-lived token for scripts and integrations, which stands in for a
+/// session with limited scopes.
+#[derive(Debug, PartialEq)]
+pub struct UserAccessToken {
+    id: Uuid,
+    user_id: Uuid,
+    name: String,
+    scopes: Vec<Scope>,
+    time_created: Option<DateTime<Utc>>,
+    expiration_time: Option<DateTime<Utc>>,
+    last_used_time: Option<DateTime<Utc>>,
+}
+
+impl UserAccessToken {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn user_id(&self) -> &Uuid {
+        &self.user_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn time_created(&self) -> Option<&DateTime<Utc>> {
+        self.time_created.as_ref()
+    }
+
+    pub fn expiration_time(&self) -> Option<&DateTime<Utc>> {
+        self.expiration_time.as_ref()
+    }
+
+    pub fn last_used_time(&self) -> Option<&DateTime<Utc>> {
+        self.last_used_time.as_ref()
+    }
+
+    /// Tokens without an expiration time stay valid until they are revoked.
+    pub fn is_expired(&self) -> bool {
+        self.expiration_time
+            .is_some_and(|expiration_time| expiration_time < Utc::now())
+    }
+}
+
+impl From<db::user_access_tokens::UserAccessTokenRow> for UserAccessToken {
+    fn from(row: db::user_access_tokens::UserAccessTokenRow) -> Self {
+        Self {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            // Scopes that are no longer known grant nothing
+            scopes: row.scopes.split(' ').filter_map(Scope::parse).collect(),
+            time_created: row.time_created,
+            expiration_time: row.expiration_time,
+            last_used_time: row.last_used_time,
+        }
+    }
+}
+
+/// Creates an access token and wraps the user key with its secret. Returns
+/// the bearer token, which cannot be recovered later.
+pub async fn create(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    name: &str,
+    scopes: &[Scope],
+    expiration_time: Option<&DateTime<Utc>>,
+) -> services::Result<(UserAccessToken, AccessToken)> {
+    let access_token = AccessToken::generate();
+
+    // Wrap the user key using the secret
+    let user_key = UserKey::from(user_key);
+    services::user_keys::store_using_key(&mut *conn, user_id, &user_key, access_token.secret.key())
+        .await?;
+
+    // Store the token
+    db::user_access_tokens::create(
+        &mut *conn,
+        &db::user_access_tokens::UserAccessTokenRow {
+            id: access_token.id,
+            user_id: *user_id,
+            user_key_id: *user_key.id(),
+            name: name.to_string(),
+            scopes: scopes
+                .iter()
+                .map(Scope::as_str)
+                .collect::<Vec<_>>()
+                .join(" "),
+            time_created: None,
+            expiration_time: expiration_time.copied(),
+            last_used_time: None,
+        },
+    )
+    .await?;
+
+    Ok((get_by_id(&mut *conn, &access_token.id).await?, access_token))
+}
+
+/// Unwraps the user key with the secret of a bearer token, which fails if the
+/// token is unknown, revoked or expired, or if the secret is incorrect.
+pub async fn authenticate(
+    conn: &mut SqliteConnection,
+    access_token: &AccessToken,
+) -> services::Result<(UserAccessToken, UserKey)> {
+    let user_access_token_row =
+        db::user_access_tokens::get_by_id(&mut *conn, &access_token.id).await?;
+    let user_key_id = user_access_token_row.user_key_id;
+
+    let mut user_access_token = UserAccessToken::from(user_access_token_row);
+    if user_access_token.is_expired() {
+        return Err(services::Error::NotFound);
+    }
+
+    // The secret can only unwrap the user key if it is correct
+    let user_key =
+        services::user_keys::get_using_key(&mut *conn, &user_key_id, access_token.secret.key())
+            .await?;
+
+    let now = Utc::now();
+    db::user_access_tokens::update_last_used_time(&mut *conn, &access_token.id, &now).await?;
+    user_access_token.last_used_time = Some(now);
+
+    Ok((user_access_token, user_key))
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> services::Result<UserAccessToken>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(db::user_access_tokens::get_by_id(executor, id)
+        .await?
+        .into())
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<Vec<UserAccessToken>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(db::user_access_tokens::get_by_user_id(executor, user_id)
+        .await?
+        .into_iter()
+        .map(UserAccessToken::from)
+        .collect())
+}
+
+/// Revokes an access token, along with its wrapping of the user key.
+pub async fn delete(conn: &mut SqliteConnection, id: &Uuid) -> services::Result<()> {
+    let user_access_token_row = db::user_access_tokens::get_by_id(&mut *conn, id).await?;
+
+    db::user_access_tokens::delete_by_id(&mut *conn, id).await?;
+    services::user_keys::delete(&mut *conn, &user_access_token_row.user_key_id).await?;
+
+    Ok(())
+}
+
+/// Revokes the access tokens of a user. Their secrets are not stored, so the
+/// user key cannot be re-wrapped for existing tokens, e.g. when the user key
+/// is rotated.
+pub async fn delete_by_user_id(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+) -> services::Result<()> {
+    for user_access_token_row in db::user_access_tokens::get_by_user_id(&mut *conn, user_id).await?
+    {
+        delete(&mut *conn, &user_access_token_row.id).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        db,
+        services::{
+            self,
+            user_access_tokens::{AccessToken, Scope},
+            user_keys::UserKey,
+        },
+    };
+
+    #[test]
+    fn parse_access_token() {
+        let access_token = AccessToken::generate();
+
+        assert_eq!(
+            AccessToken::parse(&access_token.to_string()),
+            Some(access_token)
+        );
+        assert_eq!(AccessToken::parse("pat_invalid"), None);
+        assert_eq!(AccessToken::parse("eyJhbGciOiJBMjU2R0NNS1cifQ"), None);
+    }
+
+    #[tokio::test]
+    async fn create_and_authenticate() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let user_key = UserKey::new();
+
+        // Perform test
+
+        let (user_access_token, access_token) = services::user_access_tokens::create(
+            &mut conn,
+            &user_id,
+            user_key.key(),
+            "test",
+            &[Scope::NotesRead],
+            None,
+        )
+        .await
+        .expect("failed to create user access token");
+        assert!(user_access_token.has_scope(Scope::NotesRead));
+        assert!(!user_access_token.has_scope(Scope::NotesWrite));
+
+        let (authenticated, authenticated_user_key) =
+            services::user_access_tokens::authenticate(&mut conn, &access_token)
+                .await
+                .expect("failed to authenticate with user access token");
+        assert_eq!(authenticated.id(), user_access_token.id());
+        assert!(authenticated.last_used_time().is_some());
+        assert_eq!(authenticated_user_key.key(), user_key.key());
+
+        // A token with another secret cannot unwrap the user key
+        let forged = AccessToken {
+            id: access_token.id,
+            ..AccessToken::generate()
+        };
+        assert!(
+            services::user_access_tokens::authenticate(&mut conn, &forged)
+                .await
+                .is_err_and(|e| matches!(e, services::Error::DecryptionFailed))
+        );
+
+        // Revoked tokens are rejected
+        services::user_access_tokens::delete(&mut conn, user_access_token.id())
+            .await
+            .expect("failed to delete user access token");
+        assert!(
+            services::user_access_tokens::authenticate(&mut conn, &access_token)
+                .await
+                .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticate_expired() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let user_key = UserKey::new();
+
+        let (_, access_token) = services::user_access_tokens::create(
+            &mut conn,
+            &user_id,
+            user_key.key(),
+            "test",
+            &[Scope::NotesRead],
+            Some(&(Utc::now() - Duration::minutes(1))),
+        )
+        .await
+        .expect("failed to create user access token");
+
+        // Perform test
+
+        assert!(
+            services::user_access_tokens::authenticate(&mut conn, &access_token)
+                .await
+                .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+    }
+}
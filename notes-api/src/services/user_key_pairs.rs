@@ -0,0 +1,255 @@
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng},
+};
+use crypto::envelope;
+use sqlx::SqliteExecutor;
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{db, services};
+
+/// A user's X25519 key pair. Other users seal shared note keys for the public
+/// key; the private key is stored wrapped with the user key.
+pub struct UserKeyPair {
+    id: Uuid,
+    public_key: PublicKey,
+    private_key: StaticSecret,
+}
+
+impl UserKeyPair {
+    pub fn new() -> Self {
+        let private_key = StaticSecret::random_from_rng(OsRng);
+
+        Self {
+            id: Uuid::new_v4(),
+            public_key: PublicKey::from(&private_key),
+            private_key,
+        }
+    }
+
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn private_key(&self) -> &StaticSecret {
+        &self.private_key
+    }
+
+    /// Wraps the private key with the user key. The key pair id is bound as
+    /// associated data.
+    pub fn encrypt(&self, user_key: &Key<Aes256Gcm>) -> services::Result<EncryptedUserKeyPair> {
+        let private_key_envelope =
+            envelope::seal(user_key, self.private_key.as_bytes(), self.id.as_bytes())?;
+
+        Ok(EncryptedUserKeyPair {
+            id: self.id,
+            public_key: self.public_key,
+            encrypted_private_key: private_key_envelope,
+            nonce: Vec::new(),
+        })
+    }
+}
+
+impl Default for UserKeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EncryptedUserKeyPair {
+    id: Uuid,
+    public_key: PublicKey,
+    encrypted_private_key: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+impl EncryptedUserKeyPair {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Whether the private key was wrapped before the envelope format or
+    /// with an outdated algorithm, in which case it should be re-wrapped.
+    pub fn is_outdated(&self) -> bool {
+        !self.nonce.is_empty() || envelope::is_outdated(&self.encrypted_private_key)
+    }
+
+    /// Decrypts the private key, which must be in the current envelope format
+    /// so its commitment to the user key is checked.
+    pub fn decrypt(&self, user_key: &Key<Aes256Gcm>) -> services::Result<UserKeyPair> {
+        if !self.nonce.is_empty() {
+            return Err(services::Error::DecryptionFailed);
+        }
+
+        self.to_user_key_pair(envelope::open(
+            user_key,
+            &self.encrypted_private_key,
+            self.id.as_bytes(),
+        )?)
+    }
+
+    /// Decrypts the private key in any format it was ever wrapped in,
+    /// including those without a key commitment. Only used to upgrade
+    /// outdated key pairs at sign in.
+    pub fn decrypt_outdated(&self, user_key: &Key<Aes256Gcm>) -> services::Result<UserKeyPair> {
+        let private_key_buf = if !self.nonce.is_empty() {
+            Aes256Gcm::new(user_key)
+                .decrypt(
+                    Nonce::from_slice(&self.nonce),
+                    self.encrypted_private_key.as_ref(),
+                )
+                .map_err(|_| services::Error::DecryptionFailed)?
+        } else if envelope::is_uncommitted(&self.encrypted_private_key) {
+            envelope::open_uncommitted(user_key, &self.encrypted_private_key, self.id.as_bytes())?
+        } else {
+            envelope::open(user_key, &self.encrypted_private_key, self.id.as_bytes())?
+        };
+
+        self.to_user_key_pair(private_key_buf)
+    }
+
+    /// Moves a decrypted private key into the key pair, wiping the buffers.
+    fn to_user_key_pair(&self, private_key_buf: Vec<u8>) -> services::Result<UserKeyPair> {
+        let private_key_buf = Zeroizing::new(private_key_buf);
+        let mut private_key = Zeroizing::new([0u8; 32]);
+        if private_key_buf.len() != private_key.len() {
+            return Err(services::Error::DecryptionFailed);
+        }
+        private_key.copy_from_slice(&private_key_buf);
+
+        Ok(UserKeyPair {
+            id: self.id,
+            public_key: self.public_key,
+            private_key: StaticSecret::from(*private_key),
+        })
+    }
+}
+
+pub async fn store<'e, E>(
+    executor: E,
+    user_key_pair: EncryptedUserKeyPair,
+    user_id: &Uuid,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Store user key pair in database
+    db::user_key_pairs::create(
+        executor,
+        &db::user_key_pairs::UserKeyPairRow {
+            id: user_key_pair.id,
+            user_id: *user_id,
+            public_key: user_key_pair.public_key.as_bytes().to_vec(),
+            encrypted_private_key: user_key_pair.encrypted_private_key,
+            nonce: user_key_pair.nonce,
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<EncryptedUserKeyPair>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Get user key pair from database
+    let user_key_pair_row = db::user_key_pairs::get_by_user_id(executor, user_id).await?;
+
+    let public_key: [u8; 32] = user_key_pair_row
+        .public_key
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid public key length"))?;
+
+    Ok(EncryptedUserKeyPair {
+        id: user_key_pair_row.id,
+        public_key: PublicKey::from(public_key),
+        encrypted_private_key: user_key_pair_row.encrypted_private_key,
+        nonce: user_key_pair_row.nonce,
+    })
+}
+
+pub async fn delete_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Delete user key pair from database
+    db::user_key_pairs::delete_by_user_id(executor, user_id).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{Aes256Gcm, KeyInit, aead::OsRng};
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{db, services};
+
+    #[tokio::test]
+    async fn store_and_get() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        // Perform test
+
+        let user_key = Aes256Gcm::generate_key(&mut OsRng);
+        let user_key_pair = services::user_key_pairs::UserKeyPair::new()
+            .encrypt(&user_key)
+            .expect("failed to encrypt user key pair");
+
+        services::user_key_pairs::store(&pool, user_key_pair.clone(), &user_id)
+            .await
+            .expect("failed to store user key pair");
+
+        assert_eq!(
+            services::user_key_pairs::get_by_user_id(&pool, &user_id)
+                .await
+                .expect("failed to get user key pair"),
+            user_key_pair
+        )
+    }
+
+    #[tokio::test]
+    async fn encrypt_decrypt() {
+        let user_key = Aes256Gcm::generate_key(&mut OsRng);
+        let user_key_pair = services::user_key_pairs::UserKeyPair::new();
+
+        let decrypted = user_key_pair
+            .encrypt(&user_key)
+            .expect("failed to encrypt user key pair")
+            .decrypt(&user_key)
+            .expect("failed to decrypt user key pair");
+
+        assert_eq!(decrypted.public_key(), user_key_pair.public_key());
+        assert_eq!(
+            decrypted.private_key().as_bytes(),
+            user_key_pair.private_key().as_bytes()
+        );
+    }
+}
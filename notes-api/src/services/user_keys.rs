@@ -1,25 +1,32 @@
 use aes_gcm::{
-    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
-    aead::{Aead, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng, rand_core::RngCore},
 };
-use argon2::{Argon2, PasswordHasher};
-use password_hash::SaltString;
-use sqlx::SqliteExecutor;
+use crypto::{SecretKey, envelope};
+use sqlx::{SqliteConnection, SqliteExecutor};
 use uuid::Uuid;
-
-use crate::{db, services};
+use zeroize::Zeroizing;
+
+use crate::{
+    db,
+    services::{
+        self,
+        hash_params::{HashConfig, HashParams},
+        user_recovery_codes::RecoveryCode,
+    },
+};
 
 #[derive(Debug, PartialEq)]
 pub struct UserKey {
     id: Uuid,
-    key: Key<Aes256Gcm>,
+    key: SecretKey,
 }
 
 impl UserKey {
     pub fn new() -> Self {
         Self {
             id: Uuid::new_v4(),
-            key: Aes256Gcm::generate_key(&mut OsRng),
+            key: SecretKey::generate(),
         }
     }
 
@@ -28,7 +35,12 @@ impl UserKey {
     }
 
     pub fn key(&self) -> &Key<Aes256Gcm> {
-        &self.key
+        self.key.key()
+    }
+
+    /// Gives up the user key, e.g. to move it into the claims of a session.
+    pub fn into_key(self) -> SecretKey {
+        self.key
     }
 }
 
@@ -36,13 +48,42 @@ impl From<&Key<Aes256Gcm>> for UserKey {
     fn from(key: &Key<Aes256Gcm>) -> Self {
         Self {
             id: Uuid::new_v4(),
-            key: key.clone(),
+            key: SecretKey::from(key),
         }
     }
 }
 
+/// The secret the user signs in with, under which the user key is wrapped.
+pub enum UserSecret<'a> {
+    Password(&'a str),
+
+    /// The export key of the user's OPAQUE record
+    ExportKey(&'a [u8]),
+}
+
+/// Derives the key that wraps the user key from a password. The key is
+/// written straight into a buffer that is wiped, instead of through a
+/// password hash string.
+fn derive_password_key(
+    hash_config: &HashConfig,
+    hash_params: &HashParams,
+    password: &str,
+    salt: &[u8],
+) -> services::Result<SecretKey> {
+    let mut password_key = Zeroizing::new([0u8; 32]);
+    hash_config
+        .argon2(hash_params)?
+        .hash_password_into(password.as_bytes(), salt, password_key.as_mut_slice())
+        .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?;
+
+    Ok(SecretKey::from(Key::<Aes256Gcm>::from_slice(
+        password_key.as_slice(),
+    )))
+}
+
 pub async fn store_using_password<'e, E>(
     executor: E,
+    hash_config: &HashConfig,
     user_id: &Uuid,
     user_key: &UserKey,
     password: &str,
@@ -50,28 +91,14 @@ pub async fn store_using_password<'e, E>(
 where
     E: SqliteExecutor<'e>,
 {
-    // Hash the password for use as the encryption key
-    let password_salt = SaltString::generate(&mut OsRng);
-    let password_hash = Argon2::default()
-        .hash_password(password.as_bytes(), &password_salt)
-        .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?
-        .hash
-        .ok_or(anyhow::anyhow!("empty password hash"))?
-        .as_bytes()[0..32]
-        .to_vec();
+    // Hash the password for use as the encryption key, using the target parameters
+    let mut password_salt = [0u8; 16];
+    OsRng.fill_bytes(&mut password_salt);
+    let hash_params = hash_config.target();
+    let password_key = derive_password_key(hash_config, hash_params, password, &password_salt)?;
 
     // Encrypt the user key
-    let user_key_key = Key::<Aes256Gcm>::from_slice(&password_hash);
-    let user_key_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let user_key_ciphertext = Aes256Gcm::new(user_key_key)
-        .encrypt(&user_key_nonce, user_key.key.as_ref())
-        .map_err(|_| services::Error::EncryptionFailed)?;
-
-    // Convert the password salt to a byte array
-    let mut password_salt_buf = [0u8; 16];
-    password_salt
-        .decode_b64(&mut password_salt_buf)
-        .map_err(|e| anyhow::anyhow!("failed to decode password salt: {}", e))?;
+    let user_key_envelope = envelope::seal(password_key.key(), user_key.key(), &[])?;
 
     // Store the encrypted user key, nonce and password salt
     db::user_keys::create(
@@ -79,9 +106,10 @@ where
         &db::user_keys::UserKeyRow {
             id: user_key.id,
             user_id: *user_id,
-            encrypted_key: user_key_ciphertext,
-            nonce: user_key_nonce.to_vec(),
-            salt: password_salt_buf.to_vec(),
+            encrypted_key: user_key_envelope,
+            nonce: Vec::new(),
+            salt: password_salt.to_vec(),
+            hash_params: hash_params.to_string(),
         },
     )
     .await?;
@@ -89,47 +117,288 @@ where
     Ok(())
 }
 
+/// Unwraps the user key, which must be in the current envelope format so its
+/// commitment to the password key is checked.
 pub async fn get_using_password<'e, E>(
     executor: E,
+    hash_config: &HashConfig,
+    user_key_id: &Uuid,
+    password: &str,
+) -> services::Result<UserKey>
+where
+    E: SqliteExecutor<'e>,
+{
+    unwrap_using_password(executor, hash_config, user_key_id, password, false).await
+}
+
+/// Unwraps the user key in any format it was ever wrapped in, including those
+/// without a key commitment. Only used at password sign in, where outdated
+/// user keys are re-wrapped.
+pub async fn get_outdated_using_password<'e, E>(
+    executor: E,
+    hash_config: &HashConfig,
+    user_key_id: &Uuid,
+    password: &str,
+) -> services::Result<UserKey>
+where
+    E: SqliteExecutor<'e>,
+{
+    unwrap_using_password(executor, hash_config, user_key_id, password, true).await
+}
+
+async fn unwrap_using_password<'e, E>(
+    executor: E,
+    hash_config: &HashConfig,
     user_key_id: &Uuid,
     password: &str,
-) -> anyhow::Result<UserKey>
+    accept_uncommitted: bool,
+) -> services::Result<UserKey>
 where
     E: SqliteExecutor<'e>,
 {
     // Get user key from database
     let user_key_row = db::user_keys::get_by_id(executor, user_key_id).await?;
+    let is_uncommitted =
+        !user_key_row.nonce.is_empty() || envelope::is_uncommitted(&user_key_row.encrypted_key);
+    if is_uncommitted && !accept_uncommitted {
+        return Err(services::Error::DecryptionFailed);
+    }
+
+    // Hash the password for use as the decryption key, using the parameters
+    // the user key was wrapped with
+    let password_key = derive_password_key(
+        hash_config,
+        &HashParams::parse(&user_key_row.hash_params)?,
+        password,
+        &user_key_row.salt,
+    )?;
+
+    // Decrypt the user key. User keys in the envelope format carry their own
+    // nonce.
+    let user_key_buf = Zeroizing::new(if user_key_row.nonce.is_empty() {
+        if envelope::is_uncommitted(&user_key_row.encrypted_key) {
+            envelope::open_uncommitted(password_key.key(), &user_key_row.encrypted_key, &[])?
+        } else {
+            envelope::open(password_key.key(), &user_key_row.encrypted_key, &[])?
+        }
+    } else {
+        let user_key_nonce = Nonce::from_slice(&user_key_row.nonce);
+        Aes256Gcm::new(password_key.key())
+            .decrypt(user_key_nonce, user_key_row.encrypted_key.as_ref())
+            .map_err(|_| services::Error::DecryptionFailed)?
+    });
+
+    Ok(UserKey {
+        id: *user_key_id,
+        key: SecretKey::from_slice(&user_key_buf).ok_or(services::Error::DecryptionFailed)?,
+    })
+}
+
+/// Wraps the user key with a high-entropy key, such as an OPAQUE export key or
+/// the secret of an access token, which needs no password hashing. The
+/// wrapping has no salt and no hash parameters.
+pub async fn store_using_key<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    user_key: &UserKey,
+    wrapping_key: &Key<Aes256Gcm>,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    db::user_keys::create(
+        executor,
+        &db::user_keys::UserKeyRow {
+            id: user_key.id,
+            user_id: *user_id,
+            encrypted_key: envelope::seal(wrapping_key, user_key.key(), &[])?,
+            nonce: Vec::new(),
+            salt: Vec::new(),
+            hash_params: String::new(),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
 
-    // Hash the password for use as the decryption key
-    let password_salt = SaltString::encode_b64(&user_key_row.salt)
-        .map_err(|e| anyhow::anyhow!("failed to encode salt string: {}", e))?;
-    let password_hash = Argon2::default()
-        .hash_password(password.as_bytes(), &password_salt)
-        .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?
-        .hash
-        .ok_or(anyhow::anyhow!("empty password hash"))?
-        .as_bytes()[0..32]
-        .to_vec();
-
-    // Decrypt the user key
-    let user_key_key = Key::<Aes256Gcm>::from_slice(&password_hash);
-    let user_key_nonce = Nonce::from_slice(&user_key_row.nonce);
-    let user_key_buf = Aes256Gcm::new(user_key_key)
-        .decrypt(&user_key_nonce, user_key_row.encrypted_key.as_ref())
-        .map_err(|_| services::Error::DecryptionFailed)?;
+pub async fn get_using_key<'e, E>(
+    executor: E,
+    user_key_id: &Uuid,
+    wrapping_key: &Key<Aes256Gcm>,
+) -> services::Result<UserKey>
+where
+    E: SqliteExecutor<'e>,
+{
+    let user_key_row = db::user_keys::get_by_id(executor, user_key_id).await?;
+    let user_key_buf = Zeroizing::new(envelope::open(
+        wrapping_key,
+        &user_key_row.encrypted_key,
+        &[],
+    )?);
 
     Ok(UserKey {
         id: *user_key_id,
-        key: *Key::<Aes256Gcm>::from_slice(&user_key_buf),
+        key: SecretKey::from_slice(&user_key_buf).ok_or(services::Error::DecryptionFailed)?,
     })
 }
 
+/// Whether the user key was wrapped before the envelope format, with an
+/// outdated algorithm or with outdated hash parameters, in which case it
+/// should be re-wrapped the next time the password is at hand.
+pub async fn is_outdated<'e, E>(
+    executor: E,
+    hash_config: &HashConfig,
+    user_key_id: &Uuid,
+) -> services::Result<bool>
+where
+    E: SqliteExecutor<'e>,
+{
+    let user_key_row = db::user_keys::get_by_id(executor, user_key_id).await?;
+
+    Ok(!user_key_row.nonce.is_empty()
+        || envelope::is_outdated(&user_key_row.encrypted_key)
+        || hash_config.is_outdated(&HashParams::parse(&user_key_row.hash_params)?))
+}
+
+pub async fn delete<'e, E>(executor: E, user_key_id: &Uuid) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Delete user key from database
+    db::user_keys::delete_by_id(executor, user_key_id).await?;
+
+    Ok(())
+}
+
+/// Replaces the user key by a new one after a suspected compromise. Every
+/// note key of the user is re-wrapped with the new user key, the user key is
+/// wrapped under the user's secret and a new recovery code, the key pair is
+/// replaced and all passkeys, access tokens and sessions of the user are
+/// dropped.
+///
+/// Run this inside a transaction: if it fails or the process dies halfway,
+/// the transaction is rolled back and every note key stays wrapped under the
+/// old user key, so the rotation can simply be started again.
+pub async fn rotate(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    secret: UserSecret<'_>,
+) -> services::Result<(UserKey, Option<RecoveryCode>)> {
+    let new_user_key = UserKey::new();
+
+    // Unseal note keys that were shared with the user using the old key pair
+    let user_key_pair = match services::user_key_pairs::get_by_user_id(&mut *conn, user_id).await {
+        Ok(user_key_pair) => Some(user_key_pair.decrypt(user_key)?),
+        Err(services::Error::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+
+    // Re-wrap every note key with the new user key
+    for note_key_link in services::note_keys::search(&mut *conn, user_id).await? {
+        // Note keys of notes encrypted by the client are not wrapped with the
+        // user key
+        if services::notes::get_by_id(&mut *conn, &note_key_link.note_id)
+            .await?
+            .is_end_to_end()
+        {
+            continue;
+        }
+
+        let note_key = match &user_key_pair {
+            Some(user_key_pair) if note_key_link.note_key.is_sealed() => note_key_link
+                .note_key
+                .unseal(user_key_pair, &note_key_link.note_id, user_id)?,
+            _ => note_key_link
+                .note_key
+                .decrypt(user_key, &note_key_link.note_id, user_id)?,
+        };
+
+        services::note_keys::update(
+            &mut *conn,
+            note_key.encrypt(new_user_key.key(), &note_key_link.note_id, user_id)?,
+            &note_key_link.note_id,
+            user_id,
+        )
+        .await?;
+    }
+
+    // Replace the key pair, as its private key was wrapped with the old user key
+    if user_key_pair.is_some() {
+        services::user_key_pairs::delete_by_user_id(&mut *conn, user_id).await?;
+    }
+    services::user_key_pairs::store(
+        &mut *conn,
+        services::user_key_pairs::UserKeyPair::new().encrypt(new_user_key.key())?,
+        user_id,
+    )
+    .await?;
+
+    // Re-encrypt the TOTP secret, which is encrypted with the user key
+    services::user_totp::reencrypt(&mut *conn, user_id, user_key, new_user_key.key()).await?;
+
+    // Wrap the new user key using the user's secret and a new recovery code
+    match secret {
+        UserSecret::Password(password) => {
+            services::user_passwords::change(
+                &mut *conn,
+                hash_config,
+                user_id,
+                new_user_key.key(),
+                password,
+            )
+            .await?
+        }
+        UserSecret::ExportKey(export_key) => {
+            services::user_opaque::rewrap(&mut *conn, user_id, new_user_key.key(), export_key)
+                .await?
+        }
+    }
+    let recovery_code =
+        match services::user_recovery_codes::get_by_user_id(&mut *conn, user_id).await {
+            Ok(_) => Some(
+                services::user_recovery_codes::replace(
+                    &mut *conn,
+                    hash_config,
+                    user_id,
+                    new_user_key.key(),
+                )
+                .await?,
+            ),
+            Err(services::Error::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+    // Passkeys wrap the old user key with a PRF output that only their
+    // authenticator can produce, so they have to be registered again
+    services::user_passkeys::delete_by_user_id(&mut *conn, user_id).await?;
+
+    // Access tokens wrap the old user key with a secret that is not stored
+    services::user_access_tokens::delete_by_user_id(&mut *conn, user_id).await?;
+
+    // Sessions carry the old user key
+    services::user_sessions::delete_by_user_id(&mut *conn, user_id).await?;
+
+    Ok((new_user_key, recovery_code))
+}
+
 #[cfg(test)]
 mod tests {
+    use aes_gcm::{
+        AeadCore, Aes256Gcm, Key, KeyInit,
+        aead::{Aead, OsRng},
+    };
+    use argon2::{Argon2, PasswordHasher};
+    use password_hash::SaltString;
     use utilities::db::init_db;
     use uuid::Uuid;
 
-    use crate::{db, services};
+    use crate::{
+        db,
+        services::{self, hash_params::HashConfig},
+    };
 
     #[tokio::test]
     async fn store_using_password() {
@@ -144,6 +413,7 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -154,9 +424,15 @@ mod tests {
         let password = "1234";
         let user_key = services::user_keys::UserKey::new();
 
-        services::user_keys::store_using_password(&pool, &user_id, &user_key, password)
-            .await
-            .expect("failed to store key using password");
+        services::user_keys::store_using_password(
+            &pool,
+            &HashConfig::default(),
+            &user_id,
+            &user_key,
+            password,
+        )
+        .await
+        .expect("failed to store key using password");
     }
 
     #[tokio::test]
@@ -172,6 +448,54 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let password = "1234";
+        let user_key = services::user_keys::UserKey::new();
+
+        services::user_keys::store_using_password(
+            &pool,
+            &HashConfig::default(),
+            &user_id,
+            &user_key,
+            password,
+        )
+        .await
+        .expect("failed to store key using password");
+
+        // Perform test
+
+        assert_eq!(
+            user_key,
+            services::user_keys::get_using_password(
+                &pool,
+                &HashConfig::default(),
+                &user_key.id,
+                password
+            )
+            .await
+            .expect("failed to get key using password")
+        )
+    }
+
+    #[tokio::test]
+    async fn rotate() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -180,17 +504,216 @@ mod tests {
         let password = "1234";
         let user_key = services::user_keys::UserKey::new();
 
-        services::user_keys::store_using_password(&pool, &user_id, &user_key, password)
+        services::user_keys::store_using_password(
+            &pool,
+            &HashConfig::default(),
+            &user_id,
+            &user_key,
+            password,
+        )
+        .await
+        .expect("failed to store key using password");
+        services::user_passwords::store(
+            &pool,
+            &services::user_passwords::UserPassword::new(
+                &HashConfig::default(),
+                &user_key.id,
+                password,
+            )
+            .expect("failed to create user password"),
+            &user_id,
+        )
+        .await
+        .expect("failed to store user password");
+
+        let note_id = Uuid::new_v4();
+
+        db::notes::upsert(
+            &pool,
+            &db::notes::NoteRow {
+                id: note_id,
+                encrypted_markdown: vec![1, 2, 3, 4],
+                nonce: vec![1, 2, 3, 4],
+                end_to_end: false,
+                time_created: None,
+            },
+        )
+        .await
+        .expect("failed to create note");
+
+        let note_key = services::note_keys::DecryptedNoteKey::new();
+
+        services::note_keys::store(
+            &pool,
+            note_key
+                .encrypt(user_key.key(), &note_id, &user_id)
+                .expect("failed to encrypt note key"),
+            &note_id,
+            &user_id,
+        )
+        .await
+        .expect("failed to store note key");
+
+        let user_session = services::user_sessions::UserSession::new_persistent();
+
+        services::user_sessions::store(&pool, &user_session, &user_id)
+            .await
+            .expect("failed to store user session");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let (new_user_key, _) = services::user_keys::rotate(
+            &mut conn,
+            &HashConfig::default(),
+            &user_id,
+            user_key.key(),
+            services::user_keys::UserSecret::Password(password),
+        )
+        .await
+        .expect("failed to rotate user key");
+
+        assert_ne!(new_user_key.key(), user_key.key());
+        assert_eq!(
+            services::note_keys::get(&pool, &note_id, &user_id)
+                .await
+                .expect("failed to get note key")
+                .decrypt(new_user_key.key(), &note_id, &user_id)
+                .expect("failed to decrypt note key"),
+            note_key
+        );
+
+        let user_password = services::user_passwords::get_by_user_id(&pool, &user_id)
+            .await
+            .expect("failed to get user password");
+        assert_eq!(
+            services::user_keys::get_using_password(
+                &pool,
+                &HashConfig::default(),
+                user_password.user_key_id(),
+                password
+            )
+            .await
+            .expect("failed to get key using password")
+            .key(),
+            new_user_key.key()
+        );
+        assert!(
+            services::user_keys::get_using_password(
+                &pool,
+                &HashConfig::default(),
+                &user_key.id,
+                password
+            )
             .await
-            .expect("failed to store key using password");
+            .is_err()
+        );
+        assert!(
+            services::user_sessions::get(&pool, user_session.id())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn get_outdated_using_password() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        // A user key wrapped before the envelope format, with a separate nonce
+        let password = "1234";
+        let user_key = services::user_keys::UserKey::new();
+
+        let password_salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &password_salt)
+            .expect("failed to hash password")
+            .hash
+            .expect("failed to get password hash")
+            .as_bytes()[0..32]
+            .to_vec();
+        let mut password_salt_buf = [0u8; 16];
+        password_salt
+            .decode_b64(&mut password_salt_buf)
+            .expect("failed to decode password salt");
+
+        let user_key_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        db::user_keys::create(
+            &pool,
+            &db::user_keys::UserKeyRow {
+                id: user_key.id,
+                user_id,
+                encrypted_key: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&password_hash))
+                    .encrypt(&user_key_nonce, user_key.key().as_ref())
+                    .expect("failed to encrypt user key"),
+                nonce: user_key_nonce.to_vec(),
+                salt: password_salt_buf.to_vec(),
+                hash_params: "$argon2id$v=19$m=19456,t=2,p=1".to_string(),
+            },
+        )
+        .await
+        .expect("failed to create user key");
 
         // Perform test
 
+        // Only the upgrade at sign in accepts a user key without a key
+        // commitment
+        assert!(
+            services::user_keys::get_using_password(
+                &pool,
+                &HashConfig::default(),
+                &user_key.id,
+                password
+            )
+            .await
+            .is_err()
+        );
         assert_eq!(
             user_key,
-            services::user_keys::get_using_password(&pool, &user_key.id, password)
+            services::user_keys::get_outdated_using_password(
+                &pool,
+                &HashConfig::default(),
+                &user_key.id,
+                password
+            )
+            .await
+            .expect("failed to get key using password")
+        );
+        assert!(
+            services::user_keys::is_outdated(&pool, &HashConfig::default(), &user_key.id)
                 .await
-                .expect("failed to get key using password")
+                .expect("failed to check user key")
+        );
+
+        let new_user_key = services::user_keys::UserKey::new();
+        services::user_keys::store_using_password(
+            &pool,
+            &HashConfig::default(),
+            &user_id,
+            &new_user_key,
+            password,
         )
+        .await
+        .expect("failed to store key using password");
+
+        assert!(
+            !services::user_keys::is_outdated(&pool, &HashConfig::default(), &new_user_key.id)
+                .await
+                .expect("failed to check user key")
+        );
     }
 }
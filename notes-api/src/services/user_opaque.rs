@@ -0,0 +1,561 @@
+use aes_gcm::{Aes256Gcm, Key, aead::OsRng};
+use chrono::{Duration, Utc};
+use crypto::SecretKey;
+use hkdf::Hkdf;
+use opaque_ke::{
+    CipherSuite, CredentialFinalization, CredentialRequest, RegistrationRequest,
+    RegistrationUpload, Ristretto255, ServerLogin, ServerLoginStartParameters, ServerRegistration,
+    ServerSetup, key_exchange::tripledh::TripleDh,
+};
+use sha2::{Digest, Sha256, Sha512};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    db,
+    services::{self, user_keys::UserKey},
+};
+
+/// The OPAQUE configuration, which clients have to use as well: ristretto255
+/// for the OPRF and the key exchange, 3DH, and Argon2id with the default
+/// parameters of the `argon2` crate to stretch the password.
+pub struct OpaqueCipherSuite;
+
+impl CipherSuite for OpaqueCipherSuite {
+    type OprfCs = Ristretto255;
+    type KeGroup = Ristretto255;
+    type KeyExchange = TripleDh;
+    type Ksf = argon2::Argon2<'static>;
+}
+
+/// The server's OPRF seed and key pair. Every registration depends on it, so
+/// it is persisted and must not change.
+pub type OpaqueSetup = ServerSetup<OpaqueCipherSuite>;
+
+/// The length of an export key, the output of SHA-512.
+pub const EXPORT_KEY_LEN: usize = 64;
+
+/// How long the client has to finish a login.
+const LOGIN_VALIDITY: Duration = Duration::minutes(5);
+
+/// Derives the key that wraps the user key from the export key. The export
+/// key never leaves the client, except to unlock the user key for a session,
+/// as the password did before.
+fn derive_wrapping_key(export_key: &[u8]) -> services::Result<SecretKey> {
+    if export_key.len() != EXPORT_KEY_LEN {
+        return Err(services::Error::DecryptionFailed);
+    }
+
+    let mut wrapping_key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha512>::new(None, export_key)
+        .expand(b"notes user key wrapping", wrapping_key.as_mut_slice())
+        .map_err(|e| anyhow::anyhow!("failed to derive wrapping key: {}", e))?;
+
+    Ok(SecretKey::from(Key::<Aes256Gcm>::from_slice(
+        wrapping_key.as_slice(),
+    )))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserOpaqueRecord {
+    id: Uuid,
+    user_key_id: Uuid,
+}
+
+impl UserOpaqueRecord {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn user_key_id(&self) -> &Uuid {
+        &self.user_key_id
+    }
+}
+
+/// The state of a login between the client's two messages.
+#[derive(Debug)]
+pub struct OpaqueLogin {
+    id: Uuid,
+    credential_response: Vec<u8>,
+}
+
+impl OpaqueLogin {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    /// The message to send back to the client
+    pub fn credential_response(&self) -> &[u8] {
+        &self.credential_response
+    }
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<UserOpaqueRecord>
+where
+    E: SqliteExecutor<'e>,
+{
+    let user_opaque_record_row = db::user_opaque_records::get_by_user_id(executor, user_id).await?;
+
+    Ok(UserOpaqueRecord {
+        id: user_opaque_record_row.id,
+        user_key_id: user_opaque_record_row.user_key_id,
+    })
+}
+
+/// Evaluates the OPRF on the client's blinded password, which is the first
+/// step of registering. The server keeps no state between the two steps.
+pub fn start_registration(
+    setup: &OpaqueSetup,
+    user_id: &Uuid,
+    registration_request: &[u8],
+) -> services::Result<Vec<u8>> {
+    let registration_request = RegistrationRequest::deserialize(registration_request)?;
+    let result = ServerRegistration::start(setup, registration_request, user_id.as_bytes())?;
+
+    Ok(result.message.serialize().to_vec())
+}
+
+/// Stores the client's registration record and wraps the user key with the
+/// export key. A previous record of the user is replaced, and a password of
+/// the user is dropped, as it is superseded by the record. Both their
+/// wrappings of the user key are dropped as well.
+pub async fn register(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    registration_upload: &[u8],
+    export_key: &[u8],
+) -> services::Result<()> {
+    let password_file = ServerRegistration::<OpaqueCipherSuite>::finish(
+        RegistrationUpload::deserialize(registration_upload)?,
+    );
+
+    // Drop the previous record or password
+    match delete_by_user_id(&mut *conn, user_id).await {
+        Ok(()) | Err(services::Error::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    match services::user_passwords::get_by_user_id(&mut *conn, user_id).await {
+        Ok(user_password) => {
+            services::user_passwords::delete(&mut *conn, user_password.id()).await?;
+            services::user_keys::delete(&mut *conn, user_password.user_key_id()).await?;
+        }
+        Err(services::Error::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+
+    // Wrap the user key using the export key
+    let user_key = UserKey::from(user_key);
+    services::user_keys::store_using_key(
+        &mut *conn,
+        user_id,
+        &user_key,
+        derive_wrapping_key(export_key)?.key(),
+    )
+    .await?;
+
+    // Store the record
+    db::user_opaque_records::create(
+        &mut *conn,
+        &db::user_opaque_records::UserOpaqueRecordRow {
+            id: Uuid::new_v4(),
+            user_id: *user_id,
+            user_key_id: *user_key.id(),
+            password_file: password_file.serialize().to_vec(),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Starts a login, answering the client's credential request. Logins with an
+/// unknown username, or of a user without a record, are answered with a fake
+/// response, so they cannot be told apart from a wrong password.
+pub async fn start_login(
+    conn: &mut SqliteConnection,
+    setup: &OpaqueSetup,
+    username: &str,
+    credential_request: &[u8],
+) -> services::Result<OpaqueLogin> {
+    let credential_request = CredentialRequest::deserialize(credential_request)?;
+
+    let user_opaque_record_row = match services::users::get_by_username(&mut *conn, username).await
+    {
+        Ok(user) => match db::user_opaque_records::get_by_user_id(&mut *conn, user.id()).await {
+            Ok(user_opaque_record_row) => Some(user_opaque_record_row),
+            Err(db::Error::NotFound) => None,
+            Err(e) => return Err(e.into()),
+        },
+        Err(services::Error::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+
+    // The OPRF key of a fake response is derived from the username, so it is
+    // the same for every attempt, as it is for a real user
+    let (user_id, password_file, credential_identifier) = match user_opaque_record_row {
+        Some(row) => (
+            Some(row.user_id),
+            Some(ServerRegistration::deserialize(&row.password_file)?),
+            row.user_id.as_bytes().to_vec(),
+        ),
+        None => (None, None, Sha256::digest(username.as_bytes()).to_vec()),
+    };
+
+    let result = ServerLogin::start(
+        &mut OsRng,
+        setup,
+        password_file,
+        credential_request,
+        &credential_identifier,
+        ServerLoginStartParameters::default(),
+    )?;
+
+    let now = Utc::now();
+    db::opaque_logins::delete_expired(&mut *conn, &now).await?;
+
+    let opaque_login = OpaqueLogin {
+        id: Uuid::new_v4(),
+        credential_response: result.message.serialize().to_vec(),
+    };
+    db::opaque_logins::create(
+        &mut *conn,
+        &db::opaque_logins::OpaqueLoginRow {
+            id: opaque_login.id,
+            user_id,
+            state: result.state.serialize().to_vec(),
+            expiration_time: now + LOGIN_VALIDITY,
+        },
+    )
+    .await?;
+
+    Ok(opaque_login)
+}
+
+/// Gets the user a login was started for, which is `None` if the username
+/// was unknown.
+pub async fn get_login_user_id<'e, E>(
+    executor: E,
+    login_id: &Uuid,
+) -> services::Result<Option<Uuid>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(db::opaque_logins::get_by_id(executor, login_id)
+        .await?
+        .user_id)
+}
+
+/// Finishes a login, which fails if the client did not prove knowledge of
+/// the password, and unwraps the user key with the export key. Returns the
+/// id of the user that signed in.
+pub async fn finish_login(
+    conn: &mut SqliteConnection,
+    login_id: &Uuid,
+    credential_finalization: &[u8],
+    export_key: &[u8],
+) -> services::Result<(Uuid, UserKey)> {
+    // A login can only be finished once
+    let opaque_login_row = db::opaque_logins::take_by_id(&mut *conn, login_id).await?;
+    if opaque_login_row.expiration_time < Utc::now() {
+        return Err(services::Error::NotFound);
+    }
+
+    ServerLogin::<OpaqueCipherSuite>::deserialize(&opaque_login_row.state)?.finish(
+        CredentialFinalization::deserialize(credential_finalization)?,
+    )?;
+
+    // Logins of unknown users cannot be finished by the client, but are
+    // rejected here regardless
+    let user_id = opaque_login_row.user_id.ok_or(services::Error::NotFound)?;
+    let user_key = get_user_key(&mut *conn, &user_id, export_key).await?;
+
+    Ok((user_id, user_key))
+}
+
+/// Unwraps the user key using the export key, which also proves that the
+/// client knows the password.
+pub async fn get_user_key(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    export_key: &[u8],
+) -> services::Result<UserKey> {
+    let user_opaque_record = get_by_user_id(&mut *conn, user_id).await?;
+
+    services::user_keys::get_using_key(
+        &mut *conn,
+        user_opaque_record.user_key_id(),
+        derive_wrapping_key(export_key)?.key(),
+    )
+    .await
+}
+
+/// Wraps a new user key with the export key, replacing the wrapping of the
+/// record. The record itself is kept.
+pub async fn rewrap(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    export_key: &[u8],
+) -> services::Result<()> {
+    let user_opaque_record_row =
+        db::user_opaque_records::get_by_user_id(&mut *conn, user_id).await?;
+
+    let user_key = UserKey::from(user_key);
+    services::user_keys::store_using_key(
+        &mut *conn,
+        user_id,
+        &user_key,
+        derive_wrapping_key(export_key)?.key(),
+    )
+    .await?;
+
+    db::user_opaque_records::delete_by_id(&mut *conn, &user_opaque_record_row.id).await?;
+    db::user_opaque_records::create(
+        &mut *conn,
+        &db::user_opaque_records::UserOpaqueRecordRow {
+            id: Uuid::new_v4(),
+            user_id: *user_id,
+            user_key_id: *user_key.id(),
+            password_file: user_opaque_record_row.password_file,
+        },
+    )
+    .await?;
+    services::user_keys::delete(&mut *conn, &user_opaque_record_row.user_key_id).await?;
+
+    Ok(())
+}
+
+/// Deletes the record of a user, along with its wrapping of the user key.
+pub async fn delete_by_user_id(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+) -> services::Result<()> {
+    let user_opaque_record = get_by_user_id(&mut *conn, user_id).await?;
+
+    db::user_opaque_records::delete_by_id(&mut *conn, user_opaque_record.id()).await?;
+    services::user_keys::delete(&mut *conn, user_opaque_record.user_key_id()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::aead::OsRng;
+    use opaque_ke::{
+        ClientLogin, ClientLoginFinishParameters, ClientRegistration,
+        ClientRegistrationFinishParameters, CredentialResponse, RegistrationResponse,
+    };
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        db,
+        services::{
+            self,
+            hash_params::HashConfig,
+            user_keys::UserKey,
+            user_opaque::{OpaqueCipherSuite, OpaqueSetup},
+        },
+    };
+
+    /// Registers `password` the way a client would, and returns the export key.
+    async fn register(
+        conn: &mut sqlx::SqliteConnection,
+        setup: &OpaqueSetup,
+        user_id: &Uuid,
+        user_key: &UserKey,
+        password: &str,
+    ) -> Vec<u8> {
+        let client_start =
+            ClientRegistration::<OpaqueCipherSuite>::start(&mut OsRng, password.as_bytes())
+                .expect("failed to start client registration");
+        let registration_response = services::user_opaque::start_registration(
+            setup,
+            user_id,
+            &client_start.message.serialize(),
+        )
+        .expect("failed to start registration");
+        let client_finish = client_start
+            .state
+            .finish(
+                &mut OsRng,
+                password.as_bytes(),
+                RegistrationResponse::deserialize(&registration_response)
+                    .expect("failed to deserialize registration response"),
+                ClientRegistrationFinishParameters::default(),
+            )
+            .expect("failed to finish client registration");
+
+        services::user_opaque::register(
+            conn,
+            user_id,
+            user_key.key(),
+            &client_finish.message.serialize(),
+            &client_finish.export_key,
+        )
+        .await
+        .expect("failed to register");
+
+        client_finish.export_key.to_vec()
+    }
+
+    /// Signs in with `password` the way a client would.
+    async fn login(
+        conn: &mut sqlx::SqliteConnection,
+        setup: &OpaqueSetup,
+        username: &str,
+        password: &str,
+    ) -> services::Result<(Uuid, UserKey)> {
+        let client_start = ClientLogin::<OpaqueCipherSuite>::start(&mut OsRng, password.as_bytes())
+            .expect("failed to start client login");
+        let opaque_login = services::user_opaque::start_login(
+            conn,
+            setup,
+            username,
+            &client_start.message.serialize(),
+        )
+        .await?;
+
+        // The client detects a wrong password, and cannot finish the login
+        let Ok(client_finish) = client_start.state.finish(
+            password.as_bytes(),
+            CredentialResponse::deserialize(opaque_login.credential_response())
+                .expect("failed to deserialize credential response"),
+            ClientLoginFinishParameters::default(),
+        ) else {
+            return Err(services::Error::NotFound);
+        };
+
+        services::user_opaque::finish_login(
+            conn,
+            opaque_login.id(),
+            &client_finish.message.serialize(),
+            &client_finish.export_key,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn register_and_login() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let setup = OpaqueSetup::new(&mut OsRng);
+        let user_key = UserKey::new();
+
+        register(&mut conn, &setup, &user_id, &user_key, "1234").await;
+
+        let (logged_in_user_id, logged_in_user_key) = login(&mut conn, &setup, "test", "1234")
+            .await
+            .expect("failed to login");
+        assert_eq!(logged_in_user_id, user_id);
+        assert_eq!(logged_in_user_key.key(), user_key.key());
+
+        assert!(login(&mut conn, &setup, "test", "4321").await.is_err());
+        assert!(login(&mut conn, &setup, "unknown", "1234").await.is_err());
+
+        // Registering again replaces the record and its wrapping
+        register(&mut conn, &setup, &user_id, &user_key, "4321").await;
+        assert!(login(&mut conn, &setup, "test", "1234").await.is_err());
+        assert!(login(&mut conn, &setup, "test", "4321").await.is_ok());
+        assert_eq!(
+            db::user_keys::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get user keys")
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn register_replaces_password() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let user_key = UserKey::new();
+
+        services::user_keys::store_using_password(
+            &mut *conn,
+            &HashConfig::default(),
+            &user_id,
+            &user_key,
+            "1234",
+        )
+        .await
+        .expect("failed to store user key");
+        services::user_passwords::store(
+            &mut *conn,
+            &services::user_passwords::UserPassword::new(
+                &HashConfig::default(),
+                user_key.id(),
+                "1234",
+            )
+            .expect("failed to create user password"),
+            &user_id,
+        )
+        .await
+        .expect("failed to store user password");
+
+        // Perform test
+
+        let setup = OpaqueSetup::new(&mut OsRng);
+        let export_key = register(&mut conn, &setup, &user_id, &user_key, "1234").await;
+
+        assert!(
+            services::user_passwords::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+        assert_eq!(
+            services::user_opaque::get_user_key(&mut conn, &user_id, &export_key)
+                .await
+                .expect("failed to get user key")
+                .key(),
+            user_key.key()
+        );
+        assert_eq!(
+            db::user_keys::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get user keys")
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use aes_gcm::{
+    Aes256Gcm, Key,
+    aead::{OsRng, rand_core::RngCore},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    db,
+    services::{self, hash_params::HashConfig, user_keys::UserKey},
+    webauthn::{Credential, RelyingParty},
+};
+
+/// The number of random bytes in a challenge.
+const CHALLENGE_BYTES: usize = 32;
+
+/// How long the client has to answer a challenge.
+const CHALLENGE_VALIDITY: Duration = Duration::minutes(5);
+
+/// A random challenge that the authenticator signs, so its response cannot
+/// be replayed. It is answered once, within a few minutes.
+#[derive(Debug)]
+pub struct PasskeyChallenge {
+    id: Uuid,
+    challenge: Vec<u8>,
+}
+
+impl PasskeyChallenge {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn challenge(&self) -> &[u8] {
+        &self.challenge
+    }
+}
+
+/// The response of the client to a registration challenge.
+pub struct PasskeyRegistration {
+    pub challenge_id: Uuid,
+    pub client_data_json: Vec<u8>,
+    pub attestation_object: Vec<u8>,
+
+    /// The output of the PRF extension, which wraps the user key
+    pub prf_output: Zeroizing<Vec<u8>>,
+    pub name: String,
+}
+
+/// The response of the client to a login challenge.
+pub struct PasskeyAssertion {
+    pub challenge_id: Uuid,
+    pub credential_id: Vec<u8>,
+    pub client_data_json: Vec<u8>,
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+
+    /// The output of the PRF extension, which unwraps the user key
+    pub prf_output: Zeroizing<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserPasskey {
+    id: Uuid,
+    user_id: Uuid,
+    name: String,
+    time_created: Option<DateTime<Utc>>,
+    last_used_time: Option<DateTime<Utc>>,
+}
+
+impl UserPasskey {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn user_id(&self) -> &Uuid {
+        &self.user_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn time_created(&self) -> Option<&DateTime<Utc>> {
+        self.time_created.as_ref()
+    }
+
+    pub fn last_used_time(&self) -> Option<&DateTime<Utc>> {
+        self.last_used_time.as_ref()
+    }
+}
+
+impl From<db::user_passkeys::UserPasskeyRow> for UserPasskey {
+    fn from(row: db::user_passkeys::UserPasskeyRow) -> Self {
+        Self {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            time_created: row.time_created,
+            last_used_time: row.last_used_time,
+        }
+    }
+}
+
+/// The PRF output stands in for the password of a user key wrapping. It is
+/// encoded, because wrappings take the password as a string.
+fn prf_password(prf_output: &[u8]) -> Zeroizing<String> {
+    Zeroizing::new(BASE64_URL_SAFE_NO_PAD.encode(prf_output))
+}
+
+/// Creates a challenge for registering a passkey of `user_id`, or for
+/// signing in with any passkey when no user is given.
+pub async fn create_challenge(
+    conn: &mut SqliteConnection,
+    user_id: Option<&Uuid>,
+) -> services::Result<PasskeyChallenge> {
+    let now = Utc::now();
+    db::webauthn_challenges::delete_expired(&mut *conn, &now).await?;
+
+    let mut challenge = vec![0u8; CHALLENGE_BYTES];
+    OsRng.fill_bytes(&mut challenge);
+
+    let passkey_challenge = PasskeyChallenge {
+        id: Uuid::new_v4(),
+        challenge,
+    };
+    db::webauthn_challenges::create(
+        &mut *conn,
+        &db::webauthn_challenges::WebauthnChallengeRow {
+            id: passkey_challenge.id,
+            user_id: user_id.copied(),
+            challenge: passkey_challenge.challenge.clone(),
+            expiration_time: now + CHALLENGE_VALIDITY,
+        },
+    )
+    .await?;
+
+    Ok(passkey_challenge)
+}
+
+/// Takes a challenge, which fails with `NotFound` if the challenge was
+/// answered before, expired, or was created for another user.
+async fn take_challenge<'e, E>(
+    executor: E,
+    id: &Uuid,
+    user_id: Option<&Uuid>,
+) -> services::Result<Vec<u8>>
+where
+    E: SqliteExecutor<'e>,
+{
+    let webauthn_challenge_row = db::webauthn_challenges::take_by_id(executor, id).await?;
+    if webauthn_challenge_row.user_id.as_ref() != user_id
+        || webauthn_challenge_row.expiration_time < Utc::now()
+    {
+        return Err(services::Error::NotFound);
+    }
+
+    Ok(webauthn_challenge_row.challenge)
+}
+
+/// Verifies the registration of a passkey and wraps the user key with its
+/// PRF output.
+pub async fn register(
+    conn: &mut SqliteConnection,
+    relying_party: &RelyingParty,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    registration: &PasskeyRegistration,
+) -> services::Result<UserPasskey> {
+    let challenge = take_challenge(&mut *conn, &registration.challenge_id, Some(user_id)).await?;
+    let credential = relying_party.verify_registration(
+        &challenge,
+        &registration.client_data_json,
+        &registration.attestation_object,
+    )?;
+
+    // Wrap the user key using the PRF output
+    let user_key = UserKey::from(user_key);
+    services::user_keys::store_using_password(
+        &mut *conn,
+        hash_config,
+        user_id,
+        &user_key,
+        &prf_password(&registration.prf_output),
+    )
+    .await?;
+
+    // Store the credential
+    let id = Uuid::new_v4();
+    db::user_passkeys::create(
+        &mut *conn,
+        &db::user_passkeys::UserPasskeyRow {
+            id,
+            user_id: *user_id,
+            user_key_id: *user_key.id(),
+            credential_id: credential.id,
+            public_key: credential.public_key,
+            sign_count: credential.sign_count.into(),
+            name: registration.name.clone(),
+            time_created: None,
+            last_used_time: None,
+        },
+    )
+    .await?;
+
+    get_by_id(&mut *conn, &id).await
+}
+
+/// Verifies a login with a passkey and unwraps the user key with its PRF
+/// output. Returns the id of the user the passkey belongs to.
+pub async fn authenticate(
+    conn: &mut SqliteConnection,
+    relying_party: &RelyingParty,
+    hash_config: &HashConfig,
+    assertion: &PasskeyAssertion,
+) -> services::Result<(Uuid, UserKey)> {
+    let challenge = take_challenge(&mut *conn, &assertion.challenge_id, None).await?;
+    let user_passkey_row =
+        db::user_passkeys::get_by_credential_id(&mut *conn, &assertion.credential_id).await?;
+
+    let sign_count = relying_party.verify_assertion(
+        &challenge,
+        &Credential {
+            id: user_passkey_row.credential_id,
+            public_key: user_passkey_row.public_key,
+            sign_count: user_passkey_row
+                .sign_count
+                .try_into()
+                .map_err(|e| anyhow::anyhow!("invalid sign count: {}", e))?,
+        },
+        &assertion.client_data_json,
+        &assertion.authenticator_data,
+        &assertion.signature,
+    )?;
+    db::user_passkeys::update_sign_count(
+        &mut *conn,
+        &user_passkey_row.id,
+        sign_count.into(),
+        &Utc::now(),
+    )
+    .await?;
+
+    // The PRF output can only unwrap the user key if it is correct
+    let user_key = services::user_keys::get_using_password(
+        &mut *conn,
+        hash_config,
+        &user_passkey_row.user_key_id,
+        &prf_password(&assertion.prf_output),
+    )
+    .await?;
+
+    Ok((user_passkey_row.user_id, user_key))
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> services::Result<UserPasskey>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(db::user_passkeys::get_by_id(executor, id).await?.into())
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<Vec<UserPasskey>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(db::user_passkeys::get_by_user_id(executor, user_id)
+        .await?
+        .into_iter()
+        .map(UserPasskey::from)
+        .collect())
+}
+
+/// Deletes a passkey, along with its wrapping of the user key.
+pub async fn delete(conn: &mut SqliteConnection, id: &Uuid) -> services::Result<()> {
+    let user_passkey_row = db::user_passkeys::get_by_id(&mut *conn, id).await?;
+
+    db::user_passkeys::delete_by_id(&mut *conn, id).await?;
+    services::user_keys::delete(&mut *conn, &user_passkey_row.user_key_id).await?;
+
+    Ok(())
+}
+
+/// Deletes the passkeys of a user. Only the authenticator can evaluate the
+/// PRF, so the user key cannot be re-wrapped for existing passkeys, e.g. when
+/// the user key is rotated.
+pub async fn delete_by_user_id(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+) -> services::Result<()> {
+    for user_passkey_row in db::user_passkeys::get_by_user_id(&mut *conn, user_id).await? {
+        delete(&mut *conn, &user_passkey_row.id).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+    use zeroize::Zeroizing;
+
+    use crate::{
+        db,
+        services::{
+            self,
+            hash_params::HashConfig,
+            user_keys::UserKey,
+            user_passkeys::{PasskeyAssertion, PasskeyRegistration},
+        },
+        webauthn::{self, RelyingParty, software_authenticator::SoftwareAuthenticator},
+    };
+
+    #[tokio::test]
+    async fn register_and_authenticate() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let relying_party = RelyingParty::new("localhost", "Notes", "http://localhost:5173");
+        let mut authenticator = SoftwareAuthenticator::new("localhost", "http://localhost:5173");
+        let user_key = UserKey::new();
+
+        // Register a passkey
+        let challenge = services::user_passkeys::create_challenge(&mut conn, Some(&user_id))
+            .await
+            .expect("failed to create registration challenge");
+        let registration = authenticator.register(challenge.challenge());
+        let user_passkey = services::user_passkeys::register(
+            &mut conn,
+            &relying_party,
+            &HashConfig::default(),
+            &user_id,
+            user_key.key(),
+            &PasskeyRegistration {
+                challenge_id: *challenge.id(),
+                client_data_json: registration.client_data_json,
+                attestation_object: registration.attestation_object,
+                prf_output: Zeroizing::new(authenticator.prf(&webauthn::prf_salt()).to_vec()),
+                name: "laptop".to_string(),
+            },
+        )
+        .await
+        .expect("failed to register passkey");
+        assert_eq!(user_passkey.name(), "laptop");
+
+        // Sign in with the passkey
+        let challenge = services::user_passkeys::create_challenge(&mut conn, None)
+            .await
+            .expect("failed to create login challenge");
+        let response = authenticator.assert(challenge.challenge());
+        let assertion = PasskeyAssertion {
+            challenge_id: *challenge.id(),
+            credential_id: authenticator.credential_id.clone(),
+            client_data_json: response.client_data_json,
+            authenticator_data: response.authenticator_data,
+            signature: response.signature,
+            prf_output: Zeroizing::new(authenticator.prf(&webauthn::prf_salt()).to_vec()),
+        };
+
+        let (authenticated_user_id, authenticated_user_key) =
+            services::user_passkeys::authenticate(
+                &mut conn,
+                &relying_party,
+                &HashConfig::default(),
+                &assertion,
+            )
+            .await
+            .expect("failed to authenticate with passkey");
+        assert_eq!(authenticated_user_id, user_id);
+        assert_eq!(authenticated_user_key.key(), user_key.key());
+        assert!(
+            services::user_passkeys::get_by_id(&mut *conn, user_passkey.id())
+                .await
+                .expect("failed to get passkey")
+                .last_used_time()
+                .is_some()
+        );
+
+        // The challenge cannot be answered twice
+        assert!(
+            services::user_passkeys::authenticate(
+                &mut conn,
+                &relying_party,
+                &HashConfig::default(),
+                &assertion
+            )
+            .await
+            .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+
+        // A wrong PRF output does not unwrap the user key
+        let challenge = services::user_passkeys::create_challenge(&mut conn, None)
+            .await
+            .expect("failed to create login challenge");
+        let response = authenticator.assert(challenge.challenge());
+        assert!(
+            services::user_passkeys::authenticate(
+                &mut conn,
+                &relying_party,
+                &HashConfig::default(),
+                &PasskeyAssertion {
+                    challenge_id: *challenge.id(),
+                    credential_id: authenticator.credential_id.clone(),
+                    client_data_json: response.client_data_json,
+                    authenticator_data: response.authenticator_data,
+                    signature: response.signature,
+                    prf_output: Zeroizing::new(authenticator.prf(b"other salt").to_vec()),
+                },
+            )
+            .await
+            .is_err_and(|e| matches!(e, services::Error::DecryptionFailed))
+        );
+
+        // Deleting the passkey drops its wrapping of the user key
+        services::user_passkeys::delete_by_user_id(&mut conn, &user_id)
+            .await
+            .expect("failed to delete passkeys");
+        assert!(
+            db::user_keys::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get user keys")
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn register_with_challenge_of_other_user() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let relying_party = RelyingParty::new("localhost", "Notes", "http://localhost:5173");
+        let authenticator = SoftwareAuthenticator::new("localhost", "http://localhost:5173");
+
+        // Login challenges are not bound to a user, and cannot be used to
+        // register a passkey
+        let challenge = services::user_passkeys::create_challenge(&mut conn, None)
+            .await
+            .expect("failed to create login challenge");
+        let registration = authenticator.register(challenge.challenge());
+        assert!(
+            services::user_passkeys::register(
+                &mut conn,
+                &relying_party,
+                &HashConfig::default(),
+                &user_id,
+                UserKey::new().key(),
+                &PasskeyRegistration {
+                    challenge_id: *challenge.id(),
+                    client_data_json: registration.client_data_json,
+                    attestation_object: registration.attestation_object,
+                    prf_output: Zeroizing::new(authenticator.prf(&webauthn::prf_salt()).to_vec()),
+                    name: "laptop".to_string(),
+                },
+            )
+            .await
+            .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+    }
+}
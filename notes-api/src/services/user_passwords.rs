@@ -1,10 +1,18 @@
-use aes_gcm::aead::OsRng;
-use argon2::{Argon2, PasswordHasher};
+use aes_gcm::{Aes256Gcm, Key, aead::OsRng};
+use argon2::PasswordHasher;
 use password_hash::SaltString;
-use sqlx::SqliteExecutor;
+use sqlx::{SqliteConnection, SqliteExecutor};
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
 
-use crate::{db, services};
+use crate::{
+    db,
+    services::{
+        self,
+        hash_params::{HashConfig, HashParams},
+        user_keys::UserKey,
+    },
+};
 
 #[derive(Debug, PartialEq)]
 pub struct UserPassword {
@@ -12,13 +20,20 @@ pub struct UserPassword {
     user_key_id: Uuid,
     hash: Vec<u8>,
     salt: SaltString,
+    hash_params: HashParams,
 }
 
 impl UserPassword {
-    pub fn new(user_key_id: &Uuid, password: &str) -> services::Result<Self> {
-        // Generate a salt and hash the password
+    pub fn new(
+        hash_config: &HashConfig,
+        user_key_id: &Uuid,
+        password: &str,
+    ) -> services::Result<Self> {
+        // Generate a salt and hash the password using the target parameters
         let salt = SaltString::generate(&mut OsRng);
-        let hash = Argon2::default()
+        let hash_params = hash_config.target().clone();
+        let hash = hash_config
+            .argon2(&hash_params)?
             .hash_password(password.as_bytes(), &salt)
             .map_err(|e| anyhow::anyhow!("failed to hash user password: {}", e))?
             .hash
@@ -31,12 +46,14 @@ impl UserPassword {
             user_key_id: *user_key_id,
             hash,
             salt,
+            hash_params,
         })
     }
 
-    pub fn verify(&self, password: &str) -> services::Result<bool> {
+    pub fn verify(&self, hash_config: &HashConfig, password: &str) -> services::Result<bool> {
         // Recreate the user password hash from `user_password` and user password salt
-        let hash = Argon2::default()
+        let hash = hash_config
+            .argon2(&self.hash_params)?
             .hash_password(password.as_bytes(), &self.salt)
             .map_err(|e| anyhow::anyhow!("failed to hash user password: {}", e))?
             .hash
@@ -44,8 +61,14 @@ impl UserPassword {
             .as_bytes()
             .to_vec();
 
-        // Check that the hashes match
-        Ok(self.hash == hash)
+        // Check that the hashes match, in constant time
+        Ok(self.hash.ct_eq(&hash).into())
+    }
+
+    /// Whether the password was hashed with other than the target parameters,
+    /// in which case it should be rehashed after the next successful login.
+    pub fn is_outdated(&self, hash_config: &HashConfig) -> bool {
+        hash_config.is_outdated(&self.hash_params)
     }
 
     pub fn id(&self) -> &Uuid {
@@ -81,6 +104,7 @@ where
             user_key_id: user_password.user_key_id,
             hash: user_password.hash.clone(),
             salt: salt_buf,
+            hash_params: user_password.hash_params.to_string(),
         },
     )
     .await?;
@@ -88,7 +112,33 @@ where
     Ok(())
 }
 
-pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> anyhow::Result<UserPassword>
+/// Takes as long as verifying the password of an existing user, for logins of
+/// users that do not exist or have no password. Passwords keep the parameters
+/// they were hashed with until their user signs in again, so the password is
+/// hashed with those of an existing password, picked by `username`.
+pub async fn verify_dummy<'e, E>(
+    executor: E,
+    hash_config: &HashConfig,
+    username: &str,
+    password: &str,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    let hash_params = match db::user_passwords::get_hash_params_at(
+        executor,
+        services::hash_params::dummy_index(username),
+    )
+    .await?
+    {
+        Some(hash_params) => HashParams::parse(&hash_params)?,
+        None => hash_config.target().clone(),
+    };
+
+    hash_config.hash_dummy(&hash_params, password)
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> services::Result<UserPassword>
 where
     E: SqliteExecutor<'e>,
 {
@@ -101,9 +151,98 @@ where
         hash: user_password_row.hash,
         salt: SaltString::encode_b64(&user_password_row.salt)
             .map_err(|e| anyhow::anyhow!("failed to encode salt string: {}", e))?,
+        hash_params: HashParams::parse(&user_password_row.hash_params)?,
     })
 }
 
+pub async fn delete<'e, E>(executor: E, user_password_id: &Uuid) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Delete the user password
+    db::user_passwords::delete_by_id(executor, user_password_id).await?;
+
+    Ok(())
+}
+
+/// Replaces the user's password. The user key is wrapped under the new
+/// password and its wrapping under the old password is dropped, so the user
+/// key and the note keys wrapped with it remain unchanged.
+pub async fn change(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    password: &str,
+) -> services::Result<()> {
+    let old_user_password = get_by_user_id(&mut *conn, user_id).await?;
+
+    // Wrap the user key using the new password
+    let user_key = UserKey::from(user_key);
+    services::user_keys::store_using_password(
+        &mut *conn,
+        hash_config,
+        user_id,
+        &user_key,
+        password,
+    )
+    .await?;
+
+    // Replace the user password
+    delete(&mut *conn, old_user_password.id()).await?;
+    store(
+        &mut *conn,
+        &UserPassword::new(hash_config, user_key.id(), password)?,
+        user_id,
+    )
+    .await?;
+
+    // Drop the user key wrapping of the old password
+    services::user_keys::delete(&mut *conn, old_user_password.user_key_id()).await?;
+
+    Ok(())
+}
+
+/// Sets a new password after the user signed in with a recovery code. The
+/// user key is wrapped under the new password. An OPAQUE record of the user
+/// is dropped along with its wrapping, as the user forgot that password too.
+pub async fn reset(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    password: &str,
+) -> services::Result<()> {
+    match services::user_opaque::delete_by_user_id(&mut *conn, user_id).await {
+        Ok(()) | Err(services::Error::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+
+    match get_by_user_id(&mut *conn, user_id).await {
+        Ok(_) => change(conn, hash_config, user_id, user_key, password).await,
+        Err(services::Error::NotFound) => {
+            let user_key = UserKey::from(user_key);
+            services::user_keys::store_using_password(
+                &mut *conn,
+                hash_config,
+                user_id,
+                &user_key,
+                password,
+            )
+            .await?;
+            store(
+                &mut *conn,
+                &UserPassword::new(hash_config, user_key.id(), password)?,
+                user_id,
+            )
+            .await?;
+
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use aes_gcm::{
@@ -115,7 +254,10 @@ mod tests {
     use utilities::db::init_db;
     use uuid::Uuid;
 
-    use crate::{db, services};
+    use crate::{
+        db,
+        services::{self, hash_params::HashConfig},
+    };
 
     #[tokio::test]
     async fn store() {
@@ -130,6 +272,7 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -165,6 +308,7 @@ mod tests {
                 encrypted_key: user_key_ciphertext,
                 nonce: user_key_nonce.to_vec(),
                 salt: user_key_password_salt_buf,
+                hash_params: "$argon2id$v=19$m=19456,t=2,p=1".to_string(),
             },
         )
         .await
@@ -172,9 +316,12 @@ mod tests {
 
         // Perform test
 
-        let user_password =
-            services::user_passwords::UserPassword::new(&user_key_id, &password_str)
-                .expect("failed to create user password");
+        let user_password = services::user_passwords::UserPassword::new(
+            &HashConfig::default(),
+            &user_key_id,
+            &password_str,
+        )
+        .expect("failed to create user password");
 
         services::user_passwords::store(&pool, &user_password, &user_id)
             .await
@@ -195,7 +342,8 @@ mod tests {
                 user_id,
                 user_key_id,
                 hash: user_password.hash,
-                salt: user_password_salt_buf
+                salt: user_password_salt_buf,
+                hash_params: user_password.hash_params.to_string(),
             }
         )
     }
@@ -213,6 +361,7 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -248,6 +397,7 @@ mod tests {
                 encrypted_key: user_key_ciphertext,
                 nonce: user_key_nonce.to_vec(),
                 salt: user_key_password_salt_buf,
+                hash_params: "$argon2id$v=19$m=19456,t=2,p=1".to_string(),
             },
         )
         .await
@@ -255,9 +405,12 @@ mod tests {
 
         // Perform test
 
-        let user_password =
-            services::user_passwords::UserPassword::new(&user_key_id, &password_str)
-                .expect("failed to create user password");
+        let user_password = services::user_passwords::UserPassword::new(
+            &HashConfig::default(),
+            &user_key_id,
+            &password_str,
+        )
+        .expect("failed to create user password");
 
         services::user_passwords::store(&pool, &user_password, &user_id)
             .await
@@ -275,12 +428,16 @@ mod tests {
     async fn verify_valid_password() {
         let user_key_id = Uuid::new_v4();
         let password_str = "1234";
-        let user_password = services::user_passwords::UserPassword::new(&user_key_id, password_str)
-            .expect("failed to create user password");
+        let user_password = services::user_passwords::UserPassword::new(
+            &HashConfig::default(),
+            &user_key_id,
+            password_str,
+        )
+        .expect("failed to create user password");
 
         assert!(
             user_password
-                .verify(password_str)
+                .verify(&HashConfig::default(), password_str)
                 .expect("failed to verify password")
         );
     }
@@ -288,13 +445,144 @@ mod tests {
     #[tokio::test]
     async fn verify_invalid_password() {
         let user_key_id = Uuid::new_v4();
-        let user_password = services::user_passwords::UserPassword::new(&user_key_id, "1234")
-            .expect("failed to create user password");
+        let user_password = services::user_passwords::UserPassword::new(
+            &HashConfig::default(),
+            &user_key_id,
+            "1234",
+        )
+        .expect("failed to create user password");
 
         assert!(
             !user_password
-                .verify("4321")
+                .verify(&HashConfig::default(), "4321")
+                .expect("failed to verify password")
+        );
+    }
+
+    #[tokio::test]
+    async fn change() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let user_key = services::user_keys::UserKey::new();
+
+        services::user_keys::store_using_password(
+            &pool,
+            &HashConfig::default(),
+            &user_id,
+            &user_key,
+            "1234",
+        )
+        .await
+        .expect("failed to store user key using password");
+
+        let user_password = services::user_passwords::UserPassword::new(
+            &HashConfig::default(),
+            user_key.id(),
+            "1234",
+        )
+        .expect("failed to create user password");
+
+        services::user_passwords::store(&pool, &user_password, &user_id)
+            .await
+            .expect("failed to store user password");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+
+        services::user_passwords::change(
+            &mut conn,
+            &HashConfig::default(),
+            &user_id,
+            user_key.key(),
+            "4321",
+        )
+        .await
+        .expect("failed to change user password");
+
+        let changed = services::user_passwords::get_by_user_id(&mut *conn, &user_id)
+            .await
+            .expect("failed to get user password");
+
+        assert!(
+            !changed
+                .verify(&HashConfig::default(), "1234")
+                .expect("failed to verify password")
+        );
+        assert!(
+            changed
+                .verify(&HashConfig::default(), "4321")
                 .expect("failed to verify password")
         );
+        assert_eq!(
+            services::user_keys::get_using_password(
+                &mut *conn,
+                &HashConfig::default(),
+                changed.user_key_id(),
+                "4321"
+            )
+            .await
+            .expect("failed to get user key using password")
+            .key(),
+            user_key.key()
+        );
+        assert_eq!(
+            db::user_keys::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get user keys")
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_outdated() {
+        let hash_config = HashConfig::default();
+        let hash_params = services::hash_params::HashParams::parse("$argon2id$v=19$m=8192,t=1,p=1")
+            .expect("failed to parse hash parameters");
+
+        let salt = SaltString::generate(&mut OsRng);
+        let user_password = services::user_passwords::UserPassword {
+            id: Uuid::new_v4(),
+            user_key_id: Uuid::new_v4(),
+            hash: hash_config
+                .argon2(&hash_params)
+                .expect("failed to create hasher")
+                .hash_password("1234".as_bytes(), &salt)
+                .expect("failed to hash password")
+                .hash
+                .expect("failed to get password hash")
+                .as_bytes()
+                .to_vec(),
+            salt,
+            hash_params,
+        };
+
+        assert!(
+            user_password
+                .verify(&hash_config, "1234")
+                .expect("failed to verify password")
+        );
+        assert!(user_password.is_outdated(&hash_config));
+        assert!(
+            !services::user_passwords::UserPassword::new(&hash_config, &Uuid::new_v4(), "1234")
+                .expect("failed to create user password")
+                .is_outdated(&hash_config)
+        );
     }
 }
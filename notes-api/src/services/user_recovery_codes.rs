@@ -0,0 +1,354 @@
+use std::fmt;
+
+use aes_gcm::{
+    Aes256Gcm, Key,
+    aead::{OsRng, rand_core::RngCore},
+};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    db,
+    services::{
+        self,
+        hash_params::{HashConfig, HashParams},
+        user_keys::UserKey,
+    },
+};
+
+/// Crockford's base32 alphabet, which leaves out characters that are easily
+/// confused with each other.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The number of random bytes in a recovery code (160 bits).
+const CODE_BYTES: usize = 20;
+
+/// A high-entropy code that unlocks an extra wrapping of the user key, so the
+/// user can regain access to their notes after forgetting their password. It
+/// is wiped from memory when dropped and redacted from its `Debug` output.
+#[derive(PartialEq)]
+pub struct RecoveryCode(String);
+
+impl RecoveryCode {
+    pub fn generate() -> Self {
+        Self(generate_code(CODE_BYTES))
+    }
+
+    /// Parses a recovery code as typed by a user. Separators and case are
+    /// ignored, and characters that look alike are mapped onto the alphabet.
+    /// Malformed codes fail with `InvalidCredentials`, just like wrong ones.
+    pub fn parse(input: &str) -> services::Result<Self> {
+        Ok(Self(
+            normalize_code(input, CODE_BYTES).ok_or(services::Error::InvalidCredentials)?,
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for RecoveryCode {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for RecoveryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecoveryCode([REDACTED])")
+    }
+}
+
+impl fmt::Display for RecoveryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format_code(&self.0, f)
+    }
+}
+
+/// Generates a code of `bytes` random bytes, encoding every 5 bits as a
+/// base32 character.
+pub(super) fn generate_code(bytes: usize) -> String {
+    let mut buf = Zeroizing::new(vec![0u8; bytes]);
+    OsRng.fill_bytes(buf.as_mut_slice());
+
+    let mut code = String::with_capacity(bytes * 8 / 5);
+    let (mut acc, mut bits) = (0u16, 0);
+    for &byte in buf.iter() {
+        acc = (acc << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            code.push(ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+        }
+    }
+
+    code
+}
+
+/// Normalizes a code of `bytes` random bytes as typed by a user. Separators
+/// and case are ignored, and characters that look alike are mapped onto the
+/// alphabet.
+pub(super) fn normalize_code(input: &str, bytes: usize) -> Option<String> {
+    let code = input
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        })
+        .collect::<String>();
+
+    if code.len() != bytes * 8 / 5 || !code.bytes().all(|c| ALPHABET.contains(&c)) {
+        return None;
+    }
+
+    Some(code)
+}
+
+/// Formats a code in groups of four characters.
+pub(super) fn format_code(code: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let groups: Vec<&str> = code
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+    write!(f, "{}", groups.join("-"))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserRecoveryCode {
+    id: Uuid,
+    user_key_id: Uuid,
+}
+
+impl UserRecoveryCode {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn user_key_id(&self) -> &Uuid {
+        &self.user_key_id
+    }
+}
+
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<UserRecoveryCode>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Get the user recovery code
+    let user_recovery_code_row = db::user_recovery_codes::get_by_user_id(executor, user_id).await?;
+
+    Ok(UserRecoveryCode {
+        id: user_recovery_code_row.id,
+        user_key_id: user_recovery_code_row.user_key_id,
+    })
+}
+
+/// Unwraps the user key using a recovery code.
+pub async fn get_user_key(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    recovery_code: &RecoveryCode,
+) -> services::Result<UserKey> {
+    let user_recovery_code = get_by_user_id(&mut *conn, user_id).await?;
+
+    services::user_keys::get_using_password(
+        &mut *conn,
+        hash_config,
+        user_recovery_code.user_key_id(),
+        recovery_code.as_str(),
+    )
+    .await
+}
+
+/// Takes as long as unwrapping the user key of an existing user with a
+/// recovery code, for logins of users that do not exist or have no recovery
+/// code. The code is hashed with the parameters of an existing recovery code,
+/// picked by `username`.
+pub async fn verify_dummy<'e, E>(
+    executor: E,
+    hash_config: &HashConfig,
+    username: &str,
+    recovery_code: &RecoveryCode,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    let hash_params = match db::user_recovery_codes::get_hash_params_at(
+        executor,
+        services::hash_params::dummy_index(username),
+    )
+    .await?
+    {
+        Some(hash_params) => HashParams::parse(&hash_params)?,
+        None => hash_config.target().clone(),
+    };
+
+    hash_config.hash_dummy(&hash_params, recovery_code.as_str())
+}
+
+/// Generates a new recovery code for the user and wraps the user key with it.
+/// A previous recovery code of the user, and its wrapping of the user key,
+/// is dropped.
+pub async fn replace(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+) -> services::Result<RecoveryCode> {
+    // Drop the previous recovery code
+    match get_by_user_id(&mut *conn, user_id).await {
+        Ok(user_recovery_code) => {
+            db::user_recovery_codes::delete_by_id(&mut *conn, user_recovery_code.id()).await?;
+            services::user_keys::delete(&mut *conn, user_recovery_code.user_key_id()).await?;
+        }
+        Err(services::Error::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+
+    // Wrap the user key using the new recovery code
+    let recovery_code = RecoveryCode::generate();
+    let user_key = UserKey::from(user_key);
+    services::user_keys::store_using_password(
+        &mut *conn,
+        hash_config,
+        user_id,
+        &user_key,
+        recovery_code.as_str(),
+    )
+    .await?;
+
+    // Store the user recovery code
+    db::user_recovery_codes::create(
+        &mut *conn,
+        &db::user_recovery_codes::UserRecoveryCodeRow {
+            id: Uuid::new_v4(),
+            user_id: *user_id,
+            user_key_id: *user_key.id(),
+        },
+    )
+    .await?;
+
+    Ok(recovery_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        db,
+        services::{self, hash_params::HashConfig, user_recovery_codes::RecoveryCode},
+    };
+
+    #[test]
+    fn generate_and_parse() {
+        let recovery_code = RecoveryCode::generate();
+        assert_eq!(recovery_code.as_str().len(), 32);
+
+        let formatted = recovery_code.to_string();
+        assert_eq!(formatted.len(), 39);
+        assert_eq!(
+            RecoveryCode::parse(&formatted.to_lowercase()).expect("failed to parse recovery code"),
+            recovery_code
+        );
+    }
+
+    #[test]
+    fn parse_look_alikes() {
+        assert_eq!(
+            RecoveryCode::parse("oooo-iiii-llll-0000-1111-2222-3333-4444")
+                .expect("failed to parse recovery code")
+                .as_str(),
+            "00001111111100001111222233334444"
+        );
+        assert!(matches!(
+            RecoveryCode::parse("0000-1111"),
+            Err(services::Error::InvalidCredentials)
+        ));
+        assert!(matches!(
+            RecoveryCode::parse("UUUU-1111-2222-3333-4444-5555-6666-7777"),
+            Err(services::Error::InvalidCredentials)
+        ));
+    }
+
+    #[tokio::test]
+    async fn replace_and_get_user_key() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let user_key = services::user_keys::UserKey::new();
+
+        let old_recovery_code = services::user_recovery_codes::replace(
+            &mut conn,
+            &HashConfig::default(),
+            &user_id,
+            user_key.key(),
+        )
+        .await
+        .expect("failed to create recovery code");
+        let recovery_code = services::user_recovery_codes::replace(
+            &mut conn,
+            &HashConfig::default(),
+            &user_id,
+            user_key.key(),
+        )
+        .await
+        .expect("failed to replace recovery code");
+
+        assert_eq!(
+            services::user_recovery_codes::get_user_key(
+                &mut conn,
+                &HashConfig::default(),
+                &user_id,
+                &recovery_code
+            )
+            .await
+            .expect("failed to get user key using recovery code")
+            .key(),
+            user_key.key()
+        );
+        assert!(
+            services::user_recovery_codes::get_user_key(
+                &mut conn,
+                &HashConfig::default(),
+                &user_id,
+                &old_recovery_code
+            )
+            .await
+            .is_err()
+        );
+        assert_eq!(
+            db::user_keys::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get user keys")
+                .len(),
+            1
+        );
+    }
+}
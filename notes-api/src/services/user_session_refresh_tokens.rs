@@ -0,0 +1,283 @@
+use std::fmt;
+
+use aes_gcm::{Aes256Gcm, Key};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::Utc;
+use crypto::{SecretKey, envelope};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{db, services};
+
+/// The prefix of refresh tokens, which tells them apart from other tokens.
+const TOKEN_PREFIX: &str = "rt_";
+
+/// A single-use token that the client trades for a new session token and a
+/// new refresh token. It names its row and carries the secret that unwraps
+/// the user key, which is not stored. It is wiped from memory when dropped
+/// and redacted from its `Debug` output.
+#[derive(Debug, PartialEq)]
+pub struct RefreshToken {
+    id: Uuid,
+    secret: SecretKey,
+}
+
+impl RefreshToken {
+    fn generate() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            secret: SecretKey::generate(),
+        }
+    }
+
+    /// Parses a refresh token, which is `None` if it is malformed.
+    pub fn parse(input: &str) -> Option<Self> {
+        let buf = Zeroizing::new(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(input.strip_prefix(TOKEN_PREFIX)?)
+                .ok()?,
+        );
+        if buf.len() != 48 {
+            return None;
+        }
+
+        Some(Self {
+            id: Uuid::from_slice(&buf[..16]).ok()?,
+            secret: SecretKey::from_slice(&buf[16..])?,
+        })
+    }
+}
+
+impl fmt::Display for RefreshToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = Zeroizing::new(Vec::with_capacity(48));
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(self.secret.key());
+
+        write!(
+            f,
+            "{}{}",
+            TOKEN_PREFIX,
+            Zeroizing::new(BASE64_URL_SAFE_NO_PAD.encode(&buf)).as_str()
+        )
+    }
+}
+
+/// The outcome of trading in a refresh token.
+#[derive(Debug)]
+pub enum Refresh {
+    /// The token was replaced by a new one of the same session
+    Rotated {
+        session_id: Uuid,
+        user_id: Uuid,
+        user_key: SecretKey,
+        refresh_token: RefreshToken,
+    },
+
+    /// The token was used before, so it may have been stolen. The session
+    /// and all its refresh tokens have been revoked.
+    Reused,
+}
+
+/// Creates a refresh token of a session and wraps the user key with its
+/// secret. The wrapping is bound to the session.
+pub async fn create<'e, E>(
+    executor: E,
+    session_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+) -> services::Result<RefreshToken>
+where
+    E: SqliteExecutor<'e>,
+{
+    let refresh_token = RefreshToken::generate();
+
+    db::user_session_refresh_tokens::create(
+        executor,
+        &db::user_session_refresh_tokens::UserSessionRefreshTokenRow {
+            id: refresh_token.id,
+            session_id: *session_id,
+            encrypted_user_key: Some(envelope::seal(
+                refresh_token.secret.key(),
+                user_key,
+                session_id.as_bytes(),
+            )?),
+            time_created: None,
+            time_used: None,
+        },
+    )
+    .await?;
+
+    Ok(refresh_token)
+}
+
+/// Trades in a refresh token. The token is used up and replaced by a new one
+/// that wraps the same user key, and the idle timeout of the session moves
+/// forward. Fails with `NotFound` if the token or its session is unknown or
+/// the session expired, and with `DecryptionFailed` if
+/// the secret is incorrect.
+///
+/// A token that was used before revokes its session; commit the transaction
+/// before rejecting the request, so the revocation sticks.
+pub async fn refresh(
+    conn: &mut SqliteConnection,
+    refresh_token: &RefreshToken,
+) -> services::Result<Refresh> {
+    let refresh_token_row =
+        db::user_session_refresh_tokens::get_by_id(&mut *conn, &refresh_token.id).await?;
+    let Some(encrypted_user_key) = refresh_token_row.encrypted_user_key else {
+        services::user_sessions::delete(&mut *conn, &refresh_token_row.session_id).await?;
+        return Ok(Refresh::Reused);
+    };
+
+    let user_session_row =
+        db::user_sessions::get_by_id(&mut *conn, &refresh_token_row.session_id).await?;
+    let user_id = user_session_row.user_id;
+    let user_session = services::user_sessions::UserSession::from(user_session_row);
+    if !user_session.is_valid() {
+        return Err(services::Error::NotFound);
+    }
+
+    // The secret can only unwrap the user key if it is correct, so a token
+    // that was guessed does not revoke the session
+    let user_key_buf = Zeroizing::new(envelope::open(
+        refresh_token.secret.key(),
+        &encrypted_user_key,
+        user_session.id().as_bytes(),
+    )?);
+    let user_key = SecretKey::from_slice(&user_key_buf).ok_or(services::Error::DecryptionFailed)?;
+
+    // Another request may have used the token in the meantime
+    match db::user_session_refresh_tokens::update_used(&mut *conn, &refresh_token.id, &Utc::now())
+        .await
+    {
+        Ok(()) => {}
+        Err(db::Error::NotFound) => {
+            services::user_sessions::delete(&mut *conn, user_session.id()).await?;
+            return Ok(Refresh::Reused);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    // Refreshing counts as using the session
+    services::user_sessions::touch(&mut *conn, &user_session).await?;
+
+    let new_refresh_token = create(&mut *conn, user_session.id(), user_key.key()).await?;
+
+    Ok(Refresh::Rotated {
+        session_id: *user_session.id(),
+        user_id,
+        user_key,
+        refresh_token: new_refresh_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use utilities::db::init_db;
+    use uuid::Uuid;
+
+    use crate::{
+        db,
+        services::{
+            self,
+            user_keys::UserKey,
+            user_session_refresh_tokens::{Refresh, RefreshToken},
+            user_sessions::UserSession,
+        },
+    };
+
+    #[test]
+    fn parse_refresh_token() {
+        let refresh_token = RefreshToken::generate();
+
+        assert_eq!(
+            RefreshToken::parse(&refresh_token.to_string()),
+            Some(refresh_token)
+        );
+        assert_eq!(RefreshToken::parse("rt_invalid"), None);
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_and_detects_reuse() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let user_session = UserSession::new();
+        services::user_sessions::store(&pool, &user_session, &user_id)
+            .await
+            .expect("failed to store user session");
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let user_key = UserKey::new();
+
+        let refresh_token = services::user_session_refresh_tokens::create(
+            &mut *conn,
+            user_session.id(),
+            user_key.key(),
+        )
+        .await
+        .expect("failed to create refresh token");
+
+        // Perform test
+
+        // A guessed secret is rejected, but does not revoke the session
+        let guessed = RefreshToken {
+            id: refresh_token.id,
+            ..RefreshToken::generate()
+        };
+        assert!(
+            services::user_session_refresh_tokens::refresh(&mut conn, &guessed)
+                .await
+                .is_err_and(|e| matches!(e, services::Error::DecryptionFailed))
+        );
+
+        let Refresh::Rotated {
+            session_id,
+            user_id: refreshed_user_id,
+            user_key: refreshed_user_key,
+            refresh_token: new_refresh_token,
+        } = services::user_session_refresh_tokens::refresh(&mut conn, &refresh_token)
+            .await
+            .expect("failed to refresh")
+        else {
+            panic!("refresh token was not rotated");
+        };
+        assert_eq!(&session_id, user_session.id());
+        assert_eq!(refreshed_user_id, user_id);
+        assert_eq!(refreshed_user_key.key(), user_key.key());
+
+        // Using the old token again revokes the session, and with it the new
+        // token
+        assert!(matches!(
+            services::user_session_refresh_tokens::refresh(&mut conn, &refresh_token)
+                .await
+                .expect("failed to refresh"),
+            Refresh::Reused
+        ));
+        assert!(
+            services::user_sessions::get(&mut *conn, user_session.id())
+                .await
+                .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+        assert!(
+            services::user_session_refresh_tokens::refresh(&mut conn, &new_refresh_token)
+                .await
+                .is_err_and(|e| matches!(e, services::Error::NotFound))
+        );
+    }
+}
@@ -4,34 +4,189 @@ use uuid::Uuid;
 
 use crate::{db, services};
 
+/// Where a session was signed in from. The label names the device to the
+/// user; it is derived from the user agent unless the client picks one.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SessionDevice {
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    label: Option<String>,
+}
+
+impl SessionDevice {
+    pub fn new(
+        user_agent: Option<String>,
+        ip_address: Option<String>,
+        label: Option<String>,
+    ) -> Self {
+        let label = label.or_else(|| user_agent.as_deref().and_then(label_from_user_agent));
+
+        Self {
+            user_agent,
+            ip_address,
+            label,
+        }
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    pub fn ip_address(&self) -> Option<&str> {
+        self.ip_address.as_deref()
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// How long a session stays valid without being used.
+pub const IDLE_TIMEOUT: Duration = Duration::days(7);
+
+/// How long a session stays valid at most, however often it is used.
+pub const ABSOLUTE_TIMEOUT: Duration = Duration::days(31);
+
+/// Activity is recorded at most once per interval, so that not every request
+/// causes a write.
+const ACTIVITY_INTERVAL: Duration = Duration::minutes(5);
+
 #[derive(Debug, PartialEq)]
 pub struct UserSession {
     id: Uuid,
+
+    /// The absolute timeout, which never moves
     expiration_time: Option<DateTime<Utc>>,
+
+    /// The idle timeout, which moves forward while the session is used
+    idle_expiration_time: Option<DateTime<Utc>>,
+    time_created: Option<DateTime<Utc>>,
+    last_seen_time: Option<DateTime<Utc>>,
+    device: SessionDevice,
 }
 
 impl UserSession {
-    pub fn new(validity: Duration) -> Self {
+    /// A session that expires when it has not been used for `IDLE_TIMEOUT`,
+    /// or at the latest after `ABSOLUTE_TIMEOUT`.
+    pub fn new() -> Self {
+        let now = Utc::now();
+
         Self {
             id: Uuid::new_v4(),
-            expiration_time: Some(Utc::now() + validity),
+            expiration_time: Some(now + ABSOLUTE_TIMEOUT),
+            idle_expiration_time: Some(now + IDLE_TIMEOUT),
+            time_created: Some(now),
+            last_seen_time: None,
+            device: SessionDevice::default(),
         }
     }
 
+    /// A session that stays valid until the user signs out.
     pub fn new_persistent() -> Self {
         Self {
             id: Uuid::new_v4(),
             expiration_time: None,
+            idle_expiration_time: None,
+            time_created: Some(Utc::now()),
+            last_seen_time: None,
+            device: SessionDevice::default(),
         }
     }
 
+    pub fn with_device(mut self, device: SessionDevice) -> Self {
+        self.device = device;
+        self
+    }
+
     pub fn id(&self) -> &Uuid {
         &self.id
     }
 
+    pub fn expiration_time(&self) -> Option<&DateTime<Utc>> {
+        self.expiration_time.as_ref()
+    }
+
+    pub fn idle_expiration_time(&self) -> Option<&DateTime<Utc>> {
+        self.idle_expiration_time.as_ref()
+    }
+
+    pub fn time_created(&self) -> Option<&DateTime<Utc>> {
+        self.time_created.as_ref()
+    }
+
+    pub fn last_seen_time(&self) -> Option<&DateTime<Utc>> {
+        self.last_seen_time.as_ref()
+    }
+
+    pub fn device(&self) -> &SessionDevice {
+        &self.device
+    }
+
+    /// Whether neither the idle nor the absolute timeout has passed.
     pub fn is_valid(&self) -> bool {
-        self.expiration_time
-            .is_none_or(|expiration_time| expiration_time > Utc::now())
+        let now = Utc::now();
+
+        [self.expiration_time, self.idle_expiration_time]
+            .into_iter()
+            .flatten()
+            .all(|expiration_time| expiration_time > now)
+    }
+}
+
+impl Default for UserSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<db::user_sessions::UserSessionRow> for UserSession {
+    fn from(row: db::user_sessions::UserSessionRow) -> Self {
+        Self {
+            id: row.id,
+            expiration_time: row.expiration_time,
+            idle_expiration_time: row.idle_expiration_time,
+            time_created: row.time_created,
+            last_seen_time: row.last_seen_time,
+            device: SessionDevice {
+                user_agent: row.user_agent,
+                ip_address: row.ip_address,
+                label: row.device_label,
+            },
+        }
+    }
+}
+
+/// Names the browser and operating system of a user agent, such as
+/// "Firefox on Linux".
+fn label_from_user_agent(user_agent: &str) -> Option<String> {
+    // Order matters, as most browsers also claim to be the ones before them
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+
+    match (browser, os) {
+        (Some(browser), Some(os)) => Some(format!("{} on {}", browser, os)),
+        (Some(name), None) | (None, Some(name)) => Some(name.to_string()),
+        (None, None) => None,
     }
 }
 
@@ -50,6 +205,12 @@ where
             id: user_session.id,
             user_id: *user_id,
             expiration_time: user_session.expiration_time,
+            idle_expiration_time: user_session.idle_expiration_time,
+            time_created: user_session.time_created,
+            last_seen_time: user_session.last_seen_time,
+            user_agent: user_session.device.user_agent.clone(),
+            ip_address: user_session.device.ip_address.clone(),
+            device_label: user_session.device.label.clone(),
         },
     )
     .await?;
@@ -62,12 +223,53 @@ where
     E: SqliteExecutor<'e>,
 {
     // Get the user session
-    Ok(db::user_sessions::get_by_id(executor, id)
-        .await
-        .map(|row| UserSession {
-            id: row.id,
-            expiration_time: row.expiration_time,
-        })?)
+    Ok(db::user_sessions::get_by_id(executor, id).await?.into())
+}
+
+/// Gets the sessions of a user that have not expired.
+pub async fn get_by_user_id<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+) -> services::Result<Vec<UserSession>>
+where
+    E: SqliteExecutor<'e>,
+{
+    Ok(db::user_sessions::get_by_user_id(executor, user_id)
+        .await?
+        .into_iter()
+        .map(UserSession::from)
+        .filter(UserSession::is_valid)
+        .collect())
+}
+
+/// Records that the session was used just now, which moves its idle timeout
+/// forward. Nothing is written if the session was seen within the last
+/// `ACTIVITY_INTERVAL`, so the idle timeout may lag behind by as much.
+pub async fn touch<'e, E>(executor: E, user_session: &UserSession) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    let now = Utc::now();
+    if user_session
+        .last_seen_time
+        .is_some_and(|last_seen_time| now - last_seen_time < ACTIVITY_INTERVAL)
+    {
+        return Ok(());
+    }
+
+    // Sessions without an idle timeout keep going without one
+    let idle_expiration_time = user_session
+        .idle_expiration_time
+        .map(|_| now + IDLE_TIMEOUT);
+    db::user_sessions::update_activity(
+        executor,
+        &user_session.id,
+        &now,
+        idle_expiration_time.as_ref(),
+    )
+    .await?;
+
+    Ok(())
 }
 
 pub async fn delete<'e, E>(executor: E, session_id: &Uuid) -> services::Result<()>
@@ -80,9 +282,48 @@ where
     Ok(())
 }
 
+/// Deletes a session of the user, which fails with `NotFound` if the session
+/// belongs to another user.
+pub async fn delete_of_user<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    session_id: &Uuid,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    db::user_sessions::delete_by_user_id_and_id(executor, user_id, session_id).await?;
+
+    Ok(())
+}
+
+/// Signs the user out everywhere but the session with `session_id`.
+pub async fn delete_others<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    session_id: &Uuid,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    db::user_sessions::delete_by_user_id_except(executor, user_id, session_id).await?;
+
+    Ok(())
+}
+
+pub async fn delete_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Delete all sessions of the user from database
+    db::user_sessions::delete_by_user_id(executor, user_id).await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use chrono::Duration;
+    use chrono::{Duration, Utc};
     use utilities::db::init_db;
     use uuid::Uuid;
 
@@ -101,6 +342,7 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -108,7 +350,7 @@ mod tests {
 
         // Perform test
 
-        let user_session = services::user_sessions::UserSession::new(Duration::days(31));
+        let user_session = services::user_sessions::UserSession::new();
 
         services::user_sessions::store(&pool, &user_session, &user_id)
             .await
@@ -121,7 +363,13 @@ mod tests {
             db::user_sessions::UserSessionRow {
                 id: user_session.id,
                 user_id,
-                expiration_time: user_session.expiration_time
+                expiration_time: user_session.expiration_time,
+                idle_expiration_time: user_session.idle_expiration_time,
+                time_created: user_session.time_created,
+                last_seen_time: None,
+                user_agent: None,
+                ip_address: None,
+                device_label: None,
             }
         )
     }
@@ -139,6 +387,7 @@ mod tests {
             &db::users::UserRow {
                 id: user_id,
                 username: "test".to_string(),
+                username_key: Some("test".to_string()),
             },
         )
         .await
@@ -146,7 +395,7 @@ mod tests {
 
         // Perform test
 
-        let user_session = services::user_sessions::UserSession::new(Duration::days(31));
+        let user_session = services::user_sessions::UserSession::new();
 
         services::user_sessions::store(&pool, &user_session, &user_id)
             .await
@@ -162,7 +411,7 @@ mod tests {
 
     #[tokio::test]
     async fn is_valid_non_persistent() {
-        assert!(services::user_sessions::UserSession::new(Duration::days(31)).is_valid());
+        assert!(services::user_sessions::UserSession::new().is_valid());
     }
 
     #[tokio::test]
@@ -172,6 +421,110 @@ mod tests {
 
     #[tokio::test]
     async fn is_invalid() {
-        assert!(!services::user_sessions::UserSession::new(Duration::days(-1)).is_valid());
+        assert!(
+            !services::user_sessions::UserSession {
+                expiration_time: Some(Utc::now() - Duration::days(1)),
+                ..services::user_sessions::UserSession::new()
+            }
+            .is_valid()
+        );
+    }
+
+    #[tokio::test]
+    async fn is_invalid_idle() {
+        assert!(
+            !services::user_sessions::UserSession {
+                idle_expiration_time: Some(Utc::now() - Duration::days(1)),
+                ..services::user_sessions::UserSession::new()
+            }
+            .is_valid()
+        );
+    }
+
+    #[tokio::test]
+    async fn touch() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            &pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        let user_session = services::user_sessions::UserSession {
+            idle_expiration_time: Some(Utc::now() + Duration::minutes(1)),
+            ..services::user_sessions::UserSession::new()
+        };
+        services::user_sessions::store(&pool, &user_session, &user_id)
+            .await
+            .expect("failed to store user session");
+
+        // Perform test
+
+        // The first use moves the idle timeout forward
+        services::user_sessions::touch(&pool, &user_session)
+            .await
+            .expect("failed to touch user session");
+        let touched = services::user_sessions::get(&pool, &user_session.id)
+            .await
+            .expect("failed to get user session");
+        assert!(touched.last_seen_time.is_some());
+        assert!(touched.idle_expiration_time > user_session.idle_expiration_time);
+
+        // Uses right after that are not written
+        let retouched = services::user_sessions::UserSession {
+            last_seen_time: touched
+                .last_seen_time
+                .map(|time| time - Duration::minutes(1)),
+            ..services::user_sessions::get(&pool, &user_session.id)
+                .await
+                .expect("failed to get user session")
+        };
+        services::user_sessions::touch(&pool, &retouched)
+            .await
+            .expect("failed to touch user session");
+        assert_eq!(
+            services::user_sessions::get(&pool, &user_session.id)
+                .await
+                .expect("failed to get user session"),
+            touched
+        );
+    }
+
+    #[test]
+    fn device_label_from_user_agent() {
+        let device = services::user_sessions::SessionDevice::new(
+            Some(
+                "Mozilla/5.0 (X11; Linux x86_64; rv:144.0) Gecko/20100101 Firefox/144.0"
+                    .to_string(),
+            ),
+            None,
+            None,
+        );
+        assert_eq!(device.label(), Some("Firefox on Linux"));
+
+        let device = services::user_sessions::SessionDevice::new(
+            Some("Mozilla/5.0 (iPhone; CPU iPhone OS 18_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Mobile/15E148 Safari/604.1".to_string()),
+            None,
+            None,
+        );
+        assert_eq!(device.label(), Some("Safari on iOS"));
+
+        // A label picked by the client is kept
+        let device = services::user_sessions::SessionDevice::new(
+            Some("curl/8.0".to_string()),
+            None,
+            Some("backup script".to_string()),
+        );
+        assert_eq!(device.label(), Some("backup script"));
     }
 }
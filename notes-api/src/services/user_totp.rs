@@ -0,0 +1,546 @@
+use std::fmt;
+
+use aes_gcm::{
+    Aes256Gcm, Key,
+    aead::{OsRng, rand_core::RngCore},
+};
+use chrono::{DateTime, Utc};
+use crypto::envelope;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{SqliteConnection, SqliteExecutor};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    db,
+    services::{self, user_recovery_codes},
+};
+
+/// The number of random bytes in a secret (160 bits), as recommended by
+/// RFC 4226.
+const SECRET_BYTES: usize = 20;
+
+/// The number of digits in a code.
+const DIGITS: u32 = 6;
+
+/// The number of seconds a code is valid for.
+const PERIOD: i64 = 30;
+
+/// The number of periods a code may be early or late, to allow for clock
+/// drift between the server and the authenticator.
+const SKEW: i64 = 1;
+
+/// The number of random bytes in a backup code (80 bits).
+const BACKUP_CODE_BYTES: usize = 10;
+
+/// The number of backup codes a user gets.
+const BACKUP_CODE_COUNT: usize = 10;
+
+/// The issuer shown by authenticator apps.
+const ISSUER: &str = "Notes";
+
+/// An RFC 6238 TOTP secret, using HMAC-SHA1, six digits and a 30 second
+/// period, which is what authenticator apps support. It is wiped from memory
+/// when dropped and redacted from its `Debug` output.
+pub struct TotpSecret(Zeroizing<Vec<u8>>);
+
+impl TotpSecret {
+    pub fn generate() -> Self {
+        let mut secret = Zeroizing::new(vec![0u8; SECRET_BYTES]);
+        OsRng.fill_bytes(secret.as_mut_slice());
+        Self(secret)
+    }
+
+    /// The code of a time step, computed as described by RFC 4226.
+    fn code(&self, step: i64) -> u32 {
+        let mut mac =
+            <Hmac<Sha1> as Mac>::new_from_slice(&self.0).expect("hmac accepts keys of any length");
+        mac.update(&step.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        // Dynamic truncation
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let value = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+
+        value % 10u32.pow(DIGITS)
+    }
+
+    /// Returns the time step `code` belongs to, if it is valid at `time`.
+    pub fn verify(&self, code: &str, time: DateTime<Utc>) -> Option<i64> {
+        let code = code.trim();
+        if code.len() != DIGITS as usize || !code.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let code: u32 = code.parse().ok()?;
+
+        // Every step is compared in constant time, so the timing does not
+        // reveal which digits or which step matched
+        let step = time.timestamp() / PERIOD;
+        (step - SKEW..=step + SKEW).fold(None, |matched, step| {
+            let is_match: bool = self
+                .code(step)
+                .to_be_bytes()
+                .ct_eq(&code.to_be_bytes())
+                .into();
+            matched.or(is_match.then_some(step))
+        })
+    }
+
+    /// The secret encoded in base32 as described by RFC 4648, without
+    /// padding, for users that type it into their authenticator.
+    pub fn to_base32(&self) -> String {
+        const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+        let mut encoded = String::with_capacity(self.0.len().div_ceil(5) * 8);
+        let (mut acc, mut bits) = (0u16, 0);
+        for &byte in self.0.iter() {
+            acc = (acc << 8) | byte as u16;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(ALPHABET[((acc << (5 - bits)) & 0x1f) as usize] as char);
+        }
+
+        encoded
+    }
+
+    /// The `otpauth://` URI that authenticator apps read from a QR code.
+    pub fn to_uri(&self, username: &str) -> String {
+        let label = format!("{}:{}", ISSUER, username)
+            .bytes()
+            .map(|c| match c {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => {
+                    (c as char).to_string()
+                }
+                _ => format!("%{:02X}", c),
+            })
+            .collect::<String>();
+
+        format!(
+            "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            label,
+            self.to_base32(),
+            ISSUER,
+            DIGITS,
+            PERIOD
+        )
+    }
+}
+
+impl fmt::Debug for TotpSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TotpSecret([REDACTED])")
+    }
+}
+
+/// A single-use code that passes the second step of a login in place of a
+/// TOTP code, for users that lost their authenticator. Only a hash of the
+/// code is stored, which suffices as the code has 80 random bits.
+#[derive(PartialEq)]
+pub struct BackupCode(String);
+
+impl BackupCode {
+    pub fn generate() -> Self {
+        Self(user_recovery_codes::generate_code(BACKUP_CODE_BYTES))
+    }
+
+    /// Parses a backup code as typed by a user, like a recovery code.
+    pub fn parse(input: &str) -> services::Result<Self> {
+        Ok(Self(
+            user_recovery_codes::normalize_code(input, BACKUP_CODE_BYTES)
+                .ok_or(services::Error::InvalidCredentials)?,
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        Sha256::digest(self.0.as_bytes()).to_vec()
+    }
+}
+
+impl Drop for BackupCode {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for BackupCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BackupCode([REDACTED])")
+    }
+}
+
+impl fmt::Display for BackupCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        user_recovery_codes::format_code(&self.0, f)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserTotp {
+    id: Uuid,
+    encrypted_secret: Vec<u8>,
+    confirmed: bool,
+}
+
+impl UserTotp {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    /// Whether the user confirmed the secret, after which it is required at
+    /// login.
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// The secret is encrypted with the user key, with the id of the user
+    /// TOTP as associated data.
+    pub fn decrypt(&self, user_key: &Key<Aes256Gcm>) -> services::Result<TotpSecret> {
+        Ok(TotpSecret(Zeroizing::new(envelope::open(
+            user_key,
+            &self.encrypted_secret,
+            self.id.as_bytes(),
+        )?)))
+    }
+}
+
+pub async fn get_by_user_id<'e, E>(executor: E, user_id: &Uuid) -> services::Result<UserTotp>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Get the user totp
+    let user_totp_row = db::user_totp::get_by_user_id(executor, user_id).await?;
+
+    Ok(UserTotp {
+        id: user_totp_row.id,
+        encrypted_secret: user_totp_row.encrypted_secret,
+        confirmed: user_totp_row.confirmed,
+    })
+}
+
+/// Whether the user has to pass a TOTP code at login.
+pub async fn is_enabled<'e, E>(executor: E, user_id: &Uuid) -> services::Result<bool>
+where
+    E: SqliteExecutor<'e>,
+{
+    match get_by_user_id(executor, user_id).await {
+        Ok(user_totp) => Ok(user_totp.is_confirmed()),
+        Err(services::Error::NotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Generates a new secret for the user, which is not required at login until
+/// it is confirmed with a code. A previous secret that was not confirmed is
+/// dropped.
+pub async fn enroll(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+) -> services::Result<TotpSecret> {
+    // Drop the previous secret
+    match db::user_totp::delete_by_user_id(&mut *conn, user_id).await {
+        Ok(_) | Err(db::Error::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // Store the new secret, encrypted with the user key
+    let id = Uuid::new_v4();
+    let secret = TotpSecret::generate();
+    db::user_totp::create(
+        &mut *conn,
+        &db::user_totp::UserTotpRow {
+            id,
+            user_id: *user_id,
+            encrypted_secret: envelope::seal(user_key, &secret.0, id.as_bytes())?,
+            confirmed: false,
+            last_used_step: None,
+        },
+    )
+    .await?;
+
+    Ok(secret)
+}
+
+/// Checks a code against the user's secret. An accepted code confirms the
+/// secret and cannot be used again.
+pub async fn verify(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    code: &str,
+) -> services::Result<bool> {
+    let user_totp = get_by_user_id(&mut *conn, user_id).await?;
+    let Some(step) = user_totp.decrypt(user_key)?.verify(code, Utc::now()) else {
+        return Ok(false);
+    };
+
+    match db::user_totp::update_last_used_step(&mut *conn, user_totp.id(), step).await {
+        Ok(_) => Ok(true),
+        Err(db::Error::NotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Re-encrypts the user's secret, if the user has one, when the user key is
+/// rotated.
+pub async fn reencrypt(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+    user_key: &Key<Aes256Gcm>,
+    new_user_key: &Key<Aes256Gcm>,
+) -> services::Result<()> {
+    let user_totp = match get_by_user_id(&mut *conn, user_id).await {
+        Ok(user_totp) => user_totp,
+        Err(services::Error::NotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let secret = user_totp.decrypt(user_key)?;
+    db::user_totp::update_encrypted_secret(
+        &mut *conn,
+        user_totp.id(),
+        &envelope::seal(new_user_key, &secret.0, user_totp.id().as_bytes())?,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Drops the user's secret and backup codes.
+pub async fn delete(conn: &mut SqliteConnection, user_id: &Uuid) -> services::Result<()> {
+    db::user_totp::delete_by_user_id(&mut *conn, user_id).await?;
+    db::user_totp_backup_codes::delete_by_user_id(&mut *conn, user_id).await?;
+
+    Ok(())
+}
+
+/// Generates new backup codes for the user. Previous backup codes of the user
+/// are dropped.
+pub async fn replace_backup_codes(
+    conn: &mut SqliteConnection,
+    user_id: &Uuid,
+) -> services::Result<Vec<BackupCode>> {
+    db::user_totp_backup_codes::delete_by_user_id(&mut *conn, user_id).await?;
+
+    let mut backup_codes = Vec::with_capacity(BACKUP_CODE_COUNT);
+    for _ in 0..BACKUP_CODE_COUNT {
+        let backup_code = BackupCode::generate();
+        db::user_totp_backup_codes::create(
+            &mut *conn,
+            &db::user_totp_backup_codes::UserTotpBackupCodeRow {
+                id: Uuid::new_v4(),
+                user_id: *user_id,
+                hash: backup_code.hash(),
+            },
+        )
+        .await?;
+        backup_codes.push(backup_code);
+    }
+
+    Ok(backup_codes)
+}
+
+/// Checks a backup code of the user, which is used up if it is accepted.
+pub async fn use_backup_code<'e, E>(
+    executor: E,
+    user_id: &Uuid,
+    backup_code: &BackupCode,
+) -> services::Result<bool>
+where
+    E: SqliteExecutor<'e>,
+{
+    match db::user_totp_backup_codes::delete_by_hash(executor, user_id, &backup_code.hash()).await {
+        Ok(_) => Ok(true),
+        Err(db::Error::NotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use utilities::db::init_db;
+    use uuid::Uuid;
+    use zeroize::Zeroizing;
+
+    use crate::{
+        db,
+        services::{
+            self,
+            user_totp::{BackupCode, TotpSecret},
+        },
+    };
+
+    async fn populate(pool: &sqlx::SqlitePool) -> Uuid {
+        let user_id = Uuid::new_v4();
+
+        db::users::create(
+            pool,
+            &db::users::UserRow {
+                id: user_id,
+                username: "test".to_string(),
+                username_key: Some("test".to_string()),
+            },
+        )
+        .await
+        .expect("failed to create user");
+
+        user_id
+    }
+
+    #[test]
+    fn rfc_6238_test_vectors() {
+        let secret = TotpSecret(Zeroizing::new(b"12345678901234567890".to_vec()));
+
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            let time = DateTime::<Utc>::from_timestamp(time, 0).expect("invalid timestamp");
+            assert_eq!(secret.verify(code, time), Some(time.timestamp() / 30));
+        }
+    }
+
+    #[test]
+    fn verify_with_skew() {
+        let secret = TotpSecret::generate();
+        let time = DateTime::<Utc>::from_timestamp(1_800_000_000, 0).expect("invalid timestamp");
+        let step = time.timestamp() / 30;
+
+        for offset in [-1, 0, 1] {
+            let code = format!("{:06}", secret.code(step + offset));
+            assert_eq!(secret.verify(&code, time), Some(step + offset));
+        }
+
+        let code = format!("{:06}", secret.code(step + 2));
+        assert_eq!(secret.verify(&code, time), None);
+        assert_eq!(secret.verify("12345", time), None);
+        assert_eq!(secret.verify("abcdef", time), None);
+    }
+
+    #[test]
+    fn to_uri() {
+        let secret = TotpSecret(Zeroizing::new(b"12345678901234567890".to_vec()));
+
+        assert_eq!(secret.to_base32(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(
+            secret.to_uri("jane doe"),
+            "otpauth://totp/Notes:jane%20doe?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+             &issuer=Notes&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[tokio::test]
+    async fn enroll_and_verify() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let user_key = services::user_keys::UserKey::new();
+
+        let secret = services::user_totp::enroll(&mut conn, &user_id, user_key.key())
+            .await
+            .expect("failed to enroll totp");
+        assert!(
+            !services::user_totp::is_enabled(&mut *conn, &user_id)
+                .await
+                .expect("failed to check totp")
+        );
+
+        // A valid code confirms the secret, but cannot be used twice
+        let code = format!("{:06}", secret.code(Utc::now().timestamp() / 30));
+        assert!(
+            services::user_totp::verify(&mut conn, &user_id, user_key.key(), &code)
+                .await
+                .expect("failed to verify code")
+        );
+        assert!(
+            services::user_totp::is_enabled(&mut *conn, &user_id)
+                .await
+                .expect("failed to check totp")
+        );
+        assert!(
+            !services::user_totp::verify(&mut conn, &user_id, user_key.key(), &code)
+                .await
+                .expect("failed to verify code")
+        );
+
+        // The secret follows the user key when it is rotated
+        let new_user_key = services::user_keys::UserKey::new();
+        services::user_totp::reencrypt(&mut conn, &user_id, user_key.key(), new_user_key.key())
+            .await
+            .expect("failed to re-encrypt totp secret");
+        assert_eq!(
+            services::user_totp::get_by_user_id(&mut *conn, &user_id)
+                .await
+                .expect("failed to get totp")
+                .decrypt(new_user_key.key())
+                .expect("failed to decrypt totp secret")
+                .to_base32(),
+            secret.to_base32()
+        );
+    }
+
+    #[tokio::test]
+    async fn use_backup_code() {
+        let pool = init_db().await;
+
+        // Populate database
+
+        let user_id = populate(&pool).await;
+
+        // Perform test
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+
+        let old_backup_codes = services::user_totp::replace_backup_codes(&mut conn, &user_id)
+            .await
+            .expect("failed to create backup codes");
+        let backup_codes = services::user_totp::replace_backup_codes(&mut conn, &user_id)
+            .await
+            .expect("failed to replace backup codes");
+        assert_eq!(backup_codes.len(), 10);
+
+        let backup_code = BackupCode::parse(&backup_codes[0].to_string().to_lowercase())
+            .expect("failed to parse backup code");
+        assert!(
+            services::user_totp::use_backup_code(&mut *conn, &user_id, &backup_code)
+                .await
+                .expect("failed to use backup code")
+        );
+        assert!(
+            !services::user_totp::use_backup_code(&mut *conn, &user_id, &backup_code)
+                .await
+                .expect("failed to use backup code")
+        );
+        assert!(
+            !services::user_totp::use_backup_code(&mut *conn, &user_id, &old_backup_codes[1])
+                .await
+                .expect("failed to use backup code")
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use caseless::default_case_fold_str;
+use unicode_normalization::UnicodeNormalization;
+
+pub const MIN_LENGTH: usize = 3;
+pub const MAX_LENGTH: usize = 32;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum UsernameError {
+    #[error("username must be at least {MIN_LENGTH} characters")]
+    TooShort,
+
+    #[error("username must be at most {MAX_LENGTH} characters")]
+    TooLong,
+
+    #[error("username must not contain {0:?}")]
+    InvalidCharacter(char),
+}
+
+/// A valid username in NFKC form, along with its key. Usernames are shown as
+/// chosen, but are unique by their key, so "Alice" and "alice" are the same
+/// user.
+#[derive(Debug, PartialEq)]
+pub struct Username {
+    username: String,
+    key: String,
+}
+
+impl Username {
+    /// Normalises a username to NFKC, so that compatibility forms such as
+    /// full width letters are stored as their plain letters, and validates
+    /// it. Usernames consist of letters, digits, `_`, `-` and `.`.
+    pub fn parse(username: &str) -> Result<Self, UsernameError> {
+        let username: String = username.nfkc().collect();
+
+        if let Some(c) = username
+            .chars()
+            .find(|c| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        {
+            return Err(UsernameError::InvalidCharacter(c));
+        }
+        match username.chars().count() {
+            x if x < MIN_LENGTH => return Err(UsernameError::TooShort),
+            x if x > MAX_LENGTH => return Err(UsernameError::TooLong),
+            _ => {}
+        }
+
+        Ok(Self {
+            key: key(&username),
+            username,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.username
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// Returns the key of a username, its NFKC case folded form, by which
+/// usernames are unique and looked up. Any string has a key, so usernames
+/// given at sign in need not be valid.
+pub fn key(username: &str) -> String {
+    default_case_fold_str(&username.nfkc().collect::<String>())
+        .nfkc()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::usernames::{self, Username, UsernameError};
+
+    #[test]
+    fn parse() {
+        // Compatibility forms are normalised, while the case is kept
+        let username = Username::parse("Ａlice_1").expect("failed to parse username");
+        assert_eq!(username.as_str(), "Alice_1");
+        assert_eq!(username.key(), "alice_1");
+
+        assert_eq!(Username::parse("al"), Err(UsernameError::TooShort));
+        assert_eq!(
+            Username::parse(&"a".repeat(33)),
+            Err(UsernameError::TooLong)
+        );
+        assert_eq!(
+            Username::parse("alice smith"),
+            Err(UsernameError::InvalidCharacter(' '))
+        );
+        assert_eq!(
+            Username::parse("alice\u{200b}"),
+            Err(UsernameError::InvalidCharacter('\u{200b}'))
+        );
+    }
+
+    #[test]
+    fn key() {
+        assert_eq!(usernames::key("ALICE"), usernames::key("alice"));
+        assert_eq!(usernames::key("Straße"), usernames::key("STRASSE"));
+
+        // Composed and decomposed forms have the same key
+        assert_eq!(usernames::key("Zoë"), usernames::key("zoe\u{308}"));
+    }
+}
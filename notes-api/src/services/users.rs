@@ -1,19 +1,32 @@
-use sqlx::SqliteExecutor;
+use sqlx::{SqliteConnection, SqliteExecutor};
 use uuid::Uuid;
 
-use crate::{db, services};
+use crate::{
+    db,
+    services::{
+        self,
+        hash_params::HashConfig,
+        user_key_pairs::UserKeyPair,
+        user_keys::UserKey,
+        user_passwords::UserPassword,
+        user_recovery_codes::RecoveryCode,
+        usernames::{self, Username},
+    },
+};
 
 #[derive(Debug, PartialEq)]
 pub struct User {
     id: Uuid,
     username: String,
+    username_key: Option<String>,
 }
 
 impl User {
-    pub fn new(username: String) -> Self {
+    pub fn new(username: Username) -> Self {
         Self {
             id: Uuid::new_v4(),
-            username,
+            username: username.as_str().to_string(),
+            username_key: Some(username.key().to_string()),
         }
     }
 
@@ -26,6 +39,16 @@ impl User {
     }
 }
 
+impl From<db::users::UserRow> for User {
+    fn from(user_row: db::users::UserRow) -> Self {
+        Self {
+            id: user_row.id,
+            username: user_row.username,
+            username_key: user_row.username_key,
+        }
+    }
+}
+
 pub async fn store<'e, E>(executor: E, user: &User) -> services::Result<()>
 where
     E: SqliteExecutor<'e>,
@@ -36,6 +59,7 @@ where
         &db::users::UserRow {
             id: user.id,
             username: user.username.clone(),
+            username_key: user.username_key.clone(),
         },
     )
     .await?;
@@ -43,16 +67,170 @@ where
     Ok(())
 }
 
+/// Signs a user up with a password. The user, its user key wrapped under the
+/// password and under a recovery code, and its key pair are created together,
+/// so there are no users without credentials. Returns the user key for the
+/// first session, and the recovery code, which is not shown again.
+pub async fn sign_up(
+    conn: &mut SqliteConnection,
+    hash_config: &HashConfig,
+    username: &str,
+    password: &str,
+) -> services::Result<(User, UserKey, RecoveryCode)> {
+    let user = User::new(Username::parse(username)?);
+    store(&mut *conn, &user).await?;
+
+    // Wrap the user key using the password
+    let user_key = UserKey::new();
+    services::user_keys::store_using_password(
+        &mut *conn,
+        hash_config,
+        &user.id,
+        &user_key,
+        password,
+    )
+    .await?;
+    services::user_passwords::store(
+        &mut *conn,
+        &UserPassword::new(hash_config, user_key.id(), password)?,
+        &user.id,
+    )
+    .await?;
+
+    // Create the user's key pair for note sharing
+    services::user_key_pairs::store(
+        &mut *conn,
+        UserKeyPair::new().encrypt(user_key.key())?,
+        &user.id,
+    )
+    .await?;
+
+    // Wrap the user key using a recovery code
+    let recovery_code =
+        services::user_recovery_codes::replace(&mut *conn, hash_config, &user.id, user_key.key())
+            .await?;
+
+    Ok((user, user_key, recovery_code))
+}
+
+pub async fn get_by_id<'e, E>(executor: E, id: &Uuid) -> services::Result<User>
+where
+    E: SqliteExecutor<'e>,
+{
+    // Get the user
+    Ok(db::users::get_by_id(executor, id).await?.into())
+}
+
+/// Gets a user by a username in any case or Unicode form.
 pub async fn get_by_username<'e, E>(executor: E, username: &str) -> services::Result<User>
 where
     E: SqliteExecutor<'e>,
 {
     // Get the user
-    let user_row = db::users::get_by_username(executor, username).await?;
+    Ok(
+        db::users::get_by_username(executor, username, &usernames::key(username))
+            .await?
+            .into(),
+    )
+}
 
-    Ok(User {
-        id: user_row.id,
-        username: user_row.username,
+/// Renames a user. Fails with `Conflict` if another user has a username with
+/// the same key.
+pub async fn rename<'e, E>(
+    executor: E,
+    user: &mut User,
+    username: &Username,
+) -> services::Result<()>
+where
+    E: SqliteExecutor<'e>,
+{
+    db::users::update_username(executor, &user.id, username.as_str(), Some(username.key())).await?;
+
+    user.username = username.as_str().to_string();
+    user.username_key = Some(username.key().to_string());
+
+    Ok(())
+}
+
+/// Fills in the username keys of users created before keys were introduced.
+/// Users whose key collides with another user's keep a missing key, and are
+/// returned so that they can be asked to rename.
+pub async fn fill_username_keys(conn: &mut SqliteConnection) -> services::Result<Vec<User>> {
+    let mut collisions = Vec::new();
+    for user_row in db::users::get_without_username_key(&mut *conn).await? {
+        let username_key = usernames::key(&user_row.username);
+        match db::users::update_username(
+            &mut *conn,
+            &user_row.id,
+            &user_row.username,
+            Some(&username_key),
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(db::Error::Conflict) => collisions.push(user_row.into()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(collisions)
+}
+
+/// What was destroyed when a user was deleted
+#[derive(Debug, PartialEq)]
+pub struct DeletedUser {
+    user_keys: u64,
+    note_keys: u64,
+    notes: u64,
+    sessions: u64,
+}
+
+impl DeletedUser {
+    pub fn user_keys(&self) -> u64 {
+        self.user_keys
+    }
+
+    pub fn note_keys(&self) -> u64 {
+        self.note_keys
+    }
+
+    pub fn notes(&self) -> u64 {
+        self.notes
+    }
+
+    pub fn sessions(&self) -> u64 {
+        self.sessions
+    }
+}
+
+/// Deletes a user, along with the notes no other user has a key for. The key
+/// material of the user is overwritten with zeros before the rows are
+/// deleted, so the notes cannot be recovered from pages the database has
+/// freed but not yet reused.
+pub async fn delete(conn: &mut SqliteConnection, user_id: &Uuid) -> services::Result<DeletedUser> {
+    // Shred the key material of the user
+    let user_keys = db::user_keys::overwrite_by_user_id(&mut *conn, user_id).await?;
+    let note_keys = db::note_keys::overwrite_by_user_id(&mut *conn, user_id).await?;
+    db::user_key_pairs::overwrite_by_user_id(&mut *conn, user_id).await?;
+    db::user_passwords::overwrite_by_user_id(&mut *conn, user_id).await?;
+    db::user_totp::overwrite_by_user_id(&mut *conn, user_id).await?;
+    db::user_opaque_records::overwrite_by_user_id(&mut *conn, user_id).await?;
+    db::user_session_refresh_tokens::overwrite_by_user_id(&mut *conn, user_id).await?;
+
+    // Delete the notes that are not shared with another user
+    let notes = db::notes::delete_unshared_by_user_id(&mut *conn, user_id).await?;
+
+    // Delete the user, which cascades to the rest of its rows
+    let sessions = db::user_sessions::get_by_user_id(&mut *conn, user_id)
+        .await?
+        .len() as u64;
+    db::users::delete_by_id(&mut *conn, user_id).await?;
+
+    Ok(DeletedUser {
+        user_keys,
+        note_keys,
+        notes,
+        sessions,
     })
 }
 
@@ -60,13 +238,18 @@ where
 mod tests {
     use utilities::db::init_db;
 
-    use crate::{db, services};
+    use crate::{
+        db,
+        services::{self, hash_params::HashConfig},
+    };
 
     #[tokio::test]
     async fn store() {
         let pool = init_db().await;
 
-        let user = services::users::User::new("test".to_string());
+        let user = services::users::User::new(
+            services::usernames::Username::parse("test").expect("failed to parse username"),
+        );
 
         services::users::store(&pool, &user)
             .await
@@ -78,7 +261,8 @@ mod tests {
                 .expect("failed to get user by id"),
             db::users::UserRow {
                 id: user.id,
-                username: user.username
+                username: user.username,
+                username_key: user.username_key
             }
         )
     }
@@ -87,17 +271,231 @@ mod tests {
     async fn get_by_username() {
         let pool = init_db().await;
 
-        let user = services::users::User::new("test".to_string());
+        let user = services::users::User::new(
+            services::usernames::Username::parse("test").expect("failed to parse username"),
+        );
 
         services::users::store(&pool, &user)
             .await
             .expect("failed to store user");
 
+        // Usernames are looked up in any case or Unicode form
+        assert_eq!(
+            services::users::get_by_username(&pool, "ＴＥＳＴ")
+                .await
+                .expect("failed to get user by username"),
+            user
+        )
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let pool = init_db().await;
+
+        // Populate database
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let (mut user, _, _) =
+            services::users::sign_up(&mut conn, &HashConfig::default(), "test", "1234")
+                .await
+                .expect("failed to sign up");
+        services::users::sign_up(&mut conn, &HashConfig::default(), "other", "1234")
+            .await
+            .expect("failed to sign up");
+
+        // Perform test
+        services::users::rename(
+            &mut *conn,
+            &mut user,
+            &services::usernames::Username::parse("Renamed").expect("failed to parse username"),
+        )
+        .await
+        .expect("failed to rename user");
         assert_eq!(
-            services::users::get_by_username(&pool, &user.username)
+            services::users::get_by_username(&mut *conn, "renamed")
                 .await
                 .expect("failed to get user by username"),
             user
+        );
+
+        // Usernames with the same key are taken
+        assert!(matches!(
+            services::users::rename(
+                &mut *conn,
+                &mut user,
+                &services::usernames::Username::parse("OTHER").expect("failed to parse username"),
+            )
+            .await,
+            Err(services::Error::Conflict)
+        ));
+        assert!(matches!(
+            services::users::sign_up(&mut conn, &HashConfig::default(), "RENAMED", "1234").await,
+            Err(services::Error::Conflict)
+        ));
+    }
+
+    #[tokio::test]
+    async fn fill_username_keys() {
+        let pool = init_db().await;
+
+        // Populate database
+        let mut ids = Vec::new();
+        for username in ["Test", "TEST", "other"] {
+            let id = uuid::Uuid::new_v4();
+            sqlx::query(
+                r#"
+                INSERT INTO users (id, username)
+                VALUES (?1, ?2)
+                "#,
+            )
+            .bind(id)
+            .bind(username)
+            .execute(&pool)
+            .await
+            .expect("failed to insert user");
+            ids.push(id);
+        }
+
+        // Perform test
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let collisions = services::users::fill_username_keys(&mut conn)
+            .await
+            .expect("failed to fill username keys");
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].username(), "TEST");
+
+        // The colliding user still signs in with its exact username
+        for (username, id) in [("test", ids[0]), ("TEST", ids[1]), ("OTHER", ids[2])] {
+            assert_eq!(
+                services::users::get_by_username(&mut *conn, username)
+                    .await
+                    .expect("failed to get user by username")
+                    .id(),
+                &id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_up() {
+        let pool = init_db().await;
+
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+
+        let (user, user_key, recovery_code) =
+            services::users::sign_up(&mut conn, &HashConfig::default(), "test", "1234")
+                .await
+                .expect("failed to sign up");
+
+        // The password and the recovery code both unwrap the user key
+        let (authenticated_user, _) = services::authentication::authenticate_password(
+            &mut conn,
+            &HashConfig::default(),
+            "test",
+            "1234",
         )
+        .await
+        .expect("failed to authenticate with password");
+        assert_eq!(authenticated_user, user);
+        assert_eq!(
+            services::user_recovery_codes::get_user_key(
+                &mut conn,
+                &HashConfig::default(),
+                user.id(),
+                &recovery_code
+            )
+            .await
+            .expect("failed to get user key using recovery code")
+            .key(),
+            user_key.key()
+        );
+        assert!(
+            services::user_key_pairs::get_by_user_id(&mut *conn, user.id())
+                .await
+                .is_ok()
+        );
+
+        // Usernames are unique
+        assert!(
+            services::users::sign_up(&mut conn, &HashConfig::default(), "test", "4321")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn delete() {
+        let pool = init_db().await;
+
+        // Populate database
+        let mut conn = pool.acquire().await.expect("failed to acquire connection");
+        let (user, _, _) =
+            services::users::sign_up(&mut conn, &HashConfig::default(), "test", "1234")
+                .await
+                .expect("failed to sign up");
+        let (other_user, _, _) =
+            services::users::sign_up(&mut conn, &HashConfig::default(), "other", "1234")
+                .await
+                .expect("failed to sign up");
+        let unshared_note_id = uuid::Uuid::new_v4();
+        let shared_note_id = uuid::Uuid::new_v4();
+        for (note_id, user_ids) in [
+            (unshared_note_id, vec![user.id]),
+            (shared_note_id, vec![user.id, other_user.id]),
+        ] {
+            db::notes::upsert(
+                &mut *conn,
+                &db::notes::NoteRow {
+                    id: note_id,
+                    encrypted_markdown: vec![1; 32],
+                    nonce: vec![2; 12],
+                    end_to_end: false,
+                    time_created: None,
+                },
+            )
+            .await
+            .expect("failed to create note");
+            for user_id in user_ids {
+                db::note_keys::create(
+                    &mut *conn,
+                    &db::note_keys::NoteKeyRow {
+                        id: uuid::Uuid::new_v4(),
+                        note_id,
+                        user_id,
+                        encrypted_key: vec![3; 48],
+                        nonce: vec![4; 12],
+                        ephemeral_public_key: None,
+                    },
+                )
+                .await
+                .expect("failed to create note key");
+            }
+        }
+
+        // Perform test
+        let deleted_user = services::users::delete(&mut conn, &user.id)
+            .await
+            .expect("failed to delete user");
+        assert_eq!(deleted_user.user_keys(), 2);
+        assert_eq!(deleted_user.note_keys(), 2);
+        assert_eq!(deleted_user.notes(), 1);
+        assert_eq!(deleted_user.sessions(), 0);
+
+        // Only the note no other user has a key for is gone
+        assert!(
+            db::notes::get_by_id(&mut *conn, &unshared_note_id)
+                .await
+                .is_err()
+        );
+        assert!(
+            db::notes::get_by_id(&mut *conn, &shared_note_id)
+                .await
+                .is_ok()
+        );
+        assert!(db::users::get_by_id(&mut *conn, &user.id).await.is_err());
+        assert!(
+            db::users::get_by_id(&mut *conn, &other_user.id)
+                .await
+                .is_ok()
+        );
     }
 }
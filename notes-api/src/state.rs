@@ -1,12 +1,33 @@
-use josekit::jwk::Jwk;
+use std::{env, path::PathBuf};
+
 use sqlx::{
     SqlitePool,
     sqlite::{SqliteConnectOptions, SqlitePoolOptions},
 };
 
+use aes_gcm::aead::OsRng;
+use chrono::{DateTime, Utc};
+use zeroize::Zeroizing;
+
+use crate::{
+    key_provider,
+    key_ring::KeyRing,
+    services::{self, hash_params::HashConfig, user_opaque::OpaqueSetup},
+    webauthn::RelyingParty,
+};
+
+/// Binds the wrapped pepper to its purpose.
+const PEPPER_LABEL: &str = "pepper";
+
+/// Binds the wrapped OPAQUE server setup to its purpose.
+const OPAQUE_SETUP_LABEL: &str = "opaque setup";
+
 pub struct AppState {
     pub db: SqlitePool,
-    pub jwk: Jwk,
+    pub key_ring: KeyRing,
+    pub hash_config: HashConfig,
+    pub opaque_setup: OpaqueSetup,
+    pub relying_party: RelyingParty,
 }
 
 impl AppState {
@@ -14,17 +35,55 @@ impl AppState {
         // Setup database
         let db = Self::init_db().await?;
 
-        // Setup JWT key
-        let jwk = Self::init_jwk()?;
+        // Setup the provider that wraps the server's secrets
+        let key_provider = key_provider::from_env(&Self::db_path())?;
+
+        // Setup JWT key ring
+        let key_ring = KeyRing::load_or_generate(&Self::key_ring_path(), key_provider.as_ref())?;
+
+        // Setup password hashing parameters, including the optional pepper
+        let pepper = Self::pepper_path()
+            .map(|path| {
+                key_provider::load_or_generate_secret(
+                    key_provider.as_ref(),
+                    &path,
+                    PEPPER_LABEL,
+                    32,
+                )
+            })
+            .transpose()?;
+        let hash_config = HashConfig::from_env(pepper)?;
 
-        Ok(Self { db, jwk })
+        // Setup the OPAQUE server setup, which every OPAQUE registration
+        // depends on
+        let opaque_setup = OpaqueSetup::deserialize(&key_provider::load_or_create_secret(
+            key_provider.as_ref(),
+            &Self::opaque_setup_path(),
+            OPAQUE_SETUP_LABEL,
+            || {
+                Ok(Zeroizing::new(
+                    OpaqueSetup::new(&mut OsRng).serialize().to_vec(),
+                ))
+            },
+        )?)
+        .map_err(|e| anyhow::anyhow!("failed to deserialize opaque setup: {}", e))?;
+
+        Ok(Self {
+            db,
+            key_ring,
+            hash_config,
+            opaque_setup,
+            relying_party: RelyingParty::from_env(),
+        })
     }
 
     async fn init_db() -> anyhow::Result<SqlitePool> {
         // Create database connection pool
         let connect_options = SqliteConnectOptions::new()
-            .filename("db.sqlite")
-            .create_if_missing(true);
+            .filename(Self::db_path())
+            .create_if_missing(true)
+            // Zero freed pages, so deleted key material does not linger
+            .pragma("secure_delete", "on");
         let db = SqlitePoolOptions::new()
             .max_connections(4)
             .connect_with(connect_options)
@@ -33,10 +92,58 @@ impl AppState {
         // Run database migrations
         sqlx::migrate!("./migrations").run(&db).await?;
 
+        // Fill in the username keys SQLite could not compute when migrating,
+        // and report the users that have to rename
+        let mut conn = db.acquire().await?;
+        for user in services::users::fill_username_keys(&mut conn).await? {
+            println!(
+                "username {:?} of user {} collides with another user's",
+                user.username(),
+                user.id()
+            );
+        }
+        drop(conn);
+
         Ok(db)
     }
 
-    fn init_jwk() -> anyhow::Result<Jwk> {
-        Ok(Jwk::generate_oct_key(32)?)
+    pub fn db_path() -> PathBuf {
+        PathBuf::from("db.sqlite")
+    }
+
+    pub fn key_ring_path() -> PathBuf {
+        env::var("KEY_RING_PATH")
+            .unwrap_or("key_ring.json".into())
+            .into()
+    }
+
+    pub fn opaque_setup_path() -> PathBuf {
+        env::var("OPAQUE_SETUP_PATH")
+            .unwrap_or("opaque_setup.json".into())
+            .into()
+    }
+
+    /// Whether the `X-Forwarded-For` header names the client, which is only
+    /// the case behind a proxy such as the web app. Set
+    /// `TRUST_FORWARDED_FOR=true` to enable it.
+    pub fn trust_forwarded_for() -> bool {
+        env::var("TRUST_FORWARDED_FOR").is_ok_and(|value| value == "true")
+    }
+
+    /// Data encrypted without a key commitment is only decrypted, to upgrade
+    /// it when its owner signs in, until `UNCOMMITTED_UPGRADE_DEADLINE`, an
+    /// RFC 3339 timestamp. Without it, or once it has passed, such data is
+    /// refused.
+    pub fn upgrades_uncommitted() -> bool {
+        env::var("UNCOMMITTED_UPGRADE_DEADLINE")
+            .ok()
+            .and_then(|deadline| DateTime::parse_from_rfc3339(&deadline).ok())
+            .is_some_and(|deadline| deadline > Utc::now())
+    }
+
+    /// The pepper is only used when `PEPPER_PATH` is set. Existing password
+    /// hashes are rehashed with the pepper after the next successful login.
+    pub fn pepper_path() -> Option<PathBuf> {
+        env::var("PEPPER_PATH").ok().map(PathBuf::from)
     }
 }
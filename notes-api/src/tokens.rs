@@ -1,29 +1,101 @@
+use std::{fmt, time::SystemTime};
+
 use aes_gcm::{Aes256Gcm, Key};
 use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Duration, Utc};
+use crypto::SecretKey;
 use josekit::{
     JoseError,
-    jwe::{JweHeader, alg::aesgcmkw::AesgcmkwJweAlgorithm::A256gcmkw},
-    jwk::Jwk,
+    jwe::{JweDecrypter, JweHeader, alg::aesgcmkw::AesgcmkwJweAlgorithm::A256gcmkw},
     jwt::{self, JwtPayload},
 };
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::key_ring::KeyRing;
+
+/// The `typ` header of session tokens.
+const SESSION_TOKEN_TYPE: &str = "JWT";
+
+/// The `typ` header of challenge tokens, so they cannot be used as a session.
+const CHALLENGE_TOKEN_TYPE: &str = "challenge+JWT";
+
+/// The `typ` header of end-to-end tokens, so they cannot be used as a session.
+const END_TO_END_TOKEN_TYPE: &str = "e2e+JWT";
+
+/// The `aud` claim of every token, which is only accepted by this API.
+const AUDIENCE: &str = "notes-api";
+
+/// How long a session token is valid. The client trades the refresh token of
+/// the session for a new one once it expires.
+pub const SESSION_TOKEN_VALIDITY: Duration = Duration::minutes(15);
 
 #[derive(Debug, PartialEq)]
 pub struct UserClaims {
     session_id: Uuid,
     user_id: Uuid,
-    user_key: Key<Aes256Gcm>,
+    user_key: SecretKey,
+    issued_at: DateTime<Utc>,
+    expiration_time: DateTime<Utc>,
 }
 
 impl UserClaims {
-    pub fn new(session_id: Uuid, user_id: Uuid, user_key: Key<Aes256Gcm>) -> Self {
+    pub fn new(session_id: Uuid, user_id: Uuid, user_key: SecretKey) -> Self {
+        // JWT timestamps have a precision of seconds
+        let issued_at = DateTime::from_timestamp(Utc::now().timestamp(), 0).unwrap_or_default();
+
         Self {
             session_id,
             user_id,
             user_key,
+            issued_at,
+            expiration_time: issued_at + SESSION_TOKEN_VALIDITY,
+        }
+    }
+
+    /// The session of the claims, or the access token when the user
+    /// authenticated with one. Access tokens are not accepted by the routes
+    /// that manage sessions.
+    pub fn session_id(&self) -> &Uuid {
+        &self.session_id
+    }
+
+    pub fn user_id(&self) -> &Uuid {
+        &self.user_id
+    }
+
+    pub fn user_key(&self) -> &Key<Aes256Gcm> {
+        self.user_key.key()
+    }
+
+    pub fn expiration_time(&self) -> &DateTime<Utc> {
+        &self.expiration_time
+    }
+
+    /// Claims of the same session for the routes of notes that are encrypted
+    /// end-to-end, without the user key.
+    pub fn end_to_end(&self) -> EndToEndClaims {
+        EndToEndClaims {
+            session_id: self.session_id,
+            user_id: self.user_id,
+            issued_at: self.issued_at,
+            expiration_time: self.expiration_time,
         }
     }
+}
 
+/// Claims of a session that only name the session and its user. The routes of
+/// notes that are encrypted end-to-end never need the user key, so their token
+/// does not carry it.
+#[derive(Debug, PartialEq)]
+pub struct EndToEndClaims {
+    session_id: Uuid,
+    user_id: Uuid,
+    issued_at: DateTime<Utc>,
+    expiration_time: DateTime<Utc>,
+}
+
+impl EndToEndClaims {
     pub fn session_id(&self) -> &Uuid {
         &self.session_id
     }
@@ -31,30 +103,136 @@ impl UserClaims {
     pub fn user_id(&self) -> &Uuid {
         &self.user_id
     }
+}
+
+/// Claims of a user that passed the password step of a login, but still has
+/// to pass a second factor. The token carries the user key, which the second
+/// factor needs, and expires shortly.
+#[derive(PartialEq)]
+pub struct ChallengeClaims {
+    user_id: Uuid,
+    user_key: SecretKey,
+    expiration_time: DateTime<Utc>,
+
+    /// The password chosen by a user that signed in with a recovery code. It
+    /// is only set once the second factor is passed.
+    new_password: Option<Zeroizing<String>>,
+}
+
+impl fmt::Debug for ChallengeClaims {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChallengeClaims")
+            .field("user_id", &self.user_id)
+            .field("user_key", &self.user_key)
+            .field("expiration_time", &self.expiration_time)
+            .field(
+                "new_password",
+                &self.new_password.as_ref().map(|_| "[redacted]"),
+            )
+            .finish()
+    }
+}
+
+impl ChallengeClaims {
+    pub fn new(user_id: Uuid, user_key: SecretKey, validity: Duration) -> Self {
+        Self {
+            user_id,
+            user_key,
+            // JWT expiration times have a precision of seconds
+            expiration_time: DateTime::from_timestamp((Utc::now() + validity).timestamp(), 0)
+                .unwrap_or_default(),
+            new_password: None,
+        }
+    }
+
+    pub fn with_new_password(mut self, new_password: &str) -> Self {
+        self.new_password = Some(Zeroizing::new(new_password.to_string()));
+        self
+    }
+
+    pub fn user_id(&self) -> &Uuid {
+        &self.user_id
+    }
 
     pub fn user_key(&self) -> &Key<Aes256Gcm> {
-        &self.user_key
+        self.user_key.key()
+    }
+
+    pub fn new_password(&self) -> Option<&str> {
+        self.new_password.as_deref().map(String::as_str)
+    }
+
+    pub fn into_user_key(self) -> SecretKey {
+        self.user_key
     }
 }
 
-pub fn encrypt(claims: &UserClaims, key: &Jwk) -> anyhow::Result<String> {
+pub fn encrypt(claims: &UserClaims, key_ring: &KeyRing) -> anyhow::Result<String> {
     // Create the JWE header
     let mut jwe_header = JweHeader::new();
-    jwe_header.set_token_type("JWT");
-    jwe_header.set_algorithm("A256GCMKW");
-    jwe_header.set_content_encryption("A256GCM");
+    jwe_header.set_token_type(SESSION_TOKEN_TYPE);
+    jwe_header.set_claim("session_id", Some(claims.session_id.to_string().into()))?;
+    jwe_header.set_claim("user_id", Some(claims.user_id.to_string().into()))?;
+
+    // Create the JWT payload
+    let mut jwt_payload = JwtPayload::new();
+    let user_key = Zeroizing::new(BASE64_STANDARD.encode(claims.user_key.key()));
+    jwt_payload.set_claim("user_key", Some(user_key.as_str().into()))?;
+    jwt_payload.set_issued_at(&SystemTime::from(claims.issued_at));
+    jwt_payload.set_expires_at(&SystemTime::from(claims.expiration_time));
+
+    encode(jwt_payload, jwe_header, key_ring)
+}
+
+pub fn encrypt_end_to_end(claims: &EndToEndClaims, key_ring: &KeyRing) -> anyhow::Result<String> {
+    // Create the JWE header
+    let mut jwe_header = JweHeader::new();
+    jwe_header.set_token_type(END_TO_END_TOKEN_TYPE);
     jwe_header.set_claim("session_id", Some(claims.session_id.to_string().into()))?;
     jwe_header.set_claim("user_id", Some(claims.user_id.to_string().into()))?;
 
     // Create the JWT payload
     let mut jwt_payload = JwtPayload::new();
-    jwt_payload.set_claim(
-        "user_key",
-        Some(BASE64_STANDARD.encode(&claims.user_key).into()),
-    )?;
+    jwt_payload.set_issued_at(&SystemTime::from(claims.issued_at));
+    jwt_payload.set_expires_at(&SystemTime::from(claims.expiration_time));
+
+    encode(jwt_payload, jwe_header, key_ring)
+}
+
+pub fn encrypt_challenge(claims: &ChallengeClaims, key_ring: &KeyRing) -> anyhow::Result<String> {
+    // Create the JWE header
+    let mut jwe_header = JweHeader::new();
+    jwe_header.set_token_type(CHALLENGE_TOKEN_TYPE);
+    jwe_header.set_claim("user_id", Some(claims.user_id.to_string().into()))?;
+
+    // Create the JWT payload
+    let mut jwt_payload = JwtPayload::new();
+    let user_key = Zeroizing::new(BASE64_STANDARD.encode(claims.user_key.key()));
+    jwt_payload.set_claim("user_key", Some(user_key.as_str().into()))?;
+    if let Some(new_password) = &claims.new_password {
+        jwt_payload.set_claim("new_password", Some(new_password.as_str().into()))?;
+    }
+    jwt_payload.set_expires_at(&SystemTime::from(claims.expiration_time));
+
+    encode(jwt_payload, jwe_header, key_ring)
+}
+
+fn encode(
+    mut jwt_payload: JwtPayload,
+    mut jwe_header: JweHeader,
+    key_ring: &KeyRing,
+) -> anyhow::Result<String> {
+    jwt_payload.set_audience(vec![AUDIENCE]);
+
+    // Always encrypt using the active key
+    let key = key_ring.active()?;
+
+    jwe_header.set_algorithm("A256GCMKW");
+    jwe_header.set_content_encryption("A256GCM");
+    jwe_header.set_key_id(key.kid());
 
     // Encrypt the JWT
-    let encrypter = A256gcmkw.encrypter_from_jwk(key)?;
+    let encrypter = A256gcmkw.encrypter_from_jwk(key.jwk())?;
     Ok(jwt::encode_with_encrypter(
         &jwt_payload,
         &jwe_header,
@@ -62,37 +240,156 @@ pub fn encrypt(claims: &UserClaims, key: &Jwk) -> anyhow::Result<String> {
     )?)
 }
 
-pub fn decrypt(input: &[u8], key: &Jwk) -> Result<UserClaims, TokenDecryptionError> {
-    // Decrypt the input
-    let decrypter = A256gcmkw
-        .decrypter_from_jwk(key)
-        .map_err(|_| TokenDecryptionError::Internal)?;
-    let (payload, header) = jwt::decode_with_decrypter(input, &decrypter).map_err(|e| match e {
-        JoseError::InvalidJweFormat(_) => TokenDecryptionError::InvalidKey,
-        _ => TokenDecryptionError::Internal,
-    })?;
-
+pub fn decrypt(input: &[u8], key_ring: &KeyRing) -> Result<UserClaims, TokenDecryptionError> {
+    let (payload, header) = decode(input, key_ring, SESSION_TOKEN_TYPE)?;
     let header_claims = header.claims_set();
     let payload_claims = payload.claims_set();
 
+    // Reject expired tokens
+    let issued_at = DateTime::<Utc>::from(payload.issued_at().ok_or(
+        TokenDecryptionError::InvalidClaim(anyhow::anyhow!("`iat` claim must be set")),
+    )?);
+    let expiration_time = get_expiration_time(&payload)?;
+
     // Parse user claims
     let session_id = Uuid::parse_str(get_required_claim(header_claims, "session_id")?)
         .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("session_id: {}", e)))?;
     let user_id = Uuid::parse_str(get_required_claim(header_claims, "user_id")?)
         .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("user_id: {}", e)))?;
-    let user_key = *Key::<Aes256Gcm>::from_slice(
-        &BASE64_STANDARD
-            .decode(get_required_claim(payload_claims, "user_key")?)
-            .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("user_key: {}", e)))?,
-    );
+    let user_key = get_user_key_claim(payload_claims)?;
 
     Ok(UserClaims {
         session_id,
         user_id,
         user_key,
+        issued_at,
+        expiration_time,
+    })
+}
+
+pub fn decrypt_end_to_end(
+    input: &[u8],
+    key_ring: &KeyRing,
+) -> Result<EndToEndClaims, TokenDecryptionError> {
+    let (payload, header) = decode(input, key_ring, END_TO_END_TOKEN_TYPE)?;
+    let header_claims = header.claims_set();
+
+    // Reject expired tokens
+    let issued_at = DateTime::<Utc>::from(payload.issued_at().ok_or(
+        TokenDecryptionError::InvalidClaim(anyhow::anyhow!("`iat` claim must be set")),
+    )?);
+    let expiration_time = get_expiration_time(&payload)?;
+
+    // Parse end-to-end claims
+    let session_id = Uuid::parse_str(get_required_claim(header_claims, "session_id")?)
+        .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("session_id: {}", e)))?;
+    let user_id = Uuid::parse_str(get_required_claim(header_claims, "user_id")?)
+        .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("user_id: {}", e)))?;
+
+    Ok(EndToEndClaims {
+        session_id,
+        user_id,
+        issued_at,
+        expiration_time,
+    })
+}
+
+pub fn decrypt_challenge(
+    input: &[u8],
+    key_ring: &KeyRing,
+) -> Result<ChallengeClaims, TokenDecryptionError> {
+    let (payload, header) = decode(input, key_ring, CHALLENGE_TOKEN_TYPE)?;
+
+    // Reject expired challenges
+    let expiration_time = get_expiration_time(&payload)?;
+
+    // Parse challenge claims
+    let user_id = Uuid::parse_str(get_required_claim(header.claims_set(), "user_id")?)
+        .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("user_id: {}", e)))?;
+    let user_key = get_user_key_claim(payload.claims_set())?;
+    let new_password = payload
+        .claim("new_password")
+        .and_then(|claim| claim.as_str())
+        .map(|new_password| Zeroizing::new(new_password.to_string()));
+
+    Ok(ChallengeClaims {
+        user_id,
+        user_key,
+        expiration_time,
+        new_password,
     })
 }
 
+fn decode(
+    input: &[u8],
+    key_ring: &KeyRing,
+    token_type: &str,
+) -> Result<(JwtPayload, JweHeader), TokenDecryptionError> {
+    // Create decrypters for every key that has not been retired
+    let decrypters = key_ring
+        .accepted()
+        .map(|key| Ok((key.kid(), A256gcmkw.decrypter_from_jwk(key.jwk())?)))
+        .collect::<Result<Vec<_>, JoseError>>()
+        .map_err(|_| TokenDecryptionError::Internal)?;
+
+    // Decrypt the input using the key referenced by the `kid` header
+    let (payload, header) = jwt::decode_with_decrypter_selector(input, |header| {
+        Ok(header.key_id().and_then(|kid| {
+            decrypters
+                .iter()
+                .find(|(key_id, _)| *key_id == kid)
+                .map(|(_, decrypter)| decrypter as &dyn JweDecrypter)
+        }))
+    })
+    .map_err(|e| match e {
+        JoseError::InvalidJweFormat(_) | JoseError::InvalidJwtFormat(_) => {
+            TokenDecryptionError::InvalidKey
+        }
+        _ => TokenDecryptionError::Internal,
+    })?;
+
+    // Tokens of one type cannot be used as another, nor can tokens meant for
+    // another audience
+    if header.token_type() != Some(token_type) {
+        return Err(TokenDecryptionError::InvalidKey);
+    }
+    if !payload
+        .audience()
+        .is_some_and(|audience| audience.contains(&AUDIENCE))
+    {
+        return Err(TokenDecryptionError::InvalidClaim(anyhow::anyhow!(
+            "`aud` claim must be {}",
+            AUDIENCE
+        )));
+    }
+
+    Ok((payload, header))
+}
+
+fn get_expiration_time(payload: &JwtPayload) -> Result<DateTime<Utc>, TokenDecryptionError> {
+    let expiration_time = DateTime::<Utc>::from(payload.expires_at().ok_or(
+        TokenDecryptionError::InvalidClaim(anyhow::anyhow!("`exp` claim must be set")),
+    )?);
+    if expiration_time <= Utc::now() {
+        return Err(TokenDecryptionError::Expired);
+    }
+
+    Ok(expiration_time)
+}
+
+fn get_user_key_claim(
+    claims: &josekit::Map<String, josekit::Value>,
+) -> Result<SecretKey, TokenDecryptionError> {
+    let user_key_buf = Zeroizing::new(
+        BASE64_STANDARD
+            .decode(get_required_claim(claims, "user_key")?)
+            .map_err(|e| TokenDecryptionError::InvalidClaim(anyhow::anyhow!("user_key: {}", e)))?,
+    );
+    SecretKey::from_slice(&user_key_buf).ok_or(TokenDecryptionError::InvalidClaim(anyhow::anyhow!(
+        "user_key: invalid length"
+    )))
+}
+
 fn get_required_claim<'a>(
     claims: &'a josekit::Map<String, josekit::Value>,
     name: &str,
@@ -113,33 +410,179 @@ pub enum TokenDecryptionError {
     #[error("invalid claim: {0}")]
     InvalidClaim(#[source] anyhow::Error),
 
+    #[error("token expired")]
+    Expired,
+
     #[error("internal error")]
     Internal,
 }
 
 #[cfg(test)]
 mod tests {
-    use aes_gcm::{Aes256Gcm, KeyInit, aead::OsRng};
-    use josekit::jwk::Jwk;
+    use chrono::Duration;
+    use crypto::SecretKey;
     use uuid::Uuid;
 
-    use crate::tokens::{UserClaims, decrypt, encrypt};
+    use crate::{
+        key_ring::KeyRing,
+        tokens::{
+            ChallengeClaims, TokenDecryptionError, UserClaims, decrypt, decrypt_challenge,
+            decrypt_end_to_end, encrypt, encrypt_challenge, encrypt_end_to_end,
+        },
+    };
 
     #[test]
     fn encrypt_decrypt_user_claims() {
-        let jwk = Jwk::generate_oct_key(32).expect("failed to generate jwk");
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
 
-        let user_claims = UserClaims::new(
-            Uuid::new_v4(),
-            Uuid::new_v4(),
-            Aes256Gcm::generate_key(&mut OsRng),
-        );
+        let user_claims = UserClaims::new(Uuid::new_v4(), Uuid::new_v4(), SecretKey::generate());
 
         let user_claims_encrypted =
-            encrypt(&user_claims, &jwk).expect("failed to encrypt user claims");
-        let user_claims_decrypted =
-            decrypt(user_claims_encrypted.as_bytes(), &jwk).expect("failed to decrypt user claims");
+            encrypt(&user_claims, &key_ring).expect("failed to encrypt user claims");
+        let user_claims_decrypted = decrypt(user_claims_encrypted.as_bytes(), &key_ring)
+            .expect("failed to decrypt user claims");
 
         assert_eq!(user_claims, user_claims_decrypted);
     }
+
+    #[test]
+    fn decrypt_expired_user_claims() {
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
+
+        let mut user_claims =
+            UserClaims::new(Uuid::new_v4(), Uuid::new_v4(), SecretKey::generate());
+        user_claims.expiration_time = user_claims.issued_at - Duration::minutes(1);
+        let user_claims_encrypted =
+            encrypt(&user_claims, &key_ring).expect("failed to encrypt user claims");
+
+        assert!(matches!(
+            decrypt(user_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::Expired)
+        ));
+    }
+
+    #[test]
+    fn user_claims_debug_is_redacted() {
+        let user_claims = UserClaims::new(Uuid::new_v4(), Uuid::new_v4(), SecretKey::generate());
+
+        let debug = format!("{:?}", user_claims);
+        assert!(debug.contains("[REDACTED]"));
+        assert!(!debug.contains(&format!("{:?}", user_claims.user_key())));
+    }
+
+    #[test]
+    fn decrypt_after_rotation() {
+        let mut key_ring = KeyRing::generate().expect("failed to generate key ring");
+
+        let user_claims = UserClaims::new(Uuid::new_v4(), Uuid::new_v4(), SecretKey::generate());
+        let user_claims_encrypted =
+            encrypt(&user_claims, &key_ring).expect("failed to encrypt user claims");
+
+        // Tokens of the previous key are accepted during the grace period
+        key_ring
+            .rotate(Duration::days(1))
+            .expect("failed to rotate key ring");
+        assert_eq!(
+            decrypt(user_claims_encrypted.as_bytes(), &key_ring)
+                .expect("failed to decrypt user claims"),
+            user_claims
+        );
+
+        // Tokens of retired keys are rejected
+        key_ring
+            .rotate(Duration::zero())
+            .expect("failed to rotate key ring");
+        assert!(matches!(
+            decrypt(user_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn encrypt_decrypt_end_to_end_claims() {
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
+
+        let user_claims = UserClaims::new(Uuid::new_v4(), Uuid::new_v4(), SecretKey::generate());
+        let end_to_end_claims = user_claims.end_to_end();
+        let end_to_end_claims_encrypted = encrypt_end_to_end(&end_to_end_claims, &key_ring)
+            .expect("failed to encrypt end-to-end claims");
+
+        assert_eq!(
+            decrypt_end_to_end(end_to_end_claims_encrypted.as_bytes(), &key_ring)
+                .expect("failed to decrypt end-to-end claims"),
+            end_to_end_claims
+        );
+
+        // End-to-end tokens cannot be used as session tokens, and vice versa
+        assert!(matches!(
+            decrypt(end_to_end_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::InvalidKey)
+        ));
+        let user_claims_encrypted =
+            encrypt(&user_claims, &key_ring).expect("failed to encrypt user claims");
+        assert!(matches!(
+            decrypt_end_to_end(user_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn encrypt_decrypt_challenge_claims() {
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
+
+        let challenge_claims =
+            ChallengeClaims::new(Uuid::new_v4(), SecretKey::generate(), Duration::minutes(5));
+        let challenge_claims_encrypted = encrypt_challenge(&challenge_claims, &key_ring)
+            .expect("failed to encrypt challenge claims");
+
+        assert_eq!(
+            decrypt_challenge(challenge_claims_encrypted.as_bytes(), &key_ring)
+                .expect("failed to decrypt challenge claims"),
+            challenge_claims
+        );
+
+        // The password chosen with a recovery code is carried along
+        let challenge_claims =
+            ChallengeClaims::new(Uuid::new_v4(), SecretKey::generate(), Duration::minutes(5))
+                .with_new_password("1234");
+        assert_eq!(
+            decrypt_challenge(
+                encrypt_challenge(&challenge_claims, &key_ring)
+                    .expect("failed to encrypt challenge claims")
+                    .as_bytes(),
+                &key_ring
+            )
+            .expect("failed to decrypt challenge claims")
+            .new_password(),
+            Some("1234")
+        );
+
+        // Challenge tokens cannot be used as session tokens, and vice versa
+        assert!(matches!(
+            decrypt(challenge_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::InvalidKey)
+        ));
+        let user_claims = UserClaims::new(Uuid::new_v4(), Uuid::new_v4(), SecretKey::generate());
+        let user_claims_encrypted =
+            encrypt(&user_claims, &key_ring).expect("failed to encrypt user claims");
+        assert!(matches!(
+            decrypt_challenge(user_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn decrypt_expired_challenge_claims() {
+        let key_ring = KeyRing::generate().expect("failed to generate key ring");
+
+        let challenge_claims =
+            ChallengeClaims::new(Uuid::new_v4(), SecretKey::generate(), Duration::minutes(-1));
+        let challenge_claims_encrypted = encrypt_challenge(&challenge_claims, &key_ring)
+            .expect("failed to encrypt challenge claims");
+
+        assert!(matches!(
+            decrypt_challenge(challenge_claims_encrypted.as_bytes(), &key_ring),
+            Err(TokenDecryptionError::Expired)
+        ));
+    }
 }
@@ -0,0 +1,591 @@
+//! Verification of WebAuthn registration and assertion ceremonies, so users
+//! can sign in with a passkey. Only `none` attestation is requested, so
+//! attestation statements are not verified. Passkeys using ES256 or EdDSA are
+//! supported.
+
+use std::env;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ciborium::Value;
+use p256::ecdsa::signature::Verifier;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// The COSE algorithm identifier of ECDSA using P-256 and SHA-256.
+pub const ES256: i64 = -7;
+
+/// The COSE algorithm identifier of EdDSA, which is used with Ed25519.
+pub const EDDSA: i64 = -8;
+
+/// The user presence flag of the authenticator data.
+const FLAG_USER_PRESENT: u8 = 0x01;
+
+/// The user verification flag of the authenticator data.
+const FLAG_USER_VERIFIED: u8 = 0x04;
+
+/// The flag indicating that the authenticator data includes a credential.
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// The salt passkeys evaluate the PRF extension with. PRF outputs differ per
+/// credential, so a single salt suffices and can be requested before the user
+/// picks a passkey.
+pub fn prf_salt() -> [u8; 32] {
+    Sha256::digest(b"notes user key wrapping").into()
+}
+
+/// The website passkeys are registered to. The relying party id is the
+/// domain of the web client, and the origin the URL it is served from.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    id: String,
+    name: String,
+    origin: String,
+}
+
+/// A credential created by a registration ceremony.
+#[derive(Debug, PartialEq)]
+pub struct Credential {
+    pub id: Vec<u8>,
+
+    /// The public key, as a COSE key
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+    #[serde(rename = "crossOrigin", default)]
+    cross_origin: bool,
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+
+    /// The attested credential data and extensions, if any
+    rest: &'a [u8],
+}
+
+enum PublicKey {
+    P256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl RelyingParty {
+    pub fn new(id: &str, name: &str, origin: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            origin: origin.to_string(),
+        }
+    }
+
+    /// Reads the relying party from the `WEBAUTHN_RP_ID`, `WEBAUTHN_RP_NAME`
+    /// and `WEBAUTHN_ORIGIN` environment variables, which default to the web
+    /// client's development server.
+    pub fn from_env() -> Self {
+        Self {
+            id: env::var("WEBAUTHN_RP_ID").unwrap_or("localhost".into()),
+            name: env::var("WEBAUTHN_RP_NAME").unwrap_or("Notes".into()),
+            origin: env::var("WEBAUTHN_ORIGIN").unwrap_or("http://localhost:5173".into()),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Verifies the response of `navigator.credentials.create()` to
+    /// `challenge`, and returns the new credential.
+    pub fn verify_registration(
+        &self,
+        challenge: &[u8],
+        client_data_json: &[u8],
+        attestation_object: &[u8],
+    ) -> Result<Credential, WebauthnError> {
+        self.verify_client_data(client_data_json, "webauthn.create", challenge)?;
+
+        // Only the authenticator data of the attestation object is used
+        let attestation_object: Value = ciborium::from_reader(attestation_object)
+            .map_err(|_| WebauthnError::InvalidAttestationObject)?;
+        let auth_data = attestation_object
+            .as_map()
+            .and_then(|map| {
+                map.iter()
+                    .find(|(key, _)| key.as_text() == Some("authData"))
+                    .and_then(|(_, value)| value.as_bytes())
+            })
+            .ok_or(WebauthnError::InvalidAttestationObject)?;
+
+        let auth_data = self.verify_authenticator_data(auth_data)?;
+        if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+            return Err(WebauthnError::InvalidAuthenticatorData(
+                "credential is missing",
+            ));
+        }
+
+        // Skip the AAGUID and read the credential id
+        let rest = auth_data
+            .rest
+            .get(16..)
+            .ok_or(WebauthnError::InvalidAuthenticatorData("too short"))?;
+        let (id_len, rest) = rest
+            .split_first_chunk::<2>()
+            .ok_or(WebauthnError::InvalidAuthenticatorData("too short"))?;
+        let id_len = u16::from_be_bytes(*id_len) as usize;
+        let (id, rest) = rest
+            .split_at_checked(id_len)
+            .ok_or(WebauthnError::InvalidAuthenticatorData("too short"))?;
+
+        // The public key is the CBOR item following the credential id, which
+        // may be followed by extensions
+        let mut remainder = rest;
+        let _: Value = ciborium::from_reader(&mut remainder)
+            .map_err(|_| WebauthnError::UnsupportedPublicKey)?;
+        let public_key = &rest[..rest.len() - remainder.len()];
+        parse_public_key(public_key)?;
+
+        Ok(Credential {
+            id: id.to_vec(),
+            public_key: public_key.to_vec(),
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    /// Verifies the response of `navigator.credentials.get()` to `challenge`,
+    /// using the credential's public key, and returns the new sign count.
+    pub fn verify_assertion(
+        &self,
+        challenge: &[u8],
+        credential: &Credential,
+        client_data_json: &[u8],
+        authenticator_data: &[u8],
+        signature: &[u8],
+    ) -> Result<u32, WebauthnError> {
+        self.verify_client_data(client_data_json, "webauthn.get", challenge)?;
+        let auth_data = self.verify_authenticator_data(authenticator_data)?;
+
+        // The authenticator signs its data followed by a hash of the client data
+        let mut message = authenticator_data.to_vec();
+        message.extend_from_slice(&Sha256::digest(client_data_json));
+        parse_public_key(&credential.public_key)?.verify(&message, signature)?;
+
+        // A sign count that does not increase indicates a cloned authenticator.
+        // Authenticators that do not count always report zero.
+        if (auth_data.sign_count != 0 || credential.sign_count != 0)
+            && auth_data.sign_count <= credential.sign_count
+        {
+            return Err(WebauthnError::SignCountMismatch);
+        }
+
+        Ok(auth_data.sign_count)
+    }
+
+    fn verify_client_data(
+        &self,
+        client_data_json: &[u8],
+        ceremony: &str,
+        challenge: &[u8],
+    ) -> Result<(), WebauthnError> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json)
+            .map_err(|_| WebauthnError::InvalidClientData("malformed"))?;
+
+        if client_data.ceremony != ceremony {
+            return Err(WebauthnError::InvalidClientData("wrong ceremony"));
+        }
+        if BASE64_URL_SAFE_NO_PAD.decode(&client_data.challenge).ok() != Some(challenge.to_vec()) {
+            return Err(WebauthnError::InvalidClientData("wrong challenge"));
+        }
+        if client_data.origin != self.origin || client_data.cross_origin {
+            return Err(WebauthnError::InvalidClientData("wrong origin"));
+        }
+
+        Ok(())
+    }
+
+    fn verify_authenticator_data<'a>(
+        &self,
+        auth_data: &'a [u8],
+    ) -> Result<AuthenticatorData<'a>, WebauthnError> {
+        if auth_data.len() < 37 {
+            return Err(WebauthnError::InvalidAuthenticatorData("too short"));
+        }
+
+        if auth_data[..32] != Sha256::digest(self.id.as_bytes())[..] {
+            return Err(WebauthnError::InvalidAuthenticatorData(
+                "wrong relying party",
+            ));
+        }
+
+        // Passkeys replace the password, so the user must be verified by the
+        // authenticator as well
+        let flags = auth_data[32];
+        if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+            return Err(WebauthnError::InvalidAuthenticatorData(
+                "user is not verified",
+            ));
+        }
+
+        Ok(AuthenticatorData {
+            flags,
+            sign_count: u32::from_be_bytes([
+                auth_data[33],
+                auth_data[34],
+                auth_data[35],
+                auth_data[36],
+            ]),
+            rest: &auth_data[37..],
+        })
+    }
+}
+
+impl PublicKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), WebauthnError> {
+        match self {
+            Self::P256(key) => key.verify(
+                message,
+                &p256::ecdsa::Signature::from_der(signature)
+                    .map_err(|_| WebauthnError::InvalidSignature)?,
+            ),
+            Self::Ed25519(key) => key.verify(
+                message,
+                &ed25519_dalek::Signature::from_slice(signature)
+                    .map_err(|_| WebauthnError::InvalidSignature)?,
+            ),
+        }
+        .map_err(|_| WebauthnError::InvalidSignature)
+    }
+}
+
+/// Parses a COSE key of one of the supported algorithms.
+fn parse_public_key(input: &[u8]) -> Result<PublicKey, WebauthnError> {
+    let key: Value =
+        ciborium::from_reader(input).map_err(|_| WebauthnError::UnsupportedPublicKey)?;
+    let map = key.as_map().ok_or(WebauthnError::UnsupportedPublicKey)?;
+    let get = |label: i64| {
+        map.iter()
+            .find(|(key, _)| key.as_integer() == Some(label.into()))
+            .map(|(_, value)| value)
+    };
+    let get_integer = |label: i64| get(label).and_then(Value::as_integer).map(i128::from);
+    let get_bytes = |label: i64| get(label).and_then(Value::as_bytes);
+
+    match (get_integer(1), get_integer(3), get_integer(-1)) {
+        // EC2 key on P-256
+        (Some(2), Some(alg), Some(1)) if alg == ES256 as i128 => {
+            let (x, y) = get_bytes(-2)
+                .zip(get_bytes(-3))
+                .ok_or(WebauthnError::UnsupportedPublicKey)?;
+            let point = p256::EncodedPoint::from_affine_coordinates(
+                x.as_slice().into(),
+                y.as_slice().into(),
+                false,
+            );
+            Ok(PublicKey::P256(
+                p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map_err(|_| WebauthnError::UnsupportedPublicKey)?,
+            ))
+        }
+
+        // OKP key on Ed25519
+        (Some(1), Some(alg), Some(6)) if alg == EDDSA as i128 => {
+            let x = get_bytes(-2)
+                .and_then(|x| <[u8; 32]>::try_from(x.as_slice()).ok())
+                .ok_or(WebauthnError::UnsupportedPublicKey)?;
+            Ok(PublicKey::Ed25519(
+                ed25519_dalek::VerifyingKey::from_bytes(&x)
+                    .map_err(|_| WebauthnError::UnsupportedPublicKey)?,
+            ))
+        }
+
+        _ => Err(WebauthnError::UnsupportedPublicKey),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WebauthnError {
+    #[error("invalid client data: {0}")]
+    InvalidClientData(&'static str),
+
+    #[error("invalid attestation object")]
+    InvalidAttestationObject,
+
+    #[error("invalid authenticator data: {0}")]
+    InvalidAuthenticatorData(&'static str),
+
+    #[error("unsupported public key")]
+    UnsupportedPublicKey,
+
+    #[error("invalid signature")]
+    InvalidSignature,
+
+    #[error("sign count did not increase")]
+    SignCountMismatch,
+}
+
+/// A software authenticator that performs the ceremonies the way a browser
+/// and a platform authenticator would, for testing.
+#[cfg(test)]
+pub mod software_authenticator {
+    use aes_gcm::aead::OsRng;
+    use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+    use ciborium::Value;
+    use hmac::{Hmac, Mac};
+    use p256::ecdsa::{DerSignature, SigningKey, signature::Signer};
+    use sha2::{Digest, Sha256};
+
+    pub struct SoftwareAuthenticator {
+        pub credential_id: Vec<u8>,
+        signing_key: SigningKey,
+        prf_key: [u8; 32],
+        pub sign_count: u32,
+        rp_id: String,
+        origin: String,
+    }
+
+    pub struct Registration {
+        pub client_data_json: Vec<u8>,
+        pub attestation_object: Vec<u8>,
+    }
+
+    pub struct Assertion {
+        pub client_data_json: Vec<u8>,
+        pub authenticator_data: Vec<u8>,
+        pub signature: Vec<u8>,
+    }
+
+    impl SoftwareAuthenticator {
+        pub fn new(rp_id: &str, origin: &str) -> Self {
+            Self {
+                credential_id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+                signing_key: SigningKey::random(&mut OsRng),
+                prf_key: rand_bytes(),
+                sign_count: 0,
+                rp_id: rp_id.to_string(),
+                origin: origin.to_string(),
+            }
+        }
+
+        fn client_data(&self, ceremony: &str, challenge: &[u8]) -> Vec<u8> {
+            serde_json::to_vec(&serde_json::json!({
+                "type": ceremony,
+                "challenge": BASE64_URL_SAFE_NO_PAD.encode(challenge),
+                "origin": self.origin,
+            }))
+            .expect("failed to serialize client data")
+        }
+
+        fn authenticator_data(&self, flags: u8) -> Vec<u8> {
+            let mut auth_data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+            auth_data.push(flags);
+            auth_data.extend_from_slice(&self.sign_count.to_be_bytes());
+            auth_data
+        }
+
+        pub fn register(&self, challenge: &[u8]) -> Registration {
+            let point = self.signing_key.verifying_key().to_encoded_point(false);
+            let public_key = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(super::ES256)),
+                (Value::from(-1), Value::from(1)),
+                (
+                    Value::from(-2),
+                    Value::Bytes(point.x().expect("missing x").to_vec()),
+                ),
+                (
+                    Value::from(-3),
+                    Value::Bytes(point.y().expect("missing y").to_vec()),
+                ),
+            ]);
+
+            let mut auth_data = self.authenticator_data(0x45);
+            auth_data.extend_from_slice(&[0; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            ciborium::into_writer(&public_key, &mut auth_data)
+                .expect("failed to serialize public key");
+
+            let mut attestation_object = vec![];
+            ciborium::into_writer(
+                &Value::Map(vec![
+                    (Value::from("fmt"), Value::from("none")),
+                    (Value::from("attStmt"), Value::Map(vec![])),
+                    (Value::from("authData"), Value::Bytes(auth_data)),
+                ]),
+                &mut attestation_object,
+            )
+            .expect("failed to serialize attestation object");
+
+            Registration {
+                client_data_json: self.client_data("webauthn.create", challenge),
+                attestation_object,
+            }
+        }
+
+        pub fn assert(&mut self, challenge: &[u8]) -> Assertion {
+            self.sign_count += 1;
+
+            let client_data_json = self.client_data("webauthn.get", challenge);
+            let authenticator_data = self.authenticator_data(0x05);
+
+            let mut message = authenticator_data.clone();
+            message.extend_from_slice(&Sha256::digest(&client_data_json));
+            let signature: DerSignature = self.signing_key.sign(&message);
+
+            Assertion {
+                client_data_json,
+                authenticator_data,
+                signature: signature.as_bytes().to_vec(),
+            }
+        }
+
+        /// Evaluates the PRF extension the way CTAP2 `hmac-secret` does.
+        pub fn prf(&self, salt: &[u8]) -> [u8; 32] {
+            let mut salt_input = b"WebAuthn PRF\0".to_vec();
+            salt_input.extend_from_slice(salt);
+
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.prf_key)
+                .expect("hmac accepts keys of any length");
+            mac.update(&Sha256::digest(&salt_input));
+            mac.finalize().into_bytes().into()
+        }
+    }
+
+    fn rand_bytes() -> [u8; 32] {
+        use aes_gcm::aead::rand_core::RngCore;
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webauthn::{
+        RelyingParty, WebauthnError, software_authenticator::SoftwareAuthenticator,
+    };
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty::new("localhost", "Notes", "http://localhost:5173")
+    }
+
+    #[test]
+    fn register_and_assert() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new("localhost", "http://localhost:5173");
+
+        let registration = authenticator.register(b"registration challenge");
+        let credential = relying_party
+            .verify_registration(
+                b"registration challenge",
+                &registration.client_data_json,
+                &registration.attestation_object,
+            )
+            .expect("failed to verify registration");
+        assert_eq!(credential.id, authenticator.credential_id);
+
+        let assertion = authenticator.assert(b"assertion challenge");
+        assert_eq!(
+            relying_party
+                .verify_assertion(
+                    b"assertion challenge",
+                    &credential,
+                    &assertion.client_data_json,
+                    &assertion.authenticator_data,
+                    &assertion.signature,
+                )
+                .expect("failed to verify assertion"),
+            1
+        );
+    }
+
+    #[test]
+    fn assert_with_wrong_challenge_or_origin() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new("localhost", "http://localhost:5173");
+
+        let registration = authenticator.register(b"challenge");
+        let credential = relying_party
+            .verify_registration(
+                b"challenge",
+                &registration.client_data_json,
+                &registration.attestation_object,
+            )
+            .expect("failed to verify registration");
+
+        let assertion = authenticator.assert(b"other challenge");
+        assert!(matches!(
+            relying_party.verify_assertion(
+                b"challenge",
+                &credential,
+                &assertion.client_data_json,
+                &assertion.authenticator_data,
+                &assertion.signature,
+            ),
+            Err(WebauthnError::InvalidClientData(_))
+        ));
+
+        let phished = SoftwareAuthenticator::new("localhost", "https://evil.example");
+        let registration = phished.register(b"challenge");
+        assert!(matches!(
+            relying_party.verify_registration(
+                b"challenge",
+                &registration.client_data_json,
+                &registration.attestation_object,
+            ),
+            Err(WebauthnError::InvalidClientData(_))
+        ));
+    }
+
+    #[test]
+    fn assert_with_tampered_data() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new("localhost", "http://localhost:5173");
+
+        let registration = authenticator.register(b"challenge");
+        let mut credential = relying_party
+            .verify_registration(
+                b"challenge",
+                &registration.client_data_json,
+                &registration.attestation_object,
+            )
+            .expect("failed to verify registration");
+
+        // The signature covers the authenticator data
+        let mut assertion = authenticator.assert(b"challenge");
+        assertion.authenticator_data[36] ^= 1;
+        assert!(matches!(
+            relying_party.verify_assertion(
+                b"challenge",
+                &credential,
+                &assertion.client_data_json,
+                &assertion.authenticator_data,
+                &assertion.signature,
+            ),
+            Err(WebauthnError::InvalidSignature)
+        ));
+
+        // Sign counts must increase
+        let assertion = authenticator.assert(b"challenge");
+        credential.sign_count = authenticator.sign_count;
+        assert!(matches!(
+            relying_party.verify_assertion(
+                b"challenge",
+                &credential,
+                &assertion.client_data_json,
+                &assertion.authenticator_data,
+                &assertion.signature,
+            ),
+            Err(WebauthnError::SignCountMismatch)
+        ));
+    }
+}
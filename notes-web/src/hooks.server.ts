@@ -1,10 +1,25 @@
+import { refreshSession } from '$lib/api/auth';
 import { BASE_URL } from '$lib/api/client';
+import { deleteSession, setSession } from '$lib/server/session';
 import type { Handle, HandleFetch } from '@sveltejs/kit';
 import { decodeProtectedHeader } from 'jose';
 
 export const handle: Handle = async ({ event, resolve }) => {
+	// Trade the refresh token for a new session token once the previous one
+	// has expired
+	let sessionToken = event.cookies.get('sessionToken');
+	const refreshToken = event.cookies.get('refreshToken');
+	if (!sessionToken && refreshToken) {
+		const refresh_result = await refreshSession(event.fetch, refreshToken);
+		if (refresh_result.ok) {
+			setSession(event.cookies, refresh_result.data.session);
+			sessionToken = refresh_result.data.session.token;
+		} else {
+			event.cookies.delete('refreshToken', { path: '/' });
+		}
+	}
+
 	// Get the session token from the cookies and decode non-sensitive data
-	const sessionToken = event.cookies.get('sessionToken');
 	if (sessionToken) {
 		const header = decodeProtectedHeader(sessionToken);
 		event.locals.session = {
@@ -20,16 +35,35 @@ export const handle: Handle = async ({ event, resolve }) => {
 export const handleFetch: HandleFetch = async ({ event, request, fetch }) => {
 	const url = new URL(request.url);
 
-	// Set the authorization header in requests to the data store
-	if (
-		event.locals.session &&
-		url.hostname === BASE_URL.hostname &&
-		url.port === BASE_URL.port
-	) {
-		request.headers.set(
-			'authorization',
-			`Bearer ${event.locals.session.token}`
-		);
+	if (url.hostname === BASE_URL.hostname && url.port === BASE_URL.port) {
+		// Set the authorization header in requests to the data store
+		if (event.locals.session) {
+			request.headers.set(
+				'authorization',
+				`Bearer ${event.locals.session.token}`
+			);
+		}
+
+		// Pass on where the request came from, so sessions can be told apart
+		const userAgent = event.request.headers.get('user-agent');
+		if (userAgent) {
+			request.headers.set('user-agent', userAgent);
+		}
+		request.headers.set('x-forwarded-for', event.getClientAddress());
+
+		// Drop the cookies of a session that timed out or was revoked, so the
+		// user is asked to sign in again
+		const response = await fetch(request);
+		if (
+			response.status === 401 &&
+			response.headers
+				.get('www-authenticate')
+				?.includes('error_description="session expired"')
+		) {
+			deleteSession(event.cookies);
+		}
+
+		return response;
 	}
 
 	return fetch(request);
@@ -4,28 +4,137 @@ export type User = {
 	id: string;
 };
 
+/**
+ * The session token expires within minutes; the refresh token can be traded
+ * for a new pair once, using `refreshSession()`.
+ */
 export type Session = {
 	token: string;
+	/** Authenticates the end-to-end note routes, without the user key */
+	end_to_end_token: string;
+	refresh_token: string;
+	expiration_time: string;
 };
 
-export type AuthenticationMethod = {
-	method: 'password';
-	username: string;
-	password: string;
+export type Challenge = {
+	token: string;
+	methods: ('totp' | 'backup_code')[];
+};
+
+/**
+ * Request options for `PublicKeyCredential.parseRequestOptionsFromJSON()`,
+ * which ask for the PRF output that unlocks the user's notes.
+ */
+export type PasskeyChallenge = {
+	challenge_id: string;
+	options: PublicKeyCredentialRequestOptionsJSON;
+};
+
+/**
+ * The credential response of the first step of an OPAQUE login, which the
+ * client finishes with the password.
+ */
+export type OpaqueLogin = {
+	login_id: string;
+	credential_response: string;
 };
 
+export type AuthenticationMethod =
+	| {
+			method: 'password';
+			username: string;
+			password: string;
+	  }
+	| {
+			method: 'recovery_code';
+			username: string;
+			recovery_code: string;
+			new_password: string;
+	  }
+	| {
+			method: 'totp';
+			challenge_token: string;
+			code: string;
+	  }
+	| {
+			method: 'backup_code';
+			challenge_token: string;
+			backup_code: string;
+	  }
+	| {
+			method: 'passkey';
+			challenge_id: string;
+			credential_id: string;
+			client_data_json: string;
+			authenticator_data: string;
+			signature: string;
+			prf_output: string;
+	  }
+	| {
+			method: 'opaque_start';
+			username: string;
+			credential_request: string;
+	  }
+	| {
+			method: 'opaque';
+			login_id: string;
+			credential_finalization: string;
+			export_key: string;
+	  };
+
+/**
+ * Persistent sessions last until the user signs out instead of for 31 days.
+ * The device label names the session in the sessions list; it is derived
+ * from the user agent if missing.
+ */
+export type SessionOptions = {
+	persistent?: boolean;
+	device_label?: string;
+};
+
+export async function createPasskeyChallenge(fetcher: typeof fetch) {
+	return await api<PasskeyChallenge>(fetcher, '/auth/passkey', {
+		method: 'POST'
+	});
+}
+
 export async function authenticate(
 	fetcher: typeof fetch,
-	method: AuthenticationMethod
+	method: AuthenticationMethod,
+	options: SessionOptions = {}
+) {
+	return await api<
+		| {
+				user: User;
+				session: Session;
+				recovery_code?: string;
+		  }
+		| {
+				challenge: Challenge;
+		  }
+		| {
+				opaque: OpaqueLogin;
+		  }
+	>(fetcher, '/auth', {
+		method: 'POST',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify({ ...method, ...options })
+	});
+}
+
+export async function refreshSession(
+	fetcher: typeof fetch,
+	refreshToken: string
 ) {
 	return await api<{
-		user: User;
 		session: Session;
-	}>(fetcher, '/auth', {
+	}>(fetcher, '/auth/refresh', {
 		method: 'POST',
 		headers: {
 			'content-type': 'application/json'
 		},
-		body: JSON.stringify(method)
+		body: JSON.stringify({ refresh_token: refreshToken })
 	});
 }
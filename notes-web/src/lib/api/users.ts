@@ -1,9 +1,6 @@
+import type { Session } from './auth';
 import { api } from './client';
 
-export type Session = {
-	token: string;
-};
-
 export type User = {
 	id: string;
 	username: string;
@@ -11,12 +8,14 @@ export type User = {
 
 export type CreateUser = {
 	username: string;
+	password: string;
 };
 
 export async function createUser(fetcher: typeof fetch, user: CreateUser) {
 	return await api<{
 		user: User;
 		session: Session;
+		recovery_code: string;
 	}>(fetcher, '/users', {
 		method: 'POST',
 		headers: {
@@ -26,14 +25,37 @@ export async function createUser(fetcher: typeof fetch, user: CreateUser) {
 	});
 }
 
-export type CreatePassword = {
+/**
+ * Usernames are unique regardless of case and Unicode form, so renaming to a
+ * name that only differs in case from another user's fails with a conflict.
+ */
+export type UpdateUser = {
+	username: string;
+};
+
+export async function updateUser(
+	fetcher: typeof fetch,
+	userId: string,
+	user: UpdateUser
+) {
+	return await api<User>(fetcher, `/users/${userId}`, {
+		method: 'PATCH',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(user)
+	});
+}
+
+export type UpdatePassword = {
 	password: string;
+	current_password: string;
 };
 
 export async function setUserPassword(
 	fetcher: typeof fetch,
 	userId: string,
-	password: CreatePassword
+	password: UpdatePassword
 ) {
 	return await api<void>(fetcher, `/users/${userId}/password`, {
 		method: 'PUT',
@@ -44,6 +66,320 @@ export async function setUserPassword(
 	});
 }
 
+/**
+ * Proof of the secret the user signs in with: the OPAQUE export key for users
+ * that registered with OPAQUE, and the password otherwise.
+ */
+export type UserSecret = { password: string } | { export_key: string };
+
+/**
+ * Deletes the user and the notes that are not shared with another user.
+ * Returns the counts of what was destroyed.
+ */
+export async function deleteUser(
+	fetcher: typeof fetch,
+	userId: string,
+	secret: UserSecret
+) {
+	return await api<{
+		deleted: {
+			user_keys: number;
+			note_keys: number;
+			notes: number;
+			sessions: number;
+		};
+	}>(fetcher, `/users/${userId}`, {
+		method: 'DELETE',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(secret)
+	});
+}
+
+export async function createUserRecoveryCode(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<{
+		recovery_code: string;
+	}>(fetcher, `/users/${userId}/recovery-code`, { method: 'POST' });
+}
+
+export async function rotateUserKey(
+	fetcher: typeof fetch,
+	userId: string,
+	secret: UserSecret
+) {
+	return await api<{
+		session: Session;
+		recovery_code?: string;
+	}>(fetcher, `/users/${userId}/key`, {
+		method: 'POST',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(secret)
+	});
+}
+
+export async function enrollUserTotp(fetcher: typeof fetch, userId: string) {
+	return await api<{
+		secret: string;
+		uri: string;
+	}>(fetcher, `/users/${userId}/totp`, { method: 'POST' });
+}
+
+export async function confirmUserTotp(
+	fetcher: typeof fetch,
+	userId: string,
+	code: string
+) {
+	return await api<{
+		backup_codes: string[];
+	}>(fetcher, `/users/${userId}/totp/confirm`, {
+		method: 'POST',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify({ code })
+	});
+}
+
+export async function createUserTotpBackupCodes(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<{
+		backup_codes: string[];
+	}>(fetcher, `/users/${userId}/totp/backup-codes`, { method: 'POST' });
+}
+
+export async function deleteUserTotp(
+	fetcher: typeof fetch,
+	userId: string,
+	secret: UserSecret
+) {
+	return await api<void>(fetcher, `/users/${userId}/totp`, {
+		method: 'DELETE',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(secret)
+	});
+}
+
+/**
+ * Binary fields of the OPAQUE messages are encoded as unpadded base64url.
+ */
+export type SetOpaque = {
+	registration_upload: string;
+	export_key: string;
+	current_password?: string;
+	current_export_key?: string;
+};
+
+export async function startUserOpaqueRegistration(
+	fetcher: typeof fetch,
+	userId: string,
+	registrationRequest: string
+) {
+	return await api<{
+		registration_response: string;
+	}>(fetcher, `/users/${userId}/opaque/registration`, {
+		method: 'POST',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify({ registration_request: registrationRequest })
+	});
+}
+
+export async function setUserOpaque(
+	fetcher: typeof fetch,
+	userId: string,
+	opaque: SetOpaque
+) {
+	return await api<void>(fetcher, `/users/${userId}/opaque`, {
+		method: 'PUT',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(opaque)
+	});
+}
+
+/**
+ * Creation options for `PublicKeyCredential.parseCreationOptionsFromJSON()`.
+ */
+export type UserPasskeyChallenge = {
+	challenge_id: string;
+	options: PublicKeyCredentialCreationOptionsJSON;
+};
+
+export type UserPasskey = {
+	id: string;
+	name: string;
+	time_created?: string;
+	last_used_time?: string;
+};
+
+export type CreateUserPasskey = {
+	challenge_id: string;
+	client_data_json: string;
+	attestation_object: string;
+	prf_output: string;
+	name: string;
+};
+
+export async function createUserPasskeyChallenge(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<UserPasskeyChallenge>(
+		fetcher,
+		`/users/${userId}/passkeys/challenge`,
+		{ method: 'POST' }
+	);
+}
+
+export async function createUserPasskey(
+	fetcher: typeof fetch,
+	userId: string,
+	passkey: CreateUserPasskey
+) {
+	return await api<UserPasskey>(fetcher, `/users/${userId}/passkeys`, {
+		method: 'POST',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(passkey)
+	});
+}
+
+export async function getUserPasskeys(fetcher: typeof fetch, userId: string) {
+	return await api<{
+		passkeys: UserPasskey[];
+	}>(fetcher, `/users/${userId}/passkeys`);
+}
+
+export async function deleteUserPasskey(
+	fetcher: typeof fetch,
+	userId: string,
+	passkeyId: string
+) {
+	return await api<void>(fetcher, `/users/${userId}/passkeys/${passkeyId}`, {
+		method: 'DELETE'
+	});
+}
+
+export type AccessTokenScope = 'notes:read' | 'notes:write';
+
+export type UserAccessToken = {
+	id: string;
+	name: string;
+	scopes: AccessTokenScope[];
+	time_created?: string;
+	expiration_time?: string;
+	last_used_time?: string;
+};
+
+export type CreateUserAccessToken = {
+	name: string;
+	scopes: AccessTokenScope[];
+	expiration_time?: string;
+};
+
+export async function createUserAccessToken(
+	fetcher: typeof fetch,
+	userId: string,
+	accessToken: CreateUserAccessToken
+) {
+	return await api<{
+		access_token: UserAccessToken;
+		token: string;
+	}>(fetcher, `/users/${userId}/access-tokens`, {
+		method: 'POST',
+		headers: {
+			'content-type': 'application/json'
+		},
+		body: JSON.stringify(accessToken)
+	});
+}
+
+export async function getUserAccessTokens(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<{
+		access_tokens: UserAccessToken[];
+	}>(fetcher, `/users/${userId}/access-tokens`);
+}
+
+export async function deleteUserAccessToken(
+	fetcher: typeof fetch,
+	userId: string,
+	accessTokenId: string
+) {
+	return await api<void>(
+		fetcher,
+		`/users/${userId}/access-tokens/${accessTokenId}`,
+		{ method: 'DELETE' }
+	);
+}
+
+export type LoginAttempt = {
+	id: string;
+	ip_address?: string;
+	method: string;
+	succeeded: boolean;
+	time_created: string;
+};
+
+/**
+ * Gets the latest attempts to sign in to the account, newest first.
+ */
+export async function getUserLoginAttempts(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<{
+		login_attempts: LoginAttempt[];
+	}>(fetcher, `/users/${userId}/login-attempts`);
+}
+
+export type UserSessionInfo = {
+	id: string;
+	time_created?: string;
+	last_seen_time?: string;
+	expiration_time?: string;
+	user_agent?: string;
+	ip_address?: string;
+	device_label?: string;
+	current: boolean;
+};
+
+export async function getUserSessions(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<{
+		sessions: UserSessionInfo[];
+	}>(fetcher, `/users/${userId}/sessions`);
+}
+
+/**
+ * Signs the user out everywhere but the current session.
+ */
+export async function deleteOtherUserSessions(
+	fetcher: typeof fetch,
+	userId: string
+) {
+	return await api<void>(fetcher, `/users/${userId}/sessions`, {
+		method: 'DELETE'
+	});
+}
+
 export async function deleteUserSession(
 	fetcher: typeof fetch,
 	userId: string,
@@ -0,0 +1,29 @@
+import { dev } from '$app/environment';
+import type { Session } from '$lib/api/auth';
+import type { Cookies } from '@sveltejs/kit';
+
+/**
+ * Stores the tokens of a session. The session token cookie expires along
+ * with the token, after which the refresh token is traded for a new pair.
+ */
+export function setSession(cookies: Cookies, session: Session) {
+	cookies.set('sessionToken', session.token, {
+		path: '/',
+		httpOnly: true,
+		sameSite: 'strict',
+		secure: !dev,
+		expires: new Date(session.expiration_time)
+	});
+	cookies.set('refreshToken', session.refresh_token, {
+		path: '/',
+		httpOnly: true,
+		sameSite: 'strict',
+		secure: !dev,
+		maxAge: 31 * 24 * 60 * 60
+	});
+}
+
+export function deleteSession(cookies: Cookies) {
+	cookies.delete('sessionToken', { path: '/' });
+	cookies.delete('refreshToken', { path: '/' });
+}
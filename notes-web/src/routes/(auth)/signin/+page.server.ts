@@ -1,22 +1,65 @@
 import { fail, redirect } from '@sveltejs/kit';
 import type { Actions } from './$types';
 import { authenticate } from '$lib/api/auth';
-import { dev } from '$app/environment';
+import { setSession } from '$lib/server/session';
 import type { ActionFailure } from '@sveltejs/kit';
 
 export type Error = {
 	username?: string;
+	challengeToken?: string;
 	message: string;
 };
 
+export type Challenge = {
+	username: string;
+	challengeToken: string;
+};
+
 export const actions = {
 	default: async ({
 		request,
 		fetch,
 		cookies
-	}): Promise<ActionFailure<Error>> => {
+	}): Promise<ActionFailure<Error> | Challenge> => {
 		const payload = await request.formData();
 
+		// The second step of a login of a user with TOTP enabled
+		const challengeToken = payload.get('challenge_token')?.toString();
+		if (challengeToken) {
+			const username = payload.get('username')?.toString();
+			const code = payload.get('code')?.toString().trim();
+			if (!code) {
+				return fail(400, {
+					username,
+					challengeToken,
+					message: 'code is required'
+				});
+			}
+
+			// Codes of six digits come from the authenticator, anything
+			// else is taken for a backup code
+			const auth_result = await authenticate(
+				fetch,
+				/^\d{6}$/.test(code)
+					? { method: 'totp', challenge_token: challengeToken, code }
+					: {
+							method: 'backup_code',
+							challenge_token: challengeToken,
+							backup_code: code
+						}
+			);
+			if (!auth_result.ok || !('session' in auth_result.data)) {
+				return fail(500, {
+					username,
+					challengeToken,
+					message: 'authentication failed'
+				});
+			}
+
+			setSession(cookies, auth_result.data.session);
+			redirect(303, '/');
+		}
+
 		const username = payload.get('username')?.toString();
 		if (!username) {
 			return fail(400, {
@@ -43,13 +86,16 @@ export const actions = {
 			});
 		}
 
-		cookies.set('sessionToken', auth_result.data.session.token, {
-			path: '/',
-			httpOnly: true,
-			sameSite: 'strict',
-			secure: !dev
-		});
+		// Users with TOTP enabled have to pass a code first
+		if ('challenge' in auth_result.data) {
+			return {
+				username,
+				challengeToken: auth_result.data.challenge.token
+			};
+		}
 
+		setSession(cookies, auth_result.data.session);
 		redirect(303, '/');
 	}
 } satisfies Actions;
+
@@ -1,8 +1,9 @@
 import { deleteUserSession } from '$lib/api/users';
+import { deleteSession } from '$lib/server/session';
 import { fail, redirect, type Actions } from '@sveltejs/kit';
 
 export const actions = {
-	default: async ({ locals, fetch }) => {
+	default: async ({ locals, fetch, cookies }) => {
 		if (!locals.session) {
 			redirect(303, '/signin');
 		}
@@ -18,6 +19,7 @@ export const actions = {
 			});
 		}
 
+		deleteSession(cookies);
 		redirect(303, '/signin');
 	}
 } satisfies Actions;
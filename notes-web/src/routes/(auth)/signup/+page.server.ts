@@ -1,7 +1,7 @@
 import { createUser } from '$lib/api/users';
 import { fail, redirect, type ActionFailure } from '@sveltejs/kit';
 import type { Actions } from './$types';
-import { dev } from '$app/environment';
+import { setSession } from '$lib/server/session';
 
 export type Error = {
 	username?: string;
@@ -24,23 +24,34 @@ export const actions = {
 			});
 		}
 
+		const password = payload.get('password')?.toString();
+		if (!password || password.length < 6) {
+			return fail(400, {
+				username,
+				message: 'password must contain at least 6 characters'
+			});
+		}
+
 		const user_result = await createUser(fetch, {
-			username
+			username,
+			password
 		});
 
+		if (!user_result.ok && user_result.error.message.endsWith('409')) {
+			return fail(409, {
+				username,
+				message: 'username is taken'
+			});
+		}
+
 		if (!user_result.ok) {
 			return fail(500, {
 				message: 'something went wrong'
 			});
 		}
 
-		cookies.set('sessionToken', user_result.data.session.token, {
-			path: '/',
-			httpOnly: true,
-			sameSite: 'strict',
-			secure: !dev
-		});
+		setSession(cookies, user_result.data.session);
 
-		redirect(303, '/signup/password');
+		redirect(303, '/');
 	}
 } satisfies Actions;